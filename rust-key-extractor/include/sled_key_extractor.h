@@ -0,0 +1,84 @@
+/*
+ * C API of sled-key-extractor - see src/ffi.rs
+ *
+ * Every function returns the exit code the CLI would end with (0 on success,
+ * 2 for invalid arguments, otherwise see "Exit Codes" in the README) and, if
+ * report_json isn't NULL, stores a JSON report there that the caller releases
+ * with ske_free_string().
+ */
+
+#ifndef SLED_KEY_EXTRACTOR_H
+#define SLED_KEY_EXTRACTOR_H
+
+/* Generated with cbindgen - do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Extract the keys of a sled crypto store into an export file
+ *
+ * Like `extract --sled-path SLED_PATH --output OUTPUT_PATH`, with
+ * `--skip-errors` if `skip_errors` is set. `passphrase` may be null for an
+ * unencrypted store. The report holds the number of keys, failed entries and
+ * rooms.
+ *
+ * # Safety
+ *
+ * The string arguments must be null or NUL-terminated strings, and
+ * `report_json` must be null or valid for writing a pointer.
+ */
+int32_t ske_extract(const char *sled_path,
+                    const char *passphrase,
+                    const char *output_path,
+                    bool skip_errors,
+                    char **report_json);
+
+/**
+ * Migrate the keys of a sled crypto store into a SQLite crypto store
+ *
+ * Like `migrate --sled-path SLED_PATH --target TARGET_PATH`, with
+ * `--skip-errors` if `skip_errors` is set. Either passphrase may be null. The
+ * report holds the number of sessions read and imported.
+ *
+ * # Safety
+ *
+ * As for [`ske_extract`].
+ */
+int32_t ske_migrate(const char *sled_path,
+                    const char *passphrase,
+                    const char *target_path,
+                    const char *target_passphrase,
+                    bool skip_errors,
+                    char **report_json);
+
+/**
+ * Compare the sessions of a sled crypto store with a migrated SQLite store
+ *
+ * Like `verify-migration`. Returns 1 with the full report if any session
+ * differs between the stores.
+ *
+ * # Safety
+ *
+ * As for [`ske_extract`].
+ */
+int32_t ske_verify(const char *sled_path,
+                   const char *passphrase,
+                   const char *target_path,
+                   const char *target_passphrase,
+                   char **report_json);
+
+/**
+ * Release a report returned by one of the functions above
+ *
+ * # Safety
+ *
+ * `report` must be null or a pointer returned through `report_json` that
+ * wasn't released before.
+ */
+void ske_free_string(char *report);
+
+#endif  /* SLED_KEY_EXTRACTOR_H */
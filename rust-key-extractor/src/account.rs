@@ -0,0 +1,114 @@
+//! Guarding against migrating the wrong account
+//!
+//! With many bot data directories side by side it is easy to point the tool
+//! at the wrong one and import its keys into another bot's identity. With
+//! `--expected-user` and `--expected-device` the account stored in the sled
+//! crypto store is compared with the given IDs before anything is extracted,
+//! and the run aborts with [`ExtractorError::AccountMismatch`] if they differ.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use tracing::info;
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::{load_store_cipher, redact, trees};
+
+/// Options naming the account a store must belong to
+#[derive(Args, Debug, Clone, Default)]
+pub struct ExpectedAccountArgs {
+    /// Abort unless the store belongs to this user ID
+    #[arg(long, value_name = "USER_ID")]
+    pub expected_user: Option<String>,
+
+    /// Abort unless the store belongs to this device ID
+    #[arg(long, value_name = "DEVICE_ID")]
+    pub expected_device: Option<String>,
+}
+
+impl ExpectedAccountArgs {
+    /// Whether no account is expected
+    pub fn is_empty(&self) -> bool {
+        self.expected_user.is_none() && self.expected_device.is_none()
+    }
+
+    /// Compare the account of the sled crypto store at `path`
+    pub fn check_store(
+        &self,
+        settings: &Settings,
+        path: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let db = settings.sled_config()
+            .path(path)
+            .open()
+            .map_err(ExtractorError::SledIo)
+            .context("Failed to open sled database")?;
+        let store_cipher = load_store_cipher(settings, &db, passphrase.unwrap_or(""))?;
+        let (account, _, _) = trees::extract_account(settings, &db, store_cipher.as_ref(), true)
+            .context("Failed to read the account of the store")?;
+        let account = account.ok_or_else(|| {
+            ExtractorError::AccountMismatch("the store has no account".to_string())
+        })?;
+
+        self.check(settings, &account.user_id, &account.device_id)
+    }
+
+    /// Compare a user and device ID with the expected ones
+    pub fn check(&self, settings: &Settings, user_id: &str, device_id: &str) -> Result<()> {
+        if let Some(expected) = &self.expected_user {
+            if expected != user_id {
+                return Err(ExtractorError::AccountMismatch(format!(
+                    "the store belongs to {}, not {}",
+                    redact::id(settings, user_id),
+                    redact::id(settings, expected)
+                ))
+                .into());
+            }
+        }
+        if let Some(expected) = &self.expected_device {
+            if expected != device_id {
+                return Err(ExtractorError::AccountMismatch(format!(
+                    "the store belongs to device {}, not {}",
+                    redact::id(settings, device_id),
+                    redact::id(settings, expected)
+                ))
+                .into());
+            }
+        }
+
+        info!("Store belongs to the expected account");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_compares_user_and_device() {
+        let settings = Settings::default();
+        let expected = ExpectedAccountArgs {
+            expected_user: Some("@bot:example.org".to_string()),
+            expected_device: Some("ABCDEF".to_string()),
+        };
+        assert!(expected.check(&settings, "@bot:example.org", "ABCDEF").is_ok());
+
+        let error = expected.check(&settings, "@other:example.org", "ABCDEF").unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 12);
+        assert!(expected.check(&settings, "@bot:example.org", "GHIJKL").is_err());
+
+        let user_only = ExpectedAccountArgs {
+            expected_user: Some("@bot:example.org".to_string()),
+            expected_device: None,
+        };
+        assert!(user_only.check(&settings, "@bot:example.org", "GHIJKL").is_ok());
+    }
+}
@@ -0,0 +1,260 @@
+//! Offline analysis of a single stored value
+//!
+//! `analyze-pickle` takes one raw sled value - the `raw_value` of a failed
+//! entry or a hex dump - and walks it through the stages extraction goes
+//! through: decrypting it, parsing the JSON and deserializing the pickle. Each
+//! stage is reported like a `doctor` check, and the failing one says where it
+//! broke: the line and column for JSON, the field path for pickles. The value
+//! is also tried against the pickle types of the other crypto-store trees, to
+//! tell an entry stored in the wrong tree from a corrupted one. Sessions with a
+//! libolm pickle are converted the way extraction converts them, see
+//! [`crate::legacy`].
+
+use anyhow::{Context, Result};
+use matrix_sdk_crypto::olm::{
+    InboundGroupSession, PickledAccount, PickledCrossSigningIdentity,
+    PickledInboundGroupSession, PickledOutboundGroupSession, PickledSession,
+};
+use matrix_sdk_store_encryption::{EncryptedValue, StoreCipher};
+use serde::de::DeserializeOwned;
+use zeroize::Zeroizing;
+
+use crate::doctor::Check;
+use crate::settings::Settings;
+use crate::{legacy, redact};
+
+/// Number of bytes shown on each side of the position a JSON error points at
+const CONTEXT_BYTES: usize = 32;
+
+/// Decode a value given on the command line, as hex or base64
+///
+/// Whitespace (as in hex dumps) and a leading `0x` are ignored. Input made of
+/// an even number of hex digits is read as hex, anything else as base64.
+pub fn decode_input(input: &str) -> Result<Vec<u8>> {
+    use base64::Engine;
+
+    let compact: String = input.split_whitespace().collect();
+    let compact = compact.strip_prefix("0x").unwrap_or(&compact);
+
+    if compact.len().is_multiple_of(2) && compact.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return hex::decode(compact).context("Invalid hex value");
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(compact)
+        .context("Value is neither hex nor base64")
+}
+
+/// Deserialize `value`, describing a failure with the path of the offending field
+fn deserialize_at_path<T: DeserializeOwned>(value: &serde_json::Value) -> Result<T, String> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().to_string();
+        if path == "." {
+            e.into_inner().to_string()
+        } else {
+            format!("at `{}`: {}", path, e.into_inner())
+        }
+    })
+}
+
+/// The bytes around the position a JSON error points at
+fn json_context(data: &[u8], line: usize, column: usize) -> String {
+    let line_start: usize = data
+        .split(|&byte| byte == b'\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.len() + 1)
+        .sum();
+    let offset = (line_start + column.saturating_sub(1)).min(data.len());
+    let start = offset.saturating_sub(CONTEXT_BYTES);
+    let end = (offset + CONTEXT_BYTES).min(data.len());
+
+    format!(
+        "{}⟨here⟩{}",
+        String::from_utf8_lossy(&data[start..offset]),
+        String::from_utf8_lossy(&data[offset..end])
+    )
+}
+
+/// Pickle types of the other crypto-store trees, tried when a value isn't an
+/// inbound group session
+fn other_schemas(value: &serde_json::Value) -> Vec<&'static str> {
+    let mut matches = Vec::new();
+    if deserialize_at_path::<PickledOutboundGroupSession>(value).is_ok() {
+        matches.push("outbound group session");
+    }
+    if deserialize_at_path::<PickledSession>(value).is_ok() {
+        matches.push("Olm session");
+    }
+    if deserialize_at_path::<PickledAccount>(value).is_ok() {
+        matches.push("account");
+    }
+    if deserialize_at_path::<PickledCrossSigningIdentity>(value).is_ok() {
+        matches.push("cross-signing identity");
+    }
+    matches
+}
+
+/// Decrypt `raw` if needed, stopping at the first stage that fails
+fn decrypt(
+    raw: &[u8],
+    store_cipher: Option<&StoreCipher>,
+    checks: &mut Vec<Check>,
+) -> Option<Zeroizing<Vec<u8>>> {
+    let envelope = serde_json::from_slice::<EncryptedValue>(raw);
+
+    let Some(cipher) = store_cipher else {
+        if envelope.is_ok() {
+            checks.push(Check::error(
+                "decryption",
+                "the value is encrypted, but no store cipher was given",
+                "Pass --sled-path or --store-cipher, and the store passphrase",
+            ));
+            return None;
+        }
+        checks.push(Check::ok("decryption", "no store cipher - reading the value as plaintext"));
+        return Some(Zeroizing::new(raw.to_vec()));
+    };
+
+    let envelope = match envelope {
+        Ok(envelope) => envelope,
+        Err(e) if serde_json::from_slice::<serde_json::Value>(raw).is_ok() => {
+            checks.push(Check::warning(
+                "envelope",
+                format!("not an encrypted value ({}), but valid JSON", e),
+                "The entry was stored without the store cipher; reading it as plaintext",
+            ));
+            return Some(Zeroizing::new(raw.to_vec()));
+        }
+        Err(e) => {
+            checks.push(Check::error(
+                "envelope",
+                format!("not an encrypted value: {}", e),
+                "The stored value is truncated or overwritten; it can't be recovered",
+            ));
+            return None;
+        }
+    };
+    checks.push(Check::ok("envelope", "encrypted value"));
+
+    match cipher.decrypt_value_data(envelope) {
+        Ok(plaintext) => {
+            checks.push(Check::ok("decryption", format!("{} bytes of plaintext", plaintext.len())));
+            Some(Zeroizing::new(plaintext))
+        }
+        Err(e) => {
+            checks.push(Check::error(
+                "decryption",
+                format!("{}", e),
+                "The ciphertext is damaged, or the entry was written with another store cipher",
+            ));
+            None
+        }
+    }
+}
+
+/// Analyze a raw value of the inbound group sessions tree
+///
+/// Libolm pickles are decrypted with `legacy_pickle_key`.
+pub fn analyze(
+    settings: &Settings,
+    raw: &[u8],
+    store_cipher: Option<&StoreCipher>,
+    legacy_pickle_key: &[u8],
+) -> Vec<Check> {
+    let mut checks = vec![Check::ok("input", format!("{} bytes", raw.len()))];
+
+    let Some(plaintext) = decrypt(raw, store_cipher, &mut checks) else {
+        return checks;
+    };
+
+    let value: serde_json::Value = match serde_json::from_slice(&plaintext) {
+        Ok(value) => {
+            checks.push(Check::ok("json", "valid JSON"));
+            value
+        }
+        Err(e) => {
+            checks.push(Check::error(
+                "json",
+                format!("{}: {}", e, json_context(&plaintext, e.line(), e.column())),
+                "The plaintext is truncated or corrupted; it can't be recovered",
+            ));
+            return checks;
+        }
+    };
+
+    let pickle = match deserialize_at_path::<PickledInboundGroupSession>(&value) {
+        Ok(pickle) => {
+            checks.push(Check::ok("pickle", "valid inbound group session pickle"));
+            pickle
+        }
+        Err(_) if legacy::libolm_pickle(&value).is_some() => {
+            let libolm =
+                Zeroizing::new(legacy::libolm_pickle(&value).unwrap_or_default().to_owned());
+            match legacy::upgrade_pickle(&mut value.clone(), &libolm, legacy_pickle_key) {
+                Ok(pickle) => {
+                    checks.push(Check::ok("pickle", "libolm pickle, converted to current format"));
+                    pickle
+                }
+                Err(e) => {
+                    checks.push(Check::error(
+                        "pickle",
+                        format!("libolm pickle can't be read: {:#}", e),
+                        "Pass the key the store pickled sessions with (--legacy-pickle-key)",
+                    ));
+                    return checks;
+                }
+            }
+        }
+        Err(e) => {
+            let matches = other_schemas(&value);
+            let remedy = if matches.is_empty() {
+                "The JSON matches no known pickle schema - it was likely written by a \
+                 different matrix-sdk version"
+                    .to_string()
+            } else {
+                format!(
+                    "The value is a valid {} pickle - it is stored in the wrong tree",
+                    matches.join(" / ")
+                )
+            };
+            checks.push(Check::error(
+                "pickle",
+                format!("not an inbound group session: {}", e),
+                remedy,
+            ));
+            return checks;
+        }
+    };
+
+    checks.push(match InboundGroupSession::from_pickle(pickle) {
+        Ok(session) => Check::ok(
+            "session",
+            format!("restored session of {}", redact::id(settings, session.room_id())),
+        ),
+        Err(e) => Check::error(
+            "session",
+            format!("pickle can't be restored: {}", e),
+            "The pickled session state is invalid; the key can't be recovered from this entry",
+        ),
+    });
+
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doctor::Status;
+
+    #[test]
+    fn test_analyze_reports_json_error_position() {
+        assert_eq!(decode_input("7b 22\n61 22").unwrap(), b"{\"a\"");
+        assert_eq!(decode_input("eyJhIg==").unwrap(), b"{\"a\"");
+
+        let checks = analyze(&Settings::default(), b"{\"pickle\": {\"x\": }", None, &[]);
+        let json = checks.iter().find(|check| check.name == "json").unwrap();
+
+        assert_eq!(json.status, Status::Error);
+        assert!(json.detail.contains("column 18"));
+        assert!(json.detail.contains("{\"x\": ⟨here⟩}"));
+    }
+}
@@ -0,0 +1,296 @@
+//! Reading sled stores from backup archives
+//!
+//! Often the only copy of a bot's store is a tarball of its whole data
+//! directory. With `--sled-archive` the extractor looks for the sled store in
+//! the archive (a directory holding sled's `db` and `conf` files), unpacks
+//! just that directory into a private temp directory and extracts from there.
+//! The unpacked copy is removed again at the end of the run. Plain, gzip- and
+//! zstd-compressed tar archives and zip files are supported; the kind is
+//! detected from the file's first bytes.
+//!
+//! `--backup-first` goes the other way: before a store is opened, its
+//! directory is packed into a compressed tar archive with a `sha256sum`
+//! checksum next to it, a pristine copy to return to if sled's recovery
+//! rewrites the store. Such a backup can be read again with `--sled-archive`.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use crate::error::ExtractorError;
+use crate::format::{Compression, CompressWriter};
+use crate::store::StoreCopy;
+use crate::{create_private_tmp, persist_private_tmp, report};
+
+/// Files every sled store directory has
+const SLED_FILES: [&str; 2] = ["db", "conf"];
+
+/// Container format of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Detect the format from the magic bytes at the start of the file
+    fn detect(path: &Path) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let read = file.read(&mut magic).with_context(|| format!("Failed to read {:?}", path))?;
+
+        Ok(match &magic[..read] {
+            [0x1f, 0x8b, ..] => Self::TarGz,
+            [0x28, 0xb5, 0x2f, 0xfd] => Self::TarZst,
+            [b'P', b'K', 0x03, 0x04] => Self::Zip,
+            _ => Self::Tar,
+        })
+    }
+}
+
+/// Unpack the sled store in `archive` into a temp directory
+///
+/// `inner` selects the store directory inside the archive; without it the
+/// archive must hold exactly one store. Returns the guard of the temp
+/// directory and the path of the unpacked store.
+pub fn unpack_store(archive: &Path, inner: Option<&Path>) -> Result<(StoreCopy, PathBuf)> {
+    if !archive.is_file() {
+        return Err(ExtractorError::StoreNotFound(archive.to_path_buf()).into());
+    }
+    let kind = ArchiveKind::detect(archive)?;
+    info!("Reading {:?} archive {:?}", kind, archive);
+
+    let entries = list_files(archive, kind)?;
+    let stores = find_stores(&entries);
+    let store = match inner {
+        Some(inner) => {
+            let inner = normalize(inner).context("Invalid store path inside the archive")?;
+            if !stores.contains(&inner) {
+                return Err(ExtractorError::StoreNotFound(archive.join(inner)).into());
+            }
+            inner
+        }
+        None => match stores.as_slice() {
+            [store] => store.clone(),
+            [] => anyhow::bail!("No sled store (a directory with db and conf) in {:?}", archive),
+            _ => anyhow::bail!(
+                "{:?} holds several sled stores - select one with --sled-path: {}",
+                archive,
+                stores.iter().map(|store| format!("{:?}", store)).collect::<Vec<_>>().join(", ")
+            ),
+        },
+    };
+
+    let copy = StoreCopy::empty()?;
+    info!("Unpacking {:?} to {:?}", store, copy.path());
+    let bytes = unpack_dir(archive, kind, &store, copy.path())
+        .with_context(|| format!("Failed to unpack {:?} from {:?}", store, archive))?;
+    info!("Unpacked {} bytes", bytes);
+
+    let path = copy.path().to_path_buf();
+    Ok((copy, path))
+}
+
+/// Pack the store directory `source` into a tar archive at `target`
+///
+/// The archive is zstd-compressed, gzip-compressed for a `.gz` or `.tgz` name
+/// and uncompressed for a `.tar` name. Its contents are in a directory named
+/// like the store. The SHA-256 of the archive is written to `<target>.sha256`
+/// in `sha256sum` format and returned.
+pub fn create_backup(source: &Path, target: &Path) -> Result<String> {
+    let name = target.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let compression = if name.ends_with(".tar") {
+        None
+    } else if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Some(Compression::Gzip)
+    } else {
+        Some(Compression::Zstd)
+    };
+    let store_name = source.file_name().map_or("store".into(), |name| name.to_os_string());
+
+    info!("Backing up {:?} to {:?}", source, target);
+    let (file, tmp_path) = create_private_tmp(target)
+        .with_context(|| format!("Failed to create backup {:?}", target))?;
+    let mut tar = tar::Builder::new(CompressWriter::new(file, compression)?);
+    tar.follow_symlinks(false);
+    tar.append_dir_all(&store_name, source)
+        .with_context(|| format!("Failed to archive {:?}", source))?;
+    let file = tar.into_inner().context("Failed to write backup")?.finish()?;
+    file.sync_all().context("Failed to write backup")?;
+    drop(file);
+    persist_private_tmp(&tmp_path, target)
+        .with_context(|| format!("Failed to write backup {:?}", target))?;
+
+    let digest = report::digest_file(target)?;
+    let mut checksum_name = target.as_os_str().to_os_string();
+    checksum_name.push(".sha256");
+    std::fs::write(&checksum_name, format!("{}  {}\n", digest.sha256, name))
+        .with_context(|| format!("Failed to write {:?}", checksum_name))?;
+    info!("Backup written: {} bytes, SHA-256 {}", digest.bytes, digest.sha256);
+
+    Ok(digest.sha256)
+}
+
+/// Sled store directories among the files of an archive, sorted
+fn find_stores(files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut stores: Vec<PathBuf> = files
+        .iter()
+        .filter(|file| file.file_name().is_some_and(|name| name == SLED_FILES[0]))
+        .filter_map(|file| file.parent())
+        .filter(|dir| SLED_FILES.iter().all(|name| files.contains(&dir.join(name))))
+        .map(Path::to_path_buf)
+        .collect();
+    stores.sort();
+    stores
+}
+
+/// A relative path without `.`, `..` or root components
+///
+/// Returns `None` for paths that would leave the directory they are unpacked to.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Reader over the tar stream of a (possibly compressed) tar archive
+fn tar_stream(path: &Path, kind: ArchiveKind) -> Result<Box<dyn Read>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    Ok(match kind {
+        ArchiveKind::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+        ArchiveKind::TarZst => Box::new(zstd::Decoder::new(file)?),
+        _ => Box::new(file),
+    })
+}
+
+/// Paths of the regular files in an archive
+fn list_files(path: &Path, kind: ArchiveKind) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    if kind == ArchiveKind::Zip {
+        let mut zip = zip::ZipArchive::new(File::open(path)?).context("Invalid zip archive")?;
+        for index in 0..zip.len() {
+            let entry = zip.by_index(index)?;
+            if entry.is_file() {
+                files.extend(entry.enclosed_name().as_deref().and_then(normalize));
+            }
+        }
+        return Ok(files);
+    }
+
+    let mut tar = tar::Archive::new(tar_stream(path, kind)?);
+    for entry in tar.entries().context("Invalid tar archive")? {
+        let entry = entry.context("Invalid tar archive")?;
+        if entry.header().entry_type().is_file() {
+            files.extend(normalize(&entry.path()?));
+        }
+    }
+    Ok(files)
+}
+
+/// Unpack the regular files below `dir` in the archive into `target`
+///
+/// Returns the number of bytes written.
+fn unpack_dir(path: &Path, kind: ArchiveKind, dir: &Path, target: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    let mut unpack = |name: &Path, reader: &mut dyn Read| -> Result<()> {
+        let Some(relative) = normalize(name) else {
+            return Ok(());
+        };
+        let Ok(relative) = relative.strip_prefix(dir) else {
+            return Ok(());
+        };
+        let file_path = target.join(relative);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&file_path)
+            .with_context(|| format!("Failed to create {:?}", file_path))?;
+        bytes += std::io::copy(reader, &mut file)?;
+        Ok(())
+    };
+
+    if kind == ArchiveKind::Zip {
+        let mut zip = zip::ZipArchive::new(File::open(path)?).context("Invalid zip archive")?;
+        for index in 0..zip.len() {
+            let mut entry = zip.by_index(index)?;
+            if let Some(name) = entry.enclosed_name().filter(|_| entry.is_file()) {
+                unpack(&name, &mut entry)?;
+            }
+        }
+    } else {
+        let mut tar = tar::Archive::new(tar_stream(path, kind)?);
+        for entry in tar.entries().context("Invalid tar archive")? {
+            let mut entry = entry.context("Invalid tar archive")?;
+            if entry.header().entry_type().is_file() {
+                let name = entry.path()?.into_owned();
+                unpack(&name, &mut entry)?;
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_backup_can_be_read_back() {
+        let dir = TempDir::new("backup-test");
+        let source = dir.join("crypto");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("db"), b"sled").unwrap();
+        std::fs::write(source.join("conf"), b"conf").unwrap();
+
+        let backup = dir.join("crypto.tar.zst");
+        let sha256 = create_backup(&source, &backup).unwrap();
+        let checksum = std::fs::read_to_string(dir.join("crypto.tar.zst.sha256")).unwrap();
+        assert_eq!(checksum, format!("{}  crypto.tar.zst\n", sha256));
+
+        let (_copy, store) = unpack_store(&backup, None).unwrap();
+        assert_eq!(std::fs::read(store.join("db")).unwrap(), b"sled");
+    }
+
+    #[test]
+    fn test_unpacks_only_the_store_from_a_tarball() {
+        let dir = TempDir::new("archive-test");
+        let path = dir.join("data.tar.gz");
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            File::create(&path).unwrap(),
+            flate2::Compression::fast(),
+        ));
+        for (name, data) in [
+            ("./data/crypto/db", &b"sled"[..]),
+            ("./data/crypto/conf", b"segment_size: 524288"),
+            ("./data/crypto/blobs/1", b"blob"),
+            ("./data/media/avatar.png", b"png"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o600);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let (copy, store) = unpack_store(&path, None).unwrap();
+
+        assert_eq!(std::fs::read(store.join("db")).unwrap(), b"sled");
+        assert_eq!(std::fs::read(store.join("blobs").join("1")).unwrap(), b"blob");
+        assert!(!copy.path().join("media").exists());
+        assert!(!copy.path().join("data").exists());
+    }
+}
@@ -0,0 +1,289 @@
+//! Extracting many stores in one run
+//!
+//! Deployments with one bot per sled store would otherwise need an `extract`
+//! invocation per bot. With `--sled-path-glob` every matching store directory
+//! is extracted in turn with the same flags; `--output` becomes a directory
+//! holding one export (and failed-sessions file) per store, named after the
+//! part of its path that differs between the matches, plus a
+//! `batch-summary.json` over all of them. A store that fails is recorded in
+//! the summary and the batch carries on with the next one. With
+//! `--concurrency N`, up to N stores are extracted at once (see
+//! [`crate::jobs`]). After Ctrl-C no further store is started, and the batch
+//! stops once the running stores have written their partial exports.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::{interrupt, jobs, run_extract, split, write_private_file, ExtractArgs};
+
+/// File name of the combined summary in the output directory
+pub const SUMMARY_FILE: &str = "batch-summary.json";
+
+/// Counts of one extraction
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct StoreTotals {
+    /// Keys written to the export
+    pub total_keys: usize,
+    /// Entries that could not be read
+    pub failed_keys: usize,
+    /// Rooms with keys
+    pub rooms: usize,
+}
+
+/// Outcome of one store of the batch
+#[derive(Debug, Serialize)]
+pub struct StoreResult {
+    /// Store directory
+    pub sled_path: PathBuf,
+    /// Export file or directory
+    pub output: PathBuf,
+    /// Counts of the extraction, zero if it failed
+    #[serde(flatten)]
+    pub totals: StoreTotals,
+    /// Why the store could not be extracted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Combined summary of a batch, written to [`SUMMARY_FILE`]
+#[derive(Debug, Default, Serialize)]
+pub struct BatchSummary {
+    /// Pattern the stores were selected with
+    pub pattern: String,
+    /// Keys written over all stores
+    pub total_keys: usize,
+    /// Failed entries over all stores
+    pub failed_keys: usize,
+    /// Stores that could not be extracted
+    pub failed_stores: usize,
+    /// Every store, in the order of the pattern matches
+    pub stores: Vec<StoreResult>,
+}
+
+/// Store directories matching `pattern`, sorted
+pub fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut stores = Vec::new();
+    for entry in glob::glob(pattern).with_context(|| format!("Invalid pattern {:?}", pattern))? {
+        match entry {
+            Ok(path) if path.is_dir() => stores.push(path),
+            Ok(path) => warn!("Skipping {:?} - not a directory", path),
+            Err(e) => warn!("Skipping {:?}: {}", e.path(), e.error()),
+        }
+    }
+    if stores.is_empty() {
+        return Err(ExtractorError::StoreNotFound(PathBuf::from(pattern)).into());
+    }
+    stores.sort();
+    Ok(stores)
+}
+
+/// A name for each store, from the path components that differ between them
+///
+/// `/data/bots/alice/crypto` and `/data/bots/bob/crypto` become `alice` and
+/// `bob`. Several differing components are joined with `-`.
+pub fn store_names(stores: &[PathBuf]) -> Vec<String> {
+    let components: Vec<Vec<String>> = stores.iter().map(|path| path_components(path)).collect();
+    let shortest = components.iter().map(Vec::len).min().unwrap_or(0);
+
+    let common_prefix = (0..shortest)
+        .take_while(|&i| components.iter().all(|c| c[i] == components[0][i]))
+        .count();
+    let common_suffix = (0..shortest - common_prefix)
+        .take_while(|&i| {
+            let last = &components[0][components[0].len() - 1 - i];
+            components.iter().all(|c| &c[c.len() - 1 - i] == last)
+        })
+        .count();
+
+    components
+        .iter()
+        .map(|c| {
+            let differing = &c[common_prefix..c.len() - common_suffix];
+            if differing.is_empty() {
+                // A single store: everything is common
+                c.last().cloned().unwrap_or_else(|| "store".to_string())
+            } else {
+                differing.join("-")
+            }
+        })
+        .collect()
+}
+
+fn path_components(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Extract every store matching `pattern` with the flags of `args`
+pub async fn run(
+    settings: &Settings,
+    args: ExtractArgs,
+    pattern: &str,
+    verbose: bool,
+) -> Result<()> {
+    let stores = expand(pattern)?;
+    let count = stores.len();
+    let names = store_names(&stores);
+    let output_dir = args.output.clone();
+    std::fs::create_dir_all(&output_dir)
+        .with_context(|| format!("Failed to create output directory {:?}", output_dir))?;
+    let concurrency = args.concurrency.unwrap_or(1) as usize;
+    jobs::set_concurrency(concurrency);
+    info!(
+        "Extracting {} stores matching {:?}, {} at a time",
+        count, pattern, concurrency
+    );
+
+    let split = args.split_by_room || args.chunk_size.is_some();
+    let slots = Arc::new(Semaphore::new(concurrency));
+    let mut running = JoinSet::new();
+    for (index, (store, name)) in stores.into_iter().zip(names).enumerate() {
+        let slot = slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        // Stores still waiting for a slot are left for another run
+        if interrupt::requested() {
+            break;
+        }
+        info!("=== STORE {}/{}: {} ===", index + 1, count, name);
+
+        let mut store_args = args.clone();
+        store_args.sled_path_glob = None;
+        store_args.sled_path = Some(store.clone());
+        store_args.output = if split {
+            output_dir.join(&name)
+        } else {
+            output_dir.join(split::part_file_name(&name, args.format, args.compress))
+        };
+        let failed_name = match args.compress {
+            Some(compression) => {
+                format!("{}.failed-sessions.json.{}", name, compression.extension())
+            }
+            None => format!("{}.failed-sessions.json", name),
+        };
+        store_args.failed_output = Some(output_dir.join(failed_name));
+
+        let settings = settings.clone();
+        running.spawn(async move {
+            let output = store_args.output.clone();
+            let job = move || async move { run_extract(&settings, store_args, verbose).await };
+            let result = jobs::run_isolated(name, job).await;
+            drop(slot);
+
+            let (totals, error) = match result {
+                Ok(totals) => (totals, None),
+                Err(e) => {
+                    error!("Extraction of {:?} failed: {:#}", store, e);
+                    (StoreTotals::default(), Some(format!("{:#}", e)))
+                }
+            };
+            let result = StoreResult {
+                sled_path: store,
+                output,
+                totals,
+                error,
+            };
+            (index, result)
+        });
+    }
+
+    let mut results = Vec::with_capacity(count);
+    while let Some(finished) = running.join_next().await {
+        results.push(finished.context("Extraction task failed")?);
+    }
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut summary = BatchSummary {
+        pattern: pattern.to_string(),
+        ..Default::default()
+    };
+    for (_, result) in results {
+        summary.total_keys += result.totals.total_keys;
+        summary.failed_keys += result.totals.failed_keys;
+        summary.failed_stores += usize::from(result.error.is_some());
+        summary.stores.push(result);
+    }
+    if interrupt::requested() {
+        warn!("Interrupted - leaving the remaining stores for another run");
+    }
+
+    print_summary(&summary);
+    let summary_path = output_dir.join(SUMMARY_FILE);
+    let json = serde_json::to_vec_pretty(&summary).context("Failed to serialize batch summary")?;
+    write_private_file(&summary_path, &json).context("Failed to write batch summary")?;
+    info!("Batch summary written to: {:?}", summary_path);
+
+    if interrupt::requested() {
+        return Err(ExtractorError::Interrupted(format!(
+            "{} of {} stores extracted",
+            summary.stores.len() - summary.failed_stores,
+            count
+        ))
+        .into());
+    }
+    if summary.failed_stores > 0 {
+        anyhow::bail!(
+            "{} of {} stores could not be extracted",
+            summary.failed_stores,
+            summary.stores.len()
+        );
+    }
+    Ok(())
+}
+
+fn print_summary(summary: &BatchSummary) {
+    info!("=== BATCH SUMMARY ===");
+    for store in &summary.stores {
+        match &store.error {
+            Some(error) => warn!("  {:?}: FAILED - {}", store.sled_path, error),
+            None => info!(
+                "  {:?}: {} keys in {} rooms, {} failed",
+                store.sled_path,
+                store.totals.total_keys,
+                store.totals.rooms,
+                store.totals.failed_keys
+            ),
+        }
+    }
+    info!(
+        "Stores: {} ({} failed), keys: {}, failed entries: {}",
+        summary.stores.len(),
+        summary.failed_stores,
+        summary.total_keys,
+        summary.failed_keys
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_names_keep_differing_components() {
+        let stores = vec![
+            PathBuf::from("/data/bots/alice/crypto"),
+            PathBuf::from("/data/bots/bob/crypto"),
+            PathBuf::from("/data/bots/carol/old/crypto"),
+        ];
+        assert_eq!(store_names(&stores), vec!["alice", "bob", "carol-old"]);
+
+        assert_eq!(
+            store_names(&[PathBuf::from("/data/bots/alice/crypto")]),
+            vec!["crypto"]
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Extraction throughput benchmark
+//!
+//! `bench` runs the session pipeline of a fault-tolerant extraction against a
+//! store without writing anything, and times each stage on its own over the
+//! same sessions: reading the values from sled, decrypting them with the store
+//! cipher, deserializing the pickles, rebuilding the sessions and exporting
+//! their keys. The stages run one after the other on a single thread, so their
+//! rates show where the time goes. The whole decode step is then run on the
+//! worker pool once per `--threads` value, which is what tuning `--threads` on
+//! `extract` needs.
+//!
+//! Only the regular path is measured: values that fail a stage are counted and
+//! left out of the later stages, without trying libolm conversion or salvaging.
+
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use matrix_sdk_crypto::olm::{InboundGroupSession, PickledInboundGroupSession};
+use matrix_sdk_store_encryption::EncryptedValue;
+use rayon::prelude::*;
+use serde::Serialize;
+use tracing::info;
+use zeroize::Zeroizing;
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::{decode_session, export_session, load_store_cipher, schema};
+
+/// Timing of one stage
+#[derive(Debug, Serialize)]
+pub struct StageTiming {
+    /// Name of the stage
+    pub stage: &'static str,
+    /// Sessions that went through the stage
+    pub sessions: usize,
+    /// Sessions that failed in the stage
+    pub failed: usize,
+    /// Time the stage took
+    pub seconds: f64,
+    /// Throughput of the stage
+    pub sessions_per_second: f64,
+}
+
+/// Timing of the parallel decode step with one pool size
+#[derive(Debug, Serialize)]
+pub struct PoolTiming {
+    /// Worker threads
+    pub threads: usize,
+    /// Time decoding all sessions took
+    pub seconds: f64,
+    /// Throughput of the decode step
+    pub sessions_per_second: f64,
+}
+
+/// Results of a benchmark run
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    /// Entries read from the sessions tree
+    pub entries: usize,
+    /// Whether the values were encrypted with a store cipher
+    pub encrypted: bool,
+    /// Single-threaded timing of each stage, in pipeline order
+    pub stages: Vec<StageTiming>,
+    /// Decrypt, deserialize and rebuild on the worker pool, per pool size
+    pub pool: Vec<PoolTiming>,
+}
+
+/// Time `stage` over `inputs`, keeping the outputs of the sessions that passed
+fn time_stage<I, O>(
+    stages: &mut Vec<StageTiming>,
+    name: &'static str,
+    inputs: Vec<I>,
+    mut stage: impl FnMut(I) -> Option<O>,
+) -> Vec<O> {
+    let sessions = inputs.len();
+    let started = Instant::now();
+    let outputs: Vec<O> = inputs.into_iter().filter_map(&mut stage).collect();
+    let seconds = started.elapsed().as_secs_f64();
+
+    stages.push(StageTiming {
+        stage: name,
+        sessions,
+        failed: sessions - outputs.len(),
+        seconds,
+        sessions_per_second: rate(sessions, seconds),
+    });
+    outputs
+}
+
+fn rate(sessions: usize, seconds: f64) -> f64 {
+    if seconds > 0.0 {
+        sessions as f64 / seconds
+    } else {
+        0.0
+    }
+}
+
+/// Benchmark extraction from the store at `path`
+///
+/// At most `limit` sessions are used; every value is held in memory at once.
+pub async fn run(
+    settings: &Settings,
+    path: &Path,
+    passphrase: Option<&str>,
+    limit: usize,
+    threads: &[usize],
+) -> Result<BenchReport> {
+    let db = settings.sled_config()
+        .path(path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+    let store_cipher = load_store_cipher(settings, &db, passphrase.unwrap_or(""))?;
+    let schema = schema::detect(settings, &db)?;
+    let tree = db
+        .open_tree(&schema.inbound_group_sessions)
+        .context("Failed to open inbound group sessions tree")?;
+
+    let mut stages = Vec::new();
+    let started = Instant::now();
+    let values = tree
+        .iter()
+        .values()
+        .take(limit)
+        .collect::<sled::Result<Vec<sled::IVec>>>()
+        .map_err(ExtractorError::SledIo)?;
+    let seconds = started.elapsed().as_secs_f64();
+    stages.push(StageTiming {
+        stage: "sled-read",
+        sessions: values.len(),
+        failed: 0,
+        seconds,
+        sessions_per_second: rate(values.len(), seconds),
+    });
+    info!("Benchmarking {} sessions", values.len());
+
+    let entries = values.len();
+    let cipher = store_cipher.as_ref();
+    let plaintexts = time_stage(
+        &mut stages,
+        "decrypt",
+        values.clone(),
+        |value| match cipher {
+            Some(cipher) => {
+                let envelope: EncryptedValue = serde_json::from_slice(&value).ok()?;
+                cipher.decrypt_value_data(envelope).ok().map(Zeroizing::new)
+            }
+            None => Some(Zeroizing::new(value.to_vec())),
+        },
+    );
+    let pickles = time_stage(&mut stages, "deserialize", plaintexts, |plaintext| {
+        serde_json::from_slice::<PickledInboundGroupSession>(&plaintext).ok()
+    });
+    let sessions = time_stage(&mut stages, "pickle", pickles, |pickle| {
+        InboundGroupSession::from_pickle(pickle).ok()
+    });
+
+    let started = Instant::now();
+    for session in &sessions {
+        drop(export_session(session).await);
+    }
+    let seconds = started.elapsed().as_secs_f64();
+    stages.push(StageTiming {
+        stage: "export",
+        sessions: sessions.len(),
+        failed: 0,
+        seconds,
+        sessions_per_second: rate(sessions.len(), seconds),
+    });
+
+    let mut pool = Vec::new();
+    for &thread_count in threads {
+        let workers = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .build()
+            .context("Failed to create worker pool")?;
+        let started = Instant::now();
+        workers.install(|| {
+            values.par_iter().for_each(|value| {
+                let _ = decode_session(value, cipher);
+            })
+        });
+        let seconds = started.elapsed().as_secs_f64();
+        pool.push(PoolTiming {
+            threads: workers.current_num_threads(),
+            seconds,
+            sessions_per_second: rate(entries, seconds),
+        });
+    }
+
+    Ok(BenchReport {
+        entries,
+        encrypted: store_cipher.is_some(),
+        stages,
+        pool,
+    })
+}
+
+/// Print the results as a table
+pub fn print_report(report: &BenchReport) {
+    println!(
+        "Sessions: {} ({})",
+        report.entries,
+        if report.encrypted {
+            "encrypted"
+        } else {
+            "unencrypted"
+        }
+    );
+    println!();
+    println!(
+        "{:<14} {:>10} {:>8} {:>10} {:>14}",
+        "Stage", "Sessions", "Failed", "Seconds", "Sessions/s"
+    );
+    for stage in &report.stages {
+        println!(
+            "{:<14} {:>10} {:>8} {:>10.3} {:>14.0}",
+            stage.stage, stage.sessions, stage.failed, stage.seconds, stage.sessions_per_second
+        );
+    }
+
+    if !report.pool.is_empty() {
+        println!();
+        println!("{:<14} {:>10} {:>14}", "Threads", "Seconds", "Sessions/s");
+        for run in &report.pool {
+            println!(
+                "{:<14} {:>10.3} {:>14.0}",
+                run.threads, run.seconds, run.sessions_per_second
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_stage_counts_failures() {
+        let mut stages = Vec::new();
+        let outputs = time_stage(&mut stages, "parse", vec!["1", "x", "3"], |value| {
+            value.parse::<u32>().ok()
+        });
+
+        assert_eq!(outputs, vec![1, 3]);
+        assert_eq!(stages[0].sessions, 3);
+        assert_eq!(stages[0].failed, 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! Validation of export files
+//!
+//! `check-export` looks for everything that would make an import or upload of
+//! an export fail or silently lose keys: an unsupported format version, counts
+//! that don't match the keys, duplicate sessions, empty session keys and
+//! fields that aren't valid base64. Jobs that consume exports can be gated on
+//! its exit code.
+
+use std::collections::{BTreeMap, HashSet};
+
+use base64::alphabet;
+use base64::engine::general_purpose::GeneralPurposeConfig;
+use base64::engine::{DecodePaddingMode, GeneralPurpose};
+use base64::Engine;
+use serde::Serialize;
+
+use crate::metadata::SUPPORTED_VERSIONS;
+use crate::{ExportedKeyData, ExtractionOutput};
+
+/// Matrix encodes keys as unpadded base64, but padded input is accepted too
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// A problem found in an export
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    /// Short machine-readable kind, e.g. `duplicate_session`
+    pub kind: &'static str,
+    /// Index of the key in `all_keys`, for problems with a single key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_index: Option<usize>,
+    pub message: String,
+}
+
+/// Result of checking an export
+#[derive(Debug, Default, Serialize)]
+pub struct CheckReport {
+    pub version: u32,
+    pub keys: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    fn export_problem(&mut self, kind: &'static str, message: String) {
+        self.problems.push(Problem {
+            kind,
+            key_index: None,
+            message,
+        });
+    }
+
+    fn key_problem(&mut self, kind: &'static str, index: usize, key: &ExportedKeyData, what: &str) {
+        self.problems.push(Problem {
+            kind,
+            key_index: Some(index),
+            message: format!(
+                "Key {} (session {} in {}): {}",
+                index, key.session_id, key.room_id, what
+            ),
+        });
+    }
+
+    /// Number of problems per kind
+    pub fn counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for problem in &self.problems {
+            *counts.entry(problem.kind).or_default() += 1;
+        }
+        counts
+    }
+}
+
+/// Check the fields of a single key
+fn check_key(report: &mut CheckReport, index: usize, key: &ExportedKeyData) {
+    if !key.room_id.starts_with('!') {
+        report.key_problem(
+            "invalid_room_id",
+            index,
+            key,
+            "room ID doesn't start with '!'",
+        );
+    }
+    if key.session_id.is_empty() {
+        report.key_problem("empty_session_id", index, key, "empty session ID");
+    }
+
+    if key.session_key.is_empty() {
+        report.key_problem("empty_session_key", index, key, "empty session key");
+    } else if BASE64.decode(&key.session_key).is_err() {
+        report.key_problem(
+            "invalid_base64",
+            index,
+            key,
+            "session key is not valid base64",
+        );
+    }
+
+    let mut fields = vec![("sender key", &key.sender_key)];
+    fields.extend(
+        key.sender_claimed_keys
+            .values()
+            .map(|value| ("sender claimed key", value)),
+    );
+    fields.extend(
+        key.forwarding_curve25519_key_chain
+            .iter()
+            .map(|value| ("forwarding key", value)),
+    );
+    for (field, value) in fields {
+        if BASE64.decode(value).is_err() {
+            let what = format!("{} {:?} is not valid base64", field, value);
+            report.key_problem("invalid_base64", index, key, &what);
+        }
+    }
+}
+
+/// Check an export for structural and per-key problems
+pub fn check_export(output: &ExtractionOutput) -> CheckReport {
+    let mut report = CheckReport {
+        version: output.version,
+        keys: output.all_keys.len(),
+        problems: Vec::new(),
+    };
+
+    if !SUPPORTED_VERSIONS.contains(&output.version) {
+        report.export_problem(
+            "unsupported_version",
+            format!(
+                "Format version {} is not supported (expected {:?})",
+                output.version, SUPPORTED_VERSIONS
+            ),
+        );
+    }
+    if output.version >= 2 && output.metadata.is_none() {
+        report.export_problem(
+            "missing_metadata",
+            format!("Format version {} requires metadata, but there is none", output.version),
+        );
+    }
+    if output.total_keys != output.all_keys.len() {
+        report.export_problem(
+            "count_mismatch",
+            format!(
+                "total_keys is {} but all_keys holds {} keys",
+                output.total_keys,
+                output.all_keys.len()
+            ),
+        );
+    }
+
+    let mut per_room: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for (index, key) in output.all_keys.iter().enumerate() {
+        *per_room.entry(key.room_id.as_str()).or_default() += 1;
+        if !seen.insert((key.room_id.as_str(), key.session_id.as_str())) {
+            report.key_problem(
+                "duplicate_session",
+                index,
+                key,
+                "session appears more than once",
+            );
+        }
+        check_key(&mut report, index, key);
+    }
+
+    // The room maps are derived from all_keys, so they must agree with it
+    if !output.keys_by_room.is_empty() {
+        let by_room: BTreeMap<&str, usize> = output
+            .keys_by_room
+            .iter()
+            .map(|(room_id, keys)| (room_id.as_str(), keys.len()))
+            .collect();
+        if by_room != per_room {
+            report.export_problem(
+                "count_mismatch",
+                "keys_by_room doesn't hold the same keys per room as all_keys".to_string(),
+            );
+        }
+    }
+    if !output.keys_per_room.is_empty() {
+        let per_room_counts: BTreeMap<&str, usize> = output
+            .keys_per_room
+            .iter()
+            .map(|(room_id, count)| (room_id.as_str(), *count))
+            .collect();
+        if per_room_counts != per_room {
+            report.export_problem(
+                "count_mismatch",
+                "keys_per_room doesn't match the keys per room in all_keys".to_string(),
+            );
+        }
+    }
+
+    report
+}
+
+/// Print the problems found, up to `limit` per kind
+pub fn print_report(report: &CheckReport, limit: usize) {
+    println!("Format version: {}", report.version);
+    println!("Keys:           {}", report.keys);
+
+    if report.problems.is_empty() {
+        println!();
+        println!("✓ No problems found");
+        return;
+    }
+
+    for (kind, count) in report.counts() {
+        println!();
+        println!("✗ {} ({}):", kind, count);
+        for problem in report
+            .problems
+            .iter()
+            .filter(|problem| problem.kind == kind)
+            .take(limit)
+        {
+            println!("  {}", problem.message);
+        }
+        if count > limit {
+            println!("  ... and {} more", count - limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::key_with_secret;
+
+    fn key(session_id: &str, session_key: &str) -> ExportedKeyData {
+        key_with_secret("!a:x.org", session_id, session_key)
+    }
+
+    #[test]
+    fn test_check_export_finds_problems() {
+        let output = ExtractionOutput {
+            version: 1,
+            total_keys: 4,
+            failed_keys: 0,
+            keys_by_room: Default::default(),
+            keys_per_room: Default::default(),
+            all_keys: vec![
+                key("a", "AQID"),
+                key("a", "AQID"),
+                key("b", ""),
+                key("c", "not base64!"),
+            ],
+            extra_trees: Default::default(),
+            metadata: None,
+            integrity: None,
+            partial: false,
+        };
+
+        let counts = check_export(&output).counts();
+
+        assert_eq!(counts["duplicate_session"], 1);
+        assert_eq!(counts["empty_session_key"], 1);
+        assert_eq!(counts["invalid_base64"], 1);
+        assert!(!counts.contains_key("count_mismatch"));
+    }
+}
@@ -0,0 +1,209 @@
+//! Comparison of two export files
+//!
+//! `diff` matches the keys of two exports on room and session ID and reports
+//! which were added, removed or changed, e.g. to see what a re-run of `extract`
+//! after a partial fix recovered. Session keys are only compared when both
+//! exports contain them (not written with `--no-secrets`).
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{ExportedKeyData, ExtractionOutput};
+
+/// A key identified by room and session ID
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct KeyRef {
+    pub room_id: String,
+    pub session_id: String,
+}
+
+/// A key in both exports with differing fields
+#[derive(Debug, Serialize)]
+pub struct ChangedKey {
+    #[serde(flatten)]
+    pub key: KeyRef,
+    /// Names of the fields that differ
+    pub fields: Vec<&'static str>,
+}
+
+/// Differences between two exports
+#[derive(Debug, Default, Serialize)]
+pub struct ExportDiff {
+    pub old_keys: usize,
+    pub new_keys: usize,
+    pub unchanged: usize,
+    pub added: Vec<KeyRef>,
+    pub removed: Vec<KeyRef>,
+    pub changed: Vec<ChangedKey>,
+}
+
+/// The keys of an export by room and session ID
+fn index(output: &ExtractionOutput) -> BTreeMap<KeyRef, &ExportedKeyData> {
+    output
+        .all_keys
+        .iter()
+        .map(|key| {
+            let key_ref = KeyRef {
+                room_id: key.room_id.clone(),
+                session_id: key.session_id.clone(),
+            };
+            (key_ref, key)
+        })
+        .collect()
+}
+
+/// Names of the fields that differ between two versions of a key
+fn changed_fields(old: &ExportedKeyData, new: &ExportedKeyData) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if old.algorithm != new.algorithm {
+        fields.push("algorithm");
+    }
+    if !old.session_key.is_empty()
+        && !new.session_key.is_empty()
+        && old.session_key != new.session_key
+    {
+        fields.push("session_key");
+    }
+    if old.sender_key != new.sender_key {
+        fields.push("sender_key");
+    }
+    if old.sender_claimed_keys != new.sender_claimed_keys {
+        fields.push("sender_claimed_keys");
+    }
+    if old.forwarding_curve25519_key_chain != new.forwarding_curve25519_key_chain {
+        fields.push("forwarding_curve25519_key_chain");
+    }
+    // Only recorded in format version 2
+    let recorded = [
+        (
+            "first_known_index",
+            old.first_known_index
+                .zip(new.first_known_index)
+                .map(|(a, b)| a != b),
+        ),
+        (
+            "backed_up",
+            old.backed_up.zip(new.backed_up).map(|(a, b)| a != b),
+        ),
+        (
+            "imported",
+            old.imported.zip(new.imported).map(|(a, b)| a != b),
+        ),
+    ];
+    for (field, changed) in recorded {
+        if changed == Some(true) {
+            fields.push(field);
+        }
+    }
+    if old.extra != new.extra {
+        fields.push("extra");
+    }
+    fields
+}
+
+/// Compare two exports
+pub fn diff(old: &ExtractionOutput, new: &ExtractionOutput) -> ExportDiff {
+    let old_keys = index(old);
+    let new_keys = index(new);
+    let mut diff = ExportDiff {
+        old_keys: old_keys.len(),
+        new_keys: new_keys.len(),
+        ..Default::default()
+    };
+
+    for (key_ref, old_key) in &old_keys {
+        match new_keys.get(key_ref) {
+            Some(new_key) => {
+                let fields = changed_fields(old_key, new_key);
+                if fields.is_empty() {
+                    diff.unchanged += 1;
+                } else {
+                    diff.changed.push(ChangedKey {
+                        key: key_ref.clone(),
+                        fields,
+                    });
+                }
+            }
+            None => diff.removed.push(key_ref.clone()),
+        }
+    }
+    diff.added = new_keys
+        .into_keys()
+        .filter(|key_ref| !old_keys.contains_key(key_ref))
+        .collect();
+
+    diff
+}
+
+/// Print the differences, listing every added, removed and changed key
+pub fn print_diff(diff: &ExportDiff) {
+    println!("Keys (old):  {}", diff.old_keys);
+    println!("Keys (new):  {}", diff.new_keys);
+    println!("Unchanged:   {}", diff.unchanged);
+    println!("Added:       {}", diff.added.len());
+    println!("Removed:     {}", diff.removed.len());
+    println!("Changed:     {}", diff.changed.len());
+
+    for (title, keys) in [("Added", &diff.added), ("Removed", &diff.removed)] {
+        if !keys.is_empty() {
+            println!();
+            println!("{}:", title);
+            for key in keys {
+                println!("  {}  {}", key.session_id, key.room_id);
+            }
+        }
+    }
+
+    if !diff.changed.is_empty() {
+        println!();
+        println!("Changed:");
+        for changed in &diff.changed {
+            println!(
+                "  {}  {}  ({})",
+                changed.key.session_id,
+                changed.key.room_id,
+                changed.fields.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_output;
+    use crate::testing::key_with_secret;
+
+    fn export(keys: Vec<(&str, &str)>) -> ExtractionOutput {
+        let keys = keys
+            .into_iter()
+            .map(|(session_id, session_key)| key_with_secret("!a:x.org", session_id, session_key))
+            .collect();
+        build_output(keys, 0, false)
+    }
+
+    #[test]
+    fn test_diff_matches_on_room_and_session() {
+        let old = export(vec![
+            ("same", "k1"),
+            ("gone", "k2"),
+            ("rekeyed", "k3"),
+            ("nosecret", "k4"),
+        ]);
+        let new = export(vec![
+            ("same", "k1"),
+            ("rekeyed", "k9"),
+            ("nosecret", ""),
+            ("fresh", "k5"),
+        ]);
+
+        let diff = diff(&old, &new);
+
+        assert_eq!(diff.unchanged, 2);
+        assert_eq!(diff.removed[0].session_id, "gone");
+        assert_eq!(diff.added[0].session_id, "fresh");
+        assert_eq!(diff.changed[0].key.session_id, "rekeyed");
+        assert_eq!(diff.changed[0].fields, vec!["session_key"]);
+    }
+}
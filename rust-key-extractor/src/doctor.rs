@@ -0,0 +1,393 @@
+//! Diagnosis of common migration problems
+//!
+//! `doctor` runs a series of read-only checks against a sled crypto store and
+//! reports each one with a fix, instead of failing on the first opaque error
+//! the way `extract` does. Checks that depend on an earlier one (e.g. reading
+//! the account needs a working passphrase) are skipped when it failed.
+
+use std::path::Path;
+
+
+use crate::inspect::display_key;
+use crate::trees::{self, ACCOUNT_TREE};
+use crate::settings::Settings;
+use crate::{kdf, schema, store, INBOUND_GROUP_SESSIONS_TREE};
+
+/// sled on-disk format this tool is built against
+const SLED_VERSION: (usize, usize) = (0, 34);
+
+/// Name of sled's configuration file inside the store directory
+const SLED_CONFIG_FILE: &str = "conf";
+
+/// A tree only the sled state store has, to recognize a state store passed by mistake
+pub const STATE_STORE_MARKER_TREE: &str = "room_info";
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Nothing to do
+    Ok,
+    /// Migration can continue, but the result may not be what's expected
+    Warning,
+    /// Migration will fail until this is fixed
+    Error,
+}
+
+/// Result of a single check
+#[derive(Debug)]
+pub struct Check {
+    /// What was checked
+    pub name: &'static str,
+    /// Outcome
+    pub status: Status,
+    /// What was found
+    pub detail: String,
+    /// How to fix it, for warnings and errors
+    pub remedy: Option<String>,
+}
+
+impl Check {
+    pub(crate) fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            remedy: None,
+        }
+    }
+
+    pub(crate) fn warning(
+        name: &'static str,
+        detail: impl Into<String>,
+        remedy: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status: Status::Warning,
+            detail: detail.into(),
+            remedy: Some(remedy.into()),
+        }
+    }
+
+    pub(crate) fn error(
+        name: &'static str,
+        detail: impl Into<String>,
+        remedy: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status: Status::Error,
+            detail: detail.into(),
+            remedy: Some(remedy.into()),
+        }
+    }
+}
+
+/// Read the sled format version from the store's configuration file
+///
+/// The file holds `key: value` lines followed by a 4 byte checksum.
+fn sled_version(path: &Path) -> Option<(usize, usize)> {
+    let config = std::fs::read(path.join(SLED_CONFIG_FILE)).ok()?;
+    let text = String::from_utf8_lossy(&config[..config.len().saturating_sub(4)]).into_owned();
+    let version = text.lines().find_map(|line| line.strip_prefix("version: "))?;
+    let (major, minor) = version.trim().split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Run all checks against the store at `path`
+pub fn diagnose(settings: &Settings, path: &Path, passphrase: Option<&str>) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    if !path.join("db").exists() {
+        checks.push(Check::error(
+            "store directory",
+            format!("{:?} is not a sled database (no `db` file)", path),
+            "Point --sled-path at the crypto store directory - for matrix-bot-sdk bots \
+             that is `encrypted` inside the bot's storage directory",
+        ));
+        return checks;
+    }
+    checks.push(Check::ok("store directory", format!("{:?}", path)));
+
+    checks.push(match sled_version(path) {
+        Some(version) if version == SLED_VERSION => {
+            Check::ok("sled version", format!("{}.{}", version.0, version.1))
+        }
+        Some(version) => Check::error(
+            "sled version",
+            format!(
+                "store was written by sled {}.{}, this tool reads {}.{}",
+                version.0, version.1, SLED_VERSION.0, SLED_VERSION.1
+            ),
+            "Build the extractor against the sled version the bot uses",
+        ),
+        None => Check::warning(
+            "sled version",
+            "configuration file missing or unreadable",
+            "The store may be damaged; work on a backup copy (--copy-first) when extracting",
+        ),
+    });
+
+    // A locked store can't be opened, so the remaining checks run on a copy
+    let mut store_path = path.to_path_buf();
+    let _store_copy = match store::is_locked(path) {
+        Ok(false) => {
+            checks.push(Check::ok("lock", "store is not in use"));
+            None
+        }
+        Ok(true) => {
+            let holders: Vec<_> = store::lock_holders(path)
+                .iter()
+                .map(|holder| format!("pid {}", holder.pid))
+                .collect();
+            checks.push(Check::error(
+                "lock",
+                if holders.is_empty() {
+                    "store is locked by another process".to_string()
+                } else {
+                    format!("store is locked by {}", holders.join(", "))
+                },
+                "Stop the bot before migrating, or pass --force to extract from a copy",
+            ));
+            match store::prepare_source(&mut store_path, true, true) {
+                Ok(copy) => copy,
+                Err(e) => {
+                    checks.push(Check::error(
+                        "copy",
+                        format!("{:#}", e),
+                        "Stop the bot and run doctor again",
+                    ));
+                    return checks;
+                }
+            }
+        }
+        Err(e) => {
+            checks.push(Check::warning(
+                "lock",
+                format!("{:#}", e),
+                "Check the permissions of the store directory",
+            ));
+            None
+        }
+    };
+
+    let db = match settings.sled_config().path(&store_path).open() {
+        Ok(db) => db,
+        Err(e) => {
+            checks.push(Check::error(
+                "open",
+                e.to_string(),
+                "The store is damaged; try `extract --skip-errors` on a copy (--copy-first)",
+            ));
+            return checks;
+        }
+    };
+
+    let tree_names: Vec<String> = db
+        .tree_names()
+        .iter()
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect();
+    checks.push(check_tree_names(settings, &tree_names));
+    let sessions_tree = match schema::detect(settings, &db) {
+        Ok(schema) => {
+            checks.push(if schema.layout.libolm_pickles {
+                Check::warning(
+                    "layout",
+                    format!("{} - sessions are pickled by libolm", schema.describe()),
+                    "Extract with --skip-errors, which converts libolm pickles",
+                )
+            } else {
+                Check::ok("layout", schema.describe())
+            });
+            schema.inbound_group_sessions
+        }
+        // A state store is already reported by the tree names check
+        Err(_) => INBOUND_GROUP_SESSIONS_TREE.to_string(),
+    };
+
+    // Cipher and passphrase
+    let store_cipher = match schema::find_store_cipher(settings, &db) {
+        Ok(None) => {
+            checks.push(Check::ok("store cipher", "none - store is not encrypted"));
+            if passphrase.is_some_and(|passphrase| !passphrase.is_empty()) {
+                checks.push(Check::warning(
+                    "passphrase",
+                    "a passphrase was given, but the store is not encrypted",
+                    "Drop --passphrase; it is ignored",
+                ));
+            }
+            None
+        }
+        Ok(Some(exported)) => {
+            checks.push(Check::ok("store cipher", "present - store is encrypted"));
+            match kdf::import_cipher(&settings.kdf, passphrase.unwrap_or(""), &exported) {
+                Ok(cipher) => {
+                    checks.push(Check::ok("passphrase", "store cipher unlocked"));
+                    Some(cipher)
+                }
+                Err(e) => {
+                    checks.push(Check::error(
+                        "passphrase",
+                        format!("store cipher can't be unlocked: {}", e),
+                        match passphrase {
+                            None | Some("") => "Pass the passphrase the bot opens the store with \
+                                                (--passphrase); matrix-bot-sdk uses an empty string \
+                                                unless configured otherwise",
+                            Some(_) => "Check the passphrase - it must match the one the bot \
+                                        opens the store with",
+                        },
+                    ));
+                    return checks;
+                }
+            }
+        }
+        Err(e) => {
+            checks.push(Check::error(
+                "store cipher",
+                format!("{:#}", e),
+                "The store is damaged; work on a copy",
+            ));
+            return checks;
+        }
+    };
+
+    // Schema markers
+    let markers: Vec<String> = db
+        .iter()
+        .keys()
+        .flatten()
+        .map(|key| display_key(&key))
+        .filter(|key| key.contains("version"))
+        .collect();
+    checks.push(if markers.is_empty() {
+        Check::warning(
+            "schema version",
+            "no version marker in the default tree",
+            "The store may predate versioned matrix-sdk-sled stores; extract with --skip-errors \
+             and check failed-sessions.json",
+        )
+    } else {
+        Check::ok("schema version", format!("markers: {}", markers.join(", ")))
+    });
+
+    // Account
+    checks.push(match trees::extract_account(settings, &db, store_cipher.as_ref(), true) {
+        Ok((Some(account), _, _)) => Check::ok(
+            "account",
+            format!("{} (device {})", account.user_id, account.device_id),
+        ),
+        Ok((None, _, failed)) if !failed.is_empty() => Check::error(
+            "account",
+            format!("account entry can't be decoded: {}", failed[0].error),
+            "Check the passphrase; if it is right, the account entry is corrupted",
+        ),
+        Ok((None, _, _)) => Check::error(
+            "account",
+            format!("no account in the `{}` tree", ACCOUNT_TREE),
+            "This is not the bot's crypto store, or the bot never finished setting up encryption",
+        ),
+        Err(e) => Check::error("account", format!("{:#}", e), "The account tree is damaged"),
+    });
+
+    // Sessions
+    checks.push(match db.open_tree(&sessions_tree).map(|tree| tree.len()) {
+        Ok(0) => Check::warning(
+            "inbound group sessions",
+            "the tree is empty",
+            "There are no room keys to migrate; old encrypted messages can't be decrypted anyway",
+        ),
+        Ok(count) => Check::ok("inbound group sessions", format!("{} entries", count)),
+        Err(e) => Check::error(
+            "inbound group sessions",
+            e.to_string(),
+            "The store is damaged; work on a copy",
+        ),
+    });
+
+    checks
+}
+
+/// Check that the store has the trees of a matrix-sdk-sled crypto store
+fn check_tree_names(settings: &Settings, tree_names: &[String]) -> Check {
+    let sessions_tree = schema::tree_name(settings, INBOUND_GROUP_SESSIONS_TREE);
+    if tree_names.iter().any(|name| name == sessions_tree) {
+        return Check::ok("tree names", format!("{} trees", tree_names.len()));
+    }
+
+    if tree_names.iter().any(|name| name == STATE_STORE_MARKER_TREE) {
+        return Check::error(
+            "tree names",
+            "this is a sled state store, not a crypto store",
+            "Use it with `migrate-state`; for keys point --sled-path at the crypto store",
+        );
+    }
+
+    let similar: Vec<_> = tree_names
+        .iter()
+        .filter(|name| name.contains("inbound") || name.contains("group_session"))
+        .cloned()
+        .collect();
+    if similar.is_empty() {
+        Check::error(
+            "tree names",
+            format!("no `{}` tree", INBOUND_GROUP_SESSIONS_TREE),
+            "Point --sled-path at the bot's crypto store (run `inspect` to see its trees)",
+        )
+    } else if let [name] = similar.as_slice() {
+        Check::warning(
+            "tree names",
+            format!("no `{}` tree, sessions are read from `{}`", INBOUND_GROUP_SESSIONS_TREE, name),
+            "The store uses different tree names; check the extracted keys against `stats`",
+        )
+    } else {
+        Check::error(
+            "tree names",
+            format!(
+                "no `{}` tree, but found: {}",
+                INBOUND_GROUP_SESSIONS_TREE,
+                similar.join(", ")
+            ),
+            "The store was written by a matrix-sdk-sled version with different tree names, \
+             and it is unclear which tree holds the sessions",
+        )
+    }
+}
+
+/// Print the checks with their remedies
+pub fn print_checks(checks: &[Check]) {
+    for check in checks {
+        let symbol = match check.status {
+            Status::Ok => "✓",
+            Status::Warning => "!",
+            Status::Error => "✗",
+        };
+        println!("{} {}: {}", symbol, check.name, check.detail);
+        if let Some(remedy) = &check.remedy {
+            println!("    → {}", remedy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnose_state_store() {
+        let dir = crate::testing::TempDir::new("doctor-test");
+        {
+            let db = sled::open(dir.path()).unwrap();
+            db.open_tree(STATE_STORE_MARKER_TREE).unwrap();
+            db.flush().unwrap();
+        }
+
+        let checks = diagnose(&Settings::default(), dir.path(), None);
+
+        let tree_check = checks.iter().find(|check| check.name == "tree names").unwrap();
+        assert_eq!(tree_check.status, Status::Error);
+        assert!(tree_check.detail.contains("state store"));
+        let version_check = checks.iter().find(|check| check.name == "sled version").unwrap();
+        assert_eq!(version_check.status, Status::Ok);
+    }
+}
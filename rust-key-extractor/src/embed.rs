@@ -0,0 +1,162 @@
+//! Extraction API for applications embedding the extractor
+//!
+//! A bot that migrates itself on startup doesn't want to write an export file
+//! and read it back. [`stream_keys`] hands it the keys of a sled store one by
+//! one instead, as the SDK's own [`ExportedRoomKey`] that can go straight into
+//! the new store. Entries are only read and decoded when the stream is polled,
+//! so a slow consumer holds back the extraction rather than piling keys up in
+//! memory.
+//!
+//! Like `extract --skip-errors`, a damaged entry doesn't end the stream: it
+//! yields an error for that entry and continues with the next one. Only a
+//! store that can't be opened at all yields a single error and ends the
+//! stream. Stores are read with the default [`Settings`] of the command line
+//! tool; only the read rate limit applies as it does there.
+//!
+//! [`extract_keys`] collects all keys at once instead and reports progress to
+//! a [`MigrationObserver`].
+
+use std::path::Path;
+
+use futures_util::{stream, Stream};
+use matrix_sdk_crypto::olm::ExportedRoomKey;
+use matrix_sdk_store_encryption::StoreCipher;
+
+use crate::error::ExtractorError;
+use crate::observer::MigrationObserver;
+use crate::settings::Settings;
+use crate::{decode_session, load_store_cipher, schema, throttle, FailureCategory};
+
+/// Sessions tree of an opened store, with the cipher its values are encrypted with
+struct Sessions {
+    tree: String,
+    total: usize,
+    entries: std::iter::Enumerate<throttle::Throttled<sled::Iter>>,
+    cipher: Option<StoreCipher>,
+}
+
+/// One entry of the sessions tree
+type Entry = (usize, Result<ExportedRoomKey, (FailureCategory, String)>);
+
+impl Sessions {
+    /// Read and export the next entry
+    async fn next(&mut self) -> Option<Entry> {
+        let (index, item) = self.entries.next()?;
+        let key = match item {
+            Ok((_, value)) => match decode_session(&value, self.cipher.as_ref()) {
+                Ok(session) => Ok(session.export().await),
+                Err(failure) => Err(failure),
+            },
+            Err(e) => Err((FailureCategory::SledRead, format!("Sled read error: {}", e))),
+        };
+        Some((index, key))
+    }
+}
+
+/// Category of a failure wrapped in `error`, or `fallback` for uncategorized ones
+fn categorize(
+    error: anyhow::Error,
+    fallback: impl FnOnce(String) -> ExtractorError,
+) -> ExtractorError {
+    match error.downcast::<ExtractorError>() {
+        Ok(error) => error,
+        Err(error) => fallback(format!("{:#}", error)),
+    }
+}
+
+/// Open the sessions tree of the store at `path`
+fn open(path: &Path, passphrase: Option<&str>) -> Result<Sessions, ExtractorError> {
+    if !path.exists() {
+        return Err(ExtractorError::StoreNotFound(path.to_path_buf()));
+    }
+    let settings = Settings::default();
+    let db = settings.sled_config().path(path).open()?;
+    let cipher = load_store_cipher(&settings, &db, passphrase.unwrap_or(""))
+        .map_err(|e| categorize(e, ExtractorError::WrongPassphrase))?;
+    let schema = schema::detect(&settings, &db)
+        .map_err(|e| categorize(e, ExtractorError::SchemaMismatch))?;
+    let tree = db.open_tree(&schema.inbound_group_sessions)?;
+
+    Ok(Sessions {
+        tree: schema.inbound_group_sessions,
+        total: tree.len(),
+        entries: throttle::iter(tree.iter()).enumerate(),
+        cipher,
+    })
+}
+
+/// Stream the keys of every inbound group session in the sled store at `sled_path`
+///
+/// `passphrase` unlocks the store cipher of encrypted stores. Each item is
+/// either a key or the reason one entry couldn't be read; the stream ends
+/// after the last entry, or after the first item if the store can't be
+/// opened.
+pub fn stream_keys(
+    sled_path: impl AsRef<Path>,
+    passphrase: Option<&str>,
+) -> impl Stream<Item = Result<ExportedRoomKey, ExtractorError>> + Send + 'static {
+    let opened = open(sled_path.as_ref(), passphrase);
+
+    stream::unfold(Some(opened), |state| async move {
+        let mut sessions = match state? {
+            Ok(sessions) => sessions,
+            Err(e) => return Some((Err(e), None)),
+        };
+        let item = match sessions.next().await?.1 {
+            Ok(key) => Ok(key),
+            Err((category, error)) => Err(ExtractorError::CorruptPickle(format!(
+                "{} ({})",
+                error,
+                category.label()
+            ))),
+        };
+        Some((item, Some(Ok(sessions))))
+    })
+}
+
+/// Extract the keys of every inbound group session in the sled store at `sled_path`
+///
+/// Damaged entries are skipped and reported to `observer` together with the
+/// extracted keys; only a store that can't be opened fails the call.
+pub async fn extract_keys(
+    sled_path: impl AsRef<Path>,
+    passphrase: Option<&str>,
+    observer: &mut impl MigrationObserver,
+) -> Result<Vec<ExportedRoomKey>, ExtractorError> {
+    let mut sessions = open(sled_path.as_ref(), passphrase)?;
+    observer.on_tree_start(&sessions.tree, 0, sessions.total);
+
+    let mut keys = Vec::new();
+    let mut failed = 0;
+    while let Some((index, key)) = sessions.next().await {
+        match key {
+            Ok(key) => {
+                observer.on_key(index, key.room_id.as_str(), &key.session_id);
+                keys.push(key);
+            }
+            Err((category, error)) => {
+                observer.on_failure(index, category, &error);
+                failed += 1;
+            }
+        }
+    }
+
+    observer.on_complete(keys.len(), failed);
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_missing_store_yields_one_error() {
+        let items: Vec<_> = stream_keys("/nonexistent/sled-store", None).collect().await;
+
+        assert_eq!(items.len(), 1);
+        assert!(matches!(items[0], Err(ExtractorError::StoreNotFound(_))));
+        let extracted = extract_keys("/nonexistent/sled-store", None, &mut ()).await;
+        assert!(matches!(extracted, Err(ExtractorError::StoreNotFound(_))));
+    }
+}
@@ -0,0 +1,217 @@
+//! Passphrase encryption of export files
+//!
+//! Exports hold raw Megolm session keys, so they can optionally be wrapped with
+//! a key derived from a passphrase. The key is derived with Argon2id and the
+//! (already encoded and compressed) export is sealed with ChaCha20-Poly1305.
+//!
+//! File layout, all integers little endian:
+//!
+//! | Bytes | Content                                   |
+//! |-------|-------------------------------------------|
+//! | 8     | magic `SKXENC\0\x01` (format version 1)   |
+//! | 4     | Argon2 memory cost in KiB                 |
+//! | 4     | Argon2 iterations                         |
+//! | 4     | Argon2 parallelism                        |
+//! | 16    | salt                                      |
+//! | 12    | nonce                                     |
+//! | rest  | ciphertext with Poly1305 tag              |
+//!
+//! The whole header is authenticated as associated data. It can only be
+//! authenticated after the key is derived, so cost parameters above
+//! [`MAX_KDF_FACTOR`] times the defaults are refused before deriving it.
+
+use anyhow::Result;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+use crate::error::ExtractorError;
+
+/// Magic bytes identifying an encrypted export, including the format version
+const MAGIC: &[u8; 8] = b"SKXENC\0\x01";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 3 * 4 + SALT_LEN + NONCE_LEN;
+
+/// How many times the default cost a header may ask for
+const MAX_KDF_FACTOR: u32 = 4;
+
+/// Argon2id cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub memory_kib: u32,
+    /// Number of iterations
+    pub iterations: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Whether every cost is within [`MAX_KDF_FACTOR`] times the default
+    fn within_limits(&self) -> bool {
+        let max = Self::default();
+        self.memory_kib <= max.memory_kib * MAX_KDF_FACTOR
+            && self.iterations <= max.iterations * MAX_KDF_FACTOR
+            && self.parallelism <= max.parallelism * MAX_KDF_FACTOR
+    }
+}
+
+impl Default for KdfParams {
+    /// 64 MiB, 3 iterations, 1 lane - about half a second on current hardware
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Whether `data` is an encrypted export
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Derive the file key from a passphrase
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let argon_params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(|e| anyhow::anyhow!("Invalid KDF parameters: {}", e))?;
+
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+
+    Ok(key)
+}
+
+/// Encrypt `data` with the default KDF parameters
+pub fn encrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    encrypt_with_params(data, passphrase, KdfParams::default())
+}
+
+/// Encrypt `data` with a key derived from `passphrase`
+pub fn encrypt_with_params(data: &[u8], passphrase: &str, params: KdfParams) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&params.memory_kib.to_le_bytes());
+    header.extend_from_slice(&params.iterations.to_le_bytes());
+    header.extend_from_slice(&params.parallelism.to_le_bytes());
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+
+    let key = derive_key(passphrase, &salt, params)?;
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: data,
+                aad: &header,
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt output"))?;
+
+    header.extend_from_slice(&ciphertext);
+    Ok(header)
+}
+
+/// Decrypt an encrypted export; the plaintext is wiped when dropped
+pub fn decrypt(data: &[u8], passphrase: &str) -> Result<Zeroizing<Vec<u8>>> {
+    if !is_encrypted(data) {
+        anyhow::bail!("Not an encrypted export");
+    }
+    if data.len() < HEADER_LEN {
+        anyhow::bail!("Encrypted export is truncated");
+    }
+
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let read_u32 = |offset: usize| {
+        let bytes: [u8; 4] = header[offset..offset + 4].try_into().expect("4 bytes");
+        u32::from_le_bytes(bytes)
+    };
+    let params = KdfParams {
+        memory_kib: read_u32(MAGIC.len()),
+        iterations: read_u32(MAGIC.len() + 4),
+        parallelism: read_u32(MAGIC.len() + 8),
+    };
+    let salt_start = MAGIC.len() + 12;
+    let salt = &header[salt_start..salt_start + SALT_LEN];
+    let nonce = &header[salt_start + SALT_LEN..];
+
+    // A crafted header could otherwise make Argon2 allocate gigabytes or run for hours
+    if !params.within_limits() {
+        return Err(ExtractorError::InvalidExport(format!(
+            "encrypted export asks for Argon2 costs of {} KiB, {} iterations and {} lanes, more \
+             than {} times the defaults this tool writes",
+            params.memory_kib, params.iterations, params.parallelism, MAX_KDF_FACTOR
+        ))
+        .into());
+    }
+
+    let key = derive_key(passphrase, salt, params)?;
+    ChaCha20Poly1305::new(Key::from_slice(key.as_ref()))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| ExtractorError::ExportDecryption.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters so the test doesn't spend seconds in Argon2
+    const TEST_PARAMS: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let data = br#"{"version":1}"#;
+        let encrypted = encrypt_with_params(data, "secret", TEST_PARAMS).unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(decrypt(&encrypted, "secret").unwrap().as_slice(), data);
+        assert!(decrypt(&encrypted, "wrong").is_err());
+    }
+
+    #[test]
+    fn test_excessive_kdf_costs_are_refused() {
+        let mut encrypted = encrypt_with_params(b"{}", "secret", TEST_PARAMS).unwrap();
+        // Iterations, which would otherwise run Argon2 nearly forever
+        encrypted[MAGIC.len() + 4..MAGIC.len() + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let error = decrypt(&encrypted, "secret").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExtractorError>(),
+            Some(ExtractorError::InvalidExport(_))
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+//! Configuration through environment variables
+//!
+//! Container deployments set options in the environment rather than templating
+//! command lines. The options of a command can also be given as
+//! `MATRIX_MIGRATE_<COMMAND>_<NAME>`, and the global options as
+//! `MATRIX_MIGRATE_<NAME>`, where COMMAND and NAME are the command and the long
+//! option name in upper case with dashes as underscores: `extract --sled-path`
+//! is `MATRIX_MIGRATE_EXTRACT_SLED_PATH`, `--verbose` is
+//! `MATRIX_MIGRATE_VERBOSE=true`. An option on the command line wins over the
+//! environment.
+//!
+//! Variables are scoped to their command because options of the same name
+//! mean different things in different commands (`--concurrency` of `extract`
+//! conflicts with `--sled-path`, `--limit` counts sessions or rows). The
+//! safety overrides `--force` and `--yes` have no variable: a deployment-wide
+//! setting must not silently turn off a guard.
+//!
+//! The variables are attached to the parser at runtime from the long names,
+//! so new options get one without further changes. `--help` lists them but
+//! never their values, which may be passphrases.
+//!
+//! The extraction flags accepted without a subcommand don't read the
+//! environment: they conflict with every subcommand, so setting them there
+//! would break all of them. Use `extract` instead. Likewise only the command
+//! line of the process reads the environment; the C API and `serve` jobs take
+//! their options from their callers alone.
+
+use clap::{Arg, Command};
+
+/// Prefix of every variable
+pub const PREFIX: &str = "MATRIX_MIGRATE_";
+
+/// Options that are never read from the environment
+const OVERRIDES: &[&str] = &["force", "yes"];
+
+/// `name` as it appears in a variable
+fn upper(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+/// The variable for `arg` of the command whose variables start with `scope`,
+/// if it has one
+fn variable(scope: &str, arg: &Arg) -> Option<String> {
+    let long = arg.get_long()?;
+    if matches!(long, "help" | "version") || OVERRIDES.contains(&long) {
+        return None;
+    }
+    Some(format!("{}{}", scope, upper(long)))
+}
+
+/// Let the global options of `command` and every option of its subcommands be
+/// set from the environment
+pub fn with_variables(command: Command) -> Command {
+    command
+        .mut_args(|arg| {
+            if arg.is_global_set() {
+                with_variable(PREFIX, arg)
+            } else {
+                arg
+            }
+        })
+        .mut_subcommands(|subcommand| {
+            let scope = format!("{}{}_", PREFIX, upper(subcommand.get_name()));
+            with_all_variables(&scope, subcommand)
+        })
+}
+
+/// Let every option of `command` and its subcommands be set from the environment
+fn with_all_variables(scope: &str, command: Command) -> Command {
+    command
+        .mut_args(|arg| with_variable(scope, arg))
+        .mut_subcommands(|subcommand| {
+            let scope = format!("{}{}_", scope, upper(subcommand.get_name()));
+            with_all_variables(&scope, subcommand)
+        })
+}
+
+/// Let `arg` be set from its variable, if it has one
+fn with_variable(scope: &str, arg: Arg) -> Arg {
+    match variable(scope, &arg) {
+        Some(name) => arg.env(name).hide_env_values(true),
+        None => arg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        let mut command = with_variables(<crate::Cli as clap::CommandFactory>::command());
+        command.build();
+        command
+    }
+
+    fn variable_of<'a>(command: &'a Command, subcommand: &str, id: &str) -> Option<&'a str> {
+        let subcommand = command.find_subcommand(subcommand).unwrap();
+        let arg = subcommand.get_arguments().find(|arg| arg.get_id() == id);
+        arg.and_then(Arg::get_env).and_then(|name| name.to_str())
+    }
+
+    #[test]
+    fn test_every_option_has_a_variable() {
+        let command = command();
+
+        assert_eq!(
+            variable_of(&command, "migrate", "target"),
+            Some("MATRIX_MIGRATE_MIGRATE_TARGET")
+        );
+        assert_eq!(
+            variable_of(&command, "migrate-state", "target"),
+            Some("MATRIX_MIGRATE_MIGRATE_STATE_TARGET")
+        );
+        assert_eq!(
+            variable_of(&command, "extract", "verbose"),
+            Some("MATRIX_MIGRATE_VERBOSE")
+        );
+
+        for subcommand in command.get_subcommands() {
+            for arg in subcommand.get_arguments() {
+                let Some(long) = arg.get_long() else { continue };
+                if !matches!(long, "help" | "version") && !OVERRIDES.contains(&long) {
+                    assert!(arg.get_env().is_some(), "{} has no variable", arg.get_id());
+                }
+            }
+        }
+        for arg in command.get_arguments() {
+            assert_eq!(
+                arg.get_env().is_some(),
+                arg.is_global_set(),
+                "{}",
+                arg.get_id()
+            );
+        }
+    }
+
+    #[test]
+    fn test_overrides_have_no_variable() {
+        let command = command();
+
+        assert_eq!(variable_of(&command, "import", "force"), None);
+        assert_eq!(variable_of(&command, "extract", "force"), None);
+        assert_eq!(variable_of(&command, "cleanup", "yes"), None);
+    }
+
+    #[test]
+    fn test_variables_of_other_commands_are_ignored() {
+        // Neither the unscoped name nor the one of `serve` reaches `extract`,
+        // whose --concurrency conflicts with --sled-path
+        std::env::set_var("MATRIX_MIGRATE_CONCURRENCY", "4");
+        std::env::set_var("MATRIX_MIGRATE_SERVE_CONCURRENCY", "4");
+
+        let matches = command().try_get_matches_from([
+            "sled-key-extractor",
+            "extract",
+            "--sled-path",
+            "/data/sled",
+            "--output",
+            "keys.json",
+        ]);
+
+        std::env::remove_var("MATRIX_MIGRATE_CONCURRENCY");
+        std::env::remove_var("MATRIX_MIGRATE_SERVE_CONCURRENCY");
+        let matches = matches.unwrap();
+        let (_, extract) = matches.subcommand().unwrap();
+        assert_eq!(extract.get_one::<u64>("concurrency"), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! Error categories and exit codes
+//!
+//! Most errors are carried as `anyhow::Error` with context added on the way
+//! up. Failures that scripts need to tell apart are raised as an
+//! [`ExtractorError`] at their source; [`exit_code`] finds it anywhere in the
+//! context chain and maps it to a stable process exit code. Everything else
+//! exits with 1, and clap uses 2 for invalid arguments.
+
+use std::path::PathBuf;
+
+/// Exit code for errors without a category
+pub const EXIT_FAILURE: u8 = 1;
+
+/// Errors with a stable exit code
+#[derive(Debug, thiserror::Error)]
+pub enum ExtractorError {
+    /// The sled store directory does not exist
+    #[error("Sled store path does not exist: {0:?}")]
+    StoreNotFound(PathBuf),
+
+    /// The sled store is in use by another process
+    #[error(
+        "Sled store {path:?} is locked by {holders} - stop the bot before migrating, \
+         or pass --force to read a copy of the store anyway"
+    )]
+    StoreLocked {
+        /// Store directory
+        path: PathBuf,
+        /// Description of the processes holding the lock
+        holders: String,
+    },
+
+    /// The passphrase does not unlock the store cipher
+    #[error("Wrong passphrase - {0}")]
+    WrongPassphrase(String),
+
+    /// Reading the sled database failed
+    #[error("Sled I/O error: {0}")]
+    SledIo(#[from] sled::Error),
+
+    /// The store is not the kind of store the command expects
+    #[error("Schema mismatch: {0}")]
+    SchemaMismatch(String),
+
+    /// A stored value could not be decrypted or unpickled
+    #[error("Corrupt pickle: {0}")]
+    CorruptPickle(String),
+
+    /// An encrypted export could not be decrypted
+    #[error("Failed to decrypt export - wrong passphrase or corrupted file")]
+    ExportDecryption,
+
+    /// More entries failed than `--fail-threshold` or `--max-failures` allow
+    #[error("Too many failures: {0}")]
+    TooManyFailures(String),
+
+    /// `check-export` found problems in an export file
+    #[error("Invalid export: {0}")]
+    InvalidExport(String),
+
+    /// The store or export belongs to another account than expected
+    #[error("Wrong account: {0}")]
+    AccountMismatch(String),
+
+    /// The run was interrupted and only a partial export was written
+    #[error("Interrupted: {0}")]
+    Interrupted(String),
+}
+
+impl ExtractorError {
+    /// Process exit code for this category
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::StoreNotFound(_) => 3,
+            Self::StoreLocked { .. } => 4,
+            Self::WrongPassphrase(_) => 5,
+            Self::SledIo(_) => 6,
+            Self::SchemaMismatch(_) => 7,
+            Self::CorruptPickle(_) => 8,
+            Self::ExportDecryption => 9,
+            Self::TooManyFailures(_) => 10,
+            Self::InvalidExport(_) => 11,
+            Self::AccountMismatch(_) => 12,
+            Self::Interrupted(_) => 13,
+        }
+    }
+}
+
+/// Exit code for an error, from the first [`ExtractorError`] in its chain
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<ExtractorError>())
+        .map_or(EXIT_FAILURE, ExtractorError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code_looks_through_context() {
+        let error = Err::<(), _>(ExtractorError::WrongPassphrase("test".to_string()))
+            .context("Failed to open store")
+            .unwrap_err();
+        assert_eq!(exit_code(&error), 5);
+
+        assert_eq!(exit_code(&anyhow::anyhow!("other")), EXIT_FAILURE);
+    }
+}
@@ -0,0 +1,336 @@
+//! C API for embedding the extractor in other runtimes
+//!
+//! Go bridges and C++ tools link the `cdylib` or `staticlib` build of this
+//! crate and call the functions below instead of running the binary. Each
+//! call does the same as the corresponding subcommand with default options,
+//! on a runtime of its own, so it must not be made from inside a Tokio
+//! runtime. Nothing is logged; the outcome is the return value and a JSON
+//! report. Only the passphrases passed in are used: a call never prompts on
+//! the host's terminal or reads `MATRIX_SLED_PASSPHRASE` from its environment.
+//!
+//! The return value is the exit code the CLI would end with: 0 on success, 2
+//! for invalid arguments (including ones the CLI would reject, such as an empty
+//! path) and otherwise one of the codes listed in the README.
+//! If `report_json` isn't null, it receives a JSON document owned by the
+//! caller, to be released with [`ske_free_string`]: the report of the run on
+//! success, or `{"error": ..., "exit_code": ...}` on failure.
+//!
+//! The header `include/sled_key_extractor.h` is generated with
+//! `cbindgen --config cbindgen.toml --output include/sled_key_extractor.h`;
+//! CI checks that the committed one is up to date.
+
+use std::ffi::{c_char, CStr, CString};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::error::{self, EXIT_FAILURE};
+use crate::settings::Settings;
+use crate::{parse_command, passphrase, run_extract, run_migrate, verify, Command, ExtractorError};
+
+/// Exit code for invalid arguments, as clap uses it for the CLI
+const INVALID_ARGUMENT: u8 = 2;
+
+/// Report of a failed call
+#[derive(Serialize)]
+struct ErrorReport {
+    error: String,
+    exit_code: u8,
+}
+
+/// A string argument, `None` for a null pointer
+///
+/// # Safety
+///
+/// `value` must be null or point to a NUL-terminated string.
+unsafe fn string_arg(value: *const c_char, name: &str) -> Result<Option<String>, String> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map(|value| Some(value.to_string()))
+        .map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+/// A string argument that must be given
+///
+/// # Safety
+///
+/// As for [`string_arg`].
+unsafe fn required_arg(value: *const c_char, name: &str) -> Result<String, String> {
+    string_arg(value, name)?.ok_or_else(|| format!("{} must not be null", name))
+}
+
+/// Parse the options of a subcommand, rejecting them as invalid arguments
+fn parse(args: Vec<String>) -> Result<Command, String> {
+    parse_command(args).map_err(|e| e.to_string().trim_end().to_string())
+}
+
+/// `--name=value`, so a value starting with a dash isn't taken for an option
+fn option(name: &str, value: String) -> String {
+    format!("--{}={}", name, value)
+}
+
+/// Run `task` to completion on a new multi-threaded runtime
+fn block_on<T>(task: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the async runtime")?
+        .block_on(task)
+}
+
+/// Hand `json` to the caller through `report_json`
+///
+/// # Safety
+///
+/// `report_json` must be null or valid for writing a pointer.
+unsafe fn respond(report_json: *mut *mut c_char, code: u8, json: String) -> i32 {
+    if !report_json.is_null() {
+        // JSON never contains NUL bytes, escaped or not
+        *report_json = CString::new(json).unwrap_or_default().into_raw();
+    }
+    i32::from(code)
+}
+
+/// Report `error` to the caller
+///
+/// # Safety
+///
+/// As for [`respond`].
+unsafe fn respond_error(report_json: *mut *mut c_char, error: String, code: u8) -> i32 {
+    let report = ErrorReport {
+        error,
+        exit_code: code,
+    };
+    let json = serde_json::to_string(&report).unwrap_or_default();
+    respond(report_json, code, json)
+}
+
+/// Run `call` and report its outcome, turning panics into failures
+///
+/// `call` gives the exit code for a successful run together with its report;
+/// `Err(message)` means invalid arguments.
+///
+/// # Safety
+///
+/// As for [`respond`].
+unsafe fn complete<T: Serialize>(
+    report_json: *mut *mut c_char,
+    call: impl FnOnce() -> Result<Result<(u8, T)>, String>,
+) -> i32 {
+    passphrase::set_explicit_only();
+    match std::panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(Ok((code, report)))) => match serde_json::to_string(&report) {
+            Ok(json) => respond(report_json, code, json),
+            Err(e) => respond_error(report_json, format!("Failed to serialize report: {}", e), 1),
+        },
+        Ok(Ok(Err(e))) => respond_error(report_json, format!("{:#}", e), error::exit_code(&e)),
+        Ok(Err(message)) => respond_error(report_json, message, INVALID_ARGUMENT),
+        Err(_) => respond_error(report_json, "Extraction panicked".to_string(), EXIT_FAILURE),
+    }
+}
+
+/// Extract the keys of a sled crypto store into an export file
+///
+/// Like `extract --sled-path SLED_PATH --output OUTPUT_PATH`, with
+/// `--skip-errors` if `skip_errors` is set. `passphrase` may be null for an
+/// unencrypted store. The report holds the number of keys, failed entries and
+/// rooms.
+///
+/// # Safety
+///
+/// The string arguments must be null or NUL-terminated strings, and
+/// `report_json` must be null or valid for writing a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ske_extract(
+    sled_path: *const c_char,
+    passphrase: *const c_char,
+    output_path: *const c_char,
+    skip_errors: bool,
+    report_json: *mut *mut c_char,
+) -> i32 {
+    complete(report_json, || {
+        let mut args = vec![
+            "extract".to_string(),
+            option("sled-path", required_arg(sled_path, "sled_path")?),
+            option("output", required_arg(output_path, "output_path")?),
+        ];
+        if let Some(passphrase) = string_arg(passphrase, "passphrase")? {
+            args.push(option("passphrase", passphrase));
+        }
+        if skip_errors {
+            args.push("--skip-errors".to_string());
+        }
+
+        match parse(args)? {
+            Command::Extract(args) => {
+                let settings = Settings::default();
+                Ok(block_on(run_extract(&settings, *args, false)).map(|totals| (0, totals)))
+            }
+            _ => unreachable!("parsed an extract command"),
+        }
+    })
+}
+
+/// Migrate the keys of a sled crypto store into a SQLite crypto store
+///
+/// Like `migrate --sled-path SLED_PATH --target TARGET_PATH`, with
+/// `--skip-errors` if `skip_errors` is set. Either passphrase may be null. The
+/// report holds the number of sessions read and imported.
+///
+/// # Safety
+///
+/// As for [`ske_extract`].
+#[no_mangle]
+pub unsafe extern "C" fn ske_migrate(
+    sled_path: *const c_char,
+    passphrase: *const c_char,
+    target_path: *const c_char,
+    target_passphrase: *const c_char,
+    skip_errors: bool,
+    report_json: *mut *mut c_char,
+) -> i32 {
+    complete(report_json, || {
+        let mut args = vec![
+            "migrate".to_string(),
+            option("sled-path", required_arg(sled_path, "sled_path")?),
+            option("target", required_arg(target_path, "target_path")?),
+        ];
+        if let Some(passphrase) = string_arg(passphrase, "passphrase")? {
+            args.push(option("passphrase", passphrase));
+        }
+        if let Some(passphrase) = string_arg(target_passphrase, "target_passphrase")? {
+            args.push(option("target-passphrase", passphrase));
+        }
+        if skip_errors {
+            args.push("--skip-errors".to_string());
+        }
+
+        match parse(args)? {
+            Command::Migrate(args) => {
+                let settings = Settings::default();
+                Ok(block_on(run_migrate(&settings, args)).map(|report| (0, report)))
+            }
+            _ => unreachable!("parsed a migrate command"),
+        }
+    })
+}
+
+/// Compare the sessions of a sled crypto store with a migrated SQLite store
+///
+/// Like `verify-migration`. Returns 1 with the full report if any session
+/// differs between the stores.
+///
+/// # Safety
+///
+/// As for [`ske_extract`].
+#[no_mangle]
+pub unsafe extern "C" fn ske_verify(
+    sled_path: *const c_char,
+    passphrase: *const c_char,
+    target_path: *const c_char,
+    target_passphrase: *const c_char,
+    report_json: *mut *mut c_char,
+) -> i32 {
+    complete(report_json, || {
+        let sled_path = required_arg(sled_path, "sled_path")?;
+        let passphrase = string_arg(passphrase, "passphrase")?;
+        let target_path = required_arg(target_path, "target_path")?;
+        let target_passphrase = string_arg(target_passphrase, "target_passphrase")?;
+
+        Ok(block_on(async {
+            if !Path::new(&sled_path).exists() {
+                return Err(ExtractorError::StoreNotFound(sled_path.into()).into());
+            }
+            let report = verify::verify_migration(
+                &Settings::default(),
+                Path::new(&sled_path),
+                passphrase.as_deref(),
+                Path::new(&target_path),
+                target_passphrase.as_deref(),
+            )
+            .await?;
+            let code = if report.differences() > 0 { EXIT_FAILURE } else { 0 };
+            Ok((code, report))
+        }))
+    })
+}
+
+/// Release a report returned by one of the functions above
+///
+/// # Safety
+///
+/// `report` must be null or a pointer returned through `report_json` that
+/// wasn't released before.
+#[no_mangle]
+pub unsafe extern "C" fn ske_free_string(report: *mut c_char) {
+    if !report.is_null() {
+        drop(CString::from_raw(report));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_are_reported_as_json() {
+        let sled_path = CString::new("/nonexistent/sled-store").unwrap();
+        let mut report = std::ptr::null_mut();
+
+        let code = unsafe {
+            ske_verify(
+                sled_path.as_ptr(),
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+                &mut report,
+            )
+        };
+        assert_eq!(code, 2);
+        let json: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(report) }.to_str().unwrap()).unwrap();
+        assert_eq!(json["error"], "target_path must not be null");
+        unsafe { ske_free_string(report) };
+
+        let target_path = CString::new("/nonexistent/sqlite-store").unwrap();
+        let code = unsafe {
+            ske_verify(
+                sled_path.as_ptr(),
+                std::ptr::null(),
+                target_path.as_ptr(),
+                std::ptr::null(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(code, 3);
+    }
+
+    #[test]
+    fn test_arguments_the_cli_rejects_are_invalid() {
+        let empty = CString::new("").unwrap();
+        let output_path = CString::new("/nonexistent/keys.json").unwrap();
+        let mut report = std::ptr::null_mut();
+
+        let code = unsafe {
+            ske_extract(
+                empty.as_ptr(),
+                std::ptr::null(),
+                output_path.as_ptr(),
+                false,
+                &mut report,
+            )
+        };
+        assert_eq!(code, 2);
+        let json: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(report) }.to_str().unwrap()).unwrap();
+        assert_eq!(json["exit_code"], 2);
+        assert!(json["error"].as_str().unwrap().contains("--sled-path"));
+        unsafe { ske_free_string(report) };
+    }
+}
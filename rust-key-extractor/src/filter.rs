@@ -0,0 +1,439 @@
+//! Selecting the keys to extract
+//!
+//! By default every key of the store ends up in the export. For a migration
+//! done room by room, e.g. one bridge room at a time, the extraction can be
+//! limited to some rooms or leave some out. Rooms can be listed or matched by
+//! a regular expression, e.g. all rooms of one homeserver or the per-user
+//! rooms of a bridge. Room IDs in sled keys are hashed in encrypted stores, so
+//! sessions are still decoded and filtered afterwards.
+//!
+//! Keys can also be limited to sessions created by some devices, e.g. to
+//! investigate decryption failures with a particular counterpart. Sessions only
+//! record the Curve25519 key of their sender, so users are resolved to the keys
+//! of their devices in the store's `devices` tree.
+//!
+//! For incremental runs against an account that was partially backed up,
+//! sessions already in the server-side backup can be left out. The extractor
+//! doesn't talk to the homeserver; it reads the backup's session list as
+//! returned by `GET /_matrix/client/v3/room_keys/keys`.
+//!
+//! While the old bot is still running, repeated extractions can be limited to
+//! what changed since an earlier export: sessions created since, and sessions
+//! now known from an earlier message index. The final delta before the
+//! cutover is then small, and importing it on top of the earlier export is
+//! idempotent.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use matrix_sdk_crypto::ReadOnlyDevice;
+use regex::RegexSet;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::trees::{self, DEVICES_TREE};
+use crate::{load_store_cipher, redact, ExportedKeyData, ExtractionOutput};
+
+/// Options selecting the keys that are extracted
+#[derive(Args, Debug, Clone, Default)]
+pub struct FilterArgs {
+    /// Only extract keys of these rooms (comma-separated, repeatable)
+    #[arg(long = "room", value_name = "ROOM_ID", value_delimiter = ',')]
+    pub rooms: Vec<String>,
+
+    /// Leave out keys of these rooms (comma-separated, repeatable)
+    #[arg(long = "exclude-room", value_name = "ROOM_ID", value_delimiter = ',')]
+    pub exclude_rooms: Vec<String>,
+
+    /// File with room IDs to extract, one per line (`#` lines are comments), added to --room
+    #[arg(long, value_name = "FILE")]
+    pub rooms_file: Option<PathBuf>,
+
+    /// Also extract keys of rooms whose ID matches this regular expression (repeatable),
+    /// e.g. ':example\.org$'
+    #[arg(long = "room-pattern", value_name = "REGEX")]
+    pub room_patterns: Vec<String>,
+
+    /// Only extract sessions created by these Curve25519 device keys (comma-separated,
+    /// repeatable)
+    #[arg(long = "sender-key", value_name = "KEY", value_delimiter = ',')]
+    pub sender_keys: Vec<String>,
+
+    /// Only extract sessions created by devices of these users, as known to the store
+    /// (comma-separated, repeatable)
+    #[arg(long = "sender-user", value_name = "USER_ID", value_delimiter = ',')]
+    pub sender_users: Vec<String>,
+
+    /// Leave out sessions listed in FILE, the server-side backup's response to
+    /// `GET /_matrix/client/v3/room_keys/keys`
+    #[arg(long, value_name = "FILE")]
+    pub only_missing_from_backup: Option<PathBuf>,
+
+    /// Only extract sessions that are new or improved since this earlier export
+    /// (encrypted exports are read with --since-export-passphrase)
+    #[arg(long, value_name = "FILE")]
+    pub since_export: Option<PathBuf>,
+}
+
+impl FilterArgs {
+    /// Build the filter, reading `--rooms-file` and the backup listing if given
+    pub fn build(&self) -> Result<KeyFilter> {
+        let mut include: HashSet<String> = self.rooms.iter().cloned().collect();
+        if let Some(path) = &self.rooms_file {
+            include.extend(read_rooms_file(path)?);
+            if include.is_empty() {
+                anyhow::bail!("{:?} lists no rooms", path);
+            }
+        }
+
+        let patterns = if self.room_patterns.is_empty() {
+            None
+        } else {
+            Some(RegexSet::new(&self.room_patterns).context("Invalid --room-pattern")?)
+        };
+
+        Ok(KeyFilter {
+            include,
+            patterns,
+            exclude: self.exclude_rooms.iter().cloned().collect(),
+            sender_keys: self.sender_keys.iter().cloned().collect(),
+            sender_users: self.sender_users.clone(),
+            backed_up: self
+                .only_missing_from_backup
+                .as_deref()
+                .map(read_backup_listing)
+                .transpose()?,
+            // Read by the caller, which knows the export's passphrase
+            previous: None,
+        })
+    }
+}
+
+/// Sessions of a server-side backup by room, as listed by `GET room_keys/keys`
+#[derive(Deserialize)]
+struct BackupListing {
+    rooms: HashMap<String, BackupRoom>,
+}
+
+#[derive(Deserialize)]
+struct BackupRoom {
+    /// The backed up session data is not needed, only its session ID
+    sessions: HashMap<String, IgnoredAny>,
+}
+
+/// Read the session IDs of a backup listing, by room
+fn read_backup_listing(path: &Path) -> Result<HashMap<String, HashSet<String>>> {
+    let data =
+        std::fs::read(path).with_context(|| format!("Failed to read backup listing {:?}", path))?;
+    let listing: BackupListing =
+        serde_json::from_slice(&data).context("Failed to parse backup listing")?;
+
+    let rooms: HashMap<String, HashSet<String>> = listing
+        .rooms
+        .into_iter()
+        .map(|(room_id, room)| (room_id, room.sessions.into_keys().collect()))
+        .collect();
+    info!(
+        "Backup listing holds {} sessions in {} rooms",
+        rooms.values().map(HashSet::len).sum::<usize>(),
+        rooms.len()
+    );
+    Ok(rooms)
+}
+
+/// Read room IDs from a file, skipping empty lines and comments
+fn read_rooms_file(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read rooms file {:?}", path))?;
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Decides which extracted keys go into the export
+#[derive(Debug, Default)]
+pub struct KeyFilter {
+    /// Rooms to keep; empty keeps every room unless there are patterns
+    include: HashSet<String>,
+    /// Room ID patterns to keep, in addition to `include`
+    patterns: Option<RegexSet>,
+    /// Rooms to leave out, even if included
+    exclude: HashSet<String>,
+    /// Sender keys to keep; empty keeps every sender unless there are sender users
+    sender_keys: HashSet<String>,
+    /// Users whose device keys are added to `sender_keys` by [`KeyFilter::resolve_sender_users`]
+    sender_users: Vec<String>,
+    /// Sessions already in the server-side backup, by room
+    backed_up: Option<HashMap<String, HashSet<String>>>,
+    /// First known index of every session of an earlier export, by room and session ID
+    previous: Option<HashMap<String, HashMap<String, Option<u32>>>>,
+}
+
+impl KeyFilter {
+    /// Whether the filter keeps every key
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.patterns.is_none()
+            && self.exclude.is_empty()
+            && self.sender_keys.is_empty()
+            && self.sender_users.is_empty()
+            && self.backed_up.is_none()
+            && self.previous.is_none()
+    }
+
+    /// Whether a key belongs in the export
+    pub fn matches(&self, key: &ExportedKeyData) -> bool {
+        !self.exclude.contains(&key.room_id)
+            && self.includes_room(&key.room_id)
+            && self.includes_sender(&key.sender_key)
+            && !self.is_backed_up(key)
+            && !self.was_exported(key)
+    }
+
+    /// Only keep keys that are new or improved since `previous`
+    pub fn set_previous_export(&mut self, previous: &ExtractionOutput) {
+        let mut sessions: HashMap<String, HashMap<String, Option<u32>>> = HashMap::new();
+        for key in &previous.all_keys {
+            sessions
+                .entry(key.room_id.clone())
+                .or_default()
+                .insert(key.session_id.clone(), key.first_known_index);
+        }
+        self.previous = Some(sessions);
+    }
+
+    /// Whether an earlier export has the key from the same or an earlier index
+    ///
+    /// Version 1 exports don't record the index, so any copy counts.
+    fn was_exported(&self, key: &ExportedKeyData) -> bool {
+        let previous = self
+            .previous
+            .as_ref()
+            .and_then(|rooms| rooms.get(&key.room_id))
+            .and_then(|sessions| sessions.get(&key.session_id));
+
+        match (previous, key.first_known_index) {
+            (None, _) => false,
+            (Some(Some(previous)), Some(current)) => *previous <= current,
+            (Some(_), _) => true,
+        }
+    }
+
+    fn is_backed_up(&self, key: &ExportedKeyData) -> bool {
+        self.backed_up
+            .as_ref()
+            .and_then(|rooms| rooms.get(&key.room_id))
+            .is_some_and(|sessions| sessions.contains(&key.session_id))
+    }
+
+    fn includes_sender(&self, sender_key: &str) -> bool {
+        (self.sender_keys.is_empty() && self.sender_users.is_empty())
+            || self.sender_keys.contains(sender_key)
+    }
+
+    /// Add the Curve25519 keys of the `--sender-user` devices known to the sled
+    /// crypto store at `path`
+    ///
+    /// Devices that can't be decoded are skipped; a user without any known
+    /// device only gets a warning, since their sessions can't be told apart.
+    pub fn resolve_sender_users(
+        &mut self,
+        settings: &Settings,
+        path: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<()> {
+        if self.sender_users.is_empty() {
+            return Ok(());
+        }
+
+        let db = settings
+            .sled_config()
+            .path(path)
+            .open()
+            .map_err(ExtractorError::SledIo)
+            .context("Failed to open sled database")?;
+        let store_cipher = load_store_cipher(settings, &db, passphrase.unwrap_or(""))?;
+        let (devices, _) = trees::read_tree::<ReadOnlyDevice>(
+            settings,
+            &db,
+            DEVICES_TREE,
+            store_cipher.as_ref(),
+            true,
+        )?;
+
+        for user_id in &self.sender_users {
+            let keys: Vec<String> = devices
+                .iter()
+                .filter(|device| device.user_id().as_str() == user_id)
+                .filter_map(|device| device.curve25519_key())
+                .map(|key| key.to_base64())
+                .collect();
+
+            if keys.is_empty() {
+                warn!(
+                    "No devices of {} known to the store - --sender-user matches none of \
+                     their sessions",
+                    redact::id(settings, user_id)
+                );
+            } else {
+                info!(
+                    "Sender {} has {} device key(s)",
+                    redact::id(settings, user_id),
+                    keys.len()
+                );
+            }
+            self.sender_keys.extend(keys);
+        }
+        Ok(())
+    }
+
+    fn includes_room(&self, room_id: &str) -> bool {
+        match &self.patterns {
+            Some(patterns) => self.include.contains(room_id) || patterns.is_match(room_id),
+            None => self.include.is_empty() || self.include.contains(room_id),
+        }
+    }
+
+    /// Short description for the log
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.include.is_empty() {
+            parts.push(format!("{} room(s) included", self.include.len()));
+        }
+        if let Some(patterns) = &self.patterns {
+            parts.push(format!(
+                "rooms matching {}",
+                patterns.patterns().join(" or ")
+            ));
+        }
+        if !self.exclude.is_empty() {
+            parts.push(format!("{} room(s) excluded", self.exclude.len()));
+        }
+        if !self.sender_keys.is_empty() || !self.sender_users.is_empty() {
+            parts.push(format!(
+                "{} sender key(s), {} sender user(s)",
+                self.sender_keys.len(),
+                self.sender_users.len()
+            ));
+        }
+        if self.backed_up.is_some() {
+            parts.push("sessions missing from the backup".to_string());
+        }
+        if self.previous.is_some() {
+            parts.push("sessions new since the earlier export".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn key(room_id: &str) -> ExportedKeyData {
+        testing::key(room_id, "session")
+    }
+
+    #[test]
+    fn test_rooms_file_and_exclusions() {
+        let dir = TempDir::new("rooms-test");
+        let path = dir.join("rooms.txt");
+        std::fs::write(&path, "# bridge rooms\n!a:x.org\n\n  !b:x.org\n").unwrap();
+
+        let args = FilterArgs {
+            rooms: vec!["!c:x.org".to_string()],
+            exclude_rooms: vec!["!b:x.org".to_string()],
+            rooms_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let filter = args.build().unwrap();
+
+        assert!(filter.matches(&key("!a:x.org")));
+        assert!(!filter.matches(&key("!b:x.org")));
+        assert!(filter.matches(&key("!c:x.org")));
+        assert!(!filter.matches(&key("!d:x.org")));
+        assert!(KeyFilter::default().matches(&key("!d:x.org")));
+    }
+
+    #[test]
+    fn test_room_patterns_and_sender_keys() {
+        let args = FilterArgs {
+            rooms: vec!["!a:other.org".to_string()],
+            room_patterns: vec![r":x\.org$".to_string()],
+            exclude_rooms: vec!["!b:x.org".to_string()],
+            ..Default::default()
+        };
+        let filter = args.build().unwrap();
+
+        assert!(filter.matches(&key("!a:x.org")));
+        assert!(filter.matches(&key("!a:other.org")));
+        assert!(!filter.matches(&key("!b:x.org")));
+        assert!(!filter.matches(&key("!c:x.org.evil")));
+        assert!(!filter.matches(&key("!d:other.org")));
+
+        let invalid = FilterArgs {
+            room_patterns: vec!["(".to_string()],
+            ..Default::default()
+        };
+        assert!(invalid.build().is_err());
+
+        let mut sender_key = key("!a:x.org");
+        sender_key.sender_key = "other".to_string();
+        let by_sender = FilterArgs {
+            sender_keys: vec!["other".to_string()],
+            ..Default::default()
+        };
+        let filter = by_sender.build().unwrap();
+        assert!(filter.matches(&sender_key));
+        assert!(!filter.matches(&key("!a:x.org")));
+    }
+
+    #[test]
+    fn test_only_missing_from_backup() {
+        let dir = TempDir::new("backup-listing");
+        let path = dir.join("backup.json");
+        std::fs::write(
+            &path,
+            r#"{"rooms":{"!a:x.org":{"sessions":{"session":{"first_message_index":0}}}}}"#,
+        )
+        .unwrap();
+
+        let args = FilterArgs {
+            only_missing_from_backup: Some(path.clone()),
+            ..Default::default()
+        };
+        let filter = args.build().unwrap();
+
+        assert!(!filter.matches(&key("!a:x.org")));
+        assert!(filter.matches(&key("!b:x.org")));
+    }
+
+    #[test]
+    fn test_since_export_keeps_new_and_improved_sessions() {
+        let mut old = key("!a:x.org");
+        old.first_known_index = Some(5);
+        let previous = crate::build_output(vec![old], 0, true);
+
+        let mut filter = KeyFilter::default();
+        filter.set_previous_export(&previous);
+
+        let mut same = key("!a:x.org");
+        same.first_known_index = Some(5);
+        let mut improved = key("!a:x.org");
+        improved.first_known_index = Some(0);
+
+        assert!(!filter.matches(&same));
+        assert!(filter.matches(&improved));
+        assert!(filter.matches(&key("!b:x.org")));
+    }
+}
@@ -0,0 +1,387 @@
+//! Synthetic sled crypto stores for testing
+//!
+//! `gen-fixture` writes a sled crypto store holding fake inbound group
+//! sessions, so the fault-tolerant path can be tested without a real bot's
+//! keys. The sessions are real Megolm sessions with fresh random keys, spread
+//! round-robin over `!fixture-N:example.org` rooms and saved through
+//! matrix-sdk-sled exactly like a bot would save them - encrypted with a store
+//! cipher if a passphrase is given.
+//!
+//! Afterwards, chosen entries of the sessions tree can be overwritten with
+//! corrupted values, either a fixed number or a percentage of them per kind.
+//! Which entries get which corruption is picked from `--seed`, so a fixture
+//! with the same options always has the same shape; only the key material
+//! differs between runs. A manifest lists every session with its sled key,
+//! corruption and the failure category `--skip-errors` should report for it,
+//! for tests to check extraction results against.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use matrix_sdk_sled::SledCryptoStore;
+use matrix_sdk_store_encryption::{EncryptedValue, StoreCipher};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use tracing::info;
+use vodozemac::megolm::{GroupSession, InboundGroupSession, SessionConfig};
+use vodozemac::{Curve25519PublicKey, Curve25519SecretKey, Ed25519SecretKey};
+use zeroize::Zeroizing;
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::{decode_session, import, load_store_cipher, ExportedKeyData, FailureCategory};
+
+/// Ways to damage a stored session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corruption {
+    /// Replace the value with random bytes of the same length
+    Garbage,
+    /// Cut the value in half
+    Truncate,
+    /// Store a validly encrypted (or plain) value that isn't JSON
+    BadJson,
+    /// Flip one bit of the ciphertext (encrypted stores only)
+    BitFlip,
+    /// Re-encrypt the value with a different store cipher (encrypted stores only)
+    WrongCipher,
+}
+
+impl Corruption {
+    /// Whether the corruption only applies to values encrypted with a store cipher
+    pub fn needs_cipher(self) -> bool {
+        matches!(self, Self::BitFlip | Self::WrongCipher)
+    }
+
+    /// Category a fault-tolerant extraction should report for a damaged value
+    pub fn expected_failure(self) -> FailureCategory {
+        match self {
+            Self::Garbage | Self::Truncate | Self::BadJson => FailureCategory::Json,
+            Self::BitFlip | Self::WrongCipher => FailureCategory::Decryption,
+        }
+    }
+}
+
+/// How many entries to damage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    /// A fixed number of entries
+    Count(usize),
+    /// A percentage of all sessions, rounded to the nearest entry
+    Percent(f64),
+}
+
+impl Amount {
+    /// Number of entries out of `sessions`
+    pub fn of(self, sessions: usize) -> usize {
+        match self {
+            Self::Count(count) => count,
+            Self::Percent(percent) => (sessions as f64 * percent / 100.0).round() as usize,
+        }
+    }
+}
+
+/// Parse `KIND=COUNT` or `KIND=PERCENT%`, e.g. `truncate=5` or `bit-flip=2.5%`
+pub fn parse_corruption(value: &str) -> std::result::Result<(Corruption, Amount), String> {
+    let (kind, amount) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KIND=COUNT or KIND=PERCENT%, got {:?}", value))?;
+    let kind = <Corruption as clap::ValueEnum>::from_str(kind, true)?;
+    let amount = match amount.strip_suffix('%') {
+        Some(percent) => match percent.parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Amount::Percent(percent),
+            _ => return Err(format!("invalid percentage {:?}", amount)),
+        },
+        None => Amount::Count(
+            amount
+                .parse()
+                .map_err(|_| format!("invalid count {:?}", amount))?,
+        ),
+    };
+    Ok((kind, amount))
+}
+
+/// What to generate
+#[derive(Debug, Clone, Default)]
+pub struct FixtureOptions {
+    /// Number of sessions
+    pub sessions: usize,
+    /// Number of rooms the sessions are spread over
+    pub rooms: usize,
+    /// Passphrase of the store cipher; without one the store is unencrypted
+    pub passphrase: Option<String>,
+    /// Corruptions and how many entries to apply them to
+    pub corrupt: Vec<(Corruption, Amount)>,
+    /// Seed for picking the corrupted entries
+    pub seed: u64,
+}
+
+/// One generated session
+#[derive(Debug, Serialize)]
+pub struct FixtureSession {
+    /// Room of the session
+    pub room_id: String,
+    /// Megolm session ID
+    pub session_id: String,
+    /// Raw sled key of the session entry as hex
+    pub key_hex: String,
+    /// How the stored value was damaged, if it was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corruption: Option<Corruption>,
+    /// Failure category a fault-tolerant extraction should report, if damaged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_failure: Option<FailureCategory>,
+}
+
+/// Description of a generated store, written with `--manifest`
+#[derive(Debug, Serialize)]
+pub struct FixtureManifest {
+    /// Whether the store has a store cipher
+    pub encrypted: bool,
+    /// Seed the corrupted entries were picked with
+    pub seed: u64,
+    /// Every session, in sled order
+    pub sessions: Vec<FixtureSession>,
+}
+
+/// A generated store
+pub struct Fixture {
+    /// Description of the store
+    pub manifest: FixtureManifest,
+    /// Keys of all sessions, including those whose stored value was corrupted
+    pub keys: Vec<ExportedKeyData>,
+}
+
+/// A fake Megolm session key for `room_id`
+fn fake_key(room_id: &str) -> Result<ExportedKeyData> {
+    let outbound = GroupSession::new(SessionConfig::version_1());
+    let mut inbound = InboundGroupSession::new(&outbound.session_key(), SessionConfig::version_1());
+    let session_key = inbound
+        .export_at(0)
+        .context("Failed to export the generated session")?
+        .to_base64();
+
+    Ok(ExportedKeyData {
+        room_id: room_id.to_string(),
+        session_id: outbound.session_id(),
+        algorithm: "m.megolm.v1.aes-sha2".to_string(),
+        session_key,
+        sender_key: Curve25519PublicKey::from(&Curve25519SecretKey::new()).to_base64(),
+        sender_claimed_keys: [(
+            "ed25519".to_string(),
+            Ed25519SecretKey::new().public_key().to_base64(),
+        )]
+        .into(),
+        forwarding_curve25519_key_chain: Vec::new(),
+        first_known_index: None,
+        backed_up: None,
+        imported: None,
+        extra: Default::default(),
+        sha256: String::new(),
+    })
+}
+
+/// Damage a stored value
+///
+/// `cipher` is the store cipher the value is encrypted with, if any.
+pub fn corrupt_value(
+    value: &[u8],
+    corruption: Corruption,
+    rng: &mut impl Rng,
+    cipher: Option<&StoreCipher>,
+) -> Result<Vec<u8>> {
+    let encrypt = |cipher: &StoreCipher, plaintext: Vec<u8>| -> Result<Vec<u8>> {
+        let encrypted = cipher
+            .encrypt_value_data(plaintext)
+            .context("Failed to encrypt value")?;
+        Ok(serde_json::to_vec(&encrypted)?)
+    };
+    let envelope = || -> Result<(&StoreCipher, EncryptedValue)> {
+        let cipher = cipher.with_context(|| {
+            format!("{:?} needs a store encrypted with a passphrase", corruption)
+        })?;
+        let envelope = serde_json::from_slice(value).context("Value is not an encrypted value")?;
+        Ok((cipher, envelope))
+    };
+
+    Ok(match corruption {
+        Corruption::Garbage => {
+            let mut garbage = vec![0u8; value.len().max(1)];
+            rng.fill_bytes(&mut garbage);
+            garbage
+        }
+        Corruption::Truncate => value[..value.len() / 2].to_vec(),
+        Corruption::BadJson => {
+            let plaintext = b"{\"pickle\": not json".to_vec();
+            match cipher {
+                Some(cipher) => encrypt(cipher, plaintext)?,
+                None => plaintext,
+            }
+        }
+        Corruption::BitFlip => {
+            let (_, mut envelope) = envelope()?;
+            if envelope.ciphertext.is_empty() {
+                anyhow::bail!("Encrypted value has no ciphertext to flip a bit in");
+            }
+            let bit = rng.gen_range(0..envelope.ciphertext.len() * 8);
+            envelope.ciphertext[bit / 8] ^= 1 << (bit % 8);
+            serde_json::to_vec(&envelope)?
+        }
+        Corruption::WrongCipher => {
+            let (cipher, envelope) = envelope()?;
+            let plaintext = Zeroizing::new(
+                cipher
+                    .decrypt_value_data(envelope)
+                    .context("Failed to decrypt value")?,
+            );
+            let other = StoreCipher::new().context("Failed to create a store cipher")?;
+            encrypt(&other, plaintext.to_vec())?
+        }
+    })
+}
+
+/// Generate a store at `path`, which must not exist yet
+pub async fn generate(
+    settings: &Settings,
+    path: &Path,
+    options: &FixtureOptions,
+) -> Result<Fixture> {
+    if path.exists() {
+        anyhow::bail!(
+            "{:?} already exists - the fixture is written to a new directory",
+            path
+        );
+    }
+    let corrupt: Vec<(Corruption, usize)> = options
+        .corrupt
+        .iter()
+        .map(|&(corruption, amount)| (corruption, amount.of(options.sessions)))
+        .collect();
+    let corrupted: usize = corrupt.iter().map(|(_, count)| count).sum();
+    if corrupted > options.sessions {
+        anyhow::bail!(
+            "Can't corrupt {} of {} sessions",
+            corrupted,
+            options.sessions
+        );
+    }
+    if options.passphrase.is_none() {
+        if let Some((corruption, _)) = corrupt.iter().find(|(kind, _)| kind.needs_cipher()) {
+            anyhow::bail!("{:?} needs an encrypted store - pass --passphrase", corruption);
+        }
+    }
+
+    info!(
+        "Generating {} sessions in {} rooms",
+        options.sessions,
+        options.rooms.max(1)
+    );
+    let mut keys = Vec::with_capacity(options.sessions);
+    let mut sessions = Vec::with_capacity(options.sessions);
+    for index in 0..options.sessions {
+        let key = fake_key(&format!(
+            "!fixture-{}:example.org",
+            index % options.rooms.max(1)
+        ))?;
+        sessions.push(import::session_from_key(&key).await?);
+        keys.push(key);
+    }
+
+    let db = settings
+        .sled_config()
+        .path(path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to create sled database")?;
+    let store = SledCryptoStore::open_with_database(db.clone(), options.passphrase.as_deref())
+        .await
+        .context("Failed to create Sled crypto store")?;
+    import::save_sessions(&store, sessions).await?;
+
+    // Find each session's entry by decoding it again
+    let cipher = match &options.passphrase {
+        Some(passphrase) => load_store_cipher(settings, &db, passphrase)?,
+        None => None,
+    };
+    let tree = db
+        .open_tree(crate::INBOUND_GROUP_SESSIONS_TREE)
+        .context("Failed to open inbound group sessions tree")?;
+    let mut entries = Vec::with_capacity(options.sessions);
+    for item in tree.iter() {
+        let (key, value) = item.map_err(ExtractorError::SledIo)?;
+        let session = decode_session(&value, cipher.as_ref())
+            .map_err(|(_, error)| anyhow::anyhow!("Generated session unreadable: {}", error))?;
+        entries.push(FixtureSession {
+            room_id: session.room_id().to_string(),
+            session_id: session.session_id().to_string(),
+            key_hex: hex::encode(&key),
+            corruption: None,
+            expected_failure: None,
+        });
+    }
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.shuffle(&mut rng);
+    let mut targets = order.into_iter();
+    for &(corruption, count) in &corrupt {
+        for index in targets.by_ref().take(count) {
+            let entry = &mut entries[index];
+            let key = hex::decode(&entry.key_hex)?;
+            let value = tree
+                .get(&key)
+                .map_err(ExtractorError::SledIo)?
+                .unwrap_or_default();
+            let damaged = corrupt_value(&value, corruption, &mut rng, cipher.as_ref())?;
+            tree.insert(key, damaged).map_err(ExtractorError::SledIo)?;
+            entry.corruption = Some(corruption);
+            entry.expected_failure = Some(corruption.expected_failure());
+        }
+        info!("Corrupted {} entries ({:?})", count, corruption);
+    }
+    db.flush().map_err(ExtractorError::SledIo)?;
+
+    Ok(Fixture {
+        manifest: FixtureManifest {
+            encrypted: cipher.is_some(),
+            seed: options.seed,
+            sessions: entries,
+        },
+        keys,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corruptions_damage_the_value() {
+        let value = br#"{"pickle":"abc","room_id":"!room:example.org"}"#;
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let truncated = corrupt_value(value, Corruption::Truncate, &mut rng, None).unwrap();
+        assert_eq!(truncated.len(), value.len() / 2);
+        let garbage = corrupt_value(value, Corruption::Garbage, &mut rng, None).unwrap();
+        assert_eq!(garbage.len(), value.len());
+        assert_ne!(garbage, value);
+        let bad_json = corrupt_value(value, Corruption::BadJson, &mut rng, None).unwrap();
+        assert!(serde_json::from_slice::<serde_json::Value>(&bad_json).is_err());
+        assert!(corrupt_value(value, Corruption::BitFlip, &mut rng, None).is_err());
+        assert!(corrupt_value(value, Corruption::WrongCipher, &mut rng, None).is_err());
+
+        assert_eq!(
+            parse_corruption("truncate=3"),
+            Ok((Corruption::Truncate, Amount::Count(3)))
+        );
+        assert_eq!(
+            parse_corruption("bit-flip=2.5%"),
+            Ok((Corruption::BitFlip, Amount::Percent(2.5)))
+        );
+        assert_eq!(Amount::Percent(2.5).of(200), 5);
+        assert!(parse_corruption("melt=3").is_err());
+        assert!(parse_corruption("garbage=150%").is_err());
+    }
+}
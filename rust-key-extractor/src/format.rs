@@ -0,0 +1,242 @@
+//! Export file encodings
+//!
+//! Exports are JSON by default. CBOR and MessagePack carry exactly the same
+//! structure but without whitespace and quoting overhead, which matters for
+//! exports with hundreds of thousands of keys. Any of them can additionally be
+//! compressed with zstd or gzip. Readers detect compression from the magic
+//! bytes and the encoding from the first byte, so `import` accepts every
+//! combination without extra flags.
+
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Magic bytes at the start of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Magic bytes at the start of a gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// zstd level used for exports; a good trade-off between speed and size
+const ZSTD_LEVEL: i32 = 3;
+
+/// Encoding of an export file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Pretty-printed JSON (readable, used by the upload scripts)
+    Json,
+    /// CBOR (RFC 8949)
+    Cbor,
+    /// MessagePack
+    Msgpack,
+}
+
+impl OutputFormat {
+    /// File extension used for this encoding
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Cbor => "cbor",
+            Self::Msgpack => "msgpack",
+        }
+    }
+
+    /// Guess the encoding of `data` from its first byte
+    ///
+    /// All exports are maps at the top level: JSON starts with `{` (possibly
+    /// after whitespace), CBOR maps with a major type 5 byte (`0xa0..=0xbf`) and
+    /// MessagePack maps with a fixmap or map16/map32 marker.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data.iter().find(|b| !b.is_ascii_whitespace())? {
+            b'{' => Some(Self::Json),
+            0xa0..=0xbf => Some(Self::Cbor),
+            0x80..=0x8f | 0xde | 0xdf => Some(Self::Msgpack),
+            _ => None,
+        }
+    }
+}
+
+/// Compression applied on top of the encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// Zstandard
+    Zstd,
+    /// gzip
+    Gzip,
+}
+
+impl Compression {
+    /// File extension conventionally used for this compression
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+        }
+    }
+
+    /// Guess the compression of `data` from its magic bytes
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else if data.starts_with(&GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Compress `data`, or copy it unchanged without a compression
+pub fn compress(data: &[u8], compression: Option<Compression>) -> Result<Vec<u8>> {
+    match compression {
+        None => Ok(data.to_vec()),
+        Some(Compression::Zstd) => {
+            zstd::encode_all(data, ZSTD_LEVEL).context("Failed to compress with zstd")
+        }
+        Some(Compression::Gzip) => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).context("Failed to compress with gzip")?;
+            encoder.finish().context("Failed to compress with gzip")
+        }
+    }
+}
+
+/// Writer that compresses on the fly, for exports written incrementally
+pub enum CompressWriter<W: Write> {
+    /// No compression
+    Plain(W),
+    /// Zstandard
+    Zstd(zstd::Encoder<'static, W>),
+    /// gzip
+    Gzip(flate2::write::GzEncoder<W>),
+}
+
+impl<W: Write> CompressWriter<W> {
+    /// Wrap `inner`, or pass writes through unchanged without a compression
+    pub fn new(inner: W, compression: Option<Compression>) -> Result<Self> {
+        Ok(match compression {
+            None => Self::Plain(inner),
+            Some(Compression::Zstd) => Self::Zstd(
+                zstd::Encoder::new(inner, ZSTD_LEVEL).context("Failed to start zstd stream")?,
+            ),
+            Some(Compression::Gzip) => Self::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+        })
+    }
+
+    /// Write the compression trailer and return the inner writer
+    pub fn finish(self) -> Result<W> {
+        match self {
+            Self::Plain(inner) => Ok(inner),
+            Self::Zstd(encoder) => encoder.finish().context("Failed to compress with zstd"),
+            Self::Gzip(encoder) => encoder.finish().context("Failed to compress with gzip"),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(inner) => inner.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(inner) => inner.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Decompress `data` if it starts with a known magic number
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    match Compression::detect(data) {
+        None => Ok(data.to_vec()),
+        Some(Compression::Zstd) => zstd::decode_all(data).context("Failed to decompress zstd"),
+        Some(Compression::Gzip) => {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut decompressed)
+                .context("Failed to decompress gzip")?;
+            Ok(decompressed)
+        }
+    }
+}
+
+/// Serialize a value in the given encoding
+pub fn encode<T: Serialize>(value: &T, format: OutputFormat) -> Result<Vec<u8>> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_vec_pretty(value).context("Failed to serialize to JSON")
+        }
+        OutputFormat::Cbor => {
+            let mut data = Vec::new();
+            ciborium::ser::into_writer(value, &mut data).context("Failed to serialize to CBOR")?;
+            Ok(data)
+        }
+        // Named fields keep the flattened and optional export fields working
+        OutputFormat::Msgpack => {
+            rmp_serde::to_vec_named(value).context("Failed to serialize to MessagePack")
+        }
+    }
+}
+
+/// Deserialize a value, detecting its compression and encoding
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    let data = &decompress(data)?[..];
+    let format = OutputFormat::detect(data)
+        .context("Unknown file format - expected JSON, CBOR or MessagePack")?;
+
+    match format {
+        OutputFormat::Json => serde_json::from_slice(data).context("Failed to parse JSON"),
+        OutputFormat::Cbor => ciborium::de::from_reader(data).context("Failed to parse CBOR"),
+        OutputFormat::Msgpack => {
+            rmp_serde::from_slice(data).context("Failed to parse MessagePack")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_round_trip_all_formats() {
+        let mut value = HashMap::new();
+        value.insert("session_id".to_string(), "abc".to_string());
+
+        for format in OutputFormat::value_variants() {
+            let data = encode(&value, *format).unwrap();
+            assert_eq!(OutputFormat::detect(&data), Some(*format));
+
+            let decoded: HashMap<String, String> = decode(&data).unwrap();
+            assert_eq!(decoded, value);
+        }
+    }
+
+    #[test]
+    fn test_decode_compressed() {
+        let mut value = HashMap::new();
+        value.insert("room_id".to_string(), "!room:example.org".to_string());
+        let data = encode(&value, OutputFormat::Json).unwrap();
+
+        for compression in Compression::value_variants() {
+            let compressed = compress(&data, Some(*compression)).unwrap();
+            assert_eq!(Compression::detect(&compressed), Some(*compression));
+
+            let decoded: HashMap<String, String> = decode(&compressed).unwrap();
+            assert_eq!(decoded, value);
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Import of JSON exports into a crypto store
+//!
+//! Reads the `ExtractionOutput` written by `extract` and stores every key as an
+//! inbound group session in a matrix-sdk-sqlite crypto store, or back into a
+//! matrix-sdk-sled one to roll back a migration or rebuild a corrupted store
+//! from an earlier export. Sessions are built with
+//! `InboundGroupSession::from_export`, which flags them as imported: the SDK
+//! treats them like keys restored from a backup or key file rather than keys
+//! received directly from the sending device, so events decrypted with them are
+//! not shown as fully trusted. Version 2 exports record the state of each
+//! session in the source store, which is restored instead: sessions received
+//! directly stay trusted, and sessions already in the server-side backup are
+//! not uploaded again. Sender data (see [`sender_data`]) can't be stored, but
+//! is carried over through the devices and identities its trust rests on. The
+//! account and other crypto state exported with `--include` are written as
+//! well (see [`restore`]).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use matrix_sdk_crypto::olm::{ExportedRoomKey, InboundGroupSession};
+use matrix_sdk_crypto::store::{Changes, CryptoStore};
+use matrix_sdk_sled::SledCryptoStore;
+use matrix_sdk_sqlite::SqliteCryptoStore;
+use serde::Serialize;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::error::ExtractorError;
+use crate::progress::Progress;
+use crate::settings::Settings;
+use crate::{
+    encryption, format, integrity, metadata, redact, restore, sender_data, split, ExportedKeyData,
+    ExtractionOutput,
+};
+
+/// Number of sessions written per store transaction
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Kind of crypto store to import into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportStore {
+    /// matrix-sdk-sqlite crypto store
+    Sqlite,
+    /// matrix-sdk-sled crypto store (new or existing)
+    Sled,
+}
+
+/// Summary of an import run
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    /// Number of sessions written to the target store
+    pub imported: usize,
+    /// Number of keys and tree entries that could not be imported
+    pub failed: usize,
+    /// Sessions skipped because the target store had an equal or better copy
+    pub duplicates: usize,
+    /// Sessions that replaced a copy known from a later message index
+    pub replaced: usize,
+    /// Entries of additional trees (account, devices, ...) written to the target store
+    pub trees: usize,
+    /// Sessions imported without the sender trust their sender data recorded
+    pub sender_trust_lost: usize,
+}
+
+/// Read an export written by `extract`, in any of the output formats
+///
+/// `path` may also be a directory written with `--split-by-room` or
+/// `--chunk-size`. Encrypted exports need the passphrase they were written with.
+pub fn read_export(
+    settings: &Settings,
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<ExtractionOutput> {
+    let output = if path.is_dir() {
+        info!("Reading split export directory {:?}", path);
+        split::read_split_output(path, |part| read_export_file(settings, part, passphrase))?
+    } else {
+        read_export_file(settings, path, passphrase)?
+    };
+
+    metadata::check_version(settings, &output);
+    Ok(output)
+}
+
+/// Read a single export file
+fn read_export_file(
+    settings: &Settings,
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<ExtractionOutput> {
+    let mut data = Zeroizing::new(
+        std::fs::read(path).with_context(|| format!("Failed to read export file {:?}", path))?,
+    );
+
+    if encryption::is_encrypted(&data) {
+        let passphrase = passphrase
+            .context("Export is encrypted - pass --input-passphrase to decrypt it")?;
+        info!("Decrypting export file");
+        data = encryption::decrypt(&data, passphrase)?;
+    }
+
+    let output: ExtractionOutput =
+        format::decode(&data).context("Failed to parse export file")?;
+    integrity::verify(&output, settings.integrity_passphrase())
+        .with_context(|| format!("Integrity check of {:?} failed", path))?;
+
+    Ok(output)
+}
+
+/// Convert an exported key back into the SDK's `ExportedRoomKey`
+///
+/// `ExportedKeyData` uses the same field names and encodings as the key export
+/// format, so a JSON round trip is enough.
+pub fn to_room_key(key: &ExportedKeyData) -> Result<ExportedRoomKey> {
+    let value = serde_json::to_value(key).context("Failed to serialize key")?;
+    serde_json::from_value(value).context("Invalid key data")
+}
+
+/// Build an inbound group session from a key, restoring the state recorded with it
+pub async fn session_from_key(key: &ExportedKeyData) -> Result<InboundGroupSession> {
+    let session = InboundGroupSession::from_export(&to_room_key(key)?)
+        .context("Failed to create session from export")?;
+
+    if let Some(recorded) = key.first_known_index {
+        if recorded != session.first_known_index() {
+            anyhow::bail!(
+                "Session key starts at index {}, but the export recorded {}",
+                session.first_known_index(),
+                recorded
+            );
+        }
+    }
+
+    // from_export always flags the session as imported and not backed up
+    if key.imported == Some(false) {
+        let mut pickle = session.pickle().await;
+        pickle.imported = false;
+        pickle.backed_up = key.backed_up.unwrap_or_default();
+        return InboundGroupSession::from_pickle(pickle).context("Failed to restore session state");
+    }
+    if key.backed_up == Some(true) {
+        session.mark_as_backed_up();
+    }
+    Ok(session)
+}
+
+/// Result of marking the sessions of an export as backed up
+#[derive(Debug, Default)]
+pub struct MarkSummary {
+    /// Sessions newly marked as backed up
+    pub marked: usize,
+    /// Sessions that were already marked
+    pub already_backed_up: usize,
+    /// Keys of the export without a session in the target store
+    pub missing: usize,
+}
+
+/// Check that the sender trust recorded in the keys' sender data survives the import
+///
+/// This SDK revision has no place for sender data; it derives the trust in a
+/// session's sender from the session's `imported` flag, which is restored, and
+/// from the stored device and identity of the sender. A session whose sender
+/// device (or, for verified senders, identity) isn't in the export would lose
+/// its "verified sender" state, so it is refused, or with `skip_errors` counted
+/// and imported without it. Returns the number of such sessions.
+fn check_sender_data(output: &ExtractionOutput, skip_errors: bool) -> Result<usize> {
+    let trees = &output.extra_trees;
+    let has_device = |user_id: &str, device_id: Option<&str>| {
+        trees.devices.iter().any(|device| {
+            device.user_id == user_id && device_id.is_none_or(|id| device.device_id == id)
+        })
+    };
+    let has_identity =
+        |user_id: &str| trees.identities.iter().any(|identity| identity.user_id == user_id);
+
+    let mut lost: BTreeMap<&str, usize> = BTreeMap::new();
+    for key in &output.all_keys {
+        let Some(data) = key.extra.get(sender_data::FIELD) else {
+            continue;
+        };
+        let kept = match sender_data::device(data) {
+            Some((user_id, device_id)) => {
+                has_device(user_id, device_id)
+                    && (!sender_data::needs_identity(data) || has_identity(user_id))
+            }
+            None => sender_data::is_known(data),
+        };
+        if !kept {
+            *lost.entry(sender_data::kind(data)).or_default() += 1;
+        }
+    }
+    if lost.is_empty() {
+        return Ok(0);
+    }
+
+    let total = lost.values().sum();
+    let kinds = lost
+        .iter()
+        .map(|(kind, count)| format!("{} {}", count, kind))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !skip_errors {
+        return Err(ExtractorError::InvalidExport(format!(
+            "the sender trust of {} session(s) ({}) can't be carried over - the export lacks \
+             their senders' devices or identities; extract it again with --include \
+             devices,identities, or pass --skip-errors to import them without it",
+            total, kinds
+        ))
+        .into());
+    }
+    warn!(
+        "{} sessions ({}) are imported without the sender trust they had in the source store",
+        total, kinds
+    );
+    Ok(total)
+}
+
+/// Build inbound group sessions from all keys of an export
+///
+/// In strict mode the first invalid key aborts; otherwise it is logged and counted.
+pub async fn sessions_from_export(
+    settings: &Settings,
+    output: &ExtractionOutput,
+    skip_errors: bool,
+) -> Result<(Vec<InboundGroupSession>, usize)> {
+    let mut sessions = Vec::with_capacity(output.all_keys.len());
+    let mut failed = 0;
+
+    for (index, key) in output.all_keys.iter().enumerate() {
+        let session = session_from_key(key).await;
+
+        match session {
+            Ok(session) => sessions.push(session),
+            Err(e) if skip_errors => {
+                warn!(
+                    "Key {} (session {} in {}): {:#}",
+                    index,
+                    redact::id(settings, &key.session_id),
+                    redact::id(settings, &key.room_id),
+                    e
+                );
+                failed += 1;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Key {} (session {} in {})",
+                        index,
+                        redact::id(settings, &key.session_id),
+                        redact::id(settings, &key.room_id)
+                    )
+                })
+            }
+        }
+    }
+
+    Ok((sessions, failed))
+}
+
+/// Import the keys and additional trees of an export into a crypto store
+///
+/// Sessions already in the target store are only replaced by an exported copy
+/// known from an earlier message index, so an interrupted import can be run
+/// again and an export can be imported into a store the bot has used. With
+/// `dry_run`, every session and tree entry is built and checked but the target
+/// store is not opened; `imported` and `trees` then count what would be
+/// written. A target store of another account or device is refused unless
+/// `force` is set.
+#[allow(clippy::too_many_arguments)]
+pub async fn import_export(
+    settings: &Settings,
+    output: &ExtractionOutput,
+    store: ImportStore,
+    target_path: &Path,
+    target_passphrase: Option<&str>,
+    skip_errors: bool,
+    dry_run: bool,
+    force: bool,
+) -> Result<ImportSummary> {
+    if !output.all_keys.is_empty() && output.all_keys.iter().all(|key| key.session_key.is_empty()) {
+        anyhow::bail!("Export holds no session keys - was it written with --no-secrets?");
+    }
+
+    let sender_trust_lost = check_sender_data(output, skip_errors)?;
+
+    let (sessions, failed) = sessions_from_export(settings, output, skip_errors).await?;
+    let trees = restore::restore_trees(settings, &output.extra_trees, skip_errors).await?;
+    let failed = failed + trees.failed;
+
+    if dry_run {
+        log_import_plan(settings, &sessions, store, target_path);
+        if trees.entries > 0 {
+            info!("Would write {} entries of additional trees", trees.entries);
+        }
+        return Ok(ImportSummary {
+            imported: sessions.len(),
+            failed,
+            trees: trees.entries,
+            sender_trust_lost,
+            ..Default::default()
+        });
+    }
+
+    let mut summary = ImportSummary {
+        failed,
+        sender_trust_lost,
+        ..Default::default()
+    };
+    match store {
+        ImportStore::Sqlite => {
+            info!("Opening SQLite crypto store at: {:?}", target_path);
+            let store = SqliteCryptoStore::open(target_path, target_passphrase)
+                .await
+                .context("Failed to open SQLite crypto store")?;
+            check_target_account(settings, &store, output, force).await?;
+            restore::check_target_device(settings, &store, &trees, force).await?;
+            let sessions = skip_duplicates(&store, sessions, &mut summary).await?;
+            summary.imported = save_sessions(&store, sessions).await?;
+            summary.trees = restore::save_trees(&store, trees).await?;
+        }
+        ImportStore::Sled => {
+            info!("Opening Sled crypto store at: {:?}", target_path);
+            // Same default as extraction: matrix-bot-sdk uses "" rather than no passphrase
+            let db = settings
+                .sled_config()
+                .path(target_path)
+                .open()
+                .map_err(ExtractorError::SledIo)
+                .context("Failed to open sled database")?;
+            let passphrase = target_passphrase.unwrap_or("");
+            let store = SledCryptoStore::open_with_database(db, Some(passphrase))
+                .await
+                .context("Failed to open Sled crypto store")?;
+            check_target_account(settings, &store, output, force).await?;
+            restore::check_target_device(settings, &store, &trees, force).await?;
+            let sessions = skip_duplicates(&store, sessions, &mut summary).await?;
+            summary.imported = save_sessions(&store, sessions).await?;
+            summary.trees = restore::save_trees(&store, trees).await?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Refuse to import into a store of another account than the export's
+///
+/// Only version 2 exports record their account; a target store without an
+/// account yet is a fresh one and accepts any export. With `force` a mismatch
+/// is only logged.
+async fn check_target_account<S: CryptoStore>(
+    settings: &Settings,
+    store: &S,
+    output: &ExtractionOutput,
+    force: bool,
+) -> Result<()> {
+    let Some(expected) = output.metadata.as_ref().and_then(|m| m.user_id.as_deref()) else {
+        return Ok(());
+    };
+    let account = store
+        .load_account()
+        .await
+        .context("Failed to read the account of the target store")?;
+    let Some(account) = account else {
+        return Ok(());
+    };
+
+    let user_id = account.user_id().to_string();
+    if user_id == expected {
+        return Ok(());
+    }
+    let mismatch = format!(
+        "the export is of {}, the target store belongs to {}",
+        redact::id(settings, expected),
+        redact::id(settings, &user_id)
+    );
+    if force {
+        warn!("Importing anyway because of --force: {}", mismatch);
+        return Ok(());
+    }
+    Err(ExtractorError::AccountMismatch(format!(
+        "{} - pass --force to import anyway",
+        mismatch
+    ))
+    .into())
+}
+
+/// Drop the sessions a store already has in an equal or better copy
+///
+/// A copy known from a lower message index decrypts more history, so the
+/// stored one is kept unless the imported one starts earlier.
+async fn skip_duplicates<S: CryptoStore>(
+    store: &S,
+    sessions: Vec<InboundGroupSession>,
+    summary: &mut ImportSummary,
+) -> Result<Vec<InboundGroupSession>> {
+    let mut progress = Progress::new("Checking existing sessions", 0, sessions.len() as u64);
+    let mut new_sessions = Vec::with_capacity(sessions.len());
+
+    for session in sessions {
+        let existing = store
+            .get_inbound_group_session(session.room_id(), session.session_id())
+            .await
+            .context("Failed to read inbound group session from the target store")?;
+        progress.inc(1);
+
+        match existing {
+            Some(existing) if existing.first_known_index() <= session.first_known_index() => {
+                summary.duplicates += 1;
+            }
+            Some(_) => {
+                summary.replaced += 1;
+                new_sessions.push(session);
+            }
+            None => new_sessions.push(session),
+        }
+    }
+    progress.finish();
+
+    if summary.duplicates > 0 {
+        info!(
+            "{} sessions are already in the target store and are skipped",
+            summary.duplicates
+        );
+    }
+    Ok(new_sessions)
+}
+
+/// Log what an import would write, for `--dry-run`
+fn log_import_plan(
+    settings: &Settings,
+    sessions: &[InboundGroupSession],
+    store: ImportStore,
+    target_path: &Path,
+) {
+    let mut per_room: BTreeMap<String, usize> = BTreeMap::new();
+    for session in sessions {
+        *per_room.entry(session.room_id().to_string()).or_default() += 1;
+    }
+
+    let state = if target_path.exists() { "existing" } else { "new" };
+    info!(
+        "Would write {} sessions of {} rooms to the {} {:?} store {:?}",
+        sessions.len(),
+        per_room.len(),
+        state,
+        store,
+        target_path
+    );
+    for (room_id, count) in &per_room {
+        info!("  {}: {}", redact::id(settings, room_id), count);
+    }
+}
+
+/// Mark the sessions of an export as backed up in a SQLite crypto store
+///
+/// Sessions imported from an export are not backed up as far as the SDK is
+/// concerned, so a bot would upload all of them again on its first sync. Once
+/// the export has been uploaded to the server-side backup, this flips the flag
+/// for every session of the export found in the store.
+pub async fn mark_backed_up(
+    settings: &Settings,
+    input: &Path,
+    target_path: &Path,
+    target_passphrase: Option<&str>,
+    input_passphrase: Option<&str>,
+) -> Result<MarkSummary> {
+    let output = read_export(settings, input, input_passphrase)?;
+    let mut session_ids: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for key in &output.all_keys {
+        session_ids
+            .entry(key.room_id.as_str())
+            .or_default()
+            .insert(key.session_id.as_str());
+    }
+
+    info!("Opening SQLite crypto store at: {:?}", target_path);
+    let store = SqliteCryptoStore::open(target_path, target_passphrase)
+        .await
+        .context("Failed to open SQLite crypto store")?;
+    let stored = store
+        .get_inbound_group_sessions()
+        .await
+        .context("Failed to read inbound group sessions from the SQLite store")?;
+
+    let mut summary = MarkSummary::default();
+    let mut sessions = Vec::new();
+    for session in stored {
+        let in_export = session_ids
+            .get_mut(session.room_id().as_str())
+            .is_some_and(|room| room.remove(session.session_id()));
+        if !in_export {
+            continue;
+        }
+        if session.backed_up() {
+            summary.already_backed_up += 1;
+        } else {
+            session.mark_as_backed_up();
+            sessions.push(session);
+        }
+    }
+    summary.missing = session_ids.values().map(HashSet::len).sum();
+
+    summary.marked = save_sessions(&store, sessions).await?;
+    Ok(summary)
+}
+
+/// Write sessions to a crypto store in batches
+pub async fn save_sessions<S: CryptoStore>(
+    store: &S,
+    sessions: Vec<InboundGroupSession>,
+) -> Result<usize> {
+    let mut saved = 0;
+    let mut progress = Progress::new("Importing sessions", 0, sessions.len() as u64);
+
+    for batch in sessions.chunks(IMPORT_BATCH_SIZE) {
+        let changes = Changes {
+            inbound_group_sessions: batch.to_vec(),
+            ..Default::default()
+        };
+        store
+            .save_changes(changes)
+            .await
+            .context("Failed to save inbound group sessions")?;
+
+        saved += batch.len();
+        progress.inc(batch.len() as u64);
+    }
+    progress.finish();
+
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_export_without_extra_trees() {
+        let dir = crate::testing::TempDir::new("import-test");
+        let path = dir.join("export.json");
+        std::fs::write(
+            &path,
+            r#"{"version":1,"total_keys":0,"failed_keys":0,"keys_by_room":{},"all_keys":[]}"#,
+        )
+        .unwrap();
+
+        let output = read_export(&Settings::default(), &path, None).unwrap();
+
+        assert_eq!(output.version, 1);
+        assert!(output.all_keys.is_empty());
+        assert!(output.extra_trees.outbound_group_sessions.is_empty());
+    }
+
+    #[test]
+    fn test_sender_trust_needs_devices_and_identities() {
+        let mut key = crate::testing::key("!a:x.org", "s1");
+        key.extra.insert(
+            sender_data::FIELD.to_string(),
+            serde_json::json!({"SenderVerified": {"user_id": "@a:x.org", "device_id": "DEV"}}),
+        );
+        let mut output = crate::build_output(vec![key], 0, true);
+
+        let error = check_sender_data(&output, false).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExtractorError>(),
+            Some(ExtractorError::InvalidExport(_))
+        ));
+        assert_eq!(check_sender_data(&output, true).unwrap(), 1);
+
+        output.extra_trees.devices.push(crate::trees::ExportedDevice {
+            user_id: "@a:x.org".to_string(),
+            device_id: "DEV".to_string(),
+            local_trust: "unset".to_string(),
+            data: serde_json::Value::Null,
+        });
+        assert!(check_sender_data(&output, false).is_err());
+
+        output.extra_trees.identities.push(crate::trees::ExportedIdentity {
+            user_id: "@a:x.org".to_string(),
+            own: false,
+            verified: false,
+            data: serde_json::Value::Null,
+        });
+        assert_eq!(check_sender_data(&output, false).unwrap(), 0);
+    }
+}
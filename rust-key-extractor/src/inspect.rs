@@ -0,0 +1,135 @@
+//! Overview of the trees in a sled store
+//!
+//! `inspect` lists every tree with its entry count, approximate size and a few
+//! sample keys. It only reads raw sled data and needs no passphrase; with an
+//! encrypted store most keys are hashed and show up as hex.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::ENCODE_SEPARATOR;
+
+/// Statistics for a single tree
+#[derive(Debug, Serialize)]
+pub struct TreeInfo {
+    /// Tree name
+    pub name: String,
+    /// Number of entries
+    pub entries: usize,
+    /// Sum of key and value lengths; sled's own overhead is not included
+    pub approximate_bytes: u64,
+    /// The first few keys of the tree
+    pub sample_keys: Vec<String>,
+}
+
+/// Result of inspecting a store
+#[derive(Debug, Serialize)]
+pub struct InspectReport {
+    /// Size of the whole store directory on disk, as reported by sled
+    pub size_on_disk: u64,
+    /// Trees sorted by name
+    pub trees: Vec<TreeInfo>,
+}
+
+/// Render a sled key for display
+///
+/// Components separated by sled's `0xff` separator are shown as text when they
+/// are printable UTF-8 and as hex otherwise, joined with `|`.
+pub fn display_key(key: &[u8]) -> String {
+    key.split(|byte| *byte == ENCODE_SEPARATOR)
+        .map(|part| match std::str::from_utf8(part) {
+            Ok(text) if !text.chars().any(char::is_control) => text.to_string(),
+            _ => format!("0x{}", hex::encode(part)),
+        })
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/// Collect statistics for every tree in the store at `path`
+pub fn inspect(settings: &Settings, path: &Path, samples: usize) -> Result<InspectReport> {
+    let db = settings
+        .sled_config()
+        .path(path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+
+    let mut trees = Vec::new();
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name).context("Failed to open tree")?;
+
+        let mut info = TreeInfo {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            entries: 0,
+            approximate_bytes: 0,
+            sample_keys: Vec::new(),
+        };
+        for item in tree.iter() {
+            let (key, value) = item.with_context(|| format!("Failed to read tree {}", info.name))?;
+            info.entries += 1;
+            info.approximate_bytes += (key.len() + value.len()) as u64;
+            if info.sample_keys.len() < samples {
+                info.sample_keys.push(display_key(&key));
+            }
+        }
+        trees.push(info);
+    }
+    trees.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(InspectReport {
+        size_on_disk: db.size_on_disk().context("Failed to read store size")?,
+        trees,
+    })
+}
+
+/// Format a byte count for humans
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Print a report as a table
+pub fn print_report(report: &InspectReport) {
+    println!("Size on disk: {}", human_bytes(report.size_on_disk));
+    println!();
+    println!("{:<40} {:>10} {:>12}", "TREE", "ENTRIES", "SIZE");
+    for tree in &report.trees {
+        println!(
+            "{:<40} {:>10} {:>12}",
+            tree.name,
+            tree.entries,
+            human_bytes(tree.approximate_bytes)
+        );
+        for key in &tree.sample_keys {
+            println!("    {}", key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_key_mixes_text_and_hex() {
+        let mut key = b"!room:example.org".to_vec();
+        key.push(ENCODE_SEPARATOR);
+        key.extend_from_slice(&[0x00, 0x01]);
+
+        assert_eq!(display_key(&key), "!room:example.org|0x0001");
+    }
+}
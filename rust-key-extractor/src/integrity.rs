@@ -0,0 +1,399 @@
+//! Integrity hashes for exports
+//!
+//! Every exported key carries `sha256`, a SHA-256 over its fields as canonical
+//! JSON (sorted keys, no whitespace), and the export carries `integrity`, a
+//! digest over the format version, the key hashes in order, the counts, the
+//! additional tree data and the metadata. Without `--integrity-passphrase` the
+//! digest is a plain SHA-256, which catches bitrot and truncation; with it, it
+//! is an HMAC-SHA256 keyed by PBKDF2 of the passphrase, so changes by anyone
+//! without the passphrase are detected too.
+//!
+//! Exports are checked when they are read. Without a passphrase, exports
+//! written before these fields existed are accepted unchecked; with one, only
+//! a matching keyed digest is accepted, so stripping the digest or replacing
+//! it with a plain one doesn't get a modified export through.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tracing::debug;
+use zeroize::Zeroizing;
+
+use crate::error::ExtractorError;
+use crate::metadata::ExportMetadata;
+use crate::trees::ExtraTreeExport;
+use crate::{ExportedKeyData, ExtractionOutput};
+
+/// Name of the unkeyed digest
+const SHA256: &str = "sha256";
+
+/// Name of the passphrase-keyed digest
+const HMAC_SHA256: &str = "hmac-sha256";
+
+/// PBKDF2 rounds for the HMAC key
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// File-level digest of an export
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileIntegrity {
+    /// `sha256` or `hmac-sha256`
+    pub algorithm: String,
+    /// PBKDF2 salt of the HMAC key (base64)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+    /// PBKDF2 rounds of the HMAC key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rounds: Option<u32>,
+    /// The digest (hex)
+    pub digest: String,
+}
+
+/// The fields of a key in alphabetical order, so its JSON is canonical
+#[derive(Serialize)]
+struct CanonicalKey<'a> {
+    algorithm: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backed_up: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    extra: &'a BTreeMap<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_known_index: Option<u32>,
+    forwarding_curve25519_key_chain: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imported: Option<bool>,
+    room_id: &'a str,
+    sender_claimed_keys: BTreeMap<&'a str, &'a str>,
+    sender_key: &'a str,
+    session_id: &'a str,
+    session_key: &'a str,
+}
+
+/// SHA-256 of a key's fields (hex)
+pub fn key_hash(key: &ExportedKeyData) -> String {
+    let canonical = CanonicalKey {
+        algorithm: &key.algorithm,
+        backed_up: key.backed_up,
+        extra: &key.extra,
+        first_known_index: key.first_known_index,
+        forwarding_curve25519_key_chain: &key.forwarding_curve25519_key_chain,
+        imported: key.imported,
+        room_id: &key.room_id,
+        sender_claimed_keys: key
+            .sender_claimed_keys
+            .iter()
+            .map(|(algorithm, value)| (algorithm.as_str(), value.as_str()))
+            .collect(),
+        sender_key: &key.sender_key,
+        session_id: &key.session_id,
+        session_key: &key.session_key,
+    };
+    // Holds the session key
+    let json = Zeroizing::new(serde_json::to_vec(&canonical).expect("key serializes to JSON"));
+    hex::encode(Sha256::digest(json.as_slice()))
+}
+
+/// Incremental file digest, fed in the order the parts appear in the export
+pub struct FileDigest {
+    mac: DigestState,
+    salt: Option<Vec<u8>>,
+    rounds: Option<u32>,
+}
+
+enum DigestState {
+    Plain(Sha256),
+    Keyed(Hmac<Sha256>),
+}
+
+impl FileDigest {
+    /// Start a digest for a new export, keyed by `passphrase` if there is one
+    pub fn new(version: u32, passphrase: Option<&str>) -> Self {
+        match passphrase {
+            Some(passphrase) => {
+                let mut salt = vec![0u8; 16];
+                rand::thread_rng().fill_bytes(&mut salt);
+                Self::keyed(version, passphrase, salt, PBKDF2_ROUNDS)
+            }
+            None => Self::plain(version),
+        }
+    }
+
+    fn plain(version: u32) -> Self {
+        let mut digest = Self {
+            mac: DigestState::Plain(Sha256::new()),
+            salt: None,
+            rounds: None,
+        };
+        digest.update(format!("sled-key-extractor export v{}\n", version).as_bytes());
+        digest
+    }
+
+    fn keyed(version: u32, passphrase: &str, salt: Vec<u8>, rounds: u32) -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, rounds, key.as_mut_slice());
+        let mac =
+            Hmac::<Sha256>::new_from_slice(key.as_slice()).expect("HMAC takes any key length");
+
+        let mut digest = Self {
+            mac: DigestState::Keyed(mac),
+            salt: Some(salt),
+            rounds: Some(rounds),
+        };
+        digest.update(format!("sled-key-extractor export v{}\n", version).as_bytes());
+        digest
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.mac {
+            DigestState::Plain(hasher) => hasher.update(data),
+            DigestState::Keyed(mac) => mac.update(data),
+        }
+    }
+
+    /// Add a key, by its hash
+    pub fn add_key(&mut self, key: &ExportedKeyData) {
+        let hash = if key.sha256.is_empty() {
+            key_hash(key)
+        } else {
+            key.sha256.clone()
+        };
+        self.update(hash.as_bytes());
+        self.update(b"\n");
+    }
+
+    /// Add the counts, additional tree data and metadata
+    fn add_tail(
+        &mut self,
+        total_keys: usize,
+        failed_keys: usize,
+        extra_trees: &ExtraTreeExport,
+        metadata: Option<&ExportMetadata>,
+    ) -> Result<()> {
+        self.update(format!("{} {}\n", total_keys, failed_keys).as_bytes());
+        if let Some(metadata) = metadata {
+            self.update(&serde_json::to_vec(metadata).context("Failed to serialize metadata")?);
+            self.update(b"\n");
+        }
+        // Holds pickles with private keys when trees were included
+        let trees = Zeroizing::new(
+            serde_json::to_vec(extra_trees).context("Failed to serialize tree data")?,
+        );
+        self.update(&trees);
+        Ok(())
+    }
+
+    /// Add the counts, additional tree data and metadata and return the digest
+    pub fn finish(
+        mut self,
+        total_keys: usize,
+        failed_keys: usize,
+        extra_trees: &ExtraTreeExport,
+        metadata: Option<&ExportMetadata>,
+    ) -> Result<FileIntegrity> {
+        self.add_tail(total_keys, failed_keys, extra_trees, metadata)?;
+
+        let (algorithm, digest) = match self.mac {
+            DigestState::Plain(hasher) => (SHA256, hex::encode(hasher.finalize())),
+            DigestState::Keyed(mac) => (HMAC_SHA256, hex::encode(mac.finalize().into_bytes())),
+        };
+        Ok(FileIntegrity {
+            algorithm: algorithm.to_string(),
+            salt: self
+                .salt
+                .map(|salt| base64::engine::general_purpose::STANDARD.encode(salt)),
+            rounds: self.rounds,
+            digest,
+        })
+    }
+
+    /// Whether the digest of everything added matches `expected`
+    ///
+    /// Keyed digests are compared in constant time.
+    fn matches(self, expected: &[u8]) -> bool {
+        match self.mac {
+            DigestState::Plain(hasher) => hasher.finalize().as_slice() == expected,
+            DigestState::Keyed(mac) => mac.verify_slice(expected).is_ok(),
+        }
+    }
+}
+
+/// Feed the keys of an export into `digest`
+fn add_keys(output: &ExtractionOutput, digest: &mut FileDigest) {
+    for key in &output.all_keys {
+        digest.add_key(key);
+    }
+}
+
+/// Set the file digest of an export about to be written, keyed by `passphrase`
+pub fn seal(output: &mut ExtractionOutput, passphrase: Option<&str>) -> Result<()> {
+    let mut digest = FileDigest::new(output.version, passphrase);
+    add_keys(output, &mut digest);
+    output.integrity = Some(digest.finish(
+        output.total_keys,
+        output.failed_keys,
+        &output.extra_trees,
+        output.metadata.as_ref(),
+    )?);
+    Ok(())
+}
+
+/// Check the key hashes and the file digest of an export that was read
+///
+/// With a `passphrase`, only a digest keyed by it is accepted.
+pub fn verify(output: &ExtractionOutput, passphrase: Option<&str>) -> Result<()> {
+    let mismatched = output
+        .all_keys
+        .iter()
+        .filter(|key| !key.sha256.is_empty() && key.sha256 != key_hash(key))
+        .count();
+    if mismatched > 0 {
+        return Err(ExtractorError::InvalidExport(format!(
+            "{} key(s) don't match their sha256 - the export was modified or damaged",
+            mismatched
+        ))
+        .into());
+    }
+
+    let Some(recorded) = &output.integrity else {
+        if passphrase.is_some() {
+            return Err(ExtractorError::InvalidExport(
+                "export has no file digest, but --integrity-passphrase expects a keyed one"
+                    .to_string(),
+            )
+            .into());
+        }
+        debug!("Export has no file digest - written by an older version");
+        return Ok(());
+    };
+    let mut digest = match (recorded.algorithm.as_str(), passphrase) {
+        (SHA256, None) => FileDigest::plain(output.version),
+        (SHA256, Some(_)) => {
+            return Err(ExtractorError::InvalidExport(
+                "export has a plain sha256 digest, but --integrity-passphrase expects a keyed \
+                 one"
+                .to_string(),
+            )
+            .into());
+        }
+        (HMAC_SHA256, None) => {
+            return Err(ExtractorError::InvalidExport(
+                "export has a keyed digest - pass --integrity-passphrase to check it".to_string(),
+            )
+            .into());
+        }
+        (HMAC_SHA256, Some(passphrase)) => {
+            let salt = base64::engine::general_purpose::STANDARD
+                .decode(recorded.salt.as_deref().unwrap_or_default())
+                .context("Invalid integrity salt")?;
+            let rounds = recorded.rounds.unwrap_or(PBKDF2_ROUNDS);
+            FileDigest::keyed(output.version, passphrase, salt, rounds)
+        }
+        (other, _) => anyhow::bail!("Unknown export digest algorithm {:?}", other),
+    };
+
+    add_keys(output, &mut digest);
+    digest.add_tail(
+        output.total_keys,
+        output.failed_keys,
+        &output.extra_trees,
+        output.metadata.as_ref(),
+    )?;
+    let matches = hex::decode(&recorded.digest).is_ok_and(|expected| digest.matches(&expected));
+    if !matches {
+        return Err(ExtractorError::InvalidExport(
+            "file digest doesn't match - the export was modified, damaged, or the \
+             --integrity-passphrase is wrong"
+                .to_string(),
+        )
+        .into());
+    }
+    debug!("Export digest ({}) verified", recorded.algorithm);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_output;
+    use crate::testing::key_with_secret;
+
+    #[test]
+    fn test_seal_and_verify_detect_changes() {
+        let mut key = key_with_secret("!a:x.org", "s1", "AQID");
+        key.sha256 = key_hash(&key);
+        let mut output = build_output(vec![key.clone(), key], 0, true);
+        seal(&mut output, None).unwrap();
+        verify(&output, None).unwrap();
+
+        output.all_keys.pop();
+        output.total_keys = 1;
+        assert!(verify(&output, None).is_err());
+
+        output.all_keys[0].session_key = "BAUG".to_string();
+        assert!(verify(&output, None).is_err());
+    }
+
+    /// A sealed export with a digest keyed by `passphrase`
+    fn keyed_output(passphrase: &str) -> ExtractionOutput {
+        let mut key = key_with_secret("!a:x.org", "s1", "AQID");
+        key.sha256 = key_hash(&key);
+        let mut output = build_output(vec![key], 0, true);
+        let mut digest = FileDigest::keyed(output.version, passphrase, vec![7; 16], 1_000);
+        add_keys(&output, &mut digest);
+        output.integrity = Some(
+            digest
+                .finish(output.total_keys, output.failed_keys, &output.extra_trees, None)
+                .unwrap(),
+        );
+        output
+    }
+
+    #[test]
+    fn test_keyed_digest_needs_the_passphrase() {
+        let output = keyed_output("hunter2");
+        verify(&output, Some("hunter2")).unwrap();
+        assert!(verify(&output, Some("hunter3")).is_err());
+        assert!(verify(&output, None).is_err());
+    }
+
+    #[test]
+    fn test_stripped_digest_is_rejected() {
+        let mut output = keyed_output("hunter2");
+        output.all_keys[0].session_key = "BAUG".to_string();
+        output.all_keys[0].sha256 = key_hash(&output.all_keys[0]);
+        output.integrity = None;
+
+        verify(&output, None).unwrap();
+        let error = verify(&output, Some("hunter2")).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExtractorError>(),
+            Some(ExtractorError::InvalidExport(_))
+        ));
+    }
+
+    #[test]
+    fn test_downgraded_digest_is_rejected() {
+        let mut output = keyed_output("hunter2");
+        output.all_keys[0].session_key = "BAUG".to_string();
+        output.all_keys[0].sha256 = key_hash(&output.all_keys[0]);
+        // Recompute a plain digest over the changed export, as anyone can
+        let mut digest = FileDigest::plain(output.version);
+        add_keys(&output, &mut digest);
+        output.integrity = Some(
+            digest
+                .finish(output.total_keys, output.failed_keys, &output.extra_trees, None)
+                .unwrap(),
+        );
+
+        verify(&output, None).unwrap();
+        let error = verify(&output, Some("hunter2")).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ExtractorError>(),
+            Some(ExtractorError::InvalidExport(_))
+        ));
+    }
+}
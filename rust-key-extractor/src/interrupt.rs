@@ -0,0 +1,111 @@
+//! Stopping an extraction cleanly on Ctrl-C or SIGTERM
+//!
+//! Without a handler, Ctrl-C kills a long fault-tolerant extraction and
+//! everything read so far is lost. Once [`install`] ran, the first Ctrl-C only
+//! sets a flag: the extraction loop finishes its current batch, writes the
+//! spill file and checkpoint (with `--spill-file`) and stops. The keys read so
+//! far are written to `<output>.partial` with `"partial": true`, never to the
+//! output path itself, and the run exits with
+//! [`ExtractorError::Interrupted`](crate::error::ExtractorError::Interrupted).
+//! A second Ctrl-C exits immediately.
+//!
+//! SIGTERM, which container runtimes send before killing the process after a
+//! short grace period, stops the loop the same way. With a spill file the
+//! partial export is skipped: the keys are already on disk next to the
+//! checkpoint, and writing them out again could outlast the grace period.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use tracing::{error, warn};
+
+use crate::error::ExtractorError;
+
+/// Signal that stopped the run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Ctrl-C
+    Interrupt = 1,
+    /// SIGTERM
+    Terminate = 2,
+}
+
+/// The first signal received, 0 for none
+static RECEIVED: AtomicU8 = AtomicU8::new(0);
+
+fn set(signal: Signal) {
+    let _ = RECEIVED.compare_exchange(0, signal as u8, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// Catch Ctrl-C and SIGTERM for the rest of the process
+///
+/// Must be called from within the tokio runtime.
+pub fn install() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            warn!("Failed to listen for Ctrl-C - interrupting will lose the progress");
+            return;
+        }
+        warn!(
+            "Interrupted - stopping after the current batch and writing a partial export; \
+             press Ctrl-C again to abort immediately"
+        );
+        set(Signal::Interrupt);
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            error!("Interrupted again - aborting without writing anything");
+            std::process::exit(ExtractorError::Interrupted(String::new()).exit_code().into());
+        }
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                return;
+            }
+        };
+        if terminate.recv().await.is_some() {
+            warn!("SIGTERM received - writing the checkpoint and stopping");
+            set(Signal::Terminate);
+        }
+    });
+}
+
+/// The signal that stopped the run, if any
+pub fn received() -> Option<Signal> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        1 => Some(Signal::Interrupt),
+        2 => Some(Signal::Terminate),
+        _ => None,
+    }
+}
+
+/// Whether the run was interrupted and should stop
+pub fn requested() -> bool {
+    received().is_some()
+}
+
+/// Where the export of an interrupted run is written instead of `output`
+pub fn partial_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    output.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_path_keeps_the_extension() {
+        assert_eq!(
+            partial_path(Path::new("/exports/keys.json.zst")),
+            PathBuf::from("/exports/keys.json.zst.partial")
+        );
+    }
+}
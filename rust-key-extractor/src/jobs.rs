@@ -0,0 +1,76 @@
+//! Running several migrations side by side
+//!
+//! Batch extraction (`--sled-path-glob`) and `serve` can run up to
+//! `--concurrency` stores at once. Each job runs on a blocking thread of its
+//! own, driven by the shared runtime: extraction futures aren't `Send`, and
+//! the sled reads and session decoding would otherwise hold up the other jobs
+//! on the same thread. A job that panics fails on its own without taking the
+//! others down.
+//!
+//! Every log line a job writes carries a `job{name=...}` prefix, so the
+//! interleaved output of concurrent jobs can be told apart and filtered.
+//! Progress bars are turned off while more than one job may run, since they
+//! would draw over each other; progress is logged as lines instead. For the
+//! same reason a job never prompts for a passphrase on the terminal.
+//!
+//! Jobs share no state of their own: the options of a job, such as
+//! `--legacy-pickle-key` and `--salvage`, and the counts of converted and
+//! salvaged sessions are kept per extraction (see [`crate::SessionDecoder`]).
+//! The global options (`--redact`, `--tree-name`, `--kdf`, `--sled-mode`, ...)
+//! are handed to each job as its own [`Settings`](crate::settings::Settings).
+//! Only the read rate limit, progress bars and passphrase prompting are set
+//! for the whole process.
+
+use std::future::Future;
+
+use anyhow::{anyhow, Result};
+use tracing::info_span;
+
+use crate::{passphrase, progress};
+
+/// Prepare for running up to `concurrency` jobs at once
+pub fn set_concurrency(concurrency: usize) {
+    if concurrency > 1 {
+        progress::set_bars_enabled(false);
+        passphrase::set_non_interactive();
+    }
+}
+
+/// Run the future made by `job` to completion on a thread of its own
+///
+/// Log lines written while it runs are tagged with `name`. A panic in the job
+/// is returned as an error.
+pub async fn run_isolated<T, F>(name: String, job: impl FnOnce() -> F + Send + 'static) -> Result<T>
+where
+    T: Send + 'static,
+    F: Future<Output = Result<T>>,
+{
+    let runtime = tokio::runtime::Handle::current();
+    let span = info_span!("job", name = %name);
+
+    match tokio::task::spawn_blocking(move || span.in_scope(|| runtime.block_on(job()))).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => Err(anyhow!("Job {} panicked", name)),
+        Err(e) => Err(anyhow!("Job {} was cancelled: {}", name, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_panics_stay_in_their_job() {
+        let failed = run_isolated("a".to_string(), || async {
+            if true {
+                panic!("job a broke");
+            }
+            Ok(())
+        })
+        .await;
+        let succeeded = run_isolated("b".to_string(), || async { Ok(1) }).await;
+
+        assert_eq!(failed.unwrap_err().to_string(), "Job a panicked");
+        assert_eq!(succeeded.unwrap(), 1);
+    }
+}
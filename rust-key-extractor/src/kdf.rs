@@ -0,0 +1,264 @@
+//! Key derivation overrides for the store cipher
+//!
+//! The exported store cipher records how its key is derived from the
+//! passphrase: PBKDF2 with a number of rounds and a salt, or no KDF at all
+//! for stores opened with a raw key. `StoreCipher::import` follows that
+//! record, so a store written by a fork or with non-default settings whose
+//! record doesn't match fails as if the passphrase were wrong. `--kdf` and
+//! `--kdf-rounds` rewrite the record before the cipher is imported.
+//!
+//! PBKDF2 is slow on purpose. `export-cipher` runs it once and saves the
+//! derived key ([`derive_key`]); later runs read it with `--cipher-key-file`
+//! and import the cipher with the key directly, without the passphrase.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use matrix_sdk_store_encryption::StoreCipher;
+use serde::Deserialize;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::analyze;
+use crate::error::ExtractorError;
+
+/// Name of the PBKDF2 variant in the exported cipher's `kdf_info`
+const PBKDF2_KDF: &str = "Pbkdf2ToChaCha20Poly1305";
+
+/// Length of a raw store key
+const KEY_LEN: usize = 32;
+
+/// How the store key is derived from the passphrase
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kdf {
+    /// As recorded in the exported store cipher
+    #[default]
+    Auto,
+    /// PBKDF2 from the passphrase, with the recorded salt
+    Pbkdf2,
+    /// No KDF: the passphrase is the 32-byte key itself, as hex or base64
+    RawKey,
+}
+
+/// The KDF overrides of a run and the store key from `--cipher-key-file`
+///
+/// Not `Debug`, as it holds a key.
+#[derive(Clone, Default)]
+pub struct KdfSettings {
+    kdf: Kdf,
+    rounds: Option<u32>,
+    cipher_key: Option<Zeroizing<[u8; KEY_LEN]>>,
+}
+
+impl KdfSettings {
+    /// Use `kdf` and `rounds`, or the store key saved by `export-cipher` in `key_file`
+    pub fn new(kdf: Kdf, rounds: Option<u32>, key_file: Option<&Path>) -> Result<Self> {
+        let cipher_key = key_file.map(load_key_file).transpose()?;
+        Ok(Self {
+            kdf,
+            rounds,
+            cipher_key,
+        })
+    }
+
+    /// These settings with `kdf`, `rounds` and the store key in `key_file` where given
+    pub fn overridden(
+        &self,
+        kdf: Option<Kdf>,
+        rounds: Option<u32>,
+        key_file: Option<&Path>,
+    ) -> Result<Self> {
+        let mut settings = self.clone();
+        if let Some(kdf) = kdf {
+            settings.kdf = kdf;
+        }
+        if rounds.is_some() {
+            settings.rounds = rounds;
+        }
+        if let Some(key_file) = key_file {
+            settings.cipher_key = Some(load_key_file(key_file)?);
+        }
+        Ok(settings)
+    }
+
+    /// Whether the store key comes from `--cipher-key-file` rather than a passphrase
+    pub fn key_file_in_use(&self) -> bool {
+        self.cipher_key.is_some()
+    }
+}
+
+/// Read the store key saved by `export-cipher`
+fn load_key_file(path: &Path) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let contents = Zeroizing::new(
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cipher key file {:?}", path))?,
+    );
+    Ok(raw_key(contents.trim())?)
+}
+
+/// Decode a raw store key given as hex or base64
+fn raw_key(encoded: &str) -> Result<Zeroizing<[u8; KEY_LEN]>, ExtractorError> {
+    let decoded = Zeroizing::new(analyze::decode_input(encoded).unwrap_or_default());
+    let key: [u8; KEY_LEN] = decoded.as_slice().try_into().map_err(|_| {
+        ExtractorError::WrongPassphrase(format!(
+            "the store key must be {} bytes, as hex or base64",
+            KEY_LEN
+        ))
+    })?;
+    Ok(Zeroizing::new(key))
+}
+
+/// Name of the KDF an exported store cipher records
+fn recorded_kdf(exported: &[u8]) -> String {
+    let json: Option<serde_json::Value> = serde_json::from_slice(exported).ok();
+    match json.as_ref().and_then(|json| json.get("kdf_info")) {
+        Some(serde_json::Value::String(name)) => name.clone(),
+        Some(serde_json::Value::Object(variant)) => {
+            variant.keys().next().cloned().unwrap_or_default()
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Rewrite the KDF record of an exported store cipher
+fn apply_overrides(
+    exported: &[u8],
+    kdf: Kdf,
+    rounds: Option<u32>,
+) -> Result<Cow<'_, [u8]>, ExtractorError> {
+    if kdf == Kdf::Auto && rounds.is_none() {
+        return Ok(Cow::Borrowed(exported));
+    }
+
+    let mut json: serde_json::Value = serde_json::from_slice(exported).map_err(|e| {
+        ExtractorError::SchemaMismatch(format!("exported store cipher is not JSON: {}", e))
+    })?;
+
+    if kdf == Kdf::RawKey {
+        json["kdf_info"] = serde_json::Value::String("None".to_string());
+    } else {
+        let Some(params) = json["kdf_info"].get_mut(PBKDF2_KDF) else {
+            return Err(ExtractorError::SchemaMismatch(format!(
+                "the store cipher records the {} KDF, which has no PBKDF2 salt - \
+                 try --kdf raw-key",
+                recorded_kdf(exported)
+            )));
+        };
+        if let Some(rounds) = rounds {
+            params["rounds"] = rounds.into();
+        }
+    }
+
+    let rewritten = serde_json::to_vec(&json).map_err(|e| {
+        ExtractorError::SchemaMismatch(format!("failed to rewrite the store cipher: {}", e))
+    })?;
+    Ok(Cow::Owned(rewritten))
+}
+
+/// PBKDF2 rounds and salt recorded in an exported store cipher
+fn pbkdf2_params(exported: &[u8]) -> Result<(u32, Vec<u8>), ExtractorError> {
+    let json: serde_json::Value = serde_json::from_slice(exported).map_err(|e| {
+        ExtractorError::SchemaMismatch(format!("exported store cipher is not JSON: {}", e))
+    })?;
+    let params = &json["kdf_info"][PBKDF2_KDF];
+    let rounds = params["rounds"].as_u64().and_then(|rounds| u32::try_from(rounds).ok());
+    let salt: Option<Vec<u8>> = serde_json::from_value(params["kdf_salt"].clone()).ok();
+
+    match (rounds, salt) {
+        (Some(rounds), Some(salt)) => Ok((rounds, salt)),
+        _ => Err(ExtractorError::SchemaMismatch(format!(
+            "the store cipher records the {} KDF, which has no PBKDF2 parameters",
+            recorded_kdf(exported)
+        ))),
+    }
+}
+
+/// Derive the store key from the passphrase, the way `StoreCipher::import` does
+///
+/// The key is checked against the exported cipher before it is returned.
+pub fn derive_key(
+    settings: &KdfSettings,
+    passphrase: &str,
+    exported: &[u8],
+) -> Result<Zeroizing<[u8; KEY_LEN]>, ExtractorError> {
+    let (kdf, rounds) = (settings.kdf, settings.rounds);
+
+    let key = if kdf == Kdf::RawKey {
+        raw_key(passphrase)?
+    } else {
+        let (recorded_rounds, salt) = pbkdf2_params(exported)?;
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        pbkdf2::pbkdf2_hmac::<Sha256>(
+            passphrase.as_bytes(),
+            &salt,
+            rounds.unwrap_or(recorded_rounds),
+            key.as_mut_slice(),
+        );
+        key
+    };
+
+    let exported = apply_overrides(exported, Kdf::RawKey, None)?;
+    StoreCipher::import_with_key(&key, &exported).map_err(|e| {
+        ExtractorError::WrongPassphrase(format!("failed to import store cipher: {}", e))
+    })?;
+    Ok(key)
+}
+
+/// Import an exported store cipher with the configured KDF overrides
+///
+/// With `--cipher-key-file` the passphrase is ignored.
+pub fn import_cipher(
+    settings: &KdfSettings,
+    passphrase: &str,
+    exported: &[u8],
+) -> Result<StoreCipher, ExtractorError> {
+    let (kdf, rounds) = (settings.kdf, settings.rounds);
+    let key_file = settings.cipher_key.as_ref();
+    let kdf = if key_file.is_some() { Kdf::RawKey } else { kdf };
+    let exported = apply_overrides(exported, kdf, rounds)?;
+
+    let imported = if kdf == Kdf::RawKey {
+        let key = match key_file {
+            Some(key) => key.clone(),
+            None => raw_key(passphrase)?,
+        };
+        StoreCipher::import_with_key(&key, &exported)
+    } else {
+        StoreCipher::import(passphrase, &exported)
+    };
+
+    imported.map_err(|e| match e {
+        matrix_sdk_store_encryption::Error::KdfMismatch => {
+            ExtractorError::SchemaMismatch(format!(
+                "the store cipher records the {} KDF - pass --kdf to override it",
+                recorded_kdf(&exported)
+            ))
+        }
+        e => ExtractorError::WrongPassphrase(format!("failed to import store cipher: {}", e)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_overrides() {
+        let exported = br#"{"kdf_info":{"Pbkdf2ToChaCha20Poly1305":{"rounds":200000,"kdf_salt":[1]}},
+            "ciphertext_info":{}}"#;
+
+        let rewritten = apply_overrides(exported, Kdf::Auto, Some(1000)).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&rewritten).unwrap();
+        assert_eq!(json["kdf_info"][PBKDF2_KDF]["rounds"], 1000);
+        assert_eq!(json["kdf_info"][PBKDF2_KDF]["kdf_salt"][0], 1);
+
+        let rewritten = apply_overrides(exported, Kdf::RawKey, None).unwrap();
+        assert_eq!(recorded_kdf(&rewritten), "None");
+        assert!(apply_overrides(&rewritten, Kdf::Pbkdf2, Some(1000)).is_err());
+        assert!(pbkdf2_params(&rewritten).is_err());
+        assert_eq!(pbkdf2_params(exported).unwrap(), (200000, vec![1]));
+    }
+}
@@ -0,0 +1,59 @@
+//! OS keyring integration
+//!
+//! With the `keyring` feature, store passphrases can be kept in the system
+//! keyring (Secret Service on Linux, Keychain on macOS, Credential Manager on
+//! Windows) so repeated runs on an operator's workstation don't need the secret
+//! typed or passed again. Entries live under one service name and are keyed by
+//! the canonical path of the store, so several bots don't overwrite each other.
+//! Without the feature the functions fail with a hint to rebuild.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Keyring service all entries are stored under
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "sled-key-extractor";
+
+/// Keyring account name of a store's passphrase
+#[cfg(feature = "keyring")]
+fn passphrase_account(sled_path: &Path) -> String {
+    let path = sled_path
+        .canonicalize()
+        .unwrap_or_else(|_| sled_path.to_path_buf());
+    format!("store-passphrase:{}", path.display())
+}
+
+/// Read the passphrase of the store at `sled_path`, if one was saved
+#[cfg(feature = "keyring")]
+pub fn load_passphrase(sled_path: &Path) -> Result<Option<String>> {
+    use anyhow::Context;
+
+    let entry = keyring::Entry::new(SERVICE, &passphrase_account(sled_path))
+        .context("Failed to access the keyring")?;
+    match entry.get_password() {
+        Ok(passphrase) => Ok(Some(passphrase)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e).context("Failed to read passphrase from the keyring"),
+    }
+}
+
+/// Save the passphrase of the store at `sled_path`, replacing an earlier one
+#[cfg(feature = "keyring")]
+pub fn save_passphrase(sled_path: &Path, passphrase: &str) -> Result<()> {
+    use anyhow::Context;
+
+    keyring::Entry::new(SERVICE, &passphrase_account(sled_path))
+        .and_then(|entry| entry.set_password(passphrase))
+        .context("Failed to save passphrase to the keyring")
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn load_passphrase(_sled_path: &Path) -> Result<Option<String>> {
+    anyhow::bail!("Keyring support is not compiled in - rebuild with `--features keyring`")
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn save_passphrase(_sled_path: &Path, _passphrase: &str) -> Result<()> {
+    anyhow::bail!("Keyring support is not compiled in - rebuild with `--features keyring`")
+}
@@ -0,0 +1,71 @@
+//! Fallback for sessions pickled by libolm
+//!
+//! Before matrix-sdk-crypto switched to vodozemac, the `pickle` field of an
+//! inbound group session held a libolm pickle: a base64 string encrypted with
+//! the store's pickle key. The current pickle format can't read such entries,
+//! but vodozemac's libolm compatibility layer can, so they are converted to a
+//! current pickle instead of being recorded as failed.
+//!
+//! Stores opened without a passphrase pickled with an empty key, which is the
+//! default. Otherwise the pickle key has to be given with `--legacy-pickle-key`.
+//! The key is an option of the run, not of the process (see
+//! [`crate::SessionDecoder`]), so concurrent jobs can use different ones.
+
+use anyhow::{Context, Result};
+use matrix_sdk_crypto::olm::PickledInboundGroupSession;
+use matrix_sdk_store_encryption::StoreCipher;
+use zeroize::Zeroizing;
+
+use crate::deserialize_value;
+
+/// The libolm pickle of a stored session, if it has one
+pub fn libolm_pickle(value: &serde_json::Value) -> Option<&str> {
+    value.get("pickle")?.as_str()
+}
+
+/// Convert a stored session with a libolm pickle into the current pickle format
+///
+/// `key` is the key the pickle was encrypted with. Returns `None` if the value
+/// doesn't hold a libolm pickle, so the caller can report the original error.
+pub fn upgrade_session(
+    value: &[u8],
+    store_cipher: Option<&StoreCipher>,
+    key: &[u8],
+) -> Option<Result<PickledInboundGroupSession>> {
+    let mut session: serde_json::Value = deserialize_value(value, store_cipher).ok()?;
+    let pickle = Zeroizing::new(libolm_pickle(&session)?.to_owned());
+
+    Some(upgrade_pickle(&mut session, &pickle, key))
+}
+
+/// Replace the libolm pickle of `session` with a vodozemac one and deserialize it
+pub fn upgrade_pickle(
+    session: &mut serde_json::Value,
+    pickle: &str,
+    key: &[u8],
+) -> Result<PickledInboundGroupSession> {
+    let megolm = vodozemac::megolm::InboundGroupSession::from_libolm_pickle(pickle, key)
+        .context("Failed to read libolm pickle - wrong --legacy-pickle-key?")?;
+
+    session["pickle"] =
+        serde_json::to_value(megolm.pickle()).context("Failed to convert libolm pickle")?;
+    let pickle = serde_json::from_value(session.take())
+        .context("Session with libolm pickle doesn't match the current format")?;
+
+    Ok(pickle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_string_pickles_are_legacy() {
+        let current = br#"{"pickle":{"initial_ratchet":{}},"room_id":"!a:b"}"#;
+        assert!(upgrade_session(current, None, &[]).is_none());
+        assert!(upgrade_session(b"not json", None, &[]).is_none());
+
+        let legacy = serde_json::json!({ "pickle": "AwAAAAAA", "room_id": "!a:b" });
+        assert_eq!(libolm_pickle(&legacy), Some("AwAAAAAA"));
+    }
+}
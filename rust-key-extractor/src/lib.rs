@@ -0,0 +1,3412 @@
+//! Sled Key Extractor
+//!
+//! This tool extracts Megolm session keys from a Sled-based crypto store
+//! used by the Matrix bot SDK. The extracted keys can then be uploaded
+//! to a Matrix server backup for migration to SQLite storage.
+//!
+//! Besides the `sled-key-extractor` binary, the crate can be used as a
+//! library: [`stream_keys`] and [`extract_keys`] hand the keys of a store to
+//! applications that migrate their own store, and a [`MigrationObserver`]
+//! receives progress events.
+
+mod account;
+mod analyze;
+mod archive;
+mod batch;
+mod bench;
+mod check;
+mod diff;
+mod doctor;
+mod embed;
+mod encryption;
+mod env;
+mod error;
+mod ffi;
+mod filter;
+mod fixture;
+mod format;
+mod import;
+mod inspect;
+mod integrity;
+mod interrupt;
+mod jobs;
+mod kdf;
+mod keychain;
+mod legacy;
+mod merge;
+mod metadata;
+mod observer;
+mod passphrase;
+mod progress;
+mod redact;
+mod report;
+mod restore;
+mod salvage;
+mod schema;
+mod selftest;
+mod sender_data;
+mod serve;
+mod settings;
+mod shred;
+mod snapshot;
+mod spill;
+mod split;
+mod state;
+mod stats;
+mod store;
+mod stream;
+mod summary;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod trees;
+mod tuning;
+mod verify;
+
+use anyhow::{Context, Result};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use matrix_sdk_crypto::olm::{InboundGroupSession, PickledInboundGroupSession};
+use matrix_sdk_crypto::store::CryptoStore;
+use matrix_sdk_sled::SledCryptoStore;
+use matrix_sdk_store_encryption::StoreCipher;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use settings::Settings;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tracing::{info, warn, Level};
+use tracing_subscriber::FmtSubscriber;
+use trees::{ExtraTree, ExtraTreeExport};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+pub use embed::{extract_keys, stream_keys};
+pub use error::ExtractorError;
+pub use matrix_sdk_crypto::olm::ExportedRoomKey;
+pub use observer::MigrationObserver;
+
+/// Tree name for inbound group sessions in matrix-sdk-sled
+/// Note: The constant "crypto-store-inbound-group-sessions" is used for key encoding,
+/// but the actual sled tree name is just "inbound_group_sessions". Stores with
+/// another layout are handled by [`schema::detect`].
+const INBOUND_GROUP_SESSIONS_TREE: &str = "inbound_group_sessions";
+
+/// Number of sled entries decoded in parallel before results are collected
+const DECODE_BATCH_SIZE: usize = 1024;
+
+/// Separator byte used by matrix-sdk-sled's EncodeKey trait
+pub(crate) const ENCODE_SEPARATOR: u8 = 0xff;
+
+/// Extracted key data in a format suitable for Matrix backup upload
+///
+/// Wiped from memory when dropped, since `session_key` is secret.
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct ExportedKeyData {
+    /// Room ID the key belongs to
+    room_id: String,
+    /// Session ID for this key
+    session_id: String,
+    /// Algorithm (usually m.megolm.v1.aes-sha2)
+    algorithm: String,
+    /// The actual exported key data (base64 encoded); empty with `--no-secrets`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    session_key: String,
+    /// Sender key (Curve25519)
+    sender_key: String,
+    /// Sender claimed keys
+    #[zeroize(skip)]
+    sender_claimed_keys: std::collections::HashMap<String, String>,
+    /// Forwarding chain
+    forwarding_curve25519_key_chain: Vec<String>,
+    /// Index of the first message the key can decrypt (format version 2)
+    #[zeroize(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first_known_index: Option<u32>,
+    /// Whether the session was already in the server-side key backup (format version 2)
+    #[zeroize(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backed_up: Option<bool>,
+    /// Whether the session was imported from a backup or key file rather than received
+    /// from the sending device, which the SDK trusts less (format version 2)
+    #[zeroize(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imported: Option<bool>,
+    /// Additional fields (format version 2)
+    #[zeroize(skip)]
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    extra: std::collections::BTreeMap<String, serde_json::Value>,
+    /// SHA-256 over the fields above (see `integrity::key_hash`)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    sha256: String,
+}
+
+/// Leaves out `session_key`, so keys can't leak into logs or panic messages
+impl std::fmt::Debug for ExportedKeyData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExportedKeyData")
+            .field("room_id", &self.room_id)
+            .field("session_id", &self.session_id)
+            .field("algorithm", &self.algorithm)
+            .field("sender_key", &self.sender_key)
+            .field("forwarding_curve25519_key_chain", &self.forwarding_curve25519_key_chain)
+            .field("first_known_index", &self.first_known_index)
+            .field("backed_up", &self.backed_up)
+            .field("imported", &self.imported)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Output format for the extracted keys
+#[derive(Debug, Serialize, Deserialize)]
+struct ExtractionOutput {
+    /// Version of this export format
+    version: u32,
+    /// Total number of keys extracted
+    total_keys: usize,
+    /// Number of failed extractions (if skip_errors enabled)
+    failed_keys: usize,
+    /// Extracted keys organized by room (empty with `--no-keys-by-room`)
+    #[serde(default)]
+    keys_by_room: std::collections::HashMap<String, Vec<ExportedKeyData>>,
+    /// Number of keys per room, written instead of `keys_by_room`
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    keys_per_room: std::collections::BTreeMap<String, usize>,
+    /// Flat list of all keys
+    all_keys: Vec<ExportedKeyData>,
+    /// Data from additional crypto-store trees (see `--include`)
+    #[serde(flatten)]
+    extra_trees: ExtraTreeExport,
+    /// Where and when the export was made (format version 2)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<metadata::ExportMetadata>,
+    /// Digest over the whole export, checked on import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    integrity: Option<integrity::FileIntegrity>,
+    /// Whether the run was interrupted and this export holds only part of the keys
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+}
+
+/// What went wrong with a failed entry
+///
+/// Many decryption failures usually mean some entries were written with a
+/// different passphrase; scattered JSON or pickle failures point at corruption.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureCategory {
+    /// The value could not be decrypted with the store cipher
+    Decryption,
+    /// The value is not valid JSON for the expected type
+    Json,
+    /// The pickle was read but the session could not be rebuilt from it
+    Pickle,
+    /// sled could not read the entry
+    SledRead,
+    /// Recorded by an older version without categories
+    #[default]
+    Unknown,
+}
+
+impl FailureCategory {
+    /// Name used in the failed-sessions output
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Decryption => "decryption",
+            Self::Json => "json",
+            Self::Pickle => "pickle",
+            Self::SledRead => "sled-read",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Category of an error returned by [`deserialize_value`]
+    fn of_deserialize_error(error: &anyhow::Error) -> Self {
+        if error.chain().any(|cause| cause.is::<serde_json::Error>()) {
+            Self::Json
+        } else {
+            Self::Decryption
+        }
+    }
+}
+
+/// Information about a failed session extraction
+#[derive(Debug, Serialize, Deserialize)]
+struct FailedSession {
+    /// Index in the iteration
+    index: usize,
+    /// Sled tree the entry was read from
+    #[serde(default)]
+    tree: String,
+    /// Raw key bytes as hex (for debugging)
+    key_hex: String,
+    /// Kind of failure
+    #[serde(default)]
+    category: FailureCategory,
+    /// Error message
+    error: String,
+    /// SENSITIVE: the stored value as base64, with `--include-raw-failures`
+    ///
+    /// Encrypted stores hold ciphertext here, unencrypted ones the plaintext pickle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw_value: Option<String>,
+}
+
+/// Output for failed sessions
+#[derive(Debug, Serialize, Deserialize)]
+struct FailedSessionsOutput {
+    /// Total number of failures
+    total_failed: usize,
+    /// Number of failures per category
+    #[serde(default)]
+    by_category: std::collections::BTreeMap<FailureCategory, usize>,
+    /// Whether `raw_value` holds the stored values - treat the file like the store itself
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    contains_raw_values: bool,
+    /// The store's passphrase-protected store cipher as base64, with `--include-raw-failures`
+    ///
+    /// Lets `analyze-pickle` decrypt the raw values without access to the store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    store_cipher: Option<String>,
+    /// Details of each failed session
+    sessions: Vec<FailedSession>,
+}
+
+/// Read the stored value of every failed entry into `raw_value`
+///
+/// Done once extraction has finished, so the extraction paths don't need to
+/// carry values of failed entries around. Entries sled couldn't read have no
+/// key to look up and stay without a value. Returns the exported store cipher
+/// as base64, if the store is encrypted.
+fn attach_raw_values(
+    settings: &Settings,
+    sled_path: &std::path::Path,
+    failed_sessions: &mut [FailedSession],
+) -> Result<Option<String>> {
+    use base64::Engine;
+
+    let db = settings
+        .sled_config()
+        .path(sled_path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+
+    for failed in failed_sessions {
+        let Ok(key) = hex::decode(&failed.key_hex) else {
+            continue;
+        };
+        let tree = db
+            .open_tree(&failed.tree)
+            .with_context(|| format!("Failed to open tree '{}'", failed.tree))?;
+        if let Ok(Some(value)) = tree.get(&key) {
+            failed.raw_value = Some(base64::engine::general_purpose::STANDARD.encode(&value));
+        }
+    }
+
+    let store_cipher = schema::find_store_cipher(settings, &db)?;
+    Ok(store_cipher.map(|cipher| base64::engine::general_purpose::STANDARD.encode(&cipher)))
+}
+
+/// Encode and compress a failed-sessions file, and encrypt it with `passphrase`
+///
+/// With `--include-raw-failures` the file holds the stored values, which are
+/// plaintext pickles in an unencrypted store, so it gets the same protection as
+/// the export.
+fn encode_failed_sessions(
+    output: &FailedSessionsOutput,
+    compression: Option<format::Compression>,
+    passphrase: Option<&str>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let json = Zeroizing::new(
+        serde_json::to_vec_pretty(output).context("Failed to serialize failed sessions")?,
+    );
+    let mut data = Zeroizing::new(format::compress(&json, compression)?);
+    if let Some(passphrase) = passphrase {
+        data = Zeroizing::new(encryption::encrypt(&data, passphrase)?);
+    }
+    Ok(data)
+}
+
+/// Read a failed-sessions file, decrypting it with `passphrase` if it is encrypted
+fn read_failed_sessions(
+    path: &std::path::Path,
+    passphrase: Option<&str>,
+) -> Result<FailedSessionsOutput> {
+    let mut data =
+        Zeroizing::new(std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?);
+    if encryption::is_encrypted(&data) {
+        let passphrase = passphrase.context(
+            "Failed-sessions file is encrypted - pass --input-passphrase to decrypt it",
+        )?;
+        data = encryption::decrypt(&data, passphrase)?;
+    }
+    format::decode(&data).context("Failed to parse failed-sessions file")
+}
+
+/// Count failures per category
+fn count_by_category(
+    failed_sessions: &[FailedSession],
+) -> std::collections::BTreeMap<FailureCategory, usize> {
+    let mut counts = std::collections::BTreeMap::new();
+    for failed in failed_sessions {
+        *counts.entry(failed.category).or_default() += 1;
+    }
+    counts
+}
+
+/// CLI arguments for the key extractor
+///
+/// Running without a subcommand behaves like `extract`, so existing scripts
+/// that pass the extraction flags directly keep working.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    extract: Option<ExtractArgs>,
+
+    /// Enable verbose output
+    #[arg(short, long, global = true, default_value = "false")]
+    verbose: bool,
+
+    #[command(flatten)]
+    settings: settings::SettingsArgs,
+
+    /// Read sled trees at no more than MBPS megabytes per second, to leave disk bandwidth
+    /// to other workloads on the host
+    #[arg(long, global = true, value_name = "MBPS", value_parser = throttle::parse_mbps)]
+    max_read_mbps: Option<f64>,
+
+    /// Read at idle I/O priority, so other processes get the disk first (Linux)
+    #[arg(long, global = true, default_value = "false")]
+    io_nice: bool,
+}
+
+/// Available subcommands
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Extract keys from a sled crypto store into a JSON export (default)
+    Extract(Box<ExtractArgs>),
+    /// Migrate a sled state store (sync token, filters, rooms) into SQLite
+    MigrateState(MigrateStateArgs),
+    /// Import an export file into a SQLite or sled crypto store
+    Import(ImportArgs),
+    /// Extract several sled crypto stores and import their sessions into one SQLite store
+    Migrate(MigrateArgs),
+    /// Mark the sessions of an uploaded export as backed up in a SQLite crypto store
+    MarkBackedUp(MarkBackedUpArgs),
+    /// List the trees of a sled store with entry counts, sizes and sample keys
+    Inspect(InspectArgs),
+    /// Check a sled crypto store for common migration problems
+    Doctor(DoctorArgs),
+    /// Report per-room and per-sender statistics of the keys in a sled crypto store
+    Stats(StatsArgs),
+    /// Check whether a passphrase unlocks a sled store, without extracting anything
+    VerifyPassphrase(VerifyPassphraseArgs),
+    /// Show where decrypting or unpickling a single stored value fails
+    AnalyzePickle(AnalyzePickleArgs),
+    /// Save the store cipher key derived from the passphrase, for --cipher-key-file
+    ExportCipher(ExportCipherArgs),
+    /// Compare a sled crypto store with the SQLite store it was migrated to, session by session
+    VerifyMigration(VerifyMigrationArgs),
+    /// Securely delete a sled crypto store after verifying its migration to SQLite
+    Cleanup(CleanupArgs),
+    /// Report keys added, removed or changed between two export files
+    Diff(DiffArgs),
+    /// Merge export files into one, keeping the best copy of every session
+    Merge(MergeArgs),
+    /// Validate an export file before importing or uploading it
+    CheckExport(CheckExportArgs),
+    /// Measure extraction throughput per pipeline stage and worker pool size
+    Bench(BenchArgs),
+    /// Create a sled crypto store with fake sessions, optionally corrupted, for testing
+    GenFixture(GenFixtureArgs),
+    /// Check this build by extracting and importing a generated store end to end
+    Selftest(SelftestArgs),
+    /// Run migrations submitted over an authenticated HTTP API
+    Serve(ServeArgs),
+}
+
+/// Arguments for `extract`
+#[derive(Args, Debug, Clone)]
+struct ExtractArgs {
+    /// Path to the Sled crypto store directory (with --sled-archive: its path in the archive)
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["sled_path_glob", "sled_archive", "sled_snapshot"]
+    )]
+    sled_path: Option<PathBuf>,
+
+    /// Read the store from this tar (optionally gzip or zstd compressed) or zip archive
+    #[arg(long, value_name = "FILE", conflicts_with = "sled_path_glob")]
+    sled_archive: Option<PathBuf>,
+
+    /// Read the store from a snapshot file holding the output of sled's `Db::export`
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["sled_path", "sled_path_glob", "sled_archive"]
+    )]
+    sled_snapshot: Option<PathBuf>,
+
+    /// Pack the sled store into this archive (.tar.zst, .tar.gz or .tar) with a SHA-256
+    /// checksum before it is opened
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["sled_path_glob", "sled_archive", "sled_snapshot"]
+    )]
+    backup_first: Option<PathBuf>,
+
+    /// Extract every store directory matching this pattern (e.g. '/data/bots/*/crypto');
+    /// --output is then a directory that gets one export per store and a batch summary
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        conflicts_with_all = ["sled_path", "failed_output", "spill_file", "run_report", "report"]
+    )]
+    sled_path_glob: Option<String>,
+
+    /// Extract up to N of the stores matching --sled-path-glob at once (default: 1)
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["sled_path", "sled_archive", "sled_snapshot"],
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    concurrency: Option<u64>,
+
+    /// Output file path for the extracted keys (JSON unless --format says otherwise)
+    #[arg(short, long)]
+    output: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    #[command(flatten)]
+    filter: filter::FilterArgs,
+
+    #[command(flatten)]
+    expected_account: account::ExpectedAccountArgs,
+
+    /// Skip corrupted entries instead of failing (enables fault-tolerant mode)
+    #[arg(long, default_value = "false")]
+    skip_errors: bool,
+
+    /// Output file for failed session details (only used with --skip-errors)
+    #[arg(long)]
+    failed_output: Option<PathBuf>,
+
+    /// Additional crypto-store trees to extract (comma-separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    include: Vec<ExtraTree>,
+
+    /// Extract every crypto-store tree in one run (same as including all trees)
+    #[arg(long, default_value = "false", conflicts_with = "include")]
+    migrate_all: bool,
+
+    /// Encoding of the output file
+    #[arg(long, value_enum, default_value = "json")]
+    format: format::OutputFormat,
+
+    /// Export format version to write: 2 adds metadata about the source store,
+    /// 1 is for consumers that predate it
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = metadata::CURRENT_VERSION,
+        value_parser = clap::value_parser!(u32).range(1..=2)
+    )]
+    format_version: u32,
+
+    /// Compress the output and failed-sessions files
+    #[arg(long, value_enum)]
+    compress: Option<format::Compression>,
+
+    /// Encrypt the output and failed-sessions files with a passphrase
+    /// (Argon2id + ChaCha20-Poly1305)
+    #[arg(long, default_value = "false", requires = "OutputPassphraseArgs")]
+    encrypt_output: bool,
+
+    #[command(flatten)]
+    output_passphrase: passphrase::OutputPassphraseArgs,
+
+    #[command(flatten)]
+    since_export_passphrase: passphrase::SinceExportPassphraseArgs,
+
+    /// Write one file per room into the output directory
+    #[arg(long, default_value = "false")]
+    split_by_room: bool,
+
+    /// Write at most N keys per file into the output directory
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    chunk_size: Option<u64>,
+
+    /// Append extracted keys to this file as they are extracted, and reuse it after a crash
+    #[arg(long, requires = "skip_errors")]
+    spill_file: Option<PathBuf>,
+
+    /// Sync the spill file to disk every N sessions
+    #[arg(long, value_name = "N", default_value = "10000")]
+    spill_every: usize,
+
+    /// Continue an interrupted extraction from the checkpoint next to --spill-file
+    #[arg(long, default_value = "false", requires = "spill_file")]
+    resume: bool,
+
+    /// Worker threads for decoding sessions with --skip-errors (default: all cores)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    threads: Option<u64>,
+
+    /// Key that libolm-pickled sessions of pre-vodozemac stores were encrypted with,
+    /// as hex or base64 (default: the empty key of stores opened without a passphrase)
+    #[arg(long, value_name = "KEY", requires = "skip_errors")]
+    legacy_pickle_key: Option<String>,
+
+    /// Recover sessions with slightly corrupted JSON leniently instead of recording them
+    /// as failed (truncated values, trailing bytes, duplicate or unknown fields)
+    #[arg(long, default_value = "false", requires = "skip_errors")]
+    salvage: bool,
+
+    /// Only write per-room key counts instead of duplicating every key in keys_by_room;
+    /// single-file JSON exports are then streamed to disk as keys are extracted
+    #[arg(long, default_value = "false")]
+    no_keys_by_room: bool,
+
+    /// Leave session keys out of the export, so it can be shared to discuss counts and rooms
+    #[arg(long, default_value = "false", conflicts_with_all = ["include", "migrate_all"])]
+    no_secrets: bool,
+
+    /// SENSITIVE: store the raw value of every failed entry (base64) in the failed-sessions file
+    #[arg(long, default_value = "false", requires = "skip_errors")]
+    include_raw_failures: bool,
+
+    /// Abort without writing the export when more than PERCENT of the entries fail
+    #[arg(long, value_name = "PERCENT", requires = "skip_errors", value_parser = parse_percent)]
+    fail_threshold: Option<f64>,
+
+    /// Abort without writing the export when more than N entries fail
+    #[arg(long, value_name = "N", requires = "skip_errors")]
+    max_failures: Option<usize>,
+
+    /// Write a JSON report of the run (timings, counts, SHA-256 of the output) to this file
+    #[arg(long, value_name = "FILE")]
+    run_report: Option<PathBuf>,
+
+    /// Write a human-readable summary of the run to this file (HTML for .html, else Markdown)
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `migrate-state`
+#[derive(Args, Debug)]
+struct MigrateStateArgs {
+    /// Path to the Sled state store directory
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    /// Path to the target SQLite state store directory
+    #[arg(short, long)]
+    target: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    #[command(flatten)]
+    target_passphrase: passphrase::TargetPassphraseArgs,
+
+    /// Filter names to migrate (required for encrypted stores, where names are hashed)
+    #[arg(long = "filter-name", value_delimiter = ',')]
+    filter_names: Vec<String>,
+
+    /// Additional account data event types to migrate (global and per room)
+    #[arg(long = "account-data-type", value_delimiter = ',')]
+    account_data_types: Vec<String>,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+
+    /// Read everything but don't create or write the SQLite state store
+    #[arg(long, default_value = "false")]
+    dry_run: bool,
+
+    /// Pack the sled store into this archive (.tar.zst, .tar.gz or .tar) with a SHA-256
+    /// checksum before it is opened
+    #[arg(long, value_name = "FILE")]
+    backup_first: Option<PathBuf>,
+}
+
+/// Arguments for `inspect`
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// Path to the Sled store directory
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    /// Number of sample keys to show per tree
+    #[arg(long, default_value = "3")]
+    samples: usize,
+
+    /// Print the report as JSON on stdout
+    #[arg(long, default_value = "false")]
+    json: bool,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `analyze-pickle`
+#[derive(Args, Debug)]
+struct AnalyzePickleArgs {
+    /// Raw stored value, as hex (a hex dump works) or base64
+    #[arg(required_unless_present = "from_failed")]
+    value: Option<String>,
+
+    /// Take the value from a failed-sessions file written with --include-raw-failures
+    #[arg(long, value_name = "FILE", conflicts_with = "value", requires = "entry")]
+    from_failed: Option<PathBuf>,
+
+    /// Position of the entry in the failed-sessions file (0-based)
+    #[arg(long, requires = "from_failed")]
+    entry: Option<usize>,
+
+    #[command(flatten)]
+    input_passphrase: passphrase::InputPassphraseArgs,
+
+    /// Sled crypto store to read the store cipher from
+    #[arg(short, long)]
+    sled_path: Option<PathBuf>,
+
+    /// Exported store cipher, as hex or base64 (instead of --sled-path)
+    #[arg(long, conflicts_with = "sled_path")]
+    store_cipher: Option<String>,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    /// Key that libolm pickles were encrypted with, as hex or base64 (default: empty key)
+    #[arg(long, value_name = "KEY")]
+    legacy_pickle_key: Option<String>,
+}
+
+/// Arguments for `doctor`
+#[derive(Args, Debug)]
+struct DoctorArgs {
+    /// Path to the Sled crypto store directory
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+}
+
+/// Arguments for `stats`
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// Path to the Sled crypto store directory
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    /// Number of rooms and senders to list
+    #[arg(long, default_value = "10")]
+    top: usize,
+
+    /// Print all statistics as JSON on stdout
+    #[arg(long, default_value = "false")]
+    json: bool,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `verify-passphrase`
+#[derive(Args, Debug)]
+struct VerifyPassphraseArgs {
+    /// Path to the Sled store directory
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `export-cipher`
+#[derive(Args, Debug)]
+struct ExportCipherArgs {
+    /// Path to the Sled store directory
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    /// File to write the store cipher key to (hex, readable only by the owner)
+    #[arg(short, long)]
+    output: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `verify-migration`
+#[derive(Args, Debug)]
+struct VerifyMigrationArgs {
+    /// Path to the Sled crypto store directory (the migration source)
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    /// Path to the SQLite crypto store directory (the migration target)
+    #[arg(short, long)]
+    target: PathBuf,
+
+    #[command(flatten)]
+    target_passphrase: passphrase::TargetPassphraseArgs,
+
+    /// Print the comparison as JSON on stdout
+    #[arg(long, default_value = "false")]
+    json: bool,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `cleanup`
+#[derive(Args, Debug)]
+struct CleanupArgs {
+    /// Path to the Sled crypto store directory to delete
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    /// Path to the SQLite crypto store the sled store was migrated to
+    #[arg(short, long)]
+    target: PathBuf,
+
+    #[command(flatten)]
+    target_passphrase: passphrase::TargetPassphraseArgs,
+
+    /// Times every file is overwritten with random data before it is removed
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    passes: u32,
+
+    /// Confirm the deletion; without it the store is only verified
+    #[arg(long, default_value = "false")]
+    yes: bool,
+}
+
+/// Arguments for `diff`
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// Earlier export file (or split export directory)
+    old: PathBuf,
+
+    /// Later export file (or split export directory)
+    new: PathBuf,
+
+    #[command(flatten)]
+    input_passphrase: passphrase::InputPassphraseArgs,
+
+    /// Print the differences as JSON on stdout
+    #[arg(long, default_value = "false")]
+    json: bool,
+}
+
+/// Arguments for `merge`
+#[derive(Args, Debug)]
+struct MergeArgs {
+    /// Export files (or split export directories) to merge, in order of preference on ties
+    #[arg(required = true, num_args = 2..)]
+    inputs: Vec<PathBuf>,
+
+    /// Output file for the merged export
+    #[arg(short, long)]
+    output: PathBuf,
+
+    #[command(flatten)]
+    input_passphrase: passphrase::InputPassphraseArgs,
+
+    /// Encoding of the output file
+    #[arg(long, value_enum, default_value = "json")]
+    format: format::OutputFormat,
+
+    /// Compress the output file
+    #[arg(long, value_enum)]
+    compress: Option<format::Compression>,
+
+    /// Encrypt the output file with a passphrase (Argon2id + ChaCha20-Poly1305)
+    #[arg(long, default_value = "false", requires = "OutputPassphraseArgs")]
+    encrypt_output: bool,
+
+    #[command(flatten)]
+    output_passphrase: passphrase::OutputPassphraseArgs,
+}
+
+/// Arguments for `check-export`
+#[derive(Args, Debug)]
+struct CheckExportArgs {
+    /// Export file (or split export directory) to check
+    input: PathBuf,
+
+    #[command(flatten)]
+    input_passphrase: passphrase::InputPassphraseArgs,
+
+    /// Number of problems to list per kind
+    #[arg(long, default_value = "10")]
+    limit: usize,
+
+    /// Print the problems as JSON on stdout
+    #[arg(long, default_value = "false")]
+    json: bool,
+}
+
+/// Arguments for `bench`
+#[derive(Args, Debug)]
+struct BenchArgs {
+    /// Path to the Sled crypto store directory
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    /// Benchmark at most N sessions; all of them are held in memory at once
+    #[arg(long, value_name = "N", default_value = "10000")]
+    limit: usize,
+
+    /// Worker pool sizes to time the parallel decode step with (comma-separated,
+    /// 0 for all cores)
+    #[arg(long, value_name = "N,...", value_delimiter = ',', default_values_t = [1, 0])]
+    threads: Vec<usize>,
+
+    /// Print the results as JSON on stdout
+    #[arg(long, default_value = "false")]
+    json: bool,
+
+    /// Work on a temporary copy of the sled store so the original is never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if the sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `gen-fixture`
+#[derive(Args, Debug)]
+struct GenFixtureArgs {
+    /// Directory to create the sled crypto store in (must not exist)
+    #[arg(short, long)]
+    sled_path: PathBuf,
+
+    /// Number of inbound group sessions to generate
+    #[arg(long, value_name = "N", default_value = "100")]
+    sessions: usize,
+
+    /// Number of rooms to spread the sessions over
+    #[arg(long, value_name = "N", default_value = "10")]
+    rooms: usize,
+
+    /// Encrypt the store with a store cipher protected by this passphrase
+    #[arg(short, long)]
+    passphrase: Option<String>,
+
+    /// Damage COUNT (or PERCENT% of the) session entries with KIND: garbage, truncate,
+    /// bad-json, bit-flip or wrong-cipher (repeatable)
+    #[arg(long, value_name = "KIND=COUNT", value_parser = fixture::parse_corruption)]
+    corrupt: Vec<(fixture::Corruption, fixture::Amount)>,
+
+    /// Seed for picking the corrupted entries
+    #[arg(long, default_value = "0")]
+    seed: u64,
+
+    /// Write the sessions, their sled keys and corruptions as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+}
+
+/// Arguments for `selftest`
+#[derive(Args, Debug)]
+struct SelftestArgs {
+    /// Number of fake sessions to run through the round trip
+    #[arg(long, value_name = "N", default_value = "100")]
+    sessions: usize,
+}
+
+/// Arguments for `serve`
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// Address to listen on; put a TLS-terminating proxy in front for anything but localhost
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8480")]
+    listen: std::net::SocketAddr,
+
+    /// File whose first line is the bearer token clients must send
+    #[arg(long, value_name = "FILE")]
+    token_file: PathBuf,
+
+    /// Run up to N jobs at once
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    concurrency: u64,
+
+    /// Keep the status and report of the N most recently submitted finished jobs,
+    /// forgetting older ones
+    #[arg(long, value_name = "N", default_value = "1000")]
+    keep_finished: usize,
+}
+
+/// Arguments for `import`
+#[derive(Args, Debug)]
+struct ImportArgs {
+    /// Export file written by `extract`
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Path to the target crypto store directory
+    #[arg(short, long)]
+    target: PathBuf,
+
+    /// Kind of crypto store to import into
+    #[arg(long, value_enum, default_value = "sqlite")]
+    store: import::ImportStore,
+
+    #[command(flatten)]
+    target_passphrase: passphrase::TargetPassphraseArgs,
+
+    #[command(flatten)]
+    input_passphrase: passphrase::InputPassphraseArgs,
+
+    /// Skip keys that can't be imported instead of failing
+    #[arg(long, default_value = "false")]
+    skip_errors: bool,
+
+    /// Build every session but don't open or write the target store
+    #[arg(long, default_value = "false")]
+    dry_run: bool,
+
+    /// Import even if the target store belongs to another account than the export
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Arguments for `migrate`
+#[derive(Args, Debug)]
+struct MigrateArgs {
+    /// Sled crypto store directories to read (repeatable)
+    #[arg(short, long = "sled-path", required = true, num_args = 1..)]
+    sled_paths: Vec<PathBuf>,
+
+    /// Path to the target SQLite crypto store directory
+    #[arg(short, long)]
+    target: PathBuf,
+
+    #[command(flatten)]
+    store_passphrase: passphrase::PassphraseArgs,
+
+    #[command(flatten)]
+    expected_account: account::ExpectedAccountArgs,
+
+    #[command(flatten)]
+    target_passphrase: passphrase::TargetPassphraseArgs,
+
+    /// Skip corrupted entries and keys that can't be imported instead of failing
+    #[arg(long, default_value = "false")]
+    skip_errors: bool,
+
+    /// Read every source but don't open or write the target store
+    #[arg(long, default_value = "false")]
+    dry_run: bool,
+
+    /// Work on temporary copies of the sled stores so the originals are never modified
+    #[arg(long, default_value = "false")]
+    copy_first: bool,
+
+    /// Continue even if a sled store is locked by a running process (reads a copy)
+    #[arg(long, default_value = "false")]
+    force: bool,
+}
+
+/// Counts of a `migrate` run
+#[derive(Debug, Serialize)]
+struct MigrateReport {
+    /// Sled stores read
+    sources: usize,
+    /// Distinct sessions found in the sources
+    sessions: usize,
+    /// Entries that could not be read from the sources
+    read_failures: usize,
+    /// Outcome of the import into the target store
+    #[serde(flatten)]
+    import: import::ImportSummary,
+}
+
+/// Arguments for `mark-backed-up`
+#[derive(Args, Debug)]
+struct MarkBackedUpArgs {
+    /// Export file whose keys were uploaded to the server-side backup
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Path to the SQLite crypto store the export was imported into
+    #[arg(short, long)]
+    target: PathBuf,
+
+    #[command(flatten)]
+    target_passphrase: passphrase::TargetPassphraseArgs,
+
+    #[command(flatten)]
+    input_passphrase: passphrase::InputPassphraseArgs,
+}
+
+/// Convert an ExportedRoomKey to our serializable format
+fn convert_exported_key(key: &ExportedRoomKey) -> ExportedKeyData {
+    ExportedKeyData {
+        room_id: key.room_id.to_string(),
+        session_id: key.session_id.clone(),
+        algorithm: key.algorithm.to_string(),
+        session_key: key.session_key.to_base64(),
+        sender_key: key.sender_key.to_base64(),
+        sender_claimed_keys: key
+            .sender_claimed_keys
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_base64()))
+            .collect(),
+        forwarding_curve25519_key_chain: key
+            .forwarding_curve25519_key_chain
+            .iter()
+            .map(|k| k.to_base64())
+            .collect(),
+        first_known_index: None,
+        backed_up: None,
+        imported: None,
+        extra: Default::default(),
+        sha256: String::new(),
+    }
+}
+
+/// Export a session, recording the state its key export leaves out
+async fn export_session(session: &InboundGroupSession) -> ExportedKeyData {
+    let mut key = convert_exported_key(&session.export().await);
+    key.first_known_index = Some(session.first_known_index());
+    key.backed_up = Some(session.backed_up());
+    key.imported = Some(session.has_been_imported());
+    key
+}
+
+/// Deserialize a value, optionally decrypting it first
+fn deserialize_value<T: serde::de::DeserializeOwned>(
+    data: &[u8],
+    store_cipher: Option<&StoreCipher>,
+) -> Result<T> {
+    if let Some(cipher) = store_cipher {
+        cipher
+            .decrypt_value(data)
+            .context("Failed to decrypt value")
+    } else {
+        serde_json::from_slice(data).context("Failed to deserialize JSON")
+    }
+}
+
+/// Encode a key the same way matrix-sdk-sled does (append ENCODE_SEPARATOR)
+fn encode_key(key: &str) -> Vec<u8> {
+    let mut encoded = key.as_bytes().to_vec();
+    encoded.push(ENCODE_SEPARATOR);
+    encoded
+}
+
+/// Load the store cipher from the database if it exists
+fn load_store_cipher(
+    settings: &Settings,
+    db: &sled::Db,
+    passphrase: &str,
+) -> Result<Option<StoreCipher>> {
+    if let Some(encrypted_cipher) = schema::find_store_cipher(settings, db)? {
+        info!("Found existing store cipher, importing with passphrase");
+        let cipher = kdf::import_cipher(&settings.kdf, passphrase, &encrypted_cipher)?;
+        Ok(Some(cipher))
+    } else {
+        info!("No store cipher found - data is not encrypted");
+        Ok(None)
+    }
+}
+
+/// Options and counters of decoding the sessions of one extraction
+///
+/// Kept per extraction rather than per process, so that concurrent jobs can use
+/// their own `--legacy-pickle-key` and `--salvage` and report their own counts.
+/// Not `Debug`, as it holds a key.
+#[derive(Default)]
+pub(crate) struct SessionDecoder {
+    /// Key that libolm pickles were encrypted with (see [`legacy`])
+    legacy_pickle_key: Zeroizing<Vec<u8>>,
+    /// Recover corrupted values leniently (see [`salvage`])
+    salvage: bool,
+    converted: AtomicUsize,
+    salvaged: AtomicUsize,
+    with_sender_data: AtomicUsize,
+}
+
+impl SessionDecoder {
+    pub(crate) fn new(legacy_pickle_key: Option<Vec<u8>>, salvage: bool) -> Self {
+        Self {
+            legacy_pickle_key: Zeroizing::new(legacy_pickle_key.unwrap_or_default()),
+            salvage,
+            ..Self::default()
+        }
+    }
+
+    /// Decrypt and deserialize a pickled session and rebuild it
+    ///
+    /// Sessions that don't deserialize are tried as libolm pickles and, with
+    /// salvaging enabled, recovered leniently before giving up. Errors are
+    /// returned as the category and message recorded for the failed session.
+    fn decode(
+        &self,
+        settings: &Settings,
+        value: &[u8],
+        store_cipher: Option<&StoreCipher>,
+    ) -> std::result::Result<InboundGroupSession, (FailureCategory, String)> {
+        let mut repairs = None;
+        let pickle: PickledInboundGroupSession = match deserialize_value(value, store_cipher) {
+            Ok(pickle) => pickle,
+            Err(e) => match legacy::upgrade_session(value, store_cipher, &self.legacy_pickle_key) {
+                Some(upgraded) => {
+                    let pickle = upgraded.map_err(|e| {
+                        (FailureCategory::Pickle, format!("Legacy pickle failed: {:#}", e))
+                    })?;
+                    self.converted.fetch_add(1, Ordering::Relaxed);
+                    pickle
+                }
+                None => match self
+                    .salvage
+                    .then(|| salvage::salvage_value(value, store_cipher))
+                    .flatten()
+                {
+                    Some(salvaged) => {
+                        repairs = Some(salvaged.repairs);
+                        salvaged.value
+                    }
+                    None => {
+                        return Err((
+                            FailureCategory::of_deserialize_error(&e),
+                            format!("Deserialization failed: {}", e),
+                        ))
+                    }
+                },
+            },
+        };
+
+        let session = InboundGroupSession::from_pickle(pickle).map_err(|e| {
+            (
+                FailureCategory::Pickle,
+                format!("Pickle reconstruction failed: {}", e),
+            )
+        })?;
+
+        if let Some(repairs) = repairs {
+            self.salvaged.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Salvaged session {} in {}: {}",
+                redact::id(settings, session.session_id()),
+                redact::id(settings, session.room_id()),
+                repairs.join("; ")
+            );
+        }
+
+        Ok(session)
+    }
+
+    /// Read the sender data stored next to a session (see [`sender_data`])
+    fn sender_data(
+        &self,
+        value: &[u8],
+        store_cipher: Option<&StoreCipher>,
+    ) -> Option<serde_json::Value> {
+        let sender_data = sender_data::read(value, store_cipher)?;
+        self.with_sender_data.fetch_add(1, Ordering::Relaxed);
+        Some(sender_data)
+    }
+
+    /// Log how many sessions were converted, salvaged and carry sender data
+    fn log_counts(&self) {
+        let converted = self.converted.load(Ordering::Relaxed);
+        if converted > 0 {
+            info!("{} sessions converted from libolm pickles", converted);
+        }
+        let with_sender_data = self.with_sender_data.load(Ordering::Relaxed);
+        if with_sender_data > 0 {
+            info!("{} sessions carry sender data", with_sender_data);
+        }
+        let salvaged = self.salvaged.load(Ordering::Relaxed);
+        if salvaged > 0 {
+            warn!(
+                "{} sessions salvaged from corrupted values - check their repairs above",
+                salvaged
+            );
+        }
+    }
+}
+
+/// Decrypt and deserialize a pickled session with the default options of
+/// [`SessionDecoder`] and rebuild it
+fn decode_session(
+    value: &[u8],
+    store_cipher: Option<&StoreCipher>,
+) -> std::result::Result<InboundGroupSession, (FailureCategory, String)> {
+    SessionDecoder::default().decode(&Settings::default(), value, store_cipher)
+}
+
+/// Extract keys using fault-tolerant direct sled access
+///
+/// With a spill file, keys are appended to it and synced every `spill_every`
+/// sessions together with a checkpoint, and keys already spilled by an
+/// interrupted run are reused instead of re-extracted. With `resume`, iteration
+/// continues right after the checkpoint. Sessions are decoded on `threads`
+/// worker threads (all cores if not given).
+///
+/// Every extracted key is handed to `on_key` in sled order; only the failures
+/// are returned. `observer` is told about every key and failure as well.
+/// Sessions are decoded with the options of `decoder`, which also counts them.
+#[allow(clippy::too_many_arguments)]
+async fn extract_keys_fault_tolerant(
+    settings: &Settings,
+    sled_path: &PathBuf,
+    passphrase: Option<&str>,
+    decoder: &SessionDecoder,
+    spill_path: Option<&std::path::Path>,
+    spill_every: usize,
+    resume: bool,
+    threads: Option<usize>,
+    observer: &mut dyn MigrationObserver,
+    on_key: &mut impl FnMut(ExportedKeyData) -> Result<()>,
+) -> Result<Vec<FailedSession>> {
+    info!("Opening Sled database in fault-tolerant mode");
+
+    let effective_passphrase = passphrase.unwrap_or("");
+    info!("Using passphrase: '{}'", if effective_passphrase.is_empty() { "<empty string>" } else { "<provided>" });
+
+    // Open raw sled database
+    let db = settings
+        .sled_config()
+        .path(sled_path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+
+    // Load store cipher if present
+    let store_cipher = load_store_cipher(settings, &db, effective_passphrase)?;
+    let store_cipher_ref = store_cipher.as_ref();
+
+    // Fails on a state store; opening the tree would silently create an empty one
+    let schema = schema::detect(settings, &db)
+        .with_context(|| format!("Unsupported store {:?}", sled_path))?;
+    info!("Store layout: {}", schema.describe());
+    if schema.layout.libolm_pickles {
+        info!("Sessions of this layout are libolm pickles and are converted while reading");
+    }
+
+    // Open the inbound group sessions tree
+    let sessions_tree = db
+        .open_tree(&schema.inbound_group_sessions)
+        .context("Failed to open inbound group sessions tree")?;
+
+    let total_entries = sessions_tree.len();
+    info!("Found {} entries in inbound group sessions tree", total_entries);
+
+    // Pick up keys spilled by an interrupted run
+    let spill::SpillState { keys: spilled_keys, done } = match spill_path {
+        Some(path) => spill::load_spill(path)?,
+        None => spill::SpillState::default(),
+    };
+    let mut spill_writer = spill_path.map(spill::SpillWriter::open).transpose()?;
+    let checkpoint_path = spill_path.map(spill::checkpoint_path);
+    let checkpoint = match &checkpoint_path {
+        Some(path) if resume => spill::load_checkpoint(path)?,
+        _ => None,
+    };
+
+    for key in spilled_keys {
+        on_key(key)?;
+    }
+    let mut failed_sessions: Vec<FailedSession> = Vec::new();
+    let mut success_count = 0;
+    let mut fail_count = 0;
+
+    // Continue after the checkpoint, or start from the beginning
+    let (start_index, entries) = match checkpoint {
+        Some(checkpoint) => {
+            info!(
+                "Resuming after {} processed entries (key {})",
+                checkpoint.processed, checkpoint.last_key
+            );
+            let last_key = hex::decode(&checkpoint.last_key).context("Invalid checkpoint key")?;
+            failed_sessions = checkpoint.failed_sessions;
+            let range = (
+                std::ops::Bound::Excluded(last_key),
+                std::ops::Bound::Unbounded,
+            );
+            (checkpoint.processed, throttle::iter(sessions_tree.range(range)))
+        }
+        None => {
+            if resume {
+                warn!("No checkpoint found - starting from the beginning");
+            }
+            (0, throttle::iter(sessions_tree.iter()))
+        }
+    };
+    let mut last_key: Option<sled::IVec> = None;
+    let mut since_checkpoint = 0;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .build()
+        .context("Failed to create worker pool")?;
+    info!("Decoding sessions on {} threads", pool.current_num_threads());
+
+    // Iterate through all entries in batches; decryption, JSON parsing and
+    // pickle reconstruction run in parallel, everything else stays in order
+    observer.on_tree_start(&schema.inbound_group_sessions, start_index, total_entries);
+    let mut entries = entries.enumerate();
+    let mut processed = start_index;
+    loop {
+        // Stop between batches, so the checkpoint covers everything handed to `on_key`
+        if interrupt::requested() {
+            warn!("Stopping after {} of {} entries", processed, total_entries);
+            match (spill_writer.as_mut(), &checkpoint_path, &last_key) {
+                (Some(writer), Some(path), Some(key)) => {
+                    writer.flush()?;
+                    spill::save_checkpoint(path, key, processed, &failed_sessions)?;
+                    info!("Checkpoint written - continue with --resume");
+                }
+                (None, ..) => warn!("No --spill-file given - a new run starts from the beginning"),
+                _ => {}
+            }
+            break;
+        }
+
+        let batch: Vec<(usize, sled::Result<(sled::IVec, sled::IVec)>)> = entries
+            .by_ref()
+            .take(DECODE_BATCH_SIZE)
+            .map(|(index, item)| (start_index + index, item))
+            .collect();
+        let Some((batch_last_index, _)) = batch.last() else {
+            break;
+        };
+        let batch_last_index = *batch_last_index;
+        processed = batch_last_index + 1;
+        let batch_len = batch.len();
+        since_checkpoint += batch_len;
+
+        let decoded: Vec<_> = pool.install(|| {
+            batch
+                .into_par_iter()
+                .map(|(index, item)| {
+                    let item = item.map(|(key, value)| {
+                        // Already extracted by an earlier run
+                        if !done.is_empty() && done.contains(&hex::encode(&key)) {
+                            (key, None)
+                        } else {
+                            let decoded =
+                                decoder.decode(settings, &value, store_cipher_ref).map(|session| {
+                                    (session, decoder.sender_data(&value, store_cipher_ref))
+                                });
+                            (key, Some(decoded))
+                        }
+                    });
+                    (index, item)
+                })
+                .collect()
+        });
+
+        for (index, item) in decoded {
+            match item {
+                Ok((key, decoded)) => {
+                    last_key = Some(key.clone());
+
+                    match decoded {
+                        None => {}
+                        Some(Ok((session, sender_data))) => {
+                            let mut exported = export_session(&session).await;
+                            sender_data::attach(&mut exported, sender_data);
+                            if let Some(writer) = spill_writer.as_mut() {
+                                writer.push(&key, &exported)?;
+                            }
+                            observer.on_key(index, &exported.room_id, &exported.session_id);
+                            on_key(exported)?;
+                            success_count += 1;
+                        }
+                        Some(Err((category, error))) => {
+                            warn!("Session {}: {}", index, error);
+                            observer.on_failure(index, category, &error);
+                            failed_sessions.push(FailedSession {
+                                index,
+                                tree: schema.inbound_group_sessions.clone(),
+                                key_hex: hex::encode(&key),
+                                category,
+                                error,
+                                raw_value: None,
+                            });
+                            fail_count += 1;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Session {}: Failed to read from sled - {}", index, e);
+                    let error = format!("Sled read error: {}", e);
+                    observer.on_failure(index, FailureCategory::SledRead, &error);
+                    failed_sessions.push(FailedSession {
+                        index,
+                        tree: schema.inbound_group_sessions.clone(),
+                        key_hex: String::from("<read error>"),
+                        category: FailureCategory::SledRead,
+                        error,
+                        raw_value: None,
+                    });
+                    fail_count += 1;
+                }
+            }
+        }
+
+        // Persist progress: first the spilled keys, then the checkpoint pointing past them
+        if since_checkpoint >= spill_every {
+            if let (Some(writer), Some(path), Some(key)) =
+                (spill_writer.as_mut(), &checkpoint_path, &last_key)
+            {
+                writer.flush()?;
+                spill::save_checkpoint(path, key, processed, &failed_sessions)?;
+            }
+            since_checkpoint = 0;
+        }
+    }
+    observer.on_complete(success_count, fail_count);
+
+    if let Some(writer) = spill_writer.as_mut() {
+        writer.flush()?;
+    }
+    if !done.is_empty() {
+        info!("Reused {} keys from the spill file", done.len());
+    }
+    if fail_count < failed_sessions.len() {
+        info!(
+            "{} failures carried over from the checkpoint",
+            failed_sessions.len() - fail_count
+        );
+    }
+
+    info!(
+        "Extraction complete: {} succeeded, {} failed out of {} total",
+        success_count + done.len(),
+        failed_sessions.len(),
+        total_entries
+    );
+    decoder.log_counts();
+
+    Ok(failed_sessions)
+}
+
+/// Extract all inbound group session keys from the Sled store (original strict mode)
+async fn extract_keys_strict(
+    settings: &Settings,
+    sled_path: &PathBuf,
+    passphrase: Option<&str>,
+) -> Result<Vec<ExportedKeyData>> {
+    info!("Opening Sled crypto store at: {:?}", sled_path);
+
+    // Open the Sled store
+    // Note: matrix-bot-sdk uses empty string "" as passphrase, not None
+    let effective_passphrase = passphrase.unwrap_or("");
+    info!("Using passphrase: '{}'", if effective_passphrase.is_empty() { "<empty string>" } else { "<provided>" });
+
+    // Open sled db directly and pass to open_with_database
+    let db = settings
+        .sled_config()
+        .path(sled_path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+
+    let store = SledCryptoStore::open_with_database(db, Some(effective_passphrase))
+        .await
+        .context("Failed to open Sled crypto store")?;
+
+    info!("Sled store opened successfully");
+
+    // === DIAGNOSTIC: Check account data ===
+    info!("=== DIAGNOSTICS ===");
+    match store.load_account().await {
+        Ok(Some(account)) => {
+            info!("✓ Account found!");
+            info!("  User ID: {}", redact::id(settings, account.user_id()));
+            info!("  Device ID: {}", redact::id(settings, account.device_id()));
+            info!("  Identity keys present: {}", account.identity_keys().curve25519.to_base64().len() > 0);
+        }
+        Ok(None) => warn!("✗ No account found in store!"),
+        Err(e) => warn!("✗ Error loading account: {}", e),
+    }
+
+    // === DIAGNOSTIC: Check tracked users ===
+    let tracked = store.load_tracked_users().await.unwrap_or_default();
+    info!("Tracking {} users", tracked.len());
+
+    // === Get inbound group sessions ===
+    info!("=== INBOUND SESSIONS ===");
+    let sessions: Vec<matrix_sdk_crypto::olm::InboundGroupSession> = store
+        .get_inbound_group_sessions()
+        .await
+        .context("Failed to retrieve inbound group sessions")?;
+
+    info!("Found {} inbound group sessions", sessions.len());
+
+    // Export each session
+    let mut exported_keys: Vec<ExportedKeyData> = Vec::new();
+
+    for session in sessions.iter() {
+        let exported = export_session(session).await;
+        info!("  Exported session {} in room {}",
+            redact::id(settings, &exported.session_id),
+            redact::id(settings, &exported.room_id));
+        exported_keys.push(exported);
+    }
+
+    info!("Successfully exported {} keys", exported_keys.len());
+
+    Ok(exported_keys)
+}
+
+/// Extract the additional trees requested with `--include`
+fn extract_extra_trees(
+    settings: &Settings,
+    sled_path: &PathBuf,
+    passphrase: Option<&str>,
+    include: &[ExtraTree],
+    skip_errors: bool,
+) -> Result<(ExtraTreeExport, Vec<FailedSession>)> {
+    let db = settings
+        .sled_config()
+        .path(sled_path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+
+    let store_cipher = load_store_cipher(settings, &db, passphrase.unwrap_or(""))?;
+    let store_cipher_ref = store_cipher.as_ref();
+
+    let mut export = ExtraTreeExport::default();
+    let mut failed_sessions = Vec::new();
+
+    for tree in include {
+        info!("=== {} ===", tree.label().to_uppercase());
+        match tree {
+            ExtraTree::OutboundGroupSessions => {
+                let (sessions, failed) = trees::extract_outbound_group_sessions(
+                    settings,
+                    &db,
+                    store_cipher_ref,
+                    skip_errors,
+                )?;
+                export.outbound_group_sessions = sessions;
+                failed_sessions.extend(failed);
+            }
+            ExtraTree::OlmSessions => {
+                let (sessions, failed) =
+                    trees::extract_olm_sessions(settings, &db, store_cipher_ref, skip_errors)?;
+                export.olm_sessions = sessions;
+                failed_sessions.extend(failed);
+            }
+            ExtraTree::Devices => {
+                let (devices, failed) =
+                    trees::extract_devices(settings, &db, store_cipher_ref, skip_errors)?;
+                export.devices = devices;
+                failed_sessions.extend(failed);
+            }
+            ExtraTree::Identities => {
+                let (identities, failed) =
+                    trees::extract_identities(settings, &db, store_cipher_ref, skip_errors)?;
+                export.identities = identities;
+                failed_sessions.extend(failed);
+            }
+            ExtraTree::CrossSigningKeys => {
+                let (identity, failed) = trees::extract_cross_signing_identity(
+                    settings,
+                    &db,
+                    store_cipher_ref,
+                    skip_errors,
+                )?;
+                export.cross_signing_identity = identity;
+                failed_sessions.extend(failed);
+            }
+            ExtraTree::TrackedUsers => {
+                let (users, failed) =
+                    trees::extract_tracked_users(settings, &db, store_cipher_ref, skip_errors)?;
+                export.tracked_users = users;
+                failed_sessions.extend(failed);
+            }
+            ExtraTree::KeyRequests => {
+                let (requests, failed) =
+                    trees::extract_key_requests(settings, &db, store_cipher_ref, skip_errors)?;
+                export.key_requests = requests;
+                failed_sessions.extend(failed);
+                info!(
+                    "Incoming key requests are only held in memory by matrix-sdk-sled and \
+                     can't be migrated - devices waiting on this one have to request again"
+                );
+            }
+            ExtraTree::Withheld => {
+                let (withheld, failed) =
+                    trees::extract_withheld_info(settings, &db, store_cipher_ref, skip_errors)?;
+                export.withheld = withheld;
+                failed_sessions.extend(failed);
+            }
+            ExtraTree::Account => {
+                let (account, secrets, failed) =
+                    trees::extract_account(settings, &db, store_cipher_ref, skip_errors)?;
+                export.account = account;
+                export.secrets = secrets;
+                failed_sessions.extend(failed);
+            }
+        }
+    }
+
+    Ok((export, failed_sessions))
+}
+
+/// Write a file that only the current user can read
+///
+/// Exports contain secret key material, so they are created with mode 0600 on Unix.
+/// The contents go to `<path>.tmp` first, which is synced and then renamed over
+/// `path`, so a crash never leaves a truncated file that looks like a complete export.
+fn write_private_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let (mut file, tmp_path) = create_private_tmp(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    persist_private_tmp(&tmp_path, path)
+}
+
+/// Create the `<path>.tmp` file of [`write_private_file`] for writing
+fn create_private_tmp(path: &std::path::Path) -> std::io::Result<(std::fs::File, PathBuf)> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    // A leftover from an earlier crash might have been created with other permissions
+    match std::fs::remove_file(&tmp_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let file = options.open(&tmp_path)?;
+    Ok((file, tmp_path))
+}
+
+/// Rename a synced temp file over `path` and persist the rename
+fn persist_private_tmp(tmp_path: &std::path::Path, path: &std::path::Path) -> std::io::Result<()> {
+    std::fs::rename(tmp_path, path)?;
+
+    // Persist the rename itself
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            std::path::Path::new(".")
+        } else {
+            parent
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Seal, encode, compress and encrypt an export according to the extraction flags
+///
+/// Intermediate plaintext buffers are wiped once they're no longer needed.
+fn encode_output_file(
+    settings: &Settings,
+    output: &mut ExtractionOutput,
+    args: &ExtractArgs,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let passphrase = match args.encrypt_output {
+        true => Some(args.output_passphrase.resolve()?.unwrap_or_default()),
+        false => None,
+    };
+    let passphrase = passphrase.as_deref().map(String::as_str);
+    encode_output(settings, output, args.format, args.compress, passphrase)
+}
+
+/// Seal, encode, compress and optionally encrypt an export
+fn encode_output(
+    settings: &Settings,
+    output: &mut ExtractionOutput,
+    output_format: format::OutputFormat,
+    compression: Option<format::Compression>,
+    passphrase: Option<&str>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    integrity::seal(output, settings.integrity_passphrase())?;
+    let encoded = Zeroizing::new(format::encode(output, output_format)?);
+    let mut data = Zeroizing::new(format::compress(&encoded, compression)?);
+    if let Some(passphrase) = passphrase {
+        data = Zeroizing::new(encryption::encrypt(&data, passphrase)?);
+    }
+    Ok(data)
+}
+
+/// Write an export as a directory of parts plus a manifest
+fn write_split_output(
+    settings: &Settings,
+    args: &ExtractArgs,
+    output: &ExtractionOutput,
+) -> Result<()> {
+    let dir = &args.output;
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create output directory {:?}", dir))?;
+
+    let chunk_size = args.chunk_size.map(|size| size as usize);
+    let parts = split::split_output(output, args.split_by_room, chunk_size, !args.no_keys_by_room);
+    info!("Writing {} files to {:?}", parts.len(), dir);
+
+    let mut manifest = split::SplitManifest {
+        version: 1,
+        total_keys: output.total_keys,
+        failed_keys: output.failed_keys,
+        parts: Vec::with_capacity(parts.len()),
+    };
+
+    for mut part in parts {
+        let file = split::part_file_name(&part.stem, args.format, args.compress);
+        let data = encode_output_file(settings, &mut part.output, args)?;
+        write_private_file(&dir.join(&file), &data)
+            .with_context(|| format!("Failed to write {}", file))?;
+
+        manifest.parts.push(split::ManifestPart {
+            file,
+            keys: part.output.total_keys,
+            room_id: part.room_id,
+        });
+    }
+
+    let manifest_json =
+        serde_json::to_vec_pretty(&manifest).context("Failed to serialize manifest")?;
+    write_private_file(&dir.join(split::MANIFEST_FILE), &manifest_json)
+        .context("Failed to write manifest")?;
+
+    Ok(())
+}
+
+/// Parse a percentage such as `5`, `0.5` or `5%`
+fn parse_percent(value: &str) -> std::result::Result<f64, String> {
+    let percent: f64 = value
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", value))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("{} is not between 0 and 100", percent));
+    }
+    Ok(percent)
+}
+
+/// Fail when more entries failed than `max_percent` of all entries or `max_count` allow
+fn check_fail_threshold(
+    extracted: usize,
+    failed: usize,
+    max_percent: Option<f64>,
+    max_count: Option<usize>,
+) -> Result<()> {
+    let total = extracted + failed;
+    let percent = if total == 0 {
+        0.0
+    } else {
+        failed as f64 * 100.0 / total as f64
+    };
+
+    if let Some(max_count) = max_count.filter(|max_count| failed > *max_count) {
+        return Err(ExtractorError::TooManyFailures(format!(
+            "{} of {} entries failed, more than --max-failures {}",
+            failed, total, max_count
+        ))
+        .into());
+    }
+    if let Some(max_percent) = max_percent.filter(|max_percent| percent > *max_percent) {
+        return Err(ExtractorError::TooManyFailures(format!(
+            "{} of {} entries failed ({:.2}%), more than --fail-threshold {}%",
+            failed, total, percent, max_percent
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Organize keys by room and create the output structure
+///
+/// Without `group_by_room` only the number of keys per room is recorded, so
+/// each key is held once instead of twice.
+fn build_output(
+    keys: Vec<ExportedKeyData>,
+    failed_count: usize,
+    group_by_room: bool,
+) -> ExtractionOutput {
+    let mut keys_by_room: std::collections::HashMap<String, Vec<ExportedKeyData>> =
+        std::collections::HashMap::new();
+    let mut keys_per_room = std::collections::BTreeMap::new();
+    let mut all_keys = Vec::new();
+
+    for exported_data in keys {
+        let room_id = exported_data.room_id.clone();
+
+        if group_by_room {
+            keys_by_room
+                .entry(room_id)
+                .or_insert_with(Vec::new)
+                .push(exported_data.clone());
+        } else {
+            *keys_per_room.entry(room_id).or_default() += 1;
+        }
+
+        all_keys.push(exported_data);
+    }
+
+    ExtractionOutput {
+        version: 1,
+        total_keys: all_keys.len(),
+        failed_keys: failed_count,
+        keys_by_room,
+        keys_per_room,
+        all_keys,
+        extra_trees: ExtraTreeExport::default(),
+        metadata: None,
+        integrity: None,
+        partial: false,
+    }
+}
+
+impl ExtractionOutput {
+    /// Number of keys per room, from whichever of the two room maps was written
+    fn room_counts(&self) -> std::collections::BTreeMap<String, usize> {
+        if !self.keys_per_room.is_empty() {
+            return self.keys_per_room.clone();
+        }
+        self.keys_by_room
+            .iter()
+            .map(|(room_id, keys)| (room_id.clone(), keys.len()))
+            .collect()
+    }
+}
+
+/// Run the command line tool with the arguments of this process
+pub async fn run_cli() -> ExitCode {
+    let matches = env::with_variables(Cli::command()).get_matches();
+    let cli = match Cli::from_arg_matches(&matches) {
+        Ok(cli) => cli,
+        Err(e) => e.exit(),
+    };
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+/// Parse command line arguments for one subcommand, as if given to the binary
+///
+/// Used by the C API and `serve`, which take the options of a run from their
+/// callers rather than from the process arguments.
+fn parse_command(args: Vec<String>) -> Result<Command, clap::Error> {
+    let cli = Cli::try_parse_from(std::iter::once("sled-key-extractor".to_string()).chain(args))?;
+    cli.command.ok_or_else(|| {
+        Cli::command().error(clap::error::ErrorKind::MissingSubcommand, "No subcommand given")
+    })
+}
+
+/// Set up logging and run the selected command
+async fn run(cli: Cli) -> Result<()> {
+    let settings = Settings::from_args(&cli.settings)?;
+
+    // Set up logging
+    let log_level = if cli.verbose {
+        Level::DEBUG
+    } else {
+        Level::INFO
+    };
+
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(log_level)
+        .with_target(false)
+        .with_thread_ids(false)
+        .with_file(false)
+        .with_line_number(false)
+        .with_writer(std::io::stderr)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber)
+        .context("Failed to set up logging")?;
+
+    info!("Sled Key Extractor v{}", env!("CARGO_PKG_VERSION"));
+    throttle::set_max_read_mbps(cli.max_read_mbps);
+    if cli.io_nice {
+        throttle::set_idle_io_priority().context("Failed to lower the I/O priority")?;
+    }
+
+    match cli.command {
+        Some(Command::Extract(args)) => {
+            run_extract_command(&settings, *args, cli.verbose).await
+        }
+        Some(Command::MigrateState(args)) => run_migrate_state(&settings, args).await,
+        Some(Command::Import(args)) => run_import(&settings, args).await,
+        Some(Command::Migrate(args)) => run_migrate(&settings, args).await.map(|_| ()),
+        Some(Command::MarkBackedUp(args)) => run_mark_backed_up(&settings, args).await,
+        Some(Command::Inspect(args)) => run_inspect(&settings, args),
+        Some(Command::Doctor(args)) => run_doctor(&settings, args),
+        Some(Command::Stats(args)) => run_stats(&settings, args).await,
+        Some(Command::VerifyPassphrase(args)) => run_verify_passphrase(&settings, args),
+        Some(Command::AnalyzePickle(args)) => run_analyze_pickle(&settings, args),
+        Some(Command::ExportCipher(args)) => run_export_cipher(&settings, args),
+        Some(Command::VerifyMigration(args)) => run_verify_migration(&settings, args).await,
+        Some(Command::Cleanup(args)) => run_cleanup(&settings, args).await,
+        Some(Command::Diff(args)) => run_diff(&settings, args),
+        Some(Command::Merge(args)) => run_merge(&settings, args),
+        Some(Command::CheckExport(args)) => run_check_export(&settings, args),
+        Some(Command::Bench(args)) => run_bench(&settings, args).await,
+        Some(Command::GenFixture(args)) => run_gen_fixture(&settings, args).await,
+        Some(Command::Selftest(args)) => run_selftest(args).await,
+        Some(Command::Serve(args)) => run_serve(&settings, args).await,
+        None => match cli.extract {
+            Some(args) => run_extract_command(&settings, args, cli.verbose).await,
+            None => unreachable!("clap requires the extraction flags without a subcommand"),
+        },
+    }
+}
+
+/// Run `extract`, once per store with --sled-path-glob
+async fn run_extract_command(
+    settings: &Settings,
+    mut args: ExtractArgs,
+    verbose: bool,
+) -> Result<()> {
+    // Strict mode reads all sessions in one call, so there is nothing to stop cleanly
+    if args.skip_errors {
+        interrupt::install();
+    }
+    // Prompt or read stdin once, not for every store and every written part
+    args.output_passphrase = args.output_passphrase.resolved()?;
+    args.since_export_passphrase = args.since_export_passphrase.resolved()?;
+    match args.sled_path_glob.clone() {
+        Some(pattern) => batch::run(settings, args, &pattern, verbose).await,
+        None => run_extract(settings, args, verbose).await.map(|_| ()),
+    }
+}
+
+/// Run the `migrate-state` subcommand
+async fn run_migrate_state(settings: &Settings, mut args: MigrateStateArgs) -> Result<()> {
+    info!("Sled state store path: {:?}", args.sled_path);
+    info!("Target SQLite state store: {:?}", args.target);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+    if settings.kdf.key_file_in_use() {
+        anyhow::bail!(
+            "migrate-state can't use --cipher-key-file - rooms are read through \
+             matrix-sdk-sled, which only takes a passphrase"
+        );
+    }
+
+    // Kept alive until the end of the run; removed on drop
+    let store_path = args.sled_path.clone();
+    if let Some(backup) = &args.backup_first {
+        archive::create_backup(&args.sled_path, backup)?;
+    }
+    let _store_copy = store::prepare_source(&mut args.sled_path, args.copy_first, args.force)?;
+
+    let passphrase = args.store_passphrase.resolve(settings, &args.sled_path, &store_path)?;
+    let target_passphrase = args.target_passphrase.resolve(&args.target)?;
+    let summary = state::migrate_state(
+        settings,
+        &args.sled_path,
+        passphrase.as_deref().map(String::as_str),
+        &args.target,
+        target_passphrase.as_deref().map(String::as_str),
+        &args.filter_names,
+        &args.account_data_types,
+        args.dry_run,
+    )
+    .await?;
+
+    let done = if args.dry_run { "that would be migrated" } else { "migrated" };
+    if args.dry_run {
+        info!("Dry run complete - nothing was written to {:?}", args.target);
+    } else {
+        info!("State migration complete");
+    }
+    info!("  Sync token {}: {}", done, if summary.sync_token { "yes" } else { "no" });
+    info!("  Filters {}: {}", done, summary.filters);
+    info!("  Rooms {}: {}", done, summary.rooms);
+    info!("  State events {}: {}", done, summary.state_events);
+    info!("  Member profiles {}: {}", done, summary.profiles);
+    info!("  Account data events {}: {}", done, summary.account_data);
+    info!("  Read receipts {}: {}", done, summary.receipts);
+
+    Ok(())
+}
+
+/// Run the `inspect` subcommand
+fn run_inspect(settings: &Settings, mut args: InspectArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+
+    // Kept alive until the end of the run; removed on drop
+    let _store_copy = store::prepare_source(&mut args.sled_path, args.copy_first, args.force)?;
+
+    let report = inspect::inspect(settings, &args.sled_path, args.samples)?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&report).context("Failed to serialize report")?;
+        println!("{}", json);
+    } else {
+        inspect::print_report(&report);
+    }
+
+    Ok(())
+}
+
+/// Run the `doctor` subcommand
+fn run_doctor(settings: &Settings, args: DoctorArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+
+    // Without a working candidate, check the plain --passphrase so the report is complete
+    let passphrase = args
+        .store_passphrase
+        .resolve(settings, &args.sled_path, &args.sled_path)
+        .unwrap_or_else(|e| {
+            warn!("{:#}", e);
+            args.store_passphrase.passphrase.clone().map(Zeroizing::new)
+        });
+    let checks = doctor::diagnose(
+        settings,
+        &args.sled_path,
+        passphrase.as_deref().map(String::as_str),
+    );
+    doctor::print_checks(&checks);
+
+    let errors = checks
+        .iter()
+        .filter(|check| check.status == doctor::Status::Error)
+        .count();
+    if errors > 0 {
+        anyhow::bail!("{} problem(s) found", errors);
+    }
+
+    Ok(())
+}
+
+/// Run the `stats` subcommand
+async fn run_stats(settings: &Settings, mut args: StatsArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+
+    // Kept alive until the end of the run; removed on drop
+    let store_path = args.sled_path.clone();
+    let _store_copy = store::prepare_source(&mut args.sled_path, args.copy_first, args.force)?;
+
+    let passphrase = args.store_passphrase.resolve(settings, &args.sled_path, &store_path)?;
+    let passphrase = passphrase.as_deref().map(String::as_str);
+    let stats = stats::collect_stats(settings, &args.sled_path, passphrase).await?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&stats).context("Failed to serialize statistics")?;
+        println!("{}", json);
+    } else {
+        stats::print_stats(&stats, args.top);
+    }
+
+    Ok(())
+}
+
+/// Run the `verify-passphrase` subcommand
+fn run_verify_passphrase(settings: &Settings, mut args: VerifyPassphraseArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+
+    // Kept alive until the end of the run; removed on drop
+    let store_path = args.sled_path.clone();
+    let _store_copy = store::prepare_source(&mut args.sled_path, args.copy_first, args.force)?;
+    let passphrase = args.store_passphrase.resolve(settings, &args.sled_path, &store_path)?;
+
+    let db = settings
+        .sled_config()
+        .path(&args.sled_path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+
+    let started = std::time::Instant::now();
+    let passphrase = passphrase.as_deref().map_or("", String::as_str);
+    let check = passphrase::check_passphrase(settings, &db, passphrase)?;
+    let elapsed = started.elapsed();
+
+    match check {
+        passphrase::CipherCheck::Unencrypted => {
+            println!("✓ Store is not encrypted - no passphrase needed");
+        }
+        passphrase::CipherCheck::Valid => {
+            println!("✓ Passphrase unlocks the store cipher ({:.2?})", elapsed);
+        }
+        passphrase::CipherCheck::Invalid(error) => {
+            println!("✗ Passphrase does not unlock the store cipher: {}", error);
+            return Err(ExtractorError::WrongPassphrase(error).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the `export-cipher` subcommand
+fn run_export_cipher(settings: &Settings, mut args: ExportCipherArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+
+    // Kept alive until the end of the run; removed on drop
+    let store_path = args.sled_path.clone();
+    let _store_copy = store::prepare_source(&mut args.sled_path, args.copy_first, args.force)?;
+    let passphrase = args.store_passphrase.resolve(settings, &args.sled_path, &store_path)?;
+
+    let db = settings
+        .sled_config()
+        .path(&args.sled_path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+    let exported = schema::find_store_cipher(settings, &db)?
+        .context("Store is not encrypted - there is no store cipher key to export")?;
+
+    let started = Instant::now();
+    let passphrase = passphrase.as_deref().map_or("", String::as_str);
+    let key = kdf::derive_key(&settings.kdf, passphrase, &exported)?;
+    info!("Derived the store cipher key in {:.2?}", started.elapsed());
+
+    let mut encoded = Zeroizing::new(hex::encode(key.as_slice()));
+    encoded.push('\n');
+    write_private_file(&args.output, encoded.as_bytes())
+        .with_context(|| format!("Failed to write {:?}", args.output))?;
+
+    println!("✓ Store cipher key written to {:?}", args.output);
+    println!("  Pass it with --cipher-key-file; anyone with the file can decrypt the store");
+    Ok(())
+}
+
+/// Run the `verify-migration` subcommand
+async fn run_verify_migration(
+    settings: &Settings,
+    mut args: VerifyMigrationArgs,
+) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+    info!("Target SQLite crypto store: {:?}", args.target);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+    if !args.target.exists() {
+        anyhow::bail!("Target SQLite crypto store {:?} does not exist", args.target);
+    }
+
+    // Kept alive until the end of the run; removed on drop
+    let store_path = args.sled_path.clone();
+    let _store_copy = store::prepare_source(&mut args.sled_path, args.copy_first, args.force)?;
+
+    let passphrase = args.store_passphrase.resolve(settings, &args.sled_path, &store_path)?;
+    let target_passphrase = args.target_passphrase.resolve(&args.target)?;
+    let report = verify::verify_migration(
+        settings,
+        &args.sled_path,
+        passphrase.as_deref().map(String::as_str),
+        &args.target,
+        target_passphrase.as_deref().map(String::as_str),
+    )
+    .await?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&report).context("Failed to serialize report")?;
+        println!("{}", json);
+    } else {
+        verify::print_report(&report);
+    }
+
+    let differences = report.differences();
+    if differences > 0 {
+        anyhow::bail!("{} session(s) differ between the sled and SQLite stores", differences);
+    }
+    if report.source_failed > 0 {
+        warn!(
+            "{} sled entries could not be decoded and were not compared",
+            report.source_failed
+        );
+    }
+
+    Ok(())
+}
+
+/// Run the `cleanup` subcommand
+async fn run_cleanup(settings: &Settings, args: CleanupArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+    info!("Target SQLite crypto store: {:?}", args.target);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+    if !args.target.exists() {
+        anyhow::bail!("Target SQLite crypto store {:?} does not exist", args.target);
+    }
+    // A running bot would keep writing to the store
+    store::check_unlocked(&args.sled_path, false)?;
+
+    let passphrase = args.store_passphrase.resolve(settings, &args.sled_path, &args.sled_path)?;
+    let target_passphrase = args.target_passphrase.resolve(&args.target)?;
+    let report = verify::verify_migration(
+        settings,
+        &args.sled_path,
+        passphrase.as_deref().map(String::as_str),
+        &args.target,
+        target_passphrase.as_deref().map(String::as_str),
+    )
+    .await?;
+    verify::print_report(&report);
+
+    let differences = report.differences();
+    if differences > 0 {
+        anyhow::bail!(
+            "{} session(s) differ between the sled and SQLite stores - not deleting the sled store",
+            differences
+        );
+    }
+    if report.source_failed > 0 {
+        anyhow::bail!(
+            "{} sled entries could not be decoded and compared - not deleting the sled store",
+            report.source_failed
+        );
+    }
+    info!("Migration verified: all {} sessions are in the SQLite store", report.source_sessions);
+
+    if !args.yes {
+        warn!("Not deleting {:?} without --yes", args.sled_path);
+        return Ok(());
+    }
+
+    info!("Shredding {:?} ({} pass(es))", args.sled_path, args.passes);
+    let summary = shred::shred_dir(&args.sled_path, args.passes)?;
+    info!(
+        "Sled store deleted: {} files, {} bytes overwritten",
+        summary.files, summary.bytes
+    );
+
+    Ok(())
+}
+
+/// Run the `analyze-pickle` subcommand
+fn run_analyze_pickle(settings: &Settings, args: AnalyzePickleArgs) -> Result<()> {
+    let mut embedded_cipher = None;
+    let raw = match (&args.from_failed, args.entry) {
+        (Some(path), Some(entry)) => {
+            let input_passphrase = args.input_passphrase.resolve()?;
+            let failed =
+                read_failed_sessions(path, input_passphrase.as_deref().map(String::as_str))?;
+            let session = failed.sessions.get(entry).with_context(|| {
+                format!("No entry {} - the file has {}", entry, failed.sessions.len())
+            })?;
+            info!("Entry {} of tree '{}': {}", session.index, session.tree, session.error);
+
+            let value = session
+                .raw_value
+                .as_deref()
+                .context("Entry has no raw value - extract with --include-raw-failures")?;
+            embedded_cipher = failed.store_cipher.clone();
+            analyze::decode_input(value)?
+        }
+        _ => analyze::decode_input(args.value.as_deref().unwrap_or_default())?,
+    };
+
+    let exported_cipher = if let Some(sled_path) = &args.sled_path {
+        if !sled_path.exists() {
+            return Err(ExtractorError::StoreNotFound(sled_path.clone()).into());
+        }
+        let db = settings
+        .sled_config()
+            .path(sled_path)
+            .open()
+            .map_err(ExtractorError::SledIo)
+            .context("Failed to open sled database")?;
+        schema::find_store_cipher(settings, &db)?.map(|cipher| cipher.to_vec())
+    } else if let Some(store_cipher) = &args.store_cipher {
+        Some(analyze::decode_input(store_cipher)?)
+    } else {
+        embedded_cipher.as_deref().map(analyze::decode_input).transpose()?
+    };
+
+    // Without a store there's nothing to try candidates or prompts against
+    let sled_path = args.sled_path.clone().unwrap_or_default();
+    let passphrase = args.store_passphrase.resolve(settings, &sled_path, &sled_path)?;
+    let passphrase = passphrase.as_deref().map_or("", String::as_str);
+    let store_cipher = exported_cipher
+        .map(|exported| kdf::import_cipher(&settings.kdf, passphrase, &exported))
+        .transpose()?;
+    let legacy_pickle_key = Zeroizing::new(
+        args.legacy_pickle_key
+            .as_deref()
+            .map(analyze::decode_input)
+            .transpose()
+            .context("Invalid --legacy-pickle-key")?
+            .unwrap_or_default(),
+    );
+
+    let checks = analyze::analyze(settings, &raw, store_cipher.as_ref(), &legacy_pickle_key);
+    doctor::print_checks(&checks);
+
+    if checks.iter().any(|check| check.status == doctor::Status::Error) {
+        let error = "the value can't be extracted".to_string();
+        return Err(ExtractorError::CorruptPickle(error).into());
+    }
+
+    Ok(())
+}
+
+/// Run the `import` subcommand
+async fn run_import(settings: &Settings, args: ImportArgs) -> Result<()> {
+    info!("Input file: {:?}", args.input);
+    info!("Target {:?} crypto store: {:?}", args.store, args.target);
+
+    let input_passphrase = args.input_passphrase.resolve()?;
+    let input_passphrase = input_passphrase.as_deref().map(String::as_str);
+    let output = import::read_export(settings, &args.input, input_passphrase)?;
+    info!("Export contains {} keys", output.all_keys.len());
+    let target_passphrase = args.target_passphrase.resolve(&args.target)?;
+    let summary = import::import_export(
+        settings,
+        &output,
+        args.store,
+        &args.target,
+        target_passphrase.as_deref().map(String::as_str),
+        args.skip_errors,
+        args.dry_run,
+        args.force,
+    )
+    .await?;
+
+    if args.dry_run {
+        info!("Dry run complete - nothing was written to {:?}", args.target);
+        info!("  Sessions that would be imported: {}", summary.imported);
+        info!("  Tree entries that would be imported: {}", summary.trees);
+    } else {
+        info!("Import complete");
+        info!("  Sessions imported: {}", summary.imported);
+        info!("  Replacing a later copy: {}", summary.replaced);
+        info!("  Already in the store (skipped): {}", summary.duplicates);
+        info!("  Tree entries imported: {}", summary.trees);
+    }
+    if summary.failed > 0 {
+        warn!("  Keys and tree entries failed: {}", summary.failed);
+    }
+    if summary.sender_trust_lost > 0 {
+        warn!("  Sessions without their sender trust: {}", summary.sender_trust_lost);
+    }
+
+    Ok(())
+}
+
+/// Run the `migrate` subcommand
+async fn run_migrate(settings: &Settings, args: MigrateArgs) -> Result<MigrateReport> {
+    info!("Target SQLite crypto store: {:?}", args.target);
+
+    let mut sources = Vec::with_capacity(args.sled_paths.len());
+    let mut extra_trees = ExtraTreeExport::default();
+    for (index, path) in args.sled_paths.iter().enumerate() {
+        info!("Source {}/{}: {:?}", index + 1, args.sled_paths.len(), path);
+        if !path.exists() {
+            return Err(ExtractorError::StoreNotFound(path.clone()).into());
+        }
+
+        let mut sled_path = path.clone();
+        let _store_copy = store::prepare_source(&mut sled_path, args.copy_first, args.force)?;
+        let passphrase = args.store_passphrase.resolve(settings, &sled_path, path)?;
+        let passphrase = passphrase.as_deref().map(String::as_str);
+        args.expected_account.check_store(settings, &sled_path, passphrase)?;
+
+        let (keys, failed) = if args.skip_errors {
+            let mut keys = Vec::new();
+            let failed = extract_keys_fault_tolerant(
+                settings,
+                &sled_path,
+                passphrase,
+                &SessionDecoder::default(),
+                None,
+                usize::MAX,
+                false,
+                None,
+                &mut progress::ProgressObserver::new("Extracting sessions"),
+                &mut |key| {
+                    keys.push(key);
+                    Ok(())
+                },
+            )
+            .await?;
+            (keys, failed.len())
+        } else {
+            (extract_keys_strict(settings, &sled_path, passphrase).await?, 0)
+        };
+        info!("  {} keys extracted, {} failed", keys.len(), failed);
+
+        // The account and the rest of the crypto state are those of the store
+        // the bot uses now, the last one
+        let mut failed = failed;
+        if index + 1 == args.sled_paths.len() {
+            let (trees, failed_trees) = extract_extra_trees(
+                settings,
+                &sled_path,
+                passphrase,
+                restore::TREES,
+                args.skip_errors,
+            )?;
+            extra_trees = trees;
+            failed += failed_trees.len();
+        }
+        sources.push(build_output(keys, failed, false));
+    }
+
+    let failed: usize = sources.iter().map(|source| source.failed_keys).sum();
+    let (mut output, merged) = merge::merge(settings, sources);
+    output.extra_trees = extra_trees;
+    info!(
+        "{} sessions from {} keys ({} found in more than one store)",
+        merged.sessions, merged.input_keys, merged.duplicates
+    );
+    if merged.improved > 0 {
+        info!("  Taken from a later store for a lower index: {}", merged.improved);
+    }
+
+    let target_passphrase = args.target_passphrase.resolve(&args.target)?;
+    let summary = import::import_export(
+        settings,
+        &output,
+        import::ImportStore::Sqlite,
+        &args.target,
+        target_passphrase.as_deref().map(String::as_str),
+        args.skip_errors,
+        args.dry_run,
+        false,
+    )
+    .await?;
+
+    if args.dry_run {
+        info!("Dry run complete - nothing was written to {:?}", args.target);
+        info!("  Sessions that would be imported: {}", summary.imported);
+        info!("  Tree entries that would be imported: {}", summary.trees);
+    } else {
+        info!("Migration complete");
+        info!("  Sessions imported: {}", summary.imported);
+        info!("  Replacing a later copy: {}", summary.replaced);
+        info!("  Already in the store (skipped): {}", summary.duplicates);
+        info!("  Tree entries imported: {}", summary.trees);
+    }
+    if failed > 0 {
+        warn!("  Entries that could not be read from the sources: {}", failed);
+    }
+    if summary.failed > 0 {
+        warn!("  Keys and tree entries failed: {}", summary.failed);
+    }
+    if summary.sender_trust_lost > 0 {
+        warn!("  Sessions without their sender trust: {}", summary.sender_trust_lost);
+    }
+
+    Ok(MigrateReport {
+        sources: args.sled_paths.len(),
+        sessions: merged.sessions,
+        read_failures: failed,
+        import: summary,
+    })
+}
+
+/// Run the `mark-backed-up` subcommand
+async fn run_mark_backed_up(settings: &Settings, args: MarkBackedUpArgs) -> Result<()> {
+    info!("Input file: {:?}", args.input);
+    info!("Target SQLite crypto store: {:?}", args.target);
+
+    let target_passphrase = args.target_passphrase.resolve(&args.target)?;
+    let input_passphrase = args.input_passphrase.resolve()?;
+    let summary = import::mark_backed_up(
+        settings,
+        &args.input,
+        &args.target,
+        target_passphrase.as_deref().map(String::as_str),
+        input_passphrase.as_deref().map(String::as_str),
+    )
+    .await?;
+
+    info!("Marked {} sessions as backed up", summary.marked);
+    info!("  Already marked: {}", summary.already_backed_up);
+    if summary.missing > 0 {
+        warn!(
+            "  Not in the target store: {} - were they imported?",
+            summary.missing
+        );
+    }
+
+    Ok(())
+}
+
+/// Run the `diff` subcommand
+fn run_diff(settings: &Settings, args: DiffArgs) -> Result<()> {
+    info!("Old export: {:?}", args.old);
+    info!("New export: {:?}", args.new);
+
+    let input_passphrase = args.input_passphrase.resolve()?;
+    let input_passphrase = input_passphrase.as_deref().map(String::as_str);
+    let old = import::read_export(settings, &args.old, input_passphrase)?;
+    let new = import::read_export(settings, &args.new, input_passphrase)?;
+    let diff = diff::diff(&old, &new);
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&diff).context("Failed to serialize diff")?;
+        println!("{}", json);
+    } else {
+        diff::print_diff(&diff);
+    }
+
+    Ok(())
+}
+
+/// Run the `merge` subcommand
+fn run_merge(settings: &Settings, args: MergeArgs) -> Result<()> {
+    let input_passphrase = args.input_passphrase.resolve()?;
+    let input_passphrase = input_passphrase.as_deref().map(String::as_str);
+    let mut inputs = Vec::with_capacity(args.inputs.len());
+    for path in &args.inputs {
+        info!("Reading {:?}", path);
+        let input = import::read_export(settings, path, input_passphrase)?;
+        info!("  {} keys", input.all_keys.len());
+        inputs.push(input);
+    }
+
+    let (mut output, summary) = merge::merge(settings, inputs);
+
+    let passphrase = match args.encrypt_output {
+        true => Some(args.output_passphrase.resolve()?.unwrap_or_default()),
+        false => None,
+    };
+    let passphrase = passphrase.as_deref().map(String::as_str);
+    let data = encode_output(settings, &mut output, args.format, args.compress, passphrase)?;
+    write_private_file(&args.output, &data)
+        .with_context(|| format!("Failed to write {:?}", args.output))?;
+
+    info!("Merged export written to: {:?}", args.output);
+    info!("  Keys read: {}", summary.input_keys);
+    info!("  Sessions written: {}", summary.sessions);
+    info!("  Duplicates dropped: {}", summary.duplicates);
+    info!("  Taken from a later file for a lower index: {}", summary.improved);
+
+    Ok(())
+}
+
+/// Run the `check-export` subcommand
+fn run_check_export(settings: &Settings, args: CheckExportArgs) -> Result<()> {
+    info!("Export: {:?}", args.input);
+
+    let input_passphrase = args.input_passphrase.resolve()?;
+    let input_passphrase = input_passphrase.as_deref().map(String::as_str);
+    let output = match import::read_export(settings, &args.input, input_passphrase) {
+        Ok(output) => output,
+        // Keep the exit codes of categorized failures, e.g. a wrong passphrase
+        Err(e) if error::exit_code(&e) != error::EXIT_FAILURE => return Err(e),
+        Err(e) => return Err(ExtractorError::InvalidExport(format!("{:#}", e)).into()),
+    };
+    let report = check::check_export(&output);
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&report).context("Failed to serialize report")?;
+        println!("{}", json);
+    } else {
+        check::print_report(&report, args.limit);
+    }
+
+    if !report.problems.is_empty() {
+        return Err(ExtractorError::InvalidExport(format!(
+            "{} problem(s) found",
+            report.problems.len()
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Run the `bench` subcommand
+async fn run_bench(settings: &Settings, mut args: BenchArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+
+    if !args.sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(args.sled_path.clone()).into());
+    }
+
+    // Kept alive until the end of the run; removed on drop
+    let store_path = args.sled_path.clone();
+    let _store_copy = store::prepare_source(&mut args.sled_path, args.copy_first, args.force)?;
+
+    let passphrase = args.store_passphrase.resolve(settings, &args.sled_path, &store_path)?;
+    let passphrase = passphrase.as_deref().map(String::as_str);
+    let report =
+        bench::run(settings, &args.sled_path, passphrase, args.limit, &args.threads).await?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&report).context("Failed to serialize results")?;
+        println!("{}", json);
+    } else {
+        bench::print_report(&report);
+    }
+
+    Ok(())
+}
+
+/// Run the `selftest` subcommand
+async fn run_selftest(args: SelftestArgs) -> Result<()> {
+    if args.sessions == 0 {
+        anyhow::bail!("--sessions must be at least 1");
+    }
+    info!("Running self-test with {} generated sessions", args.sessions);
+    selftest::run(args.sessions).await
+}
+
+/// Run the `serve` subcommand
+async fn run_serve(settings: &Settings, args: ServeArgs) -> Result<()> {
+    let token = std::fs::read_to_string(&args.token_file)
+        .with_context(|| format!("Failed to read token file {:?}", args.token_file))?;
+    let token = Zeroizing::new(token.lines().next().unwrap_or_default().trim().to_string());
+    if token.is_empty() {
+        anyhow::bail!("Token file {:?} is empty", args.token_file);
+    }
+
+    passphrase::set_non_interactive();
+    let listener = tokio::net::TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    let concurrency = args.concurrency as usize;
+    serve::serve(settings, listener, &token, concurrency, args.keep_finished).await
+}
+
+/// Run the `gen-fixture` subcommand
+async fn run_gen_fixture(settings: &Settings, args: GenFixtureArgs) -> Result<()> {
+    info!("Sled path: {:?}", args.sled_path);
+
+    let options = fixture::FixtureOptions {
+        sessions: args.sessions,
+        rooms: args.rooms,
+        passphrase: args.passphrase,
+        corrupt: args.corrupt,
+        seed: args.seed,
+    };
+    let manifest = fixture::generate(settings, &args.sled_path, &options).await?.manifest;
+
+    let corrupted = manifest
+        .sessions
+        .iter()
+        .filter(|session| session.corruption.is_some())
+        .count();
+    info!(
+        "Fixture written: {} sessions ({} corrupted), {}",
+        manifest.sessions.len(),
+        corrupted,
+        if manifest.encrypted { "encrypted" } else { "unencrypted" }
+    );
+
+    if let Some(path) = &args.manifest {
+        let json = serde_json::to_vec_pretty(&manifest)
+            .context("Failed to serialize manifest")?;
+        std::fs::write(path, json).with_context(|| format!("Failed to write {:?}", path))?;
+        info!("Manifest written to: {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Run the `extract` subcommand
+async fn run_extract(
+    settings: &Settings,
+    mut args: ExtractArgs,
+    verbose: bool,
+) -> Result<batch::StoreTotals> {
+    // Kept alive until the end of the run; removed on drop
+    let mut _source_copy = None;
+    let mut sled_path = if let Some(archive) = &args.sled_archive {
+        let (copy, path) = archive::unpack_store(archive, args.sled_path.as_deref())?;
+        _source_copy = Some(copy);
+        path
+    } else if let Some(snapshot) = &args.sled_snapshot {
+        let (copy, path) = snapshot::restore(settings, snapshot)?;
+        _source_copy = Some(copy);
+        path
+    } else {
+        args.sled_path.take().context("--sled-path is required")?
+    };
+    info!("Sled path: {:?}", sled_path);
+    info!("Output path: {:?}", args.output);
+    if args.skip_errors {
+        info!("Mode: FAULT-TOLERANT (will skip corrupted entries)");
+    } else {
+        info!("Mode: STRICT (will fail on any error)");
+    }
+    if !args.skip_errors
+        && schema::tree_name(settings, INBOUND_GROUP_SESSIONS_TREE) != INBOUND_GROUP_SESSIONS_TREE
+    {
+        anyhow::bail!(
+            "--tree-name {}=... needs --skip-errors - strict mode reads sessions through \
+             matrix-sdk-sled, which only knows the default name",
+            INBOUND_GROUP_SESSIONS_TREE
+        );
+    }
+    if !args.skip_errors && settings.kdf.key_file_in_use() {
+        anyhow::bail!(
+            "--cipher-key-file needs --skip-errors - strict mode opens the store through \
+             matrix-sdk-sled, which only takes a passphrase"
+        );
+    }
+
+    // Verify the Sled path exists
+    if !sled_path.exists() {
+        return Err(ExtractorError::StoreNotFound(sled_path.clone()).into());
+    }
+    let started_at = report::unix_time();
+    let started = Instant::now();
+
+    // Kept alive until the end of the run; removed on drop
+    let store_path = args
+        .sled_archive
+        .clone()
+        .or_else(|| args.sled_snapshot.clone())
+        .unwrap_or_else(|| sled_path.clone());
+    if let Some(backup) = &args.backup_first {
+        archive::create_backup(&sled_path, backup)?;
+    }
+    let _store_copy = store::prepare_source(&mut sled_path, args.copy_first, args.force)?;
+    let passphrase = args.store_passphrase.resolve(settings, &sled_path, &store_path)?;
+    let legacy_pickle_key = args
+        .legacy_pickle_key
+        .as_deref()
+        .map(analyze::decode_input)
+        .transpose()
+        .context("Invalid --legacy-pickle-key")?;
+    let decoder = SessionDecoder::new(legacy_pickle_key, args.salvage);
+
+    // Without keys_by_room a single JSON file can be written while extracting
+    let split = args.split_by_room || args.chunk_size.is_some();
+    let mut stream_writer = if args.no_keys_by_room
+        && !split
+        && !args.encrypt_output
+        && args.format == format::OutputFormat::Json
+    {
+        info!("Streaming keys to the output file");
+        Some(stream::StreamWriter::create(
+            settings,
+            &args.output,
+            args.compress,
+            args.format_version,
+        )?)
+    } else {
+        None
+    };
+    if args.no_secrets {
+        info!("Leaving session keys out of the export (--no-secrets)");
+    }
+    args.expected_account
+        .check_store(settings, &sled_path, passphrase.as_deref().map(String::as_str))?;
+    let mut key_filter = args.filter.build()?;
+    key_filter.resolve_sender_users(
+        settings,
+        &sled_path,
+        passphrase.as_deref().map(String::as_str),
+    )?;
+    if let Some(path) = &args.filter.since_export {
+        info!("Reading earlier export {:?}", path);
+        let passphrase = match args.since_export_passphrase.resolve()? {
+            Some(passphrase) => Some(passphrase),
+            None => args.output_passphrase.resolve()?,
+        };
+        let passphrase = passphrase.as_deref().map(String::as_str);
+        let previous = import::read_export(settings, path, passphrase)?;
+        info!("Earlier export holds {} keys", previous.all_keys.len());
+        key_filter.set_previous_export(&previous);
+    }
+    if !key_filter.is_empty() {
+        info!("Filtering keys: {}", key_filter.describe());
+    }
+    let no_secrets = args.no_secrets;
+    let format_version = args.format_version;
+    let mut keys = Vec::new();
+    let mut extracted = 0;
+    let mut filtered_out = 0;
+    let mut on_key = |mut key: ExportedKeyData| {
+        extracted += 1;
+        if !key_filter.matches(&key) {
+            filtered_out += 1;
+            return Ok(());
+        }
+        if no_secrets {
+            key.session_key.zeroize();
+        }
+        if format_version < 2 {
+            key.first_known_index = None;
+            key.backed_up = None;
+            key.imported = None;
+            key.extra.clear();
+        }
+        key.sha256 = integrity::key_hash(&key);
+        match stream_writer.as_mut() {
+            Some(writer) => writer.write_key(&key),
+            None => {
+                keys.push(key);
+                Ok(())
+            }
+        }
+    };
+
+    // Extract the keys
+    let phase = Instant::now();
+    let mut failed_sessions = if args.skip_errors {
+        extract_keys_fault_tolerant(
+            settings,
+            &sled_path,
+            passphrase.as_deref().map(String::as_str),
+            &decoder,
+            args.spill_file.as_deref(),
+            args.spill_every,
+            args.resume,
+            args.threads.map(|threads| threads as usize),
+            &mut progress::ProgressObserver::new("Extracting sessions"),
+            &mut on_key,
+        ).await?
+    } else {
+        let passphrase = passphrase.as_deref().map(String::as_str);
+        let keys = extract_keys_strict(settings, &sled_path, passphrase).await?;
+        for key in keys {
+            on_key(key)?;
+        }
+        Vec::new()
+    };
+    let session_failures = failed_sessions.len();
+    let extraction_time = phase.elapsed();
+
+    // The checkpoint is written; on SIGTERM leave the keys in the spill file
+    // rather than risk running past the grace period
+    if let (Some(interrupt::Signal::Terminate), Some(spill_path)) =
+        (interrupt::received(), &args.spill_file)
+    {
+        if let Some(writer) = stream_writer {
+            writer.abort();
+        }
+        return Err(ExtractorError::Interrupted(format!(
+            "terminated after {} keys - continue with --spill-file {:?} --resume",
+            extracted, spill_path
+        ))
+        .into());
+    }
+
+    // Write what was extracted so far, but never to the output path itself
+    let interrupted = interrupt::requested();
+    if interrupted {
+        args.output = interrupt::partial_path(&args.output);
+        warn!("Writing the keys extracted so far to {:?}", args.output);
+        if let Some(writer) = stream_writer.as_mut() {
+            writer.mark_partial(&args.output);
+        }
+    }
+
+    // Extract any additional trees
+    let phase = Instant::now();
+    let include = if interrupted {
+        Vec::new()
+    } else if args.migrate_all {
+        info!("Migrating ALL crypto-store trees");
+        ExtraTree::value_variants().to_vec()
+    } else {
+        args.include.clone()
+    };
+
+    let mut extra_trees = if include.is_empty() {
+        ExtraTreeExport::default()
+    } else {
+        let (extra_trees, failed) = extract_extra_trees(
+            settings,
+            &sled_path,
+            passphrase.as_deref().map(String::as_str),
+            &include,
+            args.skip_errors,
+        )?;
+        failed_sessions.extend(failed);
+        extra_trees
+    };
+    let extra_trees_time = phase.elapsed();
+
+    // Not worth losing the extracted keys over
+    let metadata = if args.format_version >= 2 {
+        let passphrase = passphrase.as_deref().map(String::as_str);
+        metadata::collect(settings, &sled_path, passphrase, started_at)
+            .map_err(|e| warn!("Failed to read the export metadata: {:#}", e))
+            .ok()
+    } else {
+        None
+    };
+
+    let phase = Instant::now();
+    let failed_count = failed_sessions.len();
+    let mut failures_by_tree: std::collections::HashMap<String, usize> =
+        std::collections::HashMap::new();
+    for failed in &failed_sessions {
+        *failures_by_tree.entry(failed.tree.clone()).or_default() += 1;
+    }
+
+    // Write failed sessions to file if requested
+    let mut failed_output_written = None;
+    if !failed_sessions.is_empty() {
+        let failed_output_path = args.failed_output.clone().unwrap_or_else(|| {
+            let mut path = args.output.clone();
+            match args.compress {
+                Some(compression) => {
+                    path.set_file_name(format!("failed-sessions.json.{}", compression.extension()))
+                }
+                None => path.set_file_name("failed-sessions.json"),
+            }
+            path
+        });
+
+        let mut store_cipher = None;
+        if args.include_raw_failures {
+            store_cipher = attach_raw_values(settings, &sled_path, &mut failed_sessions)?;
+            warn!(
+                "Failed-sessions file includes raw stored values - \
+                 handle it as carefully as the store itself"
+            );
+        }
+
+        let failed_output = FailedSessionsOutput {
+            total_failed: failed_count,
+            by_category: count_by_category(&failed_sessions),
+            contains_raw_values: args.include_raw_failures,
+            store_cipher,
+            sessions: failed_sessions,
+        };
+
+        let passphrase = match args.encrypt_output {
+            true => Some(args.output_passphrase.resolve()?.unwrap_or_default()),
+            false => None,
+        };
+        let failed_data = encode_failed_sessions(
+            &failed_output,
+            args.compress,
+            passphrase.as_deref().map(String::as_str),
+        )?;
+
+        write_private_file(&failed_output_path, &failed_data)
+            .context("Failed to write failed sessions file")?;
+
+        warn!("Failed sessions written to: {:?}", failed_output_path);
+        failed_output_written = Some(failed_output_path);
+        failed_sessions = failed_output.sessions;
+    }
+
+    // Abort before the export is completed, so automation doesn't pick up a gutted one
+    let extracted_entries =
+        extracted + include.iter().map(|tree| extra_trees.count(*tree)).sum::<usize>();
+    if let Err(e) = check_fail_threshold(
+        extracted_entries,
+        failed_count,
+        args.fail_threshold,
+        args.max_failures,
+    ) {
+        if let Some(writer) = stream_writer {
+            writer.abort();
+        }
+        return Err(e);
+    }
+
+    let (total_keys, room_counts) = match stream_writer {
+        Some(writer) => {
+            let streamed = writer.finish(failed_count, &extra_trees, metadata.as_ref())?;
+            (streamed.total_keys, streamed.keys_per_room)
+        }
+        None => {
+            // Organize and serialize
+            let mut output = build_output(keys, failed_count, !args.no_keys_by_room);
+            output.extra_trees = extra_trees;
+            output.partial = interrupted;
+            metadata::set_version(&mut output, args.format_version, metadata);
+
+            // Write to output file, or to a directory of parts
+            if split {
+                write_split_output(settings, &args, &output)?;
+            } else {
+                let data = encode_output_file(settings, &mut output, &args)?;
+                write_private_file(&args.output, &data)
+                    .context("Failed to write output file")?;
+            }
+
+            extra_trees = std::mem::take(&mut output.extra_trees);
+            (output.total_keys, output.room_counts())
+        }
+    };
+    let write_time = phase.elapsed();
+
+    if total_keys == 0 && filtered_out > 0 {
+        warn!("No keys were extracted - all {} keys were left out by filters", filtered_out);
+    } else if total_keys == 0 {
+        warn!("No keys were extracted! The store may be empty or corrupted.");
+    }
+
+    // The export is complete, so the plaintext spill file and its checkpoint are no longer needed
+    if let Some(spill_path) = args.spill_file.as_ref().filter(|_| !interrupted) {
+        for path in [spill_path.clone(), spill::checkpoint_path(spill_path)] {
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {:?}", path))?;
+            }
+        }
+    }
+
+    info!("Keys successfully exported to: {:?}", args.output);
+    info!("Total keys exported: {}", total_keys);
+    if filtered_out > 0 {
+        info!("Keys left out by filters: {}", filtered_out);
+    }
+    if failed_count > 0 {
+        warn!("Total keys failed: {}", failed_count);
+    }
+    info!("Rooms with keys: {}", room_counts.len());
+
+    let mut tree_counts = vec![(
+        "inbound-group-sessions".to_string(),
+        report::TreeCount {
+            extracted: total_keys,
+            failed: session_failures,
+        },
+    )];
+    for tree in &include {
+        let failed: usize = tree
+            .tree_names()
+            .iter()
+            .filter_map(|name| failures_by_tree.get(*name))
+            .sum();
+        tree_counts.push((
+            tree.label(),
+            report::TreeCount {
+                extracted: extra_trees.count(*tree),
+                failed,
+            },
+        ));
+    }
+
+    // Print a per-tree summary when more than the inbound sessions were extracted
+    if !include.is_empty() {
+        info!("=== PER-TREE SUMMARY ===");
+        for (label, count) in &tree_counts {
+            info!("  {}: {} extracted, {} failed", label, count.extracted, count.failed);
+        }
+    }
+    if extra_trees.account.is_some() {
+        warn!("Private account keys exported - protect this file accordingly!");
+    }
+    if extra_trees.cross_signing_identity.is_some() {
+        warn!("Private cross-signing keys exported - protect this file accordingly!");
+    }
+
+    // Print summary by room
+    if verbose {
+        info!("\nKeys per room:");
+        for (room_id, count) in &room_counts {
+            info!("  {}: {} keys", redact::id(settings, room_id), count);
+        }
+    }
+
+    if args.run_report.is_some() || args.report.is_some() {
+        let mut files = report::digest_output(&args.output)?;
+        if let Some(path) = &failed_output_written {
+            files.push(report::digest_file(path)?);
+        }
+
+        let run_report = report::RunReport {
+            version: report::REPORT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at,
+            finished_at: report::unix_time(),
+            sled_path: store_path.clone(),
+            output: args.output.clone(),
+            options: report::ReportOptions {
+                skip_errors: args.skip_errors,
+                format: args.format.extension().to_string(),
+                compression: args.compress.map(|compression| compression.extension().to_string()),
+                encrypted: args.encrypt_output,
+                split,
+                no_secrets: args.no_secrets,
+            },
+            timings: report::Timings {
+                extraction: extraction_time.as_secs_f64(),
+                extra_trees: extra_trees_time.as_secs_f64(),
+                write: write_time.as_secs_f64(),
+                total: started.elapsed().as_secs_f64(),
+            },
+            total_keys,
+            failed_keys: failed_count,
+            rooms: room_counts.len(),
+            trees: tree_counts.into_iter().collect(),
+            failures_by_tree: failures_by_tree.into_iter().collect(),
+            failures_by_category: count_by_category(&failed_sessions)
+                .into_iter()
+                .map(|(category, count)| (category.label().to_string(), count))
+                .collect(),
+            files,
+        };
+
+        if let Some(report_path) = &args.run_report {
+            let report_json =
+                serde_json::to_vec_pretty(&run_report).context("Failed to serialize run report")?;
+            write_private_file(report_path, &report_json).context("Failed to write run report")?;
+            info!("Run report written to: {:?}", report_path);
+        }
+        if let Some(report_path) = &args.report {
+            let format = summary::ReportFormat::from_path(report_path);
+            let text = summary::render(format, &run_report, &room_counts, &failed_sessions);
+            write_private_file(report_path, text.as_bytes()).context("Failed to write report")?;
+            info!("Report written to: {:?}", report_path);
+        }
+    }
+
+    if interrupted {
+        return Err(ExtractorError::Interrupted(format!(
+            "partial export with {} keys written to {:?}",
+            total_keys, args.output
+        ))
+        .into());
+    }
+
+    Ok(batch::StoreTotals {
+        total_keys,
+        failed_keys: failed_count,
+        rooms: room_counts.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extraction_output_serialization() {
+        let output = ExtractionOutput {
+            version: 1,
+            total_keys: 0,
+            failed_keys: 0,
+            keys_by_room: std::collections::HashMap::new(),
+            keys_per_room: std::collections::BTreeMap::new(),
+            all_keys: Vec::new(),
+            extra_trees: ExtraTreeExport::default(),
+            metadata: None,
+            integrity: None,
+            partial: false,
+        };
+
+        let json = serde_json::to_string(&output).unwrap();
+        let parsed: ExtractionOutput = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.total_keys, 0);
+        assert_eq!(parsed.failed_keys, 0);
+        assert!(!json.contains("outbound_group_sessions"));
+    }
+
+    #[test]
+    fn test_key_without_secret_omits_session_key() {
+        let key = testing::key("!room:example.org", "session");
+
+        let json = serde_json::to_string(&key).unwrap();
+        assert!(!json.contains("session_key"));
+
+        let parsed: ExportedKeyData = serde_json::from_str(&json).unwrap();
+        assert!(parsed.session_key.is_empty());
+    }
+
+    #[test]
+    fn test_fail_threshold() {
+        assert!(check_fail_threshold(90, 10, Some(10.0), None).is_ok());
+        assert!(check_fail_threshold(89, 11, Some(10.0), None).is_err());
+        assert!(check_fail_threshold(0, 0, Some(0.0), Some(0)).is_ok());
+        assert!(check_fail_threshold(1000, 3, None, Some(2)).is_err());
+        assert_eq!(parse_percent("5%"), Ok(5.0));
+        assert!(parse_percent("150").is_err());
+    }
+
+    #[test]
+    fn test_failed_sessions_are_encrypted_with_the_output_passphrase() {
+        let output = FailedSessionsOutput {
+            total_failed: 1,
+            by_category: Default::default(),
+            contains_raw_values: true,
+            store_cipher: None,
+            sessions: vec![FailedSession {
+                index: 0,
+                tree: INBOUND_GROUP_SESSIONS_TREE.to_string(),
+                key_hex: "01".to_string(),
+                category: FailureCategory::Json,
+                error: String::new(),
+                raw_value: Some("cGlja2xl".to_string()),
+            }],
+        };
+        let data = encode_failed_sessions(&output, None, Some("secret")).unwrap();
+        assert!(encryption::is_encrypted(&data));
+
+        let dir = testing::TempDir::new("failed-enc-test");
+        let path = dir.join("failed.json");
+        std::fs::write(&path, &data).unwrap();
+        let missing = read_failed_sessions(&path, None);
+        let read = read_failed_sessions(&path, Some("secret"));
+
+        assert!(missing.is_err());
+        assert_eq!(read.unwrap().sessions[0].raw_value.as_deref(), Some("cGlja2xl"));
+    }
+
+    #[test]
+    fn test_attach_raw_values() {
+        let dir = testing::TempDir::new("raw-test");
+        let db = sled::open(dir.path()).unwrap();
+        db.open_tree(INBOUND_GROUP_SESSIONS_TREE)
+            .unwrap()
+            .insert(b"\x01", b"pickle".as_slice())
+            .unwrap();
+        drop(db);
+
+        let failed = |key_hex: &str| FailedSession {
+            index: 0,
+            tree: INBOUND_GROUP_SESSIONS_TREE.to_string(),
+            key_hex: key_hex.to_string(),
+            category: FailureCategory::Json,
+            error: String::new(),
+            raw_value: None,
+        };
+        let mut failed_sessions = vec![failed("01"), failed("<read error>")];
+        attach_raw_values(&Settings::default(), dir.path(), &mut failed_sessions).unwrap();
+
+        assert_eq!(failed_sessions[0].raw_value.as_deref(), Some("cGlja2xl"));
+        assert!(failed_sessions[1].raw_value.is_none());
+    }
+
+    #[test]
+    fn test_write_private_file_replaces_atomically() {
+        let dir = testing::TempDir::new("write-test");
+        let path = dir.join("keys.json");
+
+        write_private_file(&path, b"old").unwrap();
+        write_private_file(&path, b"new").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!dir.join("keys.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_decoders_keep_their_own_options_and_counts() {
+        let pickle = br#"{"pickle":{},"sender_data":{"SenderVerified":{}}}"#;
+        let first = SessionDecoder::new(None, true);
+        let second = SessionDecoder::new(Some(vec![1; 32]), false);
+
+        assert!(first.sender_data(pickle, None).is_some());
+        assert!(first.sender_data(pickle, None).is_some());
+        assert!(second.sender_data(b"{}", None).is_none());
+
+        assert_eq!(first.with_sender_data.load(Ordering::Relaxed), 2);
+        assert_eq!(second.with_sender_data.load(Ordering::Relaxed), 0);
+        assert!(first.salvage && first.legacy_pickle_key.is_empty());
+        assert!(!second.salvage && second.legacy_pickle_key.len() == 32);
+    }
+}
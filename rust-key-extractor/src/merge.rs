@@ -0,0 +1,164 @@
+//! Merging export files
+//!
+//! Several extraction attempts from the same (or a damaged) store each hold
+//! part of its keys, often with overlap. `merge` combines them into one export
+//! holding every session once. A session in more than one file is taken from
+//! the copy known from the lowest message index, since that one decrypts the
+//! most history; on a tie the earlier file wins.
+
+use std::collections::HashMap;
+
+use matrix_sdk_crypto::olm::InboundGroupSession;
+use tracing::{info, warn};
+
+use crate::settings::Settings;
+use crate::trees::ExtraTreeExport;
+use crate::{build_output, import, metadata, redact, ExportedKeyData, ExtractionOutput};
+
+/// Result of merging exports
+#[derive(Debug, Default)]
+pub struct MergeSummary {
+    /// Keys over all inputs
+    pub input_keys: usize,
+    /// Sessions in the merged export
+    pub sessions: usize,
+    /// Keys dropped because another input had the same or a better copy
+    pub duplicates: usize,
+    /// Sessions taken from a later input because it started at a lower index
+    pub improved: usize,
+}
+
+/// First message index a key can decrypt from
+///
+/// Version 1 exports don't record it, so it is read from the session key. Keys
+/// without one (`--no-secrets`) compare as worst.
+fn first_known_index(key: &ExportedKeyData) -> u32 {
+    key.first_known_index
+        .or_else(|| {
+            let room_key = import::to_room_key(key).ok()?;
+            let session = InboundGroupSession::from_export(&room_key).ok()?;
+            Some(session.first_known_index())
+        })
+        .unwrap_or(u32::MAX)
+}
+
+/// Merge exports into one, keeping the best copy of every session
+///
+/// Additional tree data is taken from the first input that has any. The
+/// metadata is kept when all inputs come from the same store.
+pub fn merge(
+    settings: &Settings,
+    inputs: Vec<ExtractionOutput>,
+) -> (ExtractionOutput, MergeSummary) {
+    let mut summary = MergeSummary::default();
+    let group_by_room = inputs.iter().all(|input| input.keys_per_room.is_empty());
+    let version = inputs.iter().map(|input| input.version).max().unwrap_or(1);
+    let metadata = common_metadata(&inputs);
+
+    let mut keys: Vec<(ExportedKeyData, u32)> = Vec::new();
+    let mut positions: HashMap<(String, String), usize> = HashMap::new();
+    let mut extra_trees = ExtraTreeExport::default();
+
+    for (file, input) in inputs.into_iter().enumerate() {
+        summary.input_keys += input.all_keys.len();
+
+        for key in input.all_keys {
+            let index = first_known_index(&key);
+            let id = (key.room_id.clone(), key.session_id.clone());
+
+            match positions.get(&id) {
+                Some(&position) => {
+                    summary.duplicates += 1;
+                    if index < keys[position].1 {
+                        info!(
+                            "Input {} has session {} from index {} instead of {}",
+                            file + 1,
+                            redact::id(settings, &key.session_id),
+                            index,
+                            keys[position].1
+                        );
+                        summary.improved += 1;
+                        keys[position] = (key, index);
+                    }
+                }
+                None => {
+                    positions.insert(id, keys.len());
+                    keys.push((key, index));
+                }
+            }
+        }
+
+        if !input.extra_trees.is_empty() {
+            if extra_trees.is_empty() {
+                extra_trees = input.extra_trees;
+            } else {
+                warn!(
+                    "Input {} also has additional tree data - keeping that of an earlier input",
+                    file + 1
+                );
+            }
+        }
+    }
+
+    summary.sessions = keys.len();
+    let keys = keys.into_iter().map(|(key, _)| key).collect();
+
+    // Failures of the individual runs don't carry over: a session that failed
+    // in one attempt may well be in another
+    let mut output = build_output(keys, 0, group_by_room);
+    output.extra_trees = extra_trees;
+    metadata::set_version(&mut output, version, metadata);
+    (output, summary)
+}
+
+/// The most recent metadata, if every input has metadata of the same store
+fn common_metadata(inputs: &[ExtractionOutput]) -> Option<metadata::ExportMetadata> {
+    let mut all = inputs.iter().map(|input| input.metadata.as_ref());
+    let first = all.next()??;
+    let mut latest = first;
+    for metadata in all {
+        let metadata = metadata?;
+        if metadata.source_fingerprint != first.source_fingerprint {
+            warn!("Inputs come from different stores - the merged export has no metadata");
+            return None;
+        }
+        if metadata.extracted_at > latest.extracted_at {
+            latest = metadata;
+        }
+    }
+    Some(latest.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(session_id: &str, first_known_index: u32) -> ExportedKeyData {
+        let mut key = crate::testing::key("!a:x.org", session_id);
+        key.first_known_index = Some(first_known_index);
+        key
+    }
+
+    #[test]
+    fn test_merge_prefers_lowest_first_known_index() {
+        let first = build_output(vec![key("1", 5), key("2", 0)], 3, true);
+        let second = build_output(vec![key("1", 2), key("2", 4), key("3", 0)], 0, true);
+
+        let (merged, summary) = merge(&Settings::default(), vec![first, second]);
+
+        assert_eq!(summary.input_keys, 5);
+        assert_eq!(summary.sessions, 3);
+        assert_eq!(summary.duplicates, 2);
+        assert_eq!(summary.improved, 1);
+        let indexes: Vec<_> = merged
+            .all_keys
+            .iter()
+            .map(|key| (key.session_id.as_str(), key.first_known_index))
+            .collect();
+        assert_eq!(
+            indexes,
+            vec![("1", Some(2)), ("2", Some(0)), ("3", Some(0))]
+        );
+        assert_eq!(merged.failed_keys, 0);
+    }
+}
@@ -0,0 +1,187 @@
+//! Export format versions and the metadata of version 2 exports
+//!
+//! Version 2 exports record where their keys came from: a fingerprint of the
+//! source store, the account's user and device ID, when the keys were extracted
+//! and by which version of this tool. Keys may carry additional fields in
+//! `extra`. Version 1 exports have none of this; they are still read, and
+//! `--format-version 1` writes them for consumers that predate version 2.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::{load_store_cipher, redact, schema, trees, ExtractionOutput};
+
+/// Format version written by default
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Export format versions this build reads
+pub const SUPPORTED_VERSIONS: &[u32] = &[1, 2];
+
+/// Where and when an export was made
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportMetadata {
+    /// SHA-256 over the store's encrypted store cipher and the account's user and
+    /// device ID (hex), identifying the store without revealing anything about it
+    pub source_fingerprint: String,
+    /// User ID of the account in the store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Device ID of the account in the store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Public identity keys of the account (`ed25519`, `curve25519`), base64
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub identity_keys: BTreeMap<String, String>,
+    /// When the keys were extracted (seconds since the Unix epoch)
+    pub extracted_at: u64,
+    /// Version of sled-key-extractor that wrote the export
+    pub tool_version: String,
+}
+
+/// Read the metadata of the sled crypto store at `path`
+///
+/// A store without a readable account still gets a fingerprint, just without
+/// user and device ID.
+pub fn collect(
+    settings: &Settings,
+    path: &Path,
+    passphrase: Option<&str>,
+    extracted_at: u64,
+) -> Result<ExportMetadata> {
+    let db = settings
+        .sled_config()
+        .path(path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+    let exported_cipher = schema::find_store_cipher(settings, &db)?;
+    let store_cipher = load_store_cipher(settings, &db, passphrase.unwrap_or(""))?;
+
+    let (user_id, device_id, identity_keys) =
+        match trees::extract_account(settings, &db, store_cipher.as_ref(), true) {
+            Ok((Some(account), _, _)) => (
+                Some(account.user_id),
+                Some(account.device_id),
+                identity_keys(&account.pickle),
+            ),
+            Ok((None, _, _)) => (None, None, BTreeMap::new()),
+            Err(e) => {
+                warn!(
+                    "Failed to read the account for the export metadata: {:#}",
+                    e
+                );
+                (None, None, BTreeMap::new())
+            }
+        };
+
+    let mut hasher = Sha256::new();
+    hasher.update(exported_cipher.as_deref().unwrap_or_default());
+    for id in [&user_id, &device_id] {
+        hasher.update([0]);
+        hasher.update(id.as_deref().unwrap_or_default());
+    }
+
+    Ok(ExportMetadata {
+        source_fingerprint: hex::encode(hasher.finalize()),
+        user_id,
+        device_id,
+        identity_keys,
+        extracted_at,
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+    })
+}
+
+/// Public identity keys of a pickled account
+///
+/// Only the private keys are pickled, so the account is restored to derive them.
+fn identity_keys(account_pickle: &serde_json::Value) -> BTreeMap<String, String> {
+    let pickle = account_pickle
+        .get("pickle")
+        .cloned()
+        .and_then(|pickle| serde_json::from_value::<vodozemac::olm::AccountPickle>(pickle).ok());
+    let Some(pickle) = pickle else {
+        warn!("Failed to read the identity keys of the account");
+        return BTreeMap::new();
+    };
+
+    let keys = vodozemac::olm::Account::from_pickle(pickle).identity_keys();
+    BTreeMap::from([
+        ("ed25519".to_string(), keys.ed25519.to_base64()),
+        ("curve25519".to_string(), keys.curve25519.to_base64()),
+    ])
+}
+
+/// Set the format version of an export before it is written
+///
+/// Version 1 has no metadata; additional key fields are left out while
+/// extracting, before the keys are hashed.
+pub fn set_version(output: &mut ExtractionOutput, version: u32, metadata: Option<ExportMetadata>) {
+    output.version = version;
+    output.metadata = metadata.filter(|_| version >= 2);
+}
+
+/// Check the format version of an export that was read and log its metadata
+pub fn check_version(settings: &Settings, output: &ExtractionOutput) {
+    match output.version {
+        1 => debug!("Export has format version 1, without metadata"),
+        2 => match &output.metadata {
+            Some(metadata) => info!(
+                "Export of {} (device {}) made at {} by v{}, source {}",
+                display_id(settings, &metadata.user_id),
+                display_id(settings, &metadata.device_id),
+                metadata.extracted_at,
+                metadata.tool_version,
+                &metadata.source_fingerprint[..metadata.source_fingerprint.len().min(16)]
+            ),
+            None => warn!("Export has format version 2 but no metadata"),
+        },
+        version => warn!(
+            "Export has version {}, this build reads {:?} - continuing anyway",
+            version, SUPPORTED_VERSIONS
+        ),
+    }
+}
+
+fn display_id(settings: &Settings, id: &Option<String>) -> String {
+    id.as_deref().map_or_else(
+        || "unknown".to_string(),
+        |id| redact::id(settings, id).to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_output;
+
+    #[test]
+    fn test_version_1_drops_metadata() {
+        let mut output = build_output(Vec::new(), 0, true);
+        let metadata = ExportMetadata {
+            source_fingerprint: "ab".repeat(32),
+            user_id: Some("@bot:x.org".to_string()),
+            device_id: Some("DEVICE".to_string()),
+            identity_keys: BTreeMap::from([("ed25519".to_string(), "key".to_string())]),
+            extracted_at: 1,
+            tool_version: "0.1.0".to_string(),
+        };
+
+        set_version(&mut output, 2, Some(metadata.clone()));
+        let json = serde_json::to_string(&output).unwrap();
+        let parsed: ExtractionOutput = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.version, 2);
+        assert_eq!(parsed.metadata, Some(metadata.clone()));
+
+        set_version(&mut output, 1, Some(metadata));
+        let json = serde_json::to_string(&output).unwrap();
+        assert!(!json.contains("metadata"));
+        assert!(json.contains("\"version\":1"));
+    }
+}
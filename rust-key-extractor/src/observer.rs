@@ -0,0 +1,79 @@
+//! Progress and event callbacks
+//!
+//! An extraction reports what it does to a [`MigrationObserver`]: when it
+//! starts reading a tree, every key it extracts, every entry it has to skip
+//! and the totals at the end. The command line tool draws its progress bar
+//! from these events (see [`ProgressObserver`](crate::progress::ProgressObserver));
+//! applications embedding the extractor implement the trait to drive their own
+//! progress display or metrics and pass it to
+//! [`extract_keys`](crate::extract_keys).
+//!
+//! Events arrive in sled order from the task running the extraction. Entries
+//! skipped because an earlier, interrupted run already extracted them are not
+//! reported again, so `index` can jump forward.
+
+use crate::FailureCategory;
+
+/// Receives the events of an extraction
+///
+/// Every method does nothing by default, so implementations only need the
+/// events they care about.
+pub trait MigrationObserver {
+    /// Extraction of `tree` starts at entry `position` of `total`
+    ///
+    /// `position` is above 0 when a run resumes from a checkpoint.
+    fn on_tree_start(&mut self, tree: &str, position: usize, total: usize) {
+        let _ = (tree, position, total);
+    }
+
+    /// The key of a session in `room_id` was extracted from entry `index`
+    fn on_key(&mut self, index: usize, room_id: &str, session_id: &str) {
+        let _ = (index, room_id, session_id);
+    }
+
+    /// Entry `index` couldn't be extracted and was skipped
+    fn on_failure(&mut self, index: usize, category: FailureCategory, error: &str) {
+        let _ = (index, category, error);
+    }
+
+    /// The tree is done: `extracted` keys and `failed` skipped entries in this run
+    fn on_complete(&mut self, extracted: usize, failed: usize) {
+        let _ = (extracted, failed);
+    }
+}
+
+/// Ignores every event
+impl MigrationObserver for () {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        keys: usize,
+        failures: usize,
+    }
+
+    impl MigrationObserver for Counter {
+        fn on_key(&mut self, _index: usize, _room_id: &str, _session_id: &str) {
+            self.keys += 1;
+        }
+
+        fn on_failure(&mut self, _index: usize, _category: FailureCategory, _error: &str) {
+            self.failures += 1;
+        }
+    }
+
+    #[test]
+    fn test_unimplemented_events_are_ignored() {
+        let mut counter = Counter::default();
+        let observer: &mut dyn MigrationObserver = &mut counter;
+        observer.on_tree_start("inbound_group_sessions", 0, 2);
+        observer.on_key(0, "!room:example.org", "session");
+        observer.on_failure(1, FailureCategory::Json, "not JSON");
+        observer.on_complete(1, 1);
+
+        assert_eq!((counter.keys, counter.failures), (1, 1));
+    }
+}
@@ -0,0 +1,645 @@
+//! Store passphrase handling
+//!
+//! The passphrase only unlocks the store cipher, which is stored encrypted in
+//! the default tree. Importing it is fast compared to reading any session, so a
+//! passphrase can be checked without starting an extraction - and a list of
+//! candidates can be tried one by one when it isn't known which one a bot used.
+//!
+//! To keep the passphrase out of shell history and `ps` output it can be typed
+//! into a hidden prompt instead. On a terminal the prompt is also offered when
+//! the passphrase given on the command line doesn't work. Scripts can pass it
+//! in the `MATRIX_SLED_PASSPHRASE` environment variable or on stdin, and
+//! operators can keep it in the OS keyring (see [`crate::keychain`]).
+//!
+//! The other secrets a command takes - the passphrases of the target store,
+//! of encrypted exports read and written, and of integrity digests - can be
+//! given the same ways: as an option, in a file (`-` for stdin), at a prompt,
+//! in a `MATRIX_MIGRATE_*` variable (see [`crate::env`]) and, for the target
+//! store, from the keyring.
+//!
+//! Resolved passphrases are held in [`Zeroizing`] buffers, so they are wiped
+//! from memory once the command is done with them.
+
+use std::io::{BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::{kdf, keychain, schema};
+
+/// Environment variable read when `--passphrase` is not given
+pub const PASSPHRASE_ENV: &str = "MATRIX_SLED_PASSPHRASE";
+
+/// Number of prompts before giving up when the entered passphrase is wrong
+const PROMPT_ATTEMPTS: usize = 3;
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Set once a passphrase was read from stdin, which can only supply one
+static STDIN_USED: AtomicBool = AtomicBool::new(false);
+
+/// Never fall back to prompting for a passphrase for the rest of the run
+///
+/// For `serve` and concurrent batch jobs, where a wrong passphrase would
+/// otherwise block on a terminal nobody watches or several jobs share.
+pub fn set_non_interactive() {
+    NON_INTERACTIVE.store(true, Ordering::Relaxed);
+}
+
+static EXPLICIT_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Only use passphrases passed in explicitly for the rest of the run
+///
+/// For the C API: the terminal and environment belong to the host process, so
+/// neither a prompt nor `$MATRIX_SLED_PASSPHRASE` may stand in for a
+/// passphrase the caller left out.
+pub fn set_explicit_only() {
+    set_non_interactive();
+    EXPLICIT_ONLY.store(true, Ordering::Relaxed);
+}
+
+/// The passphrase in `$MATRIX_SLED_PASSPHRASE`, unless only explicit ones count
+fn from_environment() -> Option<String> {
+    if EXPLICIT_ONLY.load(Ordering::Relaxed) {
+        return None;
+    }
+    std::env::var(PASSPHRASE_ENV).ok()
+}
+
+/// Options selecting the passphrase of a sled store
+#[derive(Args, Debug, Clone, Default)]
+pub struct PassphraseArgs {
+    /// Passphrase of the sled store, if it is encrypted (default: $MATRIX_SLED_PASSPHRASE,
+    /// or the empty string)
+    #[arg(short, long)]
+    pub passphrase: Option<String>,
+
+    /// File with candidate passphrases, one per line (an empty line is the empty
+    /// passphrase), tried in order against the store cipher
+    #[arg(long, value_name = "FILE")]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Ask for the passphrase on the terminal without echoing it
+    #[arg(long, default_value = "false", conflicts_with = "passphrase")]
+    pub passphrase_prompt: bool,
+
+    /// Read the passphrase from the first line of stdin
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["passphrase", "passphrase_prompt"]
+    )]
+    pub passphrase_stdin: bool,
+
+    /// Use the passphrase saved in the OS keyring for this store, if there is one
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["passphrase", "passphrase_prompt", "passphrase_stdin"]
+    )]
+    pub keyring: bool,
+
+    /// Save the working passphrase in the OS keyring for later runs
+    #[arg(long, default_value = "false")]
+    pub save_to_keyring: bool,
+}
+
+impl PassphraseArgs {
+    /// Determine the passphrase to open the store at `sled_path` with
+    ///
+    /// Candidates are only tried when a passphrase file was given. Otherwise, on
+    /// a terminal, the user is prompted if the given passphrase doesn't unlock
+    /// the store. The store is closed again before returning, so the caller can
+    /// open it itself.
+    ///
+    /// `store_path` is the path the user gave, which keyring entries are keyed
+    /// by; `sled_path` may point at a copy of it (see `--copy-first`).
+    pub fn resolve(
+        &self,
+        settings: &Settings,
+        sled_path: &Path,
+        store_path: &Path,
+    ) -> Result<Option<Zeroizing<String>>> {
+        let passphrase = self.find(settings, sled_path, store_path)?;
+
+        if self.save_to_keyring {
+            let value = passphrase.as_deref().map_or("", String::as_str);
+            if let Some(db) = open_existing(settings, sled_path)? {
+                if let CipherCheck::Invalid(_) = check_passphrase(settings, &db, value)? {
+                    anyhow::bail!("Not saving the passphrase - it does not unlock the store cipher");
+                }
+            }
+            keychain::save_passphrase(store_path, value)?;
+            info!("Passphrase saved to the keyring");
+        }
+
+        Ok(passphrase)
+    }
+
+    /// Pick the passphrase from the configured sources, see [`Self::resolve`]
+    fn find(
+        &self,
+        settings: &Settings,
+        sled_path: &Path,
+        store_path: &Path,
+    ) -> Result<Option<Zeroizing<String>>> {
+        let saved = if self.keyring {
+            keychain::load_passphrase(store_path)?.map(Zeroizing::new)
+        } else {
+            None
+        };
+        if self.keyring && saved.is_none() {
+            warn!("No passphrase saved in the keyring for {:?}", store_path);
+        }
+
+        let passphrase = if self.passphrase_prompt {
+            Some(prompt("Store passphrase")?)
+        } else if self.passphrase_stdin {
+            Some(read_stdin()?)
+        } else if saved.is_some() {
+            saved
+        } else {
+            // Read here rather than via clap, so the variable doesn't conflict with the other sources
+            self.passphrase
+                .clone()
+                .or_else(from_environment)
+                .map(Zeroizing::new)
+        };
+
+        if let Some(file) = &self.passphrase_file {
+            let mut candidates: Zeroizing<Vec<String>> =
+                Zeroizing::new(passphrase.iter().map(|p| p.to_string()).collect());
+            candidates.extend(read_candidates(file)?.drain(..));
+
+            let db = open_existing(settings, sled_path)?
+                .with_context(|| format!("{:?} is not a sled database", sled_path))?;
+            let index = find_passphrase(settings, &db, &candidates)?;
+            return Ok(Some(Zeroizing::new(candidates.swap_remove(index))));
+        }
+
+        if self.passphrase_prompt
+            || NON_INTERACTIVE.load(Ordering::Relaxed)
+            || !std::io::stdin().is_terminal()
+        {
+            return Ok(passphrase);
+        }
+        let Some(db) = open_existing(settings, sled_path)? else {
+            return Ok(passphrase);
+        };
+        if !matches!(
+            check_passphrase(settings, &db, passphrase.as_deref().map_or("", String::as_str))?,
+            CipherCheck::Invalid(_)
+        ) {
+            return Ok(passphrase);
+        }
+
+        warn!("The passphrase does not unlock the store cipher");
+        for attempt in 1..=PROMPT_ATTEMPTS {
+            let entered = prompt("Store passphrase")?;
+            if !matches!(check_passphrase(settings, &db, &entered)?, CipherCheck::Invalid(_)) {
+                return Ok(Some(entered));
+            }
+            warn!("Wrong passphrase ({}/{})", attempt, PROMPT_ATTEMPTS);
+        }
+        Err(ExtractorError::WrongPassphrase("no working passphrase entered".to_string()).into())
+    }
+}
+
+/// Where one of the secrets other than the store passphrase comes from
+///
+/// At most one source is set; clap rejects the others.
+struct SecretSource<'a> {
+    /// What the secret is, for prompts and errors
+    name: &'a str,
+    value: &'a Option<String>,
+    file: &'a Option<PathBuf>,
+    prompt: bool,
+    /// Store whose keyring entry holds the secret
+    keyring: Option<&'a Path>,
+}
+
+impl SecretSource<'_> {
+    fn resolve(&self) -> Result<Option<Zeroizing<String>>> {
+        if let Some(value) = self.value {
+            return Ok(Some(Zeroizing::new(value.clone())));
+        }
+        if let Some(file) = self.file {
+            return read_secret_file(file, self.name).map(Some);
+        }
+        if self.prompt {
+            return prompt(&capitalize(self.name)).map(Some);
+        }
+        if let Some(path) = self.keyring {
+            let saved = keychain::load_passphrase(path)?;
+            if saved.is_none() {
+                warn!("No {} saved in the keyring for {:?}", self.name, path);
+            }
+            return Ok(saved.map(Zeroizing::new));
+        }
+        Ok(None)
+    }
+}
+
+/// Options selecting the passphrase of the store a command writes to or compares with
+#[derive(Args, Debug, Clone, Default)]
+#[group(multiple = false)]
+pub struct TargetPassphraseArgs {
+    /// Passphrase of the target store (a new store is encrypted with it)
+    #[arg(long)]
+    pub target_passphrase: Option<String>,
+
+    /// Read the target store passphrase from the first line of FILE (`-` for stdin)
+    #[arg(long, value_name = "FILE")]
+    pub target_passphrase_file: Option<PathBuf>,
+
+    /// Ask for the target store passphrase on the terminal without echoing it
+    #[arg(long, default_value = "false")]
+    pub target_passphrase_prompt: bool,
+
+    /// Use the passphrase saved in the OS keyring for the target store
+    #[arg(long, default_value = "false")]
+    pub target_keyring: bool,
+}
+
+impl TargetPassphraseArgs {
+    /// Determine the passphrase of the target store at `target`
+    pub fn resolve(&self, target: &Path) -> Result<Option<Zeroizing<String>>> {
+        SecretSource {
+            name: "target store passphrase",
+            value: &self.target_passphrase,
+            file: &self.target_passphrase_file,
+            prompt: self.target_passphrase_prompt,
+            keyring: self.target_keyring.then_some(target),
+        }
+        .resolve()
+    }
+}
+
+/// Options selecting the passphrase encrypted exports are read with
+#[derive(Args, Debug, Clone, Default)]
+#[group(multiple = false)]
+pub struct InputPassphraseArgs {
+    /// Passphrase the input was encrypted with (see `extract --encrypt-output`)
+    #[arg(long)]
+    pub input_passphrase: Option<String>,
+
+    /// Read the input passphrase from the first line of FILE (`-` for stdin)
+    #[arg(long, value_name = "FILE")]
+    pub input_passphrase_file: Option<PathBuf>,
+
+    /// Ask for the input passphrase on the terminal without echoing it
+    #[arg(long, default_value = "false")]
+    pub input_passphrase_prompt: bool,
+}
+
+impl InputPassphraseArgs {
+    /// Determine the passphrase of encrypted inputs, if one was given
+    pub fn resolve(&self) -> Result<Option<Zeroizing<String>>> {
+        SecretSource {
+            name: "input passphrase",
+            value: &self.input_passphrase,
+            file: &self.input_passphrase_file,
+            prompt: self.input_passphrase_prompt,
+            keyring: None,
+        }
+        .resolve()
+    }
+}
+
+/// Options selecting the passphrase for `--encrypt-output`
+#[derive(Args, Debug, Clone, Default)]
+#[group(multiple = false)]
+pub struct OutputPassphraseArgs {
+    /// Passphrase for --encrypt-output
+    #[arg(long)]
+    pub output_passphrase: Option<String>,
+
+    /// Read the passphrase for --encrypt-output from the first line of FILE (`-` for stdin)
+    #[arg(long, value_name = "FILE")]
+    pub output_passphrase_file: Option<PathBuf>,
+
+    /// Ask for the passphrase for --encrypt-output on the terminal without echoing it
+    #[arg(long, default_value = "false")]
+    pub output_passphrase_prompt: bool,
+}
+
+impl OutputPassphraseArgs {
+    /// Determine the passphrase to encrypt the output with, if one was given
+    pub fn resolve(&self) -> Result<Option<Zeroizing<String>>> {
+        SecretSource {
+            name: "output passphrase",
+            value: &self.output_passphrase,
+            file: &self.output_passphrase_file,
+            prompt: self.output_passphrase_prompt,
+            keyring: None,
+        }
+        .resolve()
+    }
+
+    /// The same options with the passphrase resolved, so that resolving them
+    /// again neither prompts nor reads a file
+    pub fn resolved(&self) -> Result<Self> {
+        Ok(Self {
+            output_passphrase: self.resolve()?.map(|passphrase| passphrase.to_string()),
+            ..Self::default()
+        })
+    }
+}
+
+/// Options selecting the passphrase of the export given to `--since-export`
+#[derive(Args, Debug, Clone, Default)]
+#[group(multiple = false)]
+pub struct SinceExportPassphraseArgs {
+    /// Passphrase the --since-export file was encrypted with (default: the passphrase for
+    /// --encrypt-output)
+    #[arg(long, requires = "since_export")]
+    pub since_export_passphrase: Option<String>,
+
+    /// Read the --since-export passphrase from the first line of FILE (`-` for stdin)
+    #[arg(long, value_name = "FILE", requires = "since_export")]
+    pub since_export_passphrase_file: Option<PathBuf>,
+
+    /// Ask for the --since-export passphrase on the terminal without echoing it
+    #[arg(long, default_value = "false", requires = "since_export")]
+    pub since_export_passphrase_prompt: bool,
+}
+
+impl SinceExportPassphraseArgs {
+    /// Determine the passphrase of the earlier export, if one was given
+    pub fn resolve(&self) -> Result<Option<Zeroizing<String>>> {
+        SecretSource {
+            name: "since-export passphrase",
+            value: &self.since_export_passphrase,
+            file: &self.since_export_passphrase_file,
+            prompt: self.since_export_passphrase_prompt,
+            keyring: None,
+        }
+        .resolve()
+    }
+
+    /// The same options with the passphrase resolved, see
+    /// [`OutputPassphraseArgs::resolved`]
+    pub fn resolved(&self) -> Result<Self> {
+        Ok(Self {
+            since_export_passphrase: self.resolve()?.map(|passphrase| passphrase.to_string()),
+            ..Self::default()
+        })
+    }
+}
+
+/// Options selecting the key of export integrity digests
+#[derive(Args, Debug, Clone, Default)]
+#[group(multiple = false)]
+pub struct IntegrityPassphraseArgs {
+    /// Key the integrity digest of written exports with PASSPHRASE (HMAC-SHA256), and check
+    /// the digest of exports read with it
+    #[arg(long, global = true, value_name = "PASSPHRASE")]
+    pub integrity_passphrase: Option<String>,
+
+    /// Read the integrity passphrase from the first line of FILE (`-` for stdin)
+    #[arg(long, global = true, value_name = "FILE")]
+    pub integrity_passphrase_file: Option<PathBuf>,
+
+    /// Ask for the integrity passphrase on the terminal without echoing it
+    #[arg(long, global = true, default_value = "false")]
+    pub integrity_passphrase_prompt: bool,
+}
+
+impl IntegrityPassphraseArgs {
+    /// Determine the integrity passphrase, if one was given
+    pub fn resolve(&self) -> Result<Option<Zeroizing<String>>> {
+        SecretSource {
+            name: "integrity passphrase",
+            value: &self.integrity_passphrase,
+            file: &self.integrity_passphrase_file,
+            prompt: self.integrity_passphrase_prompt,
+            keyring: None,
+        }
+        .resolve()
+    }
+}
+
+/// `name` with its first letter in upper case, for a prompt
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    chars.next().map_or_else(String::new, |first| {
+        first.to_uppercase().chain(chars).collect()
+    })
+}
+
+/// Read a secret from the first line of `path`, or of stdin for `-`
+fn read_secret_file(path: &Path, name: &str) -> Result<Zeroizing<String>> {
+    if path == Path::new("-") {
+        return read_stdin();
+    }
+    let contents = Zeroizing::new(
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {} file {:?}", name, path))?,
+    );
+    read_stdin_line(contents.as_bytes())
+}
+
+/// Read a passphrase from the first line of stdin
+///
+/// Only one passphrase can come from stdin; asking for a second one is an
+/// error rather than a read of whatever line follows.
+fn read_stdin() -> Result<Zeroizing<String>> {
+    if STDIN_USED.swap(true, Ordering::Relaxed) {
+        anyhow::bail!("Only one passphrase can be read from stdin");
+    }
+    read_stdin_line(std::io::stdin().lock())
+}
+
+/// Read the passphrase from the first line of `input`, without the line break
+fn read_stdin_line(mut input: impl BufRead) -> Result<Zeroizing<String>> {
+    let mut line = Zeroizing::new(String::new());
+    input
+        .read_line(&mut line)
+        .context("Failed to read passphrase from stdin")?;
+
+    let trimmed = line.strip_suffix('\n').unwrap_or(&line);
+    Ok(Zeroizing::new(trimmed.strip_suffix('\r').unwrap_or(trimmed).to_string()))
+}
+
+/// Read a passphrase from the terminal without echoing it
+fn prompt(label: &str) -> Result<Zeroizing<String>> {
+    rpassword::prompt_password(format!("{}: ", label))
+        .map(Zeroizing::new)
+        .context("Failed to read passphrase")
+}
+
+/// Open the sled database at `path`, unless there is none
+///
+/// sled creates a database where there is none, which must not happen just
+/// because a passphrase was checked.
+fn open_existing(settings: &Settings, path: &Path) -> Result<Option<sled::Db>> {
+    if !path.join("db").exists() {
+        return Ok(None);
+    }
+    let db = settings
+        .sled_config()
+        .path(path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+    Ok(Some(db))
+}
+
+/// Read candidate passphrases from a file, one per line
+fn read_candidates(path: &Path) -> Result<Zeroizing<Vec<String>>> {
+    let contents = Zeroizing::new(
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read passphrase file {:?}", path))?,
+    );
+
+    let mut candidates: Zeroizing<Vec<String>> = Zeroizing::default();
+    for line in contents.lines() {
+        let candidate = line.strip_suffix('\r').unwrap_or(line);
+        if !candidates.iter().any(|known| known == candidate) {
+            candidates.push(candidate.to_string());
+        }
+    }
+    Ok(candidates)
+}
+
+/// Try `candidates` in order and return the index of the first one that works
+fn find_passphrase(settings: &Settings, db: &sled::Db, candidates: &[String]) -> Result<usize> {
+    if candidates.is_empty() {
+        anyhow::bail!("No candidate passphrases given");
+    }
+
+    for (index, candidate) in candidates.iter().enumerate() {
+        match check_passphrase(settings, db, candidate)? {
+            CipherCheck::Unencrypted => {
+                info!("Store is not encrypted - using the first candidate");
+                return Ok(0);
+            }
+            CipherCheck::Valid => {
+                info!(
+                    "Candidate {} of {} unlocks the store cipher",
+                    index + 1,
+                    candidates.len()
+                );
+                return Ok(index);
+            }
+            CipherCheck::Invalid(_) => {}
+        }
+    }
+
+    Err(ExtractorError::WrongPassphrase(format!(
+        "none of the {} candidate passphrases unlocks the store cipher",
+        candidates.len()
+    ))
+    .into())
+}
+
+/// Result of checking a passphrase against a store
+#[derive(Debug)]
+pub enum CipherCheck {
+    /// The store has no cipher, so any passphrase works
+    Unencrypted,
+    /// The passphrase unlocks the store cipher
+    Valid,
+    /// The store cipher could not be imported with the passphrase
+    Invalid(String),
+}
+
+/// Check whether `passphrase` unlocks the store cipher of `db`
+pub fn check_passphrase(
+    settings: &Settings,
+    db: &sled::Db,
+    passphrase: &str,
+) -> Result<CipherCheck> {
+    let Some(exported) = schema::find_store_cipher(settings, db)? else {
+        return Ok(CipherCheck::Unencrypted);
+    };
+
+    Ok(match kdf::import_cipher(&settings.kdf, passphrase, &exported) {
+        Ok(_) => CipherCheck::Valid,
+        Err(e) => CipherCheck::Invalid(e.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_store_without_cipher_is_unencrypted() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        assert!(matches!(
+            check_passphrase(&Settings::default(), &db, "anything").unwrap(),
+            CipherCheck::Unencrypted
+        ));
+    }
+
+    #[test]
+    fn test_read_stdin_line_strips_line_break() {
+        let input = std::io::Cursor::new("secret with spaces \r\nignored\n");
+
+        assert_eq!(read_stdin_line(input).unwrap().as_str(), "secret with spaces ");
+    }
+
+    #[test]
+    fn test_secret_is_read_from_first_line_of_file() {
+        let dir = TempDir::new("target-passphrase");
+        let path = dir.join("passphrase");
+        std::fs::write(&path, "target secret\r\nignored\n").unwrap();
+
+        let args = TargetPassphraseArgs {
+            target_passphrase_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let passphrase = args.resolve(Path::new("/nonexistent/target"));
+
+        assert_eq!(passphrase.unwrap().unwrap().as_str(), "target secret");
+        assert!(InputPassphraseArgs::default().resolve().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_secret_sources_exclude_each_other() {
+        use clap::CommandFactory;
+
+        let parse = |args: &[&str]| {
+            crate::Cli::command().try_get_matches_from(
+                ["sled-key-extractor", "import", "-i", "keys.json", "-t", "target"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+
+        assert!(parse(&["--target-passphrase-file", "-"]).is_ok());
+        assert!(parse(&["--target-passphrase", "x", "--target-passphrase-prompt"]).is_err());
+        assert!(parse(&["--input-passphrase", "x", "--input-passphrase-file", "f"]).is_err());
+
+        let extract = |args: &[&str]| {
+            let base = ["sled-key-extractor", "extract", "--sled-path", "store", "--output", "out"];
+            crate::Cli::command()
+                .try_get_matches_from(base.iter().chain(&["--encrypt-output"]).chain(args))
+        };
+        assert!(extract(&[]).is_err());
+        assert!(extract(&["--output-passphrase-prompt"]).is_ok());
+        let since = ["--output-passphrase-prompt", "--since-export-passphrase", "old"];
+        assert!(extract(&since).is_err());
+        assert!(extract(&[&since[..], &["--since-export", "keys.json"]].concat()).is_ok());
+    }
+
+    #[test]
+    fn test_read_candidates_keeps_empty_line() {
+        let dir = TempDir::new("passphrases");
+        let path = dir.join("passphrases");
+        std::fs::write(&path, "first\r\n\nsecond\nfirst\n").unwrap();
+
+        let candidates = read_candidates(&path).unwrap();
+
+        assert_eq!(*candidates, vec!["first", "", "second"]);
+    }
+}
@@ -0,0 +1,167 @@
+//! Progress reporting for long-running loops
+//!
+//! On a terminal a progress bar shows the position against the expected total,
+//! throughput and ETA. When stderr is not a TTY (CI, `docker logs`, redirected
+//! output) the same numbers are logged as a line every few seconds instead, so
+//! logs don't fill up with redraw sequences.
+
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tracing::info;
+
+use crate::observer::MigrationObserver;
+use crate::FailureCategory;
+
+/// Interval between progress log lines when not on a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bar layout: position, percentage, rate and ETA
+const BAR_TEMPLATE: &str =
+    "{msg} [{bar:40}] {pos}/{len} ({percent}%) {per_sec} ETA {eta}";
+
+static BARS_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Allow or forbid progress bars for the rest of the run
+///
+/// Without bars, progress is logged as on a non-terminal.
+pub fn set_bars_enabled(enabled: bool) {
+    BARS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Progress of a loop over a known number of entries
+pub struct Progress {
+    label: &'static str,
+    bar: Option<ProgressBar>,
+    position: u64,
+    total: u64,
+    /// Position and time the current run started from, for the rate
+    start: (u64, Instant),
+    last_log: Instant,
+}
+
+impl Progress {
+    /// Start reporting progress towards `total` entries
+    ///
+    /// `position` is where the loop starts, e.g. after a resumed checkpoint.
+    pub fn new(label: &'static str, position: u64, total: u64) -> Self {
+        let show_bar = BARS_ENABLED.load(Ordering::Relaxed) && std::io::stderr().is_terminal();
+        let bar = show_bar.then(|| {
+            let bar = ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::stderr());
+            bar.set_style(
+                ProgressStyle::with_template(BAR_TEMPLATE)
+                    .expect("valid progress template")
+                    .progress_chars("=> "),
+            );
+            bar.set_message(label);
+            bar.set_position(position);
+            bar.reset_eta();
+            bar
+        });
+
+        let now = Instant::now();
+        Self {
+            label,
+            bar,
+            position,
+            total,
+            start: (position, now),
+            last_log: now,
+        }
+    }
+
+    /// Advance by `delta` entries
+    pub fn inc(&mut self, delta: u64) {
+        self.position += delta;
+
+        match &self.bar {
+            Some(bar) => bar.inc(delta),
+            None if self.last_log.elapsed() >= LOG_INTERVAL => {
+                self.last_log = Instant::now();
+                self.log();
+            }
+            None => {}
+        }
+    }
+
+    /// Remove the bar, or log the final position
+    pub fn finish(self) {
+        match &self.bar {
+            Some(bar) => bar.finish_and_clear(),
+            None => self.log(),
+        }
+    }
+
+    /// Log a line with the current position, rate and ETA
+    fn log(&self) {
+        let (start_position, started) = self.start;
+        let elapsed = started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (self.position - start_position) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let percent = if self.total > 0 {
+            self.position as f64 * 100.0 / self.total as f64
+        } else {
+            100.0
+        };
+        let eta = match self.total.checked_sub(self.position) {
+            Some(remaining) if rate > 0.0 => format!("{}s", (remaining as f64 / rate).round()),
+            _ => "-".to_string(),
+        };
+
+        info!(
+            "{}: {}/{} ({:.1}%), {:.0}/s, ETA {}",
+            self.label, self.position, self.total, percent, rate, eta
+        );
+    }
+}
+
+/// A progress bar over the entries of an extraction, driven by its events
+pub struct ProgressObserver {
+    label: &'static str,
+    progress: Option<Progress>,
+}
+
+impl ProgressObserver {
+    /// Show progress labeled `label` once a tree starts
+    pub fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            progress: None,
+        }
+    }
+
+    /// Move the bar to just past entry `index`
+    fn reached(&mut self, index: usize) {
+        if let Some(progress) = self.progress.as_mut() {
+            let position = index as u64 + 1;
+            if position > progress.position {
+                progress.inc(position - progress.position);
+            }
+        }
+    }
+}
+
+impl MigrationObserver for ProgressObserver {
+    fn on_tree_start(&mut self, _tree: &str, position: usize, total: usize) {
+        self.progress = Some(Progress::new(self.label, position as u64, total as u64));
+    }
+
+    fn on_key(&mut self, index: usize, _room_id: &str, _session_id: &str) {
+        self.reached(index);
+    }
+
+    fn on_failure(&mut self, index: usize, _category: FailureCategory, _error: &str) {
+        self.reached(index);
+    }
+
+    fn on_complete(&mut self, _extracted: usize, _failed: usize) {
+        if let Some(progress) = self.progress.take() {
+            progress.finish();
+        }
+    }
+}
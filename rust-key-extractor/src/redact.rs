@@ -0,0 +1,71 @@
+//! Redaction of identifiers in log output
+//!
+//! Logs end up in CI output, terminal scrollback and support tickets, so by
+//! default room IDs, session IDs, user IDs and sender keys are not written out
+//! in full: only a short prefix and a truncated SHA-256 of the whole value are
+//! logged. The hash is stable, so the same identifier can still be followed
+//! through a log and matched against an export. `--redact false` restores the
+//! full identifiers for debugging; it is part of the [`Settings`] of a run, so
+//! concurrent jobs can log with and without redaction.
+//!
+//! Session keys never reach the logs either way: `ExportedKeyData` has a
+//! `Debug` implementation that leaves them out.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::settings::Settings;
+
+/// Number of leading characters kept from a redacted identifier
+const PREFIX_CHARS: usize = 4;
+
+/// Number of hash bytes appended to a redacted identifier
+const HASH_BYTES: usize = 4;
+
+/// An identifier that is redacted when displayed, see [`id`]
+pub struct Redacted<'a> {
+    value: &'a str,
+    enabled: bool,
+}
+
+/// Wrap an identifier for logging, redacted unless the run turned it off
+pub fn id<'a>(settings: &Settings, value: &'a (impl AsRef<str> + ?Sized)) -> Redacted<'a> {
+    Redacted {
+        value: value.as_ref(),
+        enabled: settings.redact,
+    }
+}
+
+/// Redacted form of `value`: its first characters and a short hash
+fn redact(value: &str) -> String {
+    let prefix: String = value.chars().take(PREFIX_CHARS).collect();
+    let hash = Sha256::digest(value.as_bytes());
+    format!("{}…{}", prefix, hex::encode(&hash[..HASH_BYTES]))
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.enabled {
+            f.write_str(&redact(self.value))
+        } else {
+            f.write_str(self.value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_is_stable_and_hides_the_value() {
+        let room_id = "!abcdefghijklmnop:example.org";
+        let redacted = redact(room_id);
+
+        assert!(redacted.starts_with("!abc…"));
+        assert!(!redacted.contains("example.org"));
+        assert_eq!(redacted, redact(room_id));
+        assert_ne!(redacted, redact("!abcdefghijklmnop:example.com"));
+    }
+}
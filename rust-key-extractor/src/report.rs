@@ -0,0 +1,177 @@
+//! Machine-readable run reports
+//!
+//! With `--run-report` the extractor writes a JSON summary of the run next to
+//! the export: tool version, timings, per-tree counts, failures and the
+//! SHA-256 of every file it wrote. The report holds no key material, so fleet
+//! operators can archive it as evidence of each migration and later check
+//! that an export is the one that was produced.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Version of the report format
+pub const REPORT_VERSION: u32 = 1;
+
+/// Summary of an `extract` run
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunReport {
+    /// Version of this report format
+    pub version: u32,
+    /// Version of sled-key-extractor that wrote the report
+    pub tool_version: String,
+    /// Start of the run, in seconds since the Unix epoch
+    pub started_at: u64,
+    /// End of the run, in seconds since the Unix epoch
+    pub finished_at: u64,
+    /// Sled store the keys were read from
+    pub sled_path: PathBuf,
+    /// Export file or directory
+    pub output: PathBuf,
+    /// Flags that shape the export
+    pub options: ReportOptions,
+    /// Duration of each phase
+    pub timings: Timings,
+    /// Number of inbound group session keys exported
+    pub total_keys: usize,
+    /// Number of entries that could not be extracted, over all trees
+    pub failed_keys: usize,
+    /// Number of rooms with at least one key
+    pub rooms: usize,
+    /// Extracted and failed entries per tree
+    pub trees: BTreeMap<String, TreeCount>,
+    /// Number of failed entries per sled tree
+    pub failures_by_tree: BTreeMap<String, usize>,
+    /// Number of failed entries per failure category
+    #[serde(default)]
+    pub failures_by_category: BTreeMap<String, usize>,
+    /// Every file written by the run
+    pub files: Vec<FileDigest>,
+}
+
+/// Flags of the run that change what the export contains
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportOptions {
+    /// Fault-tolerant mode (`--skip-errors`)
+    pub skip_errors: bool,
+    /// Encoding of the export
+    pub format: String,
+    /// Compression of the export, if any
+    pub compression: Option<String>,
+    /// Whether the export is passphrase-encrypted
+    pub encrypted: bool,
+    /// Whether the export was split into a directory of parts
+    pub split: bool,
+    /// Whether session keys were left out (`--no-secrets`)
+    pub no_secrets: bool,
+}
+
+/// Duration of each phase of the run, in seconds
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Timings {
+    /// Reading inbound group sessions
+    pub extraction: f64,
+    /// Reading additional trees
+    pub extra_trees: f64,
+    /// Encoding and writing the export
+    pub write: f64,
+    /// The whole run
+    pub total: f64,
+}
+
+/// Extracted and failed entries of one tree
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TreeCount {
+    /// Entries exported
+    pub extracted: usize,
+    /// Entries that could not be read or decrypted
+    pub failed: usize,
+}
+
+/// Size and checksum of a written file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileDigest {
+    /// Path of the file
+    pub path: PathBuf,
+    /// Size in bytes
+    pub bytes: u64,
+    /// SHA-256 of the file contents, hex encoded
+    pub sha256: String,
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Size and SHA-256 of a file
+pub fn digest_file(path: &Path) -> Result<FileDigest> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut bytes = 0u64;
+
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        bytes += read as u64;
+    }
+
+    Ok(FileDigest {
+        path: path.to_path_buf(),
+        bytes,
+        sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// Digests of an export: the file itself, or every file of a split export directory
+pub fn digest_output(path: &Path) -> Result<Vec<FileDigest>> {
+    if !path.is_dir() {
+        return Ok(vec![digest_file(path)?]);
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path).with_context(|| format!("Failed to list {:?}", path))? {
+        let entry = entry.with_context(|| format!("Failed to list {:?}", path))?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    files.iter().map(|file| digest_file(file)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_file() {
+        let dir = crate::testing::TempDir::new("report-test");
+        let path = dir.join("export.json");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let digests = digest_output(dir.path()).unwrap();
+
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].bytes, 3);
+        assert_eq!(
+            digests[0].sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,524 @@
+//! Import of the additional crypto-store trees of an export
+//!
+//! Besides the room keys, `extract --include` exports the account and the
+//! rest of the crypto state (see [`crate::trees`]). Without it a migrated bot
+//! comes up as a new device that its peers have to verify again, can't sign
+//! with its cross-signing keys, and has to establish new Olm sessions and
+//! rotate its outbound group sessions. Each entry is rebuilt from its pickle
+//! or serialized form and written to the target store in one `save_changes`,
+//! next to the sessions written by [`crate::import`]. Olm and outbound group
+//! sessions are rebuilt with the keys of the exported account, so they can
+//! only be imported together with it. The tracked users are saved with their
+//! dirty flags, so the bot keeps receiving device list updates of everyone it
+//! shares rooms with.
+//!
+//! Only the trees in [`TREES`] can be written. An export holding data of any
+//! other tree is refused rather than imported without it.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use matrix_sdk_crypto::olm::{
+    OutboundGroupSession, PickledAccount, PickledCrossSigningIdentity, PickledOutboundGroupSession,
+    PickledSession, PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
+};
+use matrix_sdk_crypto::store::{Changes, CryptoStore, RecoveryKey};
+use matrix_sdk_crypto::types::events::room_key_withheld::RoomKeyWithheldEvent;
+use matrix_sdk_crypto::{GossipRequest, ReadOnlyDevice, ReadOnlyUserIdentities};
+use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde::de::DeserializeOwned;
+use tracing::{info, warn};
+
+use crate::error::ExtractorError;
+use crate::redact;
+use crate::settings::Settings;
+use crate::trees::{ExportedWithheldInfo, ExtraTree, ExtraTreeExport};
+
+/// Trees whose data can be written to a target store
+pub const TREES: &[ExtraTree] = &[
+    ExtraTree::OutboundGroupSessions,
+    ExtraTree::OlmSessions,
+    ExtraTree::Account,
+    ExtraTree::CrossSigningKeys,
+    ExtraTree::Devices,
+    ExtraTree::Identities,
+    ExtraTree::KeyRequests,
+    ExtraTree::TrackedUsers,
+    ExtraTree::Withheld,
+];
+
+/// Name of the server-side backup version in the account tree
+const BACKUP_VERSION_SECRET: &str = "backup_version_v1";
+
+/// Name of the backup recovery key in the account tree
+const RECOVERY_KEY_SECRET: &str = "recovery_key_v1";
+
+/// Crypto state of an export, rebuilt and ready to be saved
+#[derive(Default)]
+pub struct RestoredTrees {
+    changes: Changes,
+    /// Tracked users and whether their device lists are outdated
+    tracked_users: Vec<(OwnedUserId, bool)>,
+    skip_errors: bool,
+    /// Entries rebuilt
+    pub entries: usize,
+    /// Entries that could not be rebuilt
+    pub failed: usize,
+}
+
+impl RestoredTrees {
+    /// Take a rebuilt entry, or count it as failed in fault-tolerant mode
+    fn keep<T>(&mut self, entry: Result<T>, what: impl FnOnce() -> String) -> Result<Option<T>> {
+        match entry {
+            Ok(entry) => {
+                self.entries += 1;
+                Ok(Some(entry))
+            }
+            Err(e) if self.skip_errors => {
+                warn!("{}: {:#}", what(), e);
+                self.failed += 1;
+                Ok(None)
+            }
+            Err(e) => Err(e.context(what())),
+        }
+    }
+}
+
+/// Deserialize an entry kept as JSON in the export
+fn decode<T: DeserializeOwned>(value: &serde_json::Value) -> Result<T> {
+    serde_json::from_value(value.clone()).context("Invalid entry")
+}
+
+/// Refuse an export holding data of trees that can't be written to a store
+fn check_importable(trees: &ExtraTreeExport) -> Result<()> {
+    let unsupported: Vec<String> = ExtraTree::value_variants()
+        .iter()
+        .filter(|tree| !TREES.contains(tree) && trees.count(**tree) > 0)
+        .map(|tree| tree.label())
+        .collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+
+    Err(ExtractorError::InvalidExport(format!(
+        "the export holds {} data, which can't be imported - extract it again without it",
+        unsupported.join(", ")
+    ))
+    .into())
+}
+
+/// The withheld event of an entry, with the room and session it is stored under
+fn withheld_event(
+    entry: &ExportedWithheldInfo,
+) -> Result<(OwnedRoomId, String, RoomKeyWithheldEvent)> {
+    let room_id = entry.room_id.as_deref().context("No room ID")?;
+    let room_id = RoomId::parse(room_id).context("Invalid room ID")?;
+    let session_id = entry.session_id.clone().context("No session ID")?;
+    Ok((room_id, session_id, decode(&entry.data)?))
+}
+
+/// Rebuild the additional tree data of an export for a target store
+///
+/// In strict mode the first entry that can't be rebuilt aborts; otherwise it
+/// is logged and counted.
+pub async fn restore_trees(
+    settings: &Settings,
+    trees: &ExtraTreeExport,
+    skip_errors: bool,
+) -> Result<RestoredTrees> {
+    check_importable(trees)?;
+    let mut restored = RestoredTrees {
+        skip_errors,
+        ..Default::default()
+    };
+
+    if let Some(account) = &trees.account {
+        let entry = decode::<PickledAccount>(&account.pickle).and_then(|pickle| {
+            ReadOnlyAccount::from_pickle(pickle).context("Failed to unpickle the account")
+        });
+        restored.changes.account = restored.keep(entry, || {
+            format!("Account of {}", redact::id(settings, &account.user_id))
+        })?;
+    }
+
+    if let Some(identity) = &trees.cross_signing_identity {
+        let entry = match decode::<PickledCrossSigningIdentity>(&identity.pickle) {
+            Ok(pickle) => PrivateCrossSigningIdentity::from_pickle(pickle)
+                .await
+                .context("Failed to unpickle the private cross-signing keys"),
+            Err(e) => Err(e),
+        };
+        restored.changes.private_identity = restored.keep(entry, || {
+            format!(
+                "Private cross-signing identity of {}",
+                redact::id(settings, &identity.user_id)
+            )
+        })?;
+    }
+
+    // Our own sessions are rebuilt with the IDs and identity keys of our account
+    let owner = restored.changes.account.as_ref().map(|account| {
+        (
+            account.user_id().to_owned(),
+            account.device_id().to_owned(),
+            Arc::new(account.identity_keys()),
+        )
+    });
+    let owner = || {
+        owner.clone().context(
+            "Sessions can only be imported together with the account they belong to - \
+             extract them with --include account",
+        )
+    };
+
+    for session in &trees.olm_sessions {
+        let entry = owner().and_then(|(user_id, device_id, identity_keys)| {
+            let pickle = decode::<PickledSession>(&session.pickle)?;
+            Ok(Session::from_pickle(
+                user_id,
+                device_id,
+                identity_keys,
+                pickle,
+            ))
+        });
+        let what = || {
+            format!(
+                "Olm session with {}",
+                redact::id(settings, &session.sender_key)
+            )
+        };
+        if let Some(session) = restored.keep(entry, what)? {
+            restored.changes.sessions.push(session);
+        }
+    }
+
+    for session in &trees.outbound_group_sessions {
+        let entry = owner().and_then(|(_, device_id, identity_keys)| {
+            let pickle = decode::<PickledOutboundGroupSession>(&session.pickle)?;
+            OutboundGroupSession::from_pickle(device_id, identity_keys, pickle)
+                .context("Failed to unpickle the session")
+        });
+        let what = || {
+            format!(
+                "Outbound group session in {}",
+                redact::id(settings, &session.room_id)
+            )
+        };
+        if let Some(session) = restored.keep(entry, what)? {
+            restored.changes.outbound_group_sessions.push(session);
+        }
+    }
+
+    for secret in &trees.secrets {
+        let what = || format!("Account tree secret '{}'", secret.name);
+        match secret.name.as_str() {
+            BACKUP_VERSION_SECRET => {
+                restored.changes.backup_version = restored.keep(decode(&secret.value), what)?;
+            }
+            RECOVERY_KEY_SECRET => {
+                restored.changes.recovery_key =
+                    restored.keep(decode::<RecoveryKey>(&secret.value), what)?;
+            }
+            // Secrets of newer SDK versions, which this one has no place for
+            _ => warn!("{} is unknown and not imported", what()),
+        }
+    }
+
+    for device in &trees.devices {
+        let entry = decode::<ReadOnlyDevice>(&device.data);
+        let what = || {
+            format!(
+                "Device {} of {}",
+                redact::id(settings, &device.device_id),
+                redact::id(settings, &device.user_id)
+            )
+        };
+        if let Some(device) = restored.keep(entry, what)? {
+            restored.changes.devices.new.push(device);
+        }
+    }
+
+    for identity in &trees.identities {
+        let entry = decode::<ReadOnlyUserIdentities>(&identity.data);
+        let what = || format!("Identity of {}", redact::id(settings, &identity.user_id));
+        if let Some(identity) = restored.keep(entry, what)? {
+            restored.changes.identities.new.push(identity);
+        }
+    }
+
+    for request in &trees.key_requests {
+        let entry = decode::<GossipRequest>(&request.data);
+        let what = || format!("Key request {}", request.request_id);
+        if let Some(request) = restored.keep(entry, what)? {
+            restored.changes.key_requests.push(request);
+        }
+    }
+
+    for user in &trees.tracked_users {
+        let entry = UserId::parse(&user.user_id).context("Invalid user ID");
+        let what = || format!("Tracked user {}", redact::id(settings, &user.user_id));
+        if let Some(user_id) = restored.keep(entry, what)? {
+            restored.tracked_users.push((user_id, user.dirty));
+        }
+    }
+
+    for withheld in &trees.withheld {
+        let what = || {
+            format!(
+                "Withheld info of session {}",
+                redact::id(
+                    settings,
+                    withheld.session_id.as_deref().unwrap_or("<unknown>")
+                )
+            )
+        };
+        if let Some((room_id, session_id, event)) = restored.keep(withheld_event(withheld), what)? {
+            restored
+                .changes
+                .withheld_session_info
+                .entry(room_id)
+                .or_default()
+                .insert(session_id, event);
+        }
+    }
+
+    if restored.entries > 0 {
+        info!("Rebuilt {} entries of additional trees", restored.entries);
+    }
+    Ok(restored)
+}
+
+/// Refuse to replace the account of a target store with that of another device
+///
+/// A target store that already has the exported account, e.g. from an earlier
+/// import, accepts it again. With `force` a mismatch is only logged.
+pub async fn check_target_device<S: CryptoStore>(
+    settings: &Settings,
+    store: &S,
+    restored: &RestoredTrees,
+    force: bool,
+) -> Result<()> {
+    let Some(account) = &restored.changes.account else {
+        return Ok(());
+    };
+    let existing = store
+        .load_account()
+        .await
+        .context("Failed to read the account of the target store")?;
+    let Some(existing) = existing else {
+        return Ok(());
+    };
+
+    if existing.device_id() == account.device_id()
+        && existing.identity_keys().curve25519 == account.identity_keys().curve25519
+    {
+        return Ok(());
+    }
+    let mismatch = format!(
+        "the export holds device {}, the target store already has device {}",
+        redact::id(settings, account.device_id().as_str()),
+        redact::id(settings, existing.device_id().as_str())
+    );
+    if force {
+        warn!(
+            "Replacing the account anyway because of --force: {}",
+            mismatch
+        );
+        return Ok(());
+    }
+    Err(
+        ExtractorError::AccountMismatch(format!("{} - pass --force to replace it", mismatch))
+            .into(),
+    )
+}
+
+/// Write the rebuilt tree data to a crypto store
+///
+/// Returns the number of entries written.
+pub async fn save_trees<S: CryptoStore>(store: &S, restored: RestoredTrees) -> Result<usize> {
+    if restored.entries == 0 {
+        return Ok(0);
+    }
+
+    store
+        .save_changes(restored.changes)
+        .await
+        .context("Failed to save the additional trees")?;
+    let tracked_users: Vec<(&UserId, bool)> = restored
+        .tracked_users
+        .iter()
+        .map(|(user_id, dirty)| (&**user_id, *dirty))
+        .collect();
+    store
+        .save_tracked_users(&tracked_users)
+        .await
+        .context("Failed to save the tracked users")?;
+    Ok(restored.entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_crypto::EncryptionSettings;
+    use matrix_sdk_sqlite::SqliteCryptoStore;
+    use ruma::{OwnedDeviceId, SecondsSinceUnixEpoch};
+    use vodozemac::olm::{Account, SessionConfig};
+
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::trees::{
+        ExportedAccount, ExportedCrossSigningIdentity, ExportedOlmSession, ExportedOutboundSession,
+        ExportedSecret, TrackedUserEntry,
+    };
+
+    #[test]
+    fn test_every_tree_can_be_imported() {
+        for tree in ExtraTree::value_variants() {
+            assert!(TREES.contains(tree), "{} can't be imported", tree.label());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_need_the_account() {
+        let trees = ExtraTreeExport {
+            olm_sessions: vec![ExportedOlmSession {
+                sender_key: "key".to_string(),
+                pickle: serde_json::Value::Null,
+            }],
+            ..Default::default()
+        };
+
+        let error = restore_trees(&Settings::default(), &trees, false)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", error).contains("--include account"),
+            "{:#}",
+            error
+        );
+        let restored = restore_trees(&Settings::default(), &trees, true)
+            .await
+            .unwrap();
+        assert_eq!((restored.entries, restored.failed), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_secrets_are_skipped() {
+        let trees = ExtraTreeExport {
+            secrets: vec![ExportedSecret {
+                name: "dehydrated_device_v1".to_string(),
+                value: serde_json::json!("secret"),
+            }],
+            ..Default::default()
+        };
+
+        let restored = restore_trees(&Settings::default(), &trees, false)
+            .await
+            .unwrap();
+        assert_eq!((restored.entries, restored.failed), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_trees_round_trip_through_sqlite_store() {
+        let dir = TempDir::new("restore-test");
+        let user_id = UserId::parse("@bot:example.org").unwrap();
+        let device_id = OwnedDeviceId::from("BOTDEVICE");
+        let account = ReadOnlyAccount::new(&user_id, &device_id);
+        let identity = PrivateCrossSigningIdentity::new(user_id.clone()).await;
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let (outbound, _) = account
+            .create_group_session_pair(&room_id, EncryptionSettings::default())
+            .await
+            .unwrap();
+        let mut peer = Account::new();
+        peer.generate_one_time_keys(1);
+        let one_time_key = *peer.one_time_keys().values().next().unwrap();
+        let olm_session = Account::new().create_outbound_session(
+            SessionConfig::version_1(),
+            peer.curve25519_key(),
+            one_time_key,
+        );
+        let olm_pickle = PickledSession {
+            pickle: olm_session.pickle(),
+            sender_key: peer.curve25519_key(),
+            created_using_fallback_key: false,
+            creation_time: SecondsSinceUnixEpoch::now(),
+            last_use_time: SecondsSinceUnixEpoch::now(),
+        };
+        let trees = ExtraTreeExport {
+            outbound_group_sessions: vec![ExportedOutboundSession {
+                room_id: room_id.to_string(),
+                pickle: serde_json::to_value(outbound.pickle().await).unwrap(),
+            }],
+            olm_sessions: vec![ExportedOlmSession {
+                sender_key: peer.curve25519_key().to_base64(),
+                pickle: serde_json::to_value(&olm_pickle).unwrap(),
+            }],
+            account: Some(ExportedAccount {
+                user_id: user_id.to_string(),
+                device_id: device_id.to_string(),
+                shared: false,
+                pickle: serde_json::to_value(account.pickle().await).unwrap(),
+            }),
+            cross_signing_identity: Some(ExportedCrossSigningIdentity {
+                user_id: user_id.to_string(),
+                shared: false,
+                pickle: serde_json::to_value(identity.pickle().await).unwrap(),
+            }),
+            secrets: vec![ExportedSecret {
+                name: BACKUP_VERSION_SECRET.to_string(),
+                value: serde_json::json!("3"),
+            }],
+            tracked_users: vec![TrackedUserEntry {
+                user_id: "@alice:example.org".to_string(),
+                dirty: true,
+            }],
+            ..Default::default()
+        };
+
+        let restored = restore_trees(&Settings::default(), &trees, false)
+            .await
+            .unwrap();
+        let store = SqliteCryptoStore::open(dir.path(), None).await.unwrap();
+        let saved = save_trees(&store, restored).await.unwrap();
+        drop(store);
+
+        let store = SqliteCryptoStore::open(dir.path(), None).await.unwrap();
+        let loaded = store.load_account().await.unwrap().unwrap();
+        let backup_version = store.load_backup_keys().await.unwrap().backup_version;
+        let loaded_identity = store.load_identity().await.unwrap().unwrap();
+        let tracked_users = store.load_tracked_users().await.unwrap();
+        let olm_sessions = store
+            .get_sessions(&peer.curve25519_key().to_base64())
+            .await
+            .unwrap()
+            .unwrap();
+        let olm_session_ids: Vec<String> = olm_sessions
+            .lock()
+            .await
+            .iter()
+            .map(|session| session.session_id().to_owned())
+            .collect();
+        let loaded_outbound = store
+            .get_outbound_group_session(&room_id)
+            .await
+            .unwrap()
+            .unwrap();
+        drop(store);
+
+        assert_eq!(saved, 6);
+        assert_eq!(loaded.device_id(), account.device_id());
+        assert_eq!(
+            loaded.identity_keys().curve25519,
+            account.identity_keys().curve25519
+        );
+        assert_eq!(backup_version.as_deref(), Some("3"));
+        assert_eq!(loaded_identity.user_id(), &*user_id);
+        assert_eq!(tracked_users.len(), 1);
+        assert_eq!(tracked_users[0].user_id.as_str(), "@alice:example.org");
+        assert!(tracked_users[0].dirty);
+        assert_eq!(olm_session_ids, vec![olm_session.session_id()]);
+        assert_eq!(loaded_outbound.session_id(), outbound.session_id());
+    }
+}
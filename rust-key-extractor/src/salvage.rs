@@ -0,0 +1,209 @@
+//! Lossy recovery of slightly corrupted session values
+//!
+//! With `--salvage`, a value that decrypts but doesn't deserialize gets a
+//! second, lenient attempt before it's recorded as failed. Bytes after the
+//! end of the JSON document are dropped, a truncated document is cut back to
+//! its last complete member and closed, duplicate fields keep their last
+//! value and fields the pickle type rejects are removed. Every repair is
+//! reported, so a salvaged session can be told apart from an intact one.
+
+use matrix_sdk_store_encryption::{EncryptedValue, StoreCipher};
+use serde::de::DeserializeOwned;
+use serde_path_to_error::Segment;
+use zeroize::Zeroizing;
+
+/// Number of rejected fields removed before giving up on a value
+const MAX_REMOVED_FIELDS: usize = 16;
+
+/// A value recovered from a corrupted document
+#[derive(Debug)]
+pub struct Salvaged<T> {
+    /// The recovered value
+    pub value: T,
+    /// What had to be repaired or dropped to get it
+    pub repairs: Vec<String>,
+}
+
+/// Salvage a stored value that didn't deserialize
+///
+/// Values that don't decrypt are beyond repair and give `None`.
+pub fn salvage_value<T: DeserializeOwned>(
+    value: &[u8],
+    store_cipher: Option<&StoreCipher>,
+) -> Option<Salvaged<T>> {
+    let plaintext = match store_cipher {
+        Some(cipher) => {
+            let envelope: EncryptedValue = serde_json::from_slice(value).ok()?;
+            Zeroizing::new(cipher.decrypt_value_data(envelope).ok()?)
+        }
+        None => Zeroizing::new(value.to_vec()),
+    };
+
+    recover(&plaintext)
+}
+
+/// Deserialize a JSON document leniently
+pub fn recover<T: DeserializeOwned>(data: &[u8]) -> Option<Salvaged<T>> {
+    let mut repairs = Vec::new();
+    let mut json = parse_lenient(data, &mut repairs)?;
+
+    for _ in 0..MAX_REMOVED_FIELDS {
+        let error = match serde_path_to_error::deserialize::<_, T>(&json) {
+            Ok(value) => {
+                if repairs.is_empty() {
+                    repairs.push("duplicate fields merged, last value kept".to_string());
+                }
+                return Some(Salvaged { value, repairs });
+            }
+            Err(error) => error,
+        };
+
+        let field = unknown_field(&error.inner().to_string())?;
+        let path: Vec<Segment> = error.path().iter().cloned().collect();
+        if !remove_field(&mut json, &path, &field) {
+            return None;
+        }
+        repairs.push(format!("dropped unknown field `{}`", field));
+    }
+
+    None
+}
+
+/// Parse a JSON document, dropping trailing bytes or closing a truncated one
+fn parse_lenient(data: &[u8], repairs: &mut Vec<String>) -> Option<serde_json::Value> {
+    if let Ok(json) = serde_json::from_slice(data) {
+        return Some(json);
+    }
+
+    let mut stream = serde_json::Deserializer::from_slice(data).into_iter::<serde_json::Value>();
+    if let Some(Ok(json)) = stream.next() {
+        repairs.push(format!("dropped {} trailing bytes", data.len() - stream.byte_offset()));
+        return Some(json);
+    }
+
+    let (cut, closers) = complete_prefix(data)?;
+    let mut repaired = Zeroizing::new(data[..cut].to_vec());
+    repaired.extend_from_slice(&closers);
+    let json = serde_json::from_slice(&repaired).ok()?;
+    repairs.push(format!(
+        "truncated document: dropped {} bytes of an incomplete member, closed {} objects/arrays",
+        data.len() - cut,
+        closers.len()
+    ));
+    Some(json)
+}
+
+/// The longest prefix of a truncated document that ends on a complete member,
+/// and the brackets that close it
+fn complete_prefix(data: &[u8]) -> Option<(usize, Vec<u8>)> {
+    let mut open = Vec::new();
+    let mut cut = None;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (index, &byte) in data.iter().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' => {
+                open.push(b'}');
+                cut = Some(index + 1);
+            }
+            b'[' => {
+                open.push(b']');
+                cut = Some(index + 1);
+            }
+            b'}' | b']' => {
+                if open.pop() != Some(byte) {
+                    return None;
+                }
+                cut = Some(index + 1);
+            }
+            b',' => cut = Some(index),
+            _ => {}
+        }
+    }
+
+    // Brackets only change at cut points, so what is open now was open there
+    if open.is_empty() {
+        return None;
+    }
+    open.reverse();
+    Some((cut?, open))
+}
+
+/// Name of the field an "unknown field" error is about
+fn unknown_field(message: &str) -> Option<String> {
+    let rest = message.strip_prefix("unknown field `")?;
+    Some(rest[..rest.find('`')?].to_string())
+}
+
+/// Remove `field` from the object at `path`
+///
+/// Depending on the deserializer, the path ends at the object or at the
+/// rejected field itself.
+fn remove_field(json: &mut serde_json::Value, path: &[Segment], field: &str) -> bool {
+    let path = match path.last() {
+        Some(Segment::Map { key }) if key == field => &path[..path.len() - 1],
+        _ => path,
+    };
+
+    let mut current = json;
+    for segment in path {
+        current = match segment {
+            Segment::Map { key } => match current.get_mut(key.as_str()) {
+                Some(next) => next,
+                None => return false,
+            },
+            Segment::Seq { index } => match current.get_mut(*index) {
+                Some(next) => next,
+                None => return false,
+            },
+            _ => return false,
+        };
+    }
+
+    current
+        .as_object_mut()
+        .is_some_and(|object| object.remove(field).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Pickle {
+        room_id: String,
+        #[serde(default)]
+        chain: Vec<u32>,
+    }
+
+    #[test]
+    fn test_recover_truncated_and_padded_documents() {
+        let truncated = br#"{"room_id":"!a:b","chain":[1,2"#;
+        let salvaged = recover::<Pickle>(truncated).unwrap();
+        assert_eq!(salvaged.value.chain, vec![1]);
+        assert!(salvaged.repairs[0].contains("closed 2"));
+
+        let padded = b"{\"room_id\":\"!a:b\",\"extra\":1}\0\0\0";
+        let salvaged = recover::<Pickle>(padded).unwrap();
+        assert_eq!(salvaged.repairs, vec![
+            "dropped 3 trailing bytes".to_string(),
+            "dropped unknown field `extra`".to_string(),
+        ]);
+
+        assert!(recover::<Pickle>(br#"{"chain":[1"#).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+//! Detection of the crypto store layout
+//!
+//! Sled crypto stores have been written by two generations of code: the sled
+//! store built into matrix-sdk-crypto before it moved to its own crate, and
+//! matrix-sdk-sled. Only the latter records a `store_version` marker, and the
+//! former pickled sessions with libolm. [`detect`] reads the marker, picks the
+//! matching entry of [`LAYOUTS`] and resolves the tree the inbound group
+//! sessions are in. Stores whose sessions tree has a different name - a
+//! versioned or prefixed one - are matched by name, so they can be read
+//! without code changes.
+//!
+//! The exported store cipher is looked up under every key it is known to have
+//! been stored under, and with `--scan-for-cipher` the default tree is
+//! searched for a value shaped like one.
+//!
+//! Forks with their own tree names can be read by mapping each default tree
+//! name to the real one with `--tree-name DEFAULT=NAME`; every reader looks
+//! its tree up through [`tree_name`].
+
+use anyhow::Result;
+use clap::ValueEnum;
+use tracing::{info, warn};
+
+use crate::error::ExtractorError;
+use crate::inspect::display_key;
+use crate::settings::Settings;
+use crate::trees::ExtraTree;
+use crate::{doctor, encode_key, INBOUND_GROUP_SESSIONS_TREE};
+
+/// Key of the store version marker in the default tree
+const STORE_VERSION_KEY: &str = "store_version";
+
+/// Key of the exported store cipher in the default tree
+const STORE_CIPHER_KEY: &str = "store_cipher";
+
+/// Whether `value` looks like an exported store cipher
+///
+/// `StoreCipher::export` writes JSON with the KDF parameters and the
+/// encrypted key.
+fn is_exported_cipher(value: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(value).is_ok_and(|value| {
+        value.get("kdf_info").is_some() && value.get("ciphertext_info").is_some()
+    })
+}
+
+/// Read the exported store cipher, if the store has one
+///
+/// matrix-sdk-sled stores it under the encoded key; some versions used the
+/// plain one. With `--scan-for-cipher` the default tree is searched if it
+/// isn't under either.
+pub fn find_store_cipher(settings: &Settings, db: &sled::Db) -> Result<Option<sled::IVec>> {
+    let keys = [encode_key(STORE_CIPHER_KEY), STORE_CIPHER_KEY.as_bytes().to_vec()];
+    for (index, key) in keys.iter().enumerate() {
+        if let Some(exported) = db.get(key).map_err(ExtractorError::SledIo)? {
+            if index > 0 {
+                info!("Store cipher found under key '{}'", display_key(key));
+            }
+            return Ok(Some(exported));
+        }
+    }
+
+    if !settings.scan_for_cipher {
+        return Ok(None);
+    }
+
+    let mut found = None;
+    for item in db.iter() {
+        let (key, value) = item.map_err(ExtractorError::SledIo)?;
+        if !is_exported_cipher(&value) {
+            continue;
+        }
+        if found.is_some() {
+            warn!("Another cipher-shaped value under key '{}' - ignored", display_key(&key));
+            continue;
+        }
+        info!("Store cipher found by scanning, under key '{}'", display_key(&key));
+        found = Some(value);
+    }
+    Ok(found)
+}
+
+/// Parse a `--tree-name` value of the form `DEFAULT=NAME`
+pub fn parse_tree_override(value: &str) -> std::result::Result<(String, String), String> {
+    let (default, name) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not of the form DEFAULT=NAME", value))?;
+    let extra = ExtraTree::value_variants().iter().flat_map(|tree| tree.tree_names());
+    let known = std::iter::once(INBOUND_GROUP_SESSIONS_TREE).chain(extra.copied());
+    if !known.clone().any(|tree| tree == default) {
+        let known: Vec<_> = known.collect();
+        return Err(format!("unknown tree '{}' (known: {})", default, known.join(", ")));
+    }
+    if name.is_empty() {
+        return Err(format!("no tree name given for '{}'", default));
+    }
+    Ok((default.to_string(), name.to_string()))
+}
+
+/// Name of the tree matrix-sdk-sled calls `default` in the store being read
+pub fn tree_name<'a>(settings: &'a Settings, default: &'a str) -> &'a str {
+    settings
+        .tree_names
+        .get(default)
+        .map_or(default, String::as_str)
+}
+
+/// Tree names and encodings of one generation of sled crypto stores
+#[derive(Debug)]
+pub struct Layout {
+    /// Short name, used in logs and `doctor`
+    pub name: &'static str,
+    /// Whether stores of this layout carry a `store_version` marker
+    pub versioned: bool,
+    /// Tree holding the inbound group sessions
+    pub inbound_group_sessions: &'static str,
+    /// Whether sessions are pickled by libolm rather than vodozemac
+    pub libolm_pickles: bool,
+}
+
+/// Layouts this tool knows, newest first
+pub const LAYOUTS: &[Layout] = &[
+    Layout {
+        name: "matrix-sdk-sled",
+        versioned: true,
+        inbound_group_sessions: INBOUND_GROUP_SESSIONS_TREE,
+        libolm_pickles: false,
+    },
+    Layout {
+        name: "matrix-sdk-crypto (built-in sled store)",
+        versioned: false,
+        inbound_group_sessions: INBOUND_GROUP_SESSIONS_TREE,
+        libolm_pickles: true,
+    },
+];
+
+/// Layout of an opened store
+#[derive(Debug)]
+pub struct Schema {
+    /// Matching entry of [`LAYOUTS`]
+    pub layout: &'static Layout,
+    /// Value of the store version marker, if there is one
+    pub version: Option<u64>,
+    /// Tree the inbound group sessions are read from
+    pub inbound_group_sessions: String,
+}
+
+/// Read the store version marker
+///
+/// matrix-sdk-sled writes it as a big-endian integer, with or without the
+/// key encoding depending on the version.
+pub fn store_version(db: &sled::Db) -> Result<Option<u64>> {
+    for key in [STORE_VERSION_KEY.as_bytes().to_vec(), encode_key(STORE_VERSION_KEY)] {
+        let Some(value) = db.get(&key).map_err(ExtractorError::SledIo)? else {
+            continue;
+        };
+        if value.is_empty() || value.len() > 8 {
+            warn!("Store version marker has an unexpected length of {} bytes", value.len());
+            continue;
+        }
+        let mut bytes = [0u8; 8];
+        bytes[8 - value.len()..].copy_from_slice(&value);
+        return Ok(Some(u64::from_be_bytes(bytes)));
+    }
+    Ok(None)
+}
+
+/// The sessions tree of a store whose layout doesn't name it, matched by name
+fn find_sessions_tree(tree_names: &[String]) -> Option<&str> {
+    let mut candidates = tree_names
+        .iter()
+        .filter(|name| name.contains(INBOUND_GROUP_SESSIONS_TREE));
+    match (candidates.next(), candidates.next()) {
+        (Some(name), None) => Some(name),
+        _ => None,
+    }
+}
+
+/// Detect the layout of a crypto store
+pub fn detect(settings: &Settings, db: &sled::Db) -> Result<Schema> {
+    let version = store_version(db)?;
+    let layout = LAYOUTS
+        .iter()
+        .find(|layout| layout.versioned == version.is_some())
+        .unwrap_or(&LAYOUTS[0]);
+
+    let tree_names: Vec<String> = db
+        .tree_names()
+        .iter()
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect();
+
+    let has_tree = |tree: &str| tree_names.iter().any(|name| name == tree);
+    let overridden = tree_name(settings, INBOUND_GROUP_SESSIONS_TREE);
+    let inbound_group_sessions = if overridden != INBOUND_GROUP_SESSIONS_TREE {
+        info!("Reading sessions from `{}` (--tree-name)", overridden);
+        overridden.to_string()
+    } else if has_tree(layout.inbound_group_sessions) {
+        layout.inbound_group_sessions.to_string()
+    } else if has_tree(doctor::STATE_STORE_MARKER_TREE) {
+        return Err(ExtractorError::SchemaMismatch(
+            "this is a state store, not a crypto store".to_string(),
+        )
+        .into());
+    } else if let Some(name) = find_sessions_tree(&tree_names) {
+        info!("No `{}` tree - reading sessions from `{}`", layout.inbound_group_sessions, name);
+        name.to_string()
+    } else {
+        layout.inbound_group_sessions.to_string()
+    };
+
+    Ok(Schema {
+        layout,
+        version,
+        inbound_group_sessions,
+    })
+}
+
+impl Schema {
+    /// One-line description for logs
+    pub fn describe(&self) -> String {
+        match self.version {
+            Some(version) => format!("{} (store version {})", self.layout.name, version),
+            None => format!("{} (no store version marker)", self.layout.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_versioned_store_with_renamed_tree() {
+        let dir = crate::testing::TempDir::new("schema-test");
+        let db = sled::open(dir.path()).unwrap();
+        db.insert(STORE_VERSION_KEY, &[4u8]).unwrap();
+        db.open_tree("inbound_group_sessions_v2").unwrap();
+
+        let schema = detect(&Settings::default(), &db).unwrap();
+        drop(db);
+
+        assert_eq!(schema.version, Some(4));
+        assert_eq!(schema.layout.name, "matrix-sdk-sled");
+        assert_eq!(schema.inbound_group_sessions, "inbound_group_sessions_v2");
+        assert!(is_exported_cipher(br#"{"kdf_info":{},"ciphertext_info":{}}"#));
+        assert!(!is_exported_cipher(&[4u8]));
+
+        assert_eq!(
+            parse_tree_override("session=bot_session").unwrap(),
+            ("session".to_string(), "bot_session".to_string())
+        );
+        assert!(parse_tree_override("sessions=bot_session").is_err());
+    }
+}
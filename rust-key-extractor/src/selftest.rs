@@ -0,0 +1,165 @@
+//! Built-in round trip test
+//!
+//! Before trusting a build with production keys, operators can run `selftest`
+//! on the target platform. It generates a fixture store with fake sessions
+//! (see [`fixture`](crate::fixture)) in a private temp directory, extracts it
+//! in strict and in fault-tolerant mode, imports the export into a fresh SQLite
+//! crypto store and compares every step with the generated keys. Everything is
+//! removed again at the end; no real store is touched.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use crate::fixture::{self, FixtureOptions};
+use crate::import::{self, ImportStore};
+use crate::settings::Settings;
+use crate::store::StoreCopy;
+use crate::{
+    build_output, extract_keys_fault_tolerant, extract_keys_strict, verify, ExportedKeyData,
+    SessionDecoder,
+};
+
+/// Passphrase of the fixture store and the SQLite store
+const PASSPHRASE: &str = "selftest";
+
+/// Keys by room and session ID
+fn by_id(keys: &[ExportedKeyData]) -> HashMap<(&str, &str), &ExportedKeyData> {
+    keys.iter()
+        .map(|key| ((key.room_id.as_str(), key.session_id.as_str()), key))
+        .collect()
+}
+
+/// Check that `extracted` holds exactly the `expected` keys
+pub fn compare_keys(expected: &[ExportedKeyData], extracted: &[ExportedKeyData]) -> Result<()> {
+    if expected.len() != extracted.len() {
+        anyhow::bail!("expected {} keys, got {}", expected.len(), extracted.len());
+    }
+    let extracted = by_id(extracted);
+    for key in expected {
+        let Some(found) = extracted.get(&(key.room_id.as_str(), key.session_id.as_str())) else {
+            anyhow::bail!("session {} in {} is missing", key.session_id, key.room_id);
+        };
+        if found.session_key != key.session_key
+            || found.sender_key != key.sender_key
+            || found.sender_claimed_keys != key.sender_claimed_keys
+        {
+            anyhow::bail!("session {} in {} differs", key.session_id, key.room_id);
+        }
+    }
+    Ok(())
+}
+
+/// Run the round trip with `sessions` generated sessions
+pub async fn run(sessions: usize) -> Result<()> {
+    let settings = Settings::default();
+    let dir = StoreCopy::empty()?;
+    let sled_path = dir.path().join("sled");
+    let sqlite_path = dir.path().join("sqlite");
+
+    info!("[1/5] Generating a fixture store with {} sessions", sessions);
+    let options = FixtureOptions {
+        sessions,
+        rooms: sessions.clamp(1, 10),
+        passphrase: Some(PASSPHRASE.to_string()),
+        ..Default::default()
+    };
+    let generated = fixture::generate(&settings, &sled_path, &options)
+        .await
+        .context("Generating the fixture store failed")?;
+
+    info!("[2/5] Extracting in strict mode");
+    let strict = extract_keys_strict(&settings, &sled_path, Some(PASSPHRASE))
+        .await
+        .context("Strict extraction failed")?;
+    compare_keys(&generated.keys, &strict).context("Strict extraction returned wrong keys")?;
+
+    info!("[3/5] Extracting in fault-tolerant mode");
+    let mut keys = Vec::new();
+    let failed = extract_keys_fault_tolerant(
+        &settings,
+        &sled_path,
+        Some(PASSPHRASE),
+        &SessionDecoder::default(),
+        None,
+        usize::MAX,
+        false,
+        None,
+        &mut (),
+        &mut |key| {
+            keys.push(key);
+            Ok(())
+        },
+    )
+    .await
+    .context("Fault-tolerant extraction failed")?;
+    if !failed.is_empty() {
+        anyhow::bail!("Fault-tolerant extraction failed on {} sessions", failed.len());
+    }
+    compare_keys(&generated.keys, &keys)
+        .context("Fault-tolerant extraction returned wrong keys")?;
+
+    info!("[4/5] Importing into a new SQLite crypto store");
+    let output = build_output(keys, 0, false);
+    let summary = import::import_export(
+        &settings,
+        &output,
+        ImportStore::Sqlite,
+        &sqlite_path,
+        Some(PASSPHRASE),
+        false,
+        false,
+        false,
+    )
+    .await
+    .context("Import failed")?;
+    if summary.imported != sessions {
+        anyhow::bail!("Imported {} of {} sessions", summary.imported, sessions);
+    }
+
+    info!("[5/5] Comparing the sled and SQLite stores");
+    verify_stores(&settings, &sled_path, &sqlite_path).await?;
+
+    info!("Self-test passed: {} sessions survived the round trip", sessions);
+    Ok(())
+}
+
+async fn verify_stores(settings: &Settings, sled_path: &Path, sqlite_path: &Path) -> Result<()> {
+    let report = verify::verify_migration(
+        settings,
+        sled_path,
+        Some(PASSPHRASE),
+        sqlite_path,
+        Some(PASSPHRASE),
+    )
+    .await
+    .context("Comparing the stores failed")?;
+    if report.differences() > 0 || report.source_failed > 0 {
+        verify::print_report(&report);
+        anyhow::bail!(
+            "{} sessions differ between the sled and SQLite stores",
+            report.differences() + report.source_failed
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::key_with_secret;
+
+    #[test]
+    fn test_compare_keys_spots_differences() {
+        let key = |session_id: &str, session_key: &str| {
+            key_with_secret("!room:example.org", session_id, session_key)
+        };
+
+        let expected = vec![key("a", "key-a"), key("b", "key-b")];
+        assert!(compare_keys(&expected, &[key("b", "key-b"), key("a", "key-a")]).is_ok());
+        assert!(compare_keys(&expected, &[key("a", "key-a")]).is_err());
+        assert!(compare_keys(&expected, &[key("a", "key-a"), key("b", "other")]).is_err());
+    }
+}
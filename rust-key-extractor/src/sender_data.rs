@@ -0,0 +1,126 @@
+//! Sender data of inbound group sessions
+//!
+//! Newer matrix-sdk-crypto versions store what is known about the sender of a
+//! session next to it (`sender_data`: unknown device, device info, verified
+//! sender, ...), which clients show as "verified sender" indicators. The SDK
+//! revision this tool is built against predates that field and drops it when
+//! reading a pickle, so it is read from the stored value separately and kept
+//! in the key's `extra` fields.
+//!
+//! Target stores opened through this SDK revision can't hold it either. This
+//! revision works out the trust in a session's sender when decrypting, from
+//! the session's `imported` flag and the stored device and identity of the
+//! sender, so `import` maps sender data onto those: it restores the flag and
+//! requires the sender's device, and the identity of verified senders, to be
+//! in the export (see [`device`] and [`needs_identity`]).
+
+use matrix_sdk_store_encryption::StoreCipher;
+use serde::Deserialize;
+
+use crate::{deserialize_value, ExportedKeyData};
+
+/// Name of the field in pickles and in a key's `extra` fields
+pub const FIELD: &str = "sender_data";
+
+/// The only field of a pickled session read here
+#[derive(Deserialize)]
+struct StoredSenderData {
+    #[serde(default)]
+    sender_data: Option<serde_json::Value>,
+}
+
+/// The sender data of a stored session, if it has any
+pub fn read(value: &[u8], store_cipher: Option<&StoreCipher>) -> Option<serde_json::Value> {
+    let stored: StoredSenderData = deserialize_value(value, store_cipher).ok()?;
+    stored.sender_data.filter(|data| !data.is_null())
+}
+
+/// Keep the sender data of a session with its exported key
+pub fn attach(key: &mut ExportedKeyData, sender_data: Option<serde_json::Value>) {
+    if let Some(sender_data) = sender_data {
+        key.extra.insert(FIELD.to_string(), sender_data);
+    }
+}
+
+/// Kind of sender data, e.g. `SenderVerified`, for logs and reports
+///
+/// The SDK serializes it as an externally tagged enum.
+pub fn kind(sender_data: &serde_json::Value) -> &str {
+    match sender_data {
+        serde_json::Value::Object(map) => map.keys().next().map_or("unknown", String::as_str),
+        serde_json::Value::String(kind) => kind,
+        _ => "unknown",
+    }
+}
+
+/// Inner value of the sender data variant, e.g. the `KnownSenderData`
+fn inner(sender_data: &serde_json::Value) -> Option<&serde_json::Value> {
+    sender_data.as_object()?.values().next()
+}
+
+/// User and device ID of the sending device the sender data names
+///
+/// `None` when the device wasn't known to the source store, in which case no
+/// trust was established that could be lost. The device ID is missing in
+/// sender data of older SDK versions.
+pub fn device(sender_data: &serde_json::Value) -> Option<(&str, Option<&str>)> {
+    let device = match kind(sender_data) {
+        "DeviceInfo" => inner(sender_data)?.get("device_keys")?,
+        "SenderUnverified" | "SenderVerified" | "VerificationViolation" => inner(sender_data)?,
+        _ => return None,
+    };
+    let user_id = device.get("user_id")?.as_str()?;
+    Some((user_id, device.get("device_id").and_then(|id| id.as_str())))
+}
+
+/// Whether the trust in the sender rests on their verified identity
+pub fn needs_identity(sender_data: &serde_json::Value) -> bool {
+    matches!(kind(sender_data), "SenderVerified" | "VerificationViolation")
+}
+
+/// Whether this tool knows how to carry the sender data over
+pub fn is_known(sender_data: &serde_json::Value) -> bool {
+    matches!(
+        kind(sender_data),
+        "UnknownDevice"
+            | "DeviceInfo"
+            | "SenderUnverified"
+            | "SenderVerified"
+            | "VerificationViolation"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_sender_data_from_plain_pickle() {
+        let pickle =
+            br#"{"pickle":{},"sender_data":{"SenderVerified":{"user_id":"@a:x.org"}}}"#;
+        let sender_data = read(pickle, None).unwrap();
+        assert_eq!(kind(&sender_data), "SenderVerified");
+
+        assert!(read(br#"{"pickle":{},"room_id":"!a:x.org"}"#, None).is_none());
+    }
+
+    #[test]
+    fn test_sending_device() {
+        let verified = serde_json::json!({
+            "SenderVerified": {"user_id": "@a:x.org", "device_id": "DEV", "master_key": "k"}
+        });
+        assert_eq!(device(&verified), Some(("@a:x.org", Some("DEV"))));
+        assert!(needs_identity(&verified));
+
+        let device_info = serde_json::json!({
+            "DeviceInfo": {"device_keys": {"user_id": "@a:x.org", "device_id": "DEV"}}
+        });
+        assert_eq!(device(&device_info), Some(("@a:x.org", Some("DEV"))));
+        assert!(!needs_identity(&device_info));
+
+        let unknown = serde_json::json!({"UnknownDevice": {"legacy_session": false}});
+        assert_eq!(device(&unknown), None);
+        assert!(is_known(&unknown));
+        assert!(!is_known(&serde_json::json!({"SomethingNew": {}})));
+    }
+}
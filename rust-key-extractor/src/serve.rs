@@ -0,0 +1,654 @@
+//! HTTP API for driving migrations remotely
+//!
+//! A migration dashboard managing hundreds of bots can't SSH into every host
+//! to run `migrate`. `serve` accepts the same migrations as jobs over HTTP:
+//!
+//! | Request                 | Response                                        |
+//! |-------------------------|-------------------------------------------------|
+//! | `POST /jobs`            | Queue a migration, `202` with the job status    |
+//! | `GET /jobs`             | Status of every job                             |
+//! | `GET /jobs/{id}`        | Status of one job                               |
+//! | `GET /jobs/{id}/report` | Counts of a finished migration, as in the C API |
+//! | `GET /summary`          | Jobs per state and counts over finished jobs    |
+//! | `GET /health`           | `ok`, without authentication                    |
+//!
+//! Every other request needs `Authorization: Bearer <token>` with the token
+//! from `--token-file`. A job is the `migrate` options as JSON; they are
+//! validated when the job is submitted, so a bad request is rejected with a
+//! `4xx` status rather than queued as a job bound to fail. Jobs start in
+//! submission order, up to `--concurrency` at a time, each isolated as
+//! described in [`crate::jobs`]. A job runs with the global options the
+//! server was started with, except for those it sets itself: `redact`,
+//! `tree_names`, `scan_for_cipher`, `kdf`, `kdf_rounds`, `cipher_key_file`,
+//! `integrity_passphrase` and the sled tuning.
+//!
+//! Jobs are kept in memory only, and only the `--keep-finished` most recent
+//! finished ones: older ones are forgotten as new ones finish. On Ctrl-C or
+//! SIGTERM the server stops accepting requests, waits for the running jobs
+//! and abandons the queued ones.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
+
+use crate::error::exit_code;
+use crate::kdf::Kdf;
+use crate::report::unix_time;
+use crate::schema;
+use crate::settings::Settings;
+use crate::tuning::SledMode;
+use crate::{jobs, parse_command, run_migrate, Command, MigrateArgs, MigrateReport};
+
+/// A migration to run, with the options of `migrate` and the global options
+/// it sets for itself
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobRequest {
+    sled_paths: Vec<PathBuf>,
+    target: PathBuf,
+    #[serde(default)]
+    passphrase: Option<String>,
+    #[serde(default)]
+    target_passphrase: Option<String>,
+    #[serde(default)]
+    expected_user: Option<String>,
+    #[serde(default)]
+    expected_device: Option<String>,
+    #[serde(default)]
+    skip_errors: bool,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    copy_first: bool,
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    redact: Option<bool>,
+    /// Tree names by default name, as with `--tree-name DEFAULT=NAME`
+    #[serde(default)]
+    tree_names: HashMap<String, String>,
+    #[serde(default)]
+    scan_for_cipher: Option<bool>,
+    #[serde(default)]
+    kdf: Option<Kdf>,
+    #[serde(default)]
+    kdf_rounds: Option<u32>,
+    #[serde(default)]
+    cipher_key_file: Option<PathBuf>,
+    #[serde(default)]
+    integrity_passphrase: Option<String>,
+    #[serde(default)]
+    sled_cache_mb: Option<u64>,
+    #[serde(default)]
+    sled_mode: Option<SledMode>,
+    #[serde(default)]
+    sled_flush_ms: Option<u64>,
+}
+
+impl JobRequest {
+    /// The `migrate` command line for this job
+    ///
+    /// Values are attached with `=` so they can't be taken for flags.
+    fn command_line(&self) -> Vec<String> {
+        let mut args = vec!["migrate".to_string()];
+        for path in &self.sled_paths {
+            args.push(format!("--sled-path={}", path.display()));
+        }
+        args.push(format!("--target={}", self.target.display()));
+
+        let options = [
+            ("passphrase", &self.passphrase),
+            ("target-passphrase", &self.target_passphrase),
+            ("expected-user", &self.expected_user),
+            ("expected-device", &self.expected_device),
+        ];
+        for (name, value) in options {
+            if let Some(value) = value {
+                args.push(format!("--{}={}", name, value));
+            }
+        }
+        let flags = [
+            ("skip-errors", self.skip_errors),
+            ("dry-run", self.dry_run),
+            ("copy-first", self.copy_first),
+            ("force", self.force),
+        ];
+        for (name, set) in flags {
+            if set {
+                args.push(format!("--{}", name));
+            }
+        }
+        args
+    }
+
+    /// The server's `settings` with the global options this job sets
+    fn settings(&self, settings: &Settings) -> Result<Settings> {
+        let mut settings = settings.clone();
+        for (default, name) in &self.tree_names {
+            let (default, name) = schema::parse_tree_override(&format!("{}={}", default, name))
+                .map_err(|e| anyhow::anyhow!("Invalid tree_names: {}", e))?;
+            settings.tree_names.insert(default, name);
+        }
+        let key_file = self.cipher_key_file.as_deref();
+        settings.kdf = settings
+            .kdf
+            .overridden(self.kdf, self.kdf_rounds, key_file)?;
+        if let Some(redact) = self.redact {
+            settings.redact = redact;
+        }
+        if let Some(scan_for_cipher) = self.scan_for_cipher {
+            settings.scan_for_cipher = scan_for_cipher;
+        }
+        if let Some(passphrase) = &self.integrity_passphrase {
+            settings.integrity_passphrase = Some(Zeroizing::new(passphrase.clone()));
+        }
+        let tuning = &mut settings.tuning;
+        tuning.cache_mb = self.sled_cache_mb.or(tuning.cache_mb);
+        tuning.mode = self.sled_mode.or(tuning.mode);
+        tuning.flush_ms = self.sled_flush_ms.or(tuning.flush_ms);
+        Ok(settings)
+    }
+}
+
+/// Where a job is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// What the API reports about a job
+#[derive(Debug, Clone, Serialize)]
+struct JobStatus {
+    id: u64,
+    state: JobState,
+    sled_paths: Vec<PathBuf>,
+    target: PathBuf,
+    dry_run: bool,
+    /// Unix times of the state changes
+    submitted_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<u64>,
+    /// Why a failed job failed, and the exit code `migrate` would have ended with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<u8>,
+}
+
+struct Job {
+    status: JobStatus,
+    /// Counts of a succeeded job
+    report: Option<MigrateReport>,
+}
+
+/// Totals over the jobs kept
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    queued: usize,
+    running: usize,
+    succeeded: usize,
+    failed: usize,
+    /// Sums over the reports of succeeded jobs
+    sessions: usize,
+    imported: usize,
+    read_failures: usize,
+    import_failures: usize,
+}
+
+/// State shared by the request handlers and the job runner
+struct Service {
+    /// SHA-256 of the bearer token
+    token_digest: [u8; 32],
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    /// Id of the next job submitted
+    next_id: AtomicU64,
+    queue: mpsc::UnboundedSender<(u64, MigrateArgs, Settings)>,
+    /// Settings of the jobs that don't set global options of their own
+    settings: Settings,
+    /// Number of finished jobs to keep
+    keep_finished: usize,
+}
+
+impl Service {
+    /// Whether `token` is the configured one, compared in constant time
+    fn authorized(&self, token: &str) -> bool {
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        digest
+            .iter()
+            .zip(self.token_digest.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+    }
+
+    /// Run `update` on the job `id`, if it exists
+    fn update<T>(&self, id: u64, update: impl FnOnce(&mut Job) -> T) -> Option<T> {
+        self.jobs.lock().unwrap().get_mut(&id).map(update)
+    }
+
+    /// Forget the earliest submitted finished jobs beyond `keep_finished`
+    fn evict_finished(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished: Vec<u64> = jobs
+            .iter()
+            .filter(|(_, job)| job.status.finished_at.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        let excess = finished.len().saturating_sub(self.keep_finished);
+        for id in &finished[..excess] {
+            jobs.remove(id);
+        }
+    }
+}
+
+/// A JSON error response
+fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+async fn authenticate(
+    State(service): State<Arc<Service>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if service.authorized(token) => next.run(request).await,
+        _ => error_response(StatusCode::UNAUTHORIZED, "Missing or wrong bearer token"),
+    }
+}
+
+async fn submit_job(
+    State(service): State<Arc<Service>>,
+    request: Result<Json<JobRequest>, JsonRejection>,
+) -> Response {
+    let Json(request) = match request {
+        Ok(request) => request,
+        Err(rejection) => return error_response(rejection.status(), rejection.body_text()),
+    };
+    if request.sled_paths.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "sled_paths must not be empty");
+    }
+    let args = match parse_command(request.command_line()) {
+        Ok(Command::Migrate(args)) => args,
+        Ok(_) => unreachable!("parsed a migrate command"),
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+    };
+    let settings = match request.settings(&service.settings) {
+        Ok(settings) => settings,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+    };
+
+    let status = {
+        let mut jobs = service.jobs.lock().unwrap();
+        let id = service.next_id.fetch_add(1, Ordering::Relaxed);
+        let status = JobStatus {
+            id,
+            state: JobState::Queued,
+            sled_paths: request.sled_paths.clone(),
+            target: request.target.clone(),
+            dry_run: request.dry_run,
+            submitted_at: unix_time(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+            exit_code: None,
+        };
+        jobs.insert(
+            id,
+            Job {
+                status: status.clone(),
+                report: None,
+            },
+        );
+        status
+    };
+    info!(
+        "Job {} queued: {} store(s) into {:?}",
+        status.id,
+        status.sled_paths.len(),
+        status.target
+    );
+    if service.queue.send((status.id, args, settings)).is_err() {
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The server is shutting down",
+        );
+    }
+
+    (StatusCode::ACCEPTED, Json(status)).into_response()
+}
+
+async fn list_jobs(State(service): State<Arc<Service>>) -> Response {
+    let jobs = service.jobs.lock().unwrap();
+    let statuses: Vec<&JobStatus> = jobs.values().map(|job| &job.status).collect();
+    Json(statuses).into_response()
+}
+
+async fn job_status(State(service): State<Arc<Service>>, Path(id): Path<u64>) -> Response {
+    match service.update(id, |job| job.status.clone()) {
+        Some(status) => Json(status).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, format!("No job {}", id)),
+    }
+}
+
+async fn job_report(State(service): State<Arc<Service>>, Path(id): Path<u64>) -> Response {
+    let report = |job: &mut Job| {
+        (
+            job.status.state,
+            job.report.as_ref().map(serde_json::to_value),
+        )
+    };
+    match service.update(id, report) {
+        Some((_, Some(Err(e)))) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to serialize report: {}", e),
+        ),
+        Some((_, Some(Ok(report)))) => Json(report).into_response(),
+        Some((state, None)) => error_response(
+            StatusCode::CONFLICT,
+            format!("Job {} has no report - it is {:?}", id, state).to_lowercase(),
+        ),
+        None => error_response(StatusCode::NOT_FOUND, format!("No job {}", id)),
+    }
+}
+
+async fn summary(State(service): State<Arc<Service>>) -> Response {
+    let mut summary = Summary::default();
+    for job in service.jobs.lock().unwrap().values() {
+        match job.status.state {
+            JobState::Queued => summary.queued += 1,
+            JobState::Running => summary.running += 1,
+            JobState::Succeeded => summary.succeeded += 1,
+            JobState::Failed => summary.failed += 1,
+        }
+        if let Some(report) = &job.report {
+            summary.sessions += report.sessions;
+            summary.imported += report.import.imported;
+            summary.read_failures += report.read_failures;
+            summary.import_failures += report.import.failed;
+        }
+    }
+    Json(summary).into_response()
+}
+
+/// Run job `id` and record its outcome
+async fn run_job(service: Arc<Service>, id: u64, args: MigrateArgs, settings: Settings) {
+    service.update(id, |job| {
+        job.status.state = JobState::Running;
+        job.status.started_at = Some(unix_time());
+    });
+    info!("Job {} started", id);
+
+    let job = move || async move { run_migrate(&settings, args).await };
+    let result = jobs::run_isolated(id.to_string(), job).await;
+
+    service.update(id, |job| {
+        job.status.finished_at = Some(unix_time());
+        match result {
+            Ok(report) => {
+                info!("Job {} succeeded", id);
+                job.status.state = JobState::Succeeded;
+                job.report = Some(report);
+            }
+            Err(e) => {
+                error!("Job {} failed: {:#}", id, e);
+                job.status.state = JobState::Failed;
+                job.status.error = Some(format!("{:#}", e));
+                job.status.exit_code = Some(exit_code(&e));
+            }
+        }
+    });
+    service.evict_finished();
+}
+
+/// Start queued jobs in order, up to `concurrency` at once, until the queue is closed
+async fn run_jobs(
+    service: Arc<Service>,
+    mut queue: mpsc::UnboundedReceiver<(u64, MigrateArgs, Settings)>,
+    concurrency: usize,
+    running: &mut JoinSet<()>,
+) {
+    let slots = Arc::new(Semaphore::new(concurrency));
+    while let Some((id, args, settings)) = queue.recv().await {
+        let slot = slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        while running.try_join_next().is_some() {}
+
+        let service = service.clone();
+        running.spawn(async move {
+            run_job(service, id, args, settings).await;
+            drop(slot);
+        });
+    }
+}
+
+/// Serve the API on `listener` until Ctrl-C or SIGTERM
+pub async fn serve(
+    settings: &Settings,
+    listener: TcpListener,
+    token: &str,
+    concurrency: usize,
+    keep_finished: usize,
+) -> Result<()> {
+    jobs::set_concurrency(concurrency);
+    let (queue, jobs) = mpsc::unbounded_channel();
+    let service = Arc::new(Service {
+        token_digest: Sha256::digest(token.as_bytes()).into(),
+        jobs: Mutex::new(BTreeMap::new()),
+        next_id: AtomicU64::new(1),
+        queue,
+        settings: settings.clone(),
+        keep_finished,
+    });
+
+    let app = Router::new()
+        .route("/jobs", get(list_jobs).post(submit_job))
+        .route("/jobs/:id", get(job_status))
+        .route("/jobs/:id/report", get(job_report))
+        .route("/summary", get(summary))
+        .route_layer(middleware::from_fn_with_state(
+            service.clone(),
+            authenticate,
+        ))
+        .route("/health", get(|| async { "ok" }))
+        .with_state(service.clone());
+
+    info!(
+        "Listening on http://{}",
+        listener
+            .local_addr()
+            .context("Failed to get the listening address")?
+    );
+    let mut running = JoinSet::new();
+    tokio::select! {
+        served = axum::serve(listener, app).with_graceful_shutdown(shutdown_signal()) => {
+            served.context("HTTP server failed")?;
+        }
+        () = run_jobs(service.clone(), jobs, concurrency, &mut running) => {
+            unreachable!("the service holds the queue open")
+        }
+    }
+
+    // Jobs run on blocking threads, which can't be cancelled
+    while running.try_join_next().is_some() {}
+    if !running.is_empty() {
+        info!("Waiting for {} running job(s) to finish", running.len());
+        while running.join_next().await.is_some() {}
+    }
+
+    let queued = service
+        .jobs
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|job| job.status.state == JobState::Queued)
+        .count();
+    if queued > 0 {
+        warn!("Stopped with {} queued job(s) left", queued);
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+    info!("Shutting down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Send one request and return the status code and JSON body
+    async fn request(
+        address: std::net::SocketAddr,
+        method: &str,
+        path: &str,
+        token: &str,
+        body: &str,
+    ) -> (u16, Value) {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            token,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_jobs_are_authenticated_run_and_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = async {
+            let (status, _) = request(address, "GET", "/jobs", "wrong", "").await;
+            assert_eq!(status, 401);
+            let (status, body) =
+                request(address, "POST", "/jobs", "token", r#"{"sled_paths": []}"#).await;
+            assert_eq!(status, 422, "{}", body);
+            let empty = r#"{"sled_paths": [], "target": "/tmp/x"}"#;
+            let (status, body) = request(address, "POST", "/jobs", "token", empty).await;
+            assert_eq!(status, 400);
+            assert_eq!(body["error"], "sled_paths must not be empty");
+            let kdf = r#"{"sled_paths": ["/a"], "target": "/tmp/x", "kdf": "md5"}"#;
+            let (status, body) = request(address, "POST", "/jobs", "token", kdf).await;
+            assert_eq!(status, 422, "{}", body);
+            let tree = r#"{"sled_paths": ["/a"], "target": "/tmp/x", "tree_names": {"a": "b"}}"#;
+            let (status, body) = request(address, "POST", "/jobs", "token", tree).await;
+            assert_eq!(status, 400);
+            assert!(body["error"].as_str().unwrap().contains("unknown tree 'a'"));
+
+            let job = r#"{"sled_paths": ["/nonexistent/sled-store"], "target": "/tmp/x",
+                "redact": false, "kdf": "pbkdf2", "sled_cache_mb": 64}"#;
+            let (status, body) = request(address, "POST", "/jobs", "token", job).await;
+            assert_eq!(status, 202, "{}", body);
+            assert_eq!(body["state"], "queued");
+
+            let status = loop {
+                let (_, status) = request(address, "GET", "/jobs/1", "token", "").await;
+                if status["state"] == "failed" {
+                    break status;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            };
+            assert_eq!(status["exit_code"], 3);
+            let (code, _) = request(address, "GET", "/jobs/1/report", "token", "").await;
+            assert_eq!(code, 409);
+            let (code, _) = request(address, "GET", "/jobs/2", "token", "").await;
+            assert_eq!(code, 404);
+            let (_, summary) = request(address, "GET", "/summary", "token", "").await;
+            assert_eq!(
+                (summary["failed"].as_u64(), summary["running"].as_u64()),
+                (Some(1), Some(0))
+            );
+
+            // Only the most recent finished job is kept
+            let (status, body) = request(address, "POST", "/jobs", "token", job).await;
+            assert_eq!((status, body["id"].as_u64()), (202, Some(2)));
+            loop {
+                let (_, status) = request(address, "GET", "/jobs/2", "token", "").await;
+                if status["state"] == "failed" {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            let (code, _) = request(address, "GET", "/jobs/1", "token", "").await;
+            assert_eq!(code, 404);
+            let (_, jobs) = request(address, "GET", "/jobs", "token", "").await;
+            assert_eq!(jobs.as_array().map(Vec::len), Some(1));
+        };
+
+        let settings = Settings::default();
+        tokio::select! {
+            served = serve(&settings, listener, "token", 2, 1) => {
+                panic!("server stopped: {:?}", served)
+            }
+            () = client => {}
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Options that shape how a run reads and writes stores and exports
+//!
+//! The global options - `--redact`, `--tree-name`, `--scan-for-cipher`, the
+//! KDF overrides, `--cipher-key-file`, `--integrity-passphrase` and the sled
+//! tuning - are collected into the [`Settings`] of a run, which is handed to
+//! whatever needs them, like the [`crate::SessionDecoder`] of an extraction.
+//! Nothing is kept in process-wide state, so the concurrent jobs of `serve`
+//! can each run with settings of their own.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use zeroize::Zeroizing;
+
+use crate::kdf::{self, KdfSettings};
+use crate::tuning::{SledMode, Tuning};
+use crate::{passphrase, schema};
+
+/// The global options on the command line
+#[derive(Args, Debug, Clone)]
+pub struct SettingsArgs {
+    /// Shorten room, session and user IDs in logs to a prefix and a hash
+    /// (`--redact false` logs them in full)
+    #[arg(long, global = true, default_value = "true", action = clap::ArgAction::Set)]
+    pub redact: bool,
+
+    /// Read the tree matrix-sdk-sled calls DEFAULT from NAME, for forks with their own
+    /// tree names (repeatable, e.g. `inbound_group_sessions=bot_inbound_group_sessions`)
+    #[arg(
+        long,
+        global = true,
+        value_name = "DEFAULT=NAME",
+        value_parser = schema::parse_tree_override
+    )]
+    pub tree_name: Vec<(String, String)>,
+
+    /// Search the store for the store cipher if it isn't under a known key
+    #[arg(long, global = true, default_value = "false")]
+    pub scan_for_cipher: bool,
+
+    /// How the store key is derived from the passphrase, for stores whose exported
+    /// cipher records the wrong KDF
+    #[arg(long, global = true, value_enum, default_value = "auto")]
+    pub kdf: kdf::Kdf,
+
+    /// PBKDF2 rounds to use instead of the ones recorded in the exported cipher
+    #[arg(long, global = true, value_name = "N")]
+    pub kdf_rounds: Option<u32>,
+
+    /// Unlock the store cipher with the key saved by `export-cipher` instead of a passphrase
+    #[arg(long, global = true, value_name = "FILE")]
+    pub cipher_key_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub integrity_passphrase: passphrase::IntegrityPassphraseArgs,
+
+    /// Size of sled's page cache in MiB (sled's default is 1024); lower it on hosts with
+    /// little memory, raise it to iterate large stores faster
+    #[arg(long, global = true, value_name = "MB")]
+    pub sled_cache_mb: Option<u64>,
+
+    /// sled storage mode for the databases the tool opens
+    #[arg(long, global = true, value_enum)]
+    pub sled_mode: Option<SledMode>,
+
+    /// Interval of sled's background flush in milliseconds, 0 to turn it off
+    /// (sled's default is 500)
+    #[arg(long, global = true, value_name = "MS")]
+    pub sled_flush_ms: Option<u64>,
+}
+
+/// The settings of one run or job
+///
+/// Not `Debug`, as it holds the integrity passphrase and the store key.
+#[derive(Clone)]
+pub struct Settings {
+    /// Shorten identifiers in logs (see [`crate::redact`])
+    pub redact: bool,
+    /// Names of the trees a forked store uses, by default name
+    pub tree_names: HashMap<String, String>,
+    /// Search the default tree for the store cipher (see [`schema::find_store_cipher`])
+    pub scan_for_cipher: bool,
+    /// KDF overrides and the store key from `--cipher-key-file`
+    pub kdf: KdfSettings,
+    /// Key of the integrity digests of exports (see [`crate::integrity`])
+    pub integrity_passphrase: Option<Zeroizing<String>>,
+    /// Options of the sled databases opened
+    pub tuning: Tuning,
+}
+
+impl Default for Settings {
+    /// The settings of a command line without global options
+    fn default() -> Self {
+        Self {
+            redact: true,
+            tree_names: HashMap::new(),
+            scan_for_cipher: false,
+            kdf: KdfSettings::default(),
+            integrity_passphrase: None,
+            tuning: Tuning::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Settings from the command line, reading the key file and integrity passphrase
+    pub fn from_args(args: &SettingsArgs) -> Result<Self> {
+        Ok(Self {
+            redact: args.redact,
+            tree_names: args.tree_name.iter().cloned().collect(),
+            scan_for_cipher: args.scan_for_cipher,
+            kdf: KdfSettings::new(args.kdf, args.kdf_rounds, args.cipher_key_file.as_deref())?,
+            integrity_passphrase: args.integrity_passphrase.resolve()?,
+            tuning: Tuning {
+                cache_mb: args.sled_cache_mb,
+                mode: args.sled_mode,
+                flush_ms: args.sled_flush_ms,
+            },
+        })
+    }
+
+    /// The integrity passphrase, if one was given
+    pub fn integrity_passphrase(&self) -> Option<&str> {
+        self.integrity_passphrase.as_deref().map(String::as_str)
+    }
+
+    /// A `sled::Config` with the tuning of this run
+    pub fn sled_config(&self) -> sled::Config {
+        self.tuning.sled_config()
+    }
+}
@@ -0,0 +1,105 @@
+//! Secure deletion of a migrated sled store
+//!
+//! A sled crypto store holds the account's long-lived keys and every room key
+//! it received, so it shouldn't linger on disk once the migration is verified.
+//! Every file is overwritten with random data, synced to disk and truncated
+//! before it is removed, then the directories themselves are removed.
+//!
+//! Overwriting in place only reaches the original blocks on filesystems that
+//! write in place. Copy-on-write filesystems (btrfs, ZFS), journaling of data
+//! and SSD wear levelling can keep old copies around; there, full-disk
+//! encryption is the only reliable protection.
+
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+
+/// Size of the random buffer written per call
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What was shredded
+#[derive(Debug, Default)]
+pub struct ShredSummary {
+    /// Files overwritten and removed
+    pub files: usize,
+    /// Bytes overwritten per pass
+    pub bytes: u64,
+}
+
+/// Overwrite every file below `path` `passes` times and remove the directory
+pub fn shred_dir(path: &Path, passes: u32) -> Result<ShredSummary> {
+    let mut summary = ShredSummary::default();
+    shred_contents(path, passes, &mut summary)?;
+    std::fs::remove_dir(path).with_context(|| format!("Failed to remove {:?}", path))?;
+    Ok(summary)
+}
+
+fn shred_contents(dir: &Path, passes: u32, summary: &mut ShredSummary) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            shred_contents(&path, passes, summary)?;
+            std::fs::remove_dir(&path).with_context(|| format!("Failed to remove {:?}", path))?;
+        } else {
+            // Symlinks are removed without touching what they point to
+            if file_type.is_file() {
+                summary.bytes += shred_file(&path, passes)?;
+            }
+            std::fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}", path))?;
+            summary.files += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Overwrite a file with random data and truncate it; returns its former size
+fn shred_file(path: &Path, passes: u32) -> Result<u64> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {:?} for shredding", path))?;
+    let len = file.metadata()?.len();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+
+    for _ in 0..passes {
+        file.seek(SeekFrom::Start(0))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = remaining.min(CHUNK_SIZE as u64) as usize;
+            rand::thread_rng().fill_bytes(&mut buffer[..chunk]);
+            file.write_all(&buffer[..chunk])
+                .with_context(|| format!("Failed to overwrite {:?}", path))?;
+            remaining -= chunk as u64;
+        }
+        file.sync_all().with_context(|| format!("Failed to sync {:?}", path))?;
+    }
+
+    file.set_len(0)?;
+    file.sync_all()?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shred_removes_everything() {
+        let dir = crate::testing::TempDir::new("shred-test");
+        std::fs::create_dir_all(dir.join("blobs")).unwrap();
+        std::fs::write(dir.join("db"), vec![7u8; CHUNK_SIZE + 10]).unwrap();
+        std::fs::write(dir.join("blobs").join("1"), b"blob").unwrap();
+
+        let summary = shred_dir(dir.path(), 2).unwrap();
+
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.bytes, CHUNK_SIZE as u64 + 14);
+        assert!(!dir.path().exists());
+    }
+}
@@ -0,0 +1,120 @@
+//! Reading sled export snapshots
+//!
+//! sled can dump a database with `Db::export` - a list of collections, each a
+//! type (always `tree`), a name and its key/value pairs - and load one with
+//! `Db::import`, e.g. to move between sled versions. Users who only kept such a
+//! dump instead of the store directory can pass it with `--sled-snapshot`: it
+//! is loaded into a fresh sled database in a private temp directory, which is
+//! then extracted like any other store and removed at the end of the run.
+//!
+//! sled leaves writing the dump to a file to the caller. The snapshot file is
+//! a map holding the dump under `collections`, as
+//! `[[type, name, [[key, value], ...]], ...]` with byte strings as arrays of
+//! numbers, in any encoding and compression exports are read in.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::error::ExtractorError;
+use crate::format;
+use crate::settings::Settings;
+use crate::store::StoreCopy;
+
+/// The only collection type sled exports
+const TREE_COLLECTION: &[u8] = b"tree";
+
+/// One exported collection: type, name and `[key, value]` pairs
+pub type Collection = (Vec<u8>, Vec<u8>, Vec<Vec<Vec<u8>>>);
+
+/// Contents of a snapshot file
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Output of `Db::export`, with the entry iterators collected
+    pub collections: Vec<Collection>,
+}
+
+/// Load the snapshot at `path` into a sled database in a temp directory
+///
+/// Returns the guard of the temp directory and the path of the database.
+pub fn restore(settings: &Settings, path: &Path) -> Result<(StoreCopy, PathBuf)> {
+    if !path.is_file() {
+        return Err(ExtractorError::StoreNotFound(path.to_path_buf()).into());
+    }
+    info!("Reading sled snapshot {:?}", path);
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let snapshot: Snapshot = format::decode(&data).context("Failed to parse sled snapshot")?;
+
+    let copy = StoreCopy::empty()?;
+    let db = settings
+        .sled_config()
+        .path(copy.path())
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to create sled database for the snapshot")?;
+
+    let mut entries = 0;
+    for (collection_type, name, pairs) in snapshot.collections {
+        let tree_name = String::from_utf8_lossy(&name).into_owned();
+        if collection_type != TREE_COLLECTION {
+            return Err(ExtractorError::SchemaMismatch(format!(
+                "Collection {:?} has unknown type {:?}",
+                tree_name,
+                String::from_utf8_lossy(&collection_type)
+            ))
+            .into());
+        }
+
+        let tree = db.open_tree(&name).map_err(ExtractorError::SledIo)?;
+        for pair in pairs {
+            let [key, value]: [Vec<u8>; 2] = pair.try_into().map_err(|_| {
+                ExtractorError::SchemaMismatch(format!(
+                    "Entry of {:?} is not a [key, value] pair",
+                    tree_name
+                ))
+            })?;
+            tree.insert(key, value).map_err(ExtractorError::SledIo)?;
+            entries += 1;
+        }
+    }
+    db.flush().map_err(ExtractorError::SledIo)?;
+    info!("Loaded {} entries into {:?}", entries, copy.path());
+
+    // sled locks the directory while open
+    drop(db);
+    let db_path = copy.path().to_path_buf();
+    Ok((copy, db_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restores_exported_database() {
+        let dir = crate::testing::TempDir::new("snapshot-test");
+        let source = dir.join("store");
+        let db = sled::open(&source).unwrap();
+        db.open_tree("inbound_group_sessions").unwrap().insert(b"key", b"pickle").unwrap();
+        let snapshot = Snapshot {
+            collections: db
+                .export()
+                .into_iter()
+                .map(|(kind, name, pairs)| (kind, name, pairs.collect()))
+                .collect(),
+        };
+        drop(db);
+        std::fs::remove_dir_all(&source).unwrap();
+
+        let file = dir.join("store.cbor");
+        std::fs::write(&file, format::encode(&snapshot, format::OutputFormat::Cbor).unwrap())
+            .unwrap();
+        let (_copy, path) = restore(&Settings::default(), &file).unwrap();
+
+        let db = sled::open(&path).unwrap();
+        let tree = db.open_tree("inbound_group_sessions").unwrap();
+        assert_eq!(tree.get(b"key").unwrap().unwrap().as_ref(), b"pickle");
+    }
+}
@@ -0,0 +1,211 @@
+//! Spill file for long-running extractions
+//!
+//! In fault-tolerant mode extracted keys are appended to a JSON-lines spill
+//! file every N sessions and synced to disk. If the process dies, the next run
+//! loads the spill file, skips the sled entries it already covers and only
+//! processes the rest. The spill file holds plaintext keys, so it is created
+//! with mode 0600 and removed once the export has been written.
+//!
+//! Next to the spill file a checkpoint records the last processed sled key.
+//! With `--resume` iteration starts right after it instead of walking (and
+//! skipping) every entry from the start again.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{write_private_file, ExportedKeyData, FailedSession};
+
+/// A key in the spill file, together with the sled entry it came from
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpilledKey {
+    /// Raw sled key of the session entry as hex
+    pub sled_key: String,
+    /// The extracted key
+    pub key: ExportedKeyData,
+}
+
+/// Keys recovered from an earlier, interrupted run
+#[derive(Debug, Default)]
+pub struct SpillState {
+    /// Recovered keys
+    pub keys: Vec<ExportedKeyData>,
+    /// Sled keys (hex) that don't need to be processed again
+    pub done: HashSet<String>,
+}
+
+/// Load the keys of an earlier run
+///
+/// A torn last line from a crash mid-write is ignored.
+pub fn load_spill(path: &Path) -> Result<SpillState> {
+    let mut state = SpillState::default();
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open spill file {:?}", path)),
+    };
+
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.context("Failed to read spill file")?;
+        match serde_json::from_str::<SpilledKey>(&line) {
+            Ok(spilled) => {
+                state.done.insert(spilled.sled_key);
+                state.keys.push(spilled.key);
+            }
+            Err(e) => warn!("Ignoring unreadable spill line {}: {}", line_number + 1, e),
+        }
+    }
+
+    info!("Recovered {} keys from spill file {:?}", state.keys.len(), path);
+    Ok(state)
+}
+
+/// Progress of an extraction at the time of the last spill flush
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Raw sled key of the last processed entry as hex
+    pub last_key: String,
+    /// Number of entries processed up to and including `last_key`
+    pub processed: usize,
+    /// Entries that failed up to `last_key`
+    pub failed_sessions: Vec<FailedSession>,
+}
+
+/// Borrowed form of [`Checkpoint`] for writing
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    last_key: String,
+    processed: usize,
+    failed_sessions: &'a [FailedSession],
+}
+
+/// Path of the checkpoint belonging to a spill file
+pub fn checkpoint_path(spill_path: &Path) -> PathBuf {
+    let mut name = spill_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".checkpoint");
+    spill_path.with_file_name(name)
+}
+
+/// Load a checkpoint, if one was written
+pub fn load_checkpoint(path: &Path) -> Result<Option<Checkpoint>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(
+            serde_json::from_slice(&data).context("Failed to parse checkpoint")?,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read checkpoint {:?}", path)),
+    }
+}
+
+/// Atomically replace the checkpoint
+///
+/// Must only be called after the spill file was flushed, so the checkpoint
+/// never points past keys that are actually on disk.
+pub fn save_checkpoint(
+    path: &Path,
+    last_key: &[u8],
+    processed: usize,
+    failed_sessions: &[FailedSession],
+) -> Result<()> {
+    let checkpoint = CheckpointRef {
+        last_key: hex::encode(last_key),
+        processed,
+        failed_sessions,
+    };
+    let data = serde_json::to_vec(&checkpoint).context("Failed to serialize checkpoint")?;
+    write_private_file(path, &data).context("Failed to write checkpoint")
+}
+
+/// Appends keys to the spill file
+pub struct SpillWriter {
+    file: BufWriter<File>,
+}
+
+impl SpillWriter {
+    /// Open `path` for appending
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let file = options
+            .open(path)
+            .with_context(|| format!("Failed to open spill file {:?}", path))?;
+
+        // Terminate a torn last line so new entries start on a line of their own
+        let torn = if file.metadata()?.len() > 0 {
+            let mut last = [0u8; 1];
+            let mut reader = File::open(path)?;
+            reader.seek(SeekFrom::End(-1))?;
+            reader.read_exact(&mut last)?;
+            last[0] != b'\n'
+        } else {
+            false
+        };
+
+        let mut file = BufWriter::new(file);
+        if torn {
+            file.write_all(b"\n").context("Failed to write spill file")?;
+        }
+
+        Ok(Self { file })
+    }
+
+    /// Queue a key; it reaches the disk with the next [`flush`](Self::flush)
+    pub fn push(&mut self, sled_key: &[u8], key: &ExportedKeyData) -> Result<()> {
+        let spilled = SpilledKey {
+            sled_key: hex::encode(sled_key),
+            key: key.clone(),
+        };
+        serde_json::to_writer(&mut self.file, &spilled).context("Failed to write spill file")?;
+        self.file.write_all(b"\n").context("Failed to write spill file")
+    }
+
+    /// Write all pending keys and sync them to disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush().context("Failed to flush spill file")?;
+        self.file
+            .get_ref()
+            .sync_data()
+            .context("Failed to sync spill file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn test_spill_round_trip_ignores_torn_line() {
+        let dir = TempDir::new("spill-test");
+        let path = dir.join("keys.spill");
+        let key = testing::key("!room:example.org", "session");
+
+        let mut writer = SpillWriter::open(&path).unwrap();
+        writer.push(b"\x01\x02", &key).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"sled_key\":\"03")
+            .unwrap();
+
+        let state = load_spill(&path).unwrap();
+
+        assert_eq!(state.keys.len(), 1);
+        assert!(state.done.contains("0102"));
+    }
+}
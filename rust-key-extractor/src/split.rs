@@ -0,0 +1,201 @@
+//! Split exports
+//!
+//! Writes an export as a directory of smaller files instead of one monolithic
+//! file: one per room, one per N keys, or both. Every part is a complete
+//! `ExtractionOutput`, so it can be uploaded or imported on its own. Data from
+//! additional trees goes into its own part, and a `manifest.json` lists all
+//! parts with their key counts.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::format::{Compression, OutputFormat};
+use crate::trees::ExtraTreeExport;
+use crate::{build_output, ExportedKeyData, ExtractionOutput};
+
+/// File name of the manifest inside a split export directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// File stem of the part holding additional tree data
+const EXTRA_TREES_STEM: &str = "extra-trees";
+
+/// Index of a split export directory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SplitManifest {
+    /// Version of the manifest format
+    pub version: u32,
+    /// Total number of keys over all parts
+    pub total_keys: usize,
+    /// Number of failed extractions
+    pub failed_keys: usize,
+    /// The parts, in the order they were written
+    pub parts: Vec<ManifestPart>,
+}
+
+/// A single file of a split export
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestPart {
+    /// File name relative to the export directory
+    pub file: String,
+    /// Number of keys in this part
+    pub keys: usize,
+    /// Room of the keys, when split by room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+}
+
+/// One part of a split export, ready to be encoded
+pub struct OutputPart {
+    /// File name without extension
+    pub stem: String,
+    /// Room of the keys, when split by room
+    pub room_id: Option<String>,
+    /// The part's content
+    pub output: ExtractionOutput,
+}
+
+/// Replace everything but ASCII alphanumerics, `-`, `_` and `.` so room IDs
+/// can be used in file names
+fn sanitize(room_id: &str) -> String {
+    room_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// File name of a part for the given encoding and compression
+pub fn part_file_name(stem: &str, format: OutputFormat, compression: Option<Compression>) -> String {
+    match compression {
+        Some(compression) => format!("{}.{}.{}", stem, format.extension(), compression.extension()),
+        None => format!("{}.{}", stem, format.extension()),
+    }
+}
+
+/// Split an export by room and/or into chunks of at most `chunk_size` keys
+///
+/// `group_by_room` is passed on to [`build_output`] for every part.
+pub fn split_output(
+    output: &ExtractionOutput,
+    by_room: bool,
+    chunk_size: Option<usize>,
+    group_by_room: bool,
+) -> Vec<OutputPart> {
+    let mut groups: BTreeMap<Option<String>, Vec<ExportedKeyData>> = BTreeMap::new();
+    for key in &output.all_keys {
+        let group = by_room.then(|| key.room_id.clone());
+        groups.entry(group).or_default().push(key.clone());
+    }
+
+    // Every part is a complete export in the same format version
+    let part_output = |keys: Vec<ExportedKeyData>| {
+        let mut part = build_output(keys, 0, group_by_room);
+        part.version = output.version;
+        part.metadata = output.metadata.clone();
+        part.partial = output.partial;
+        part
+    };
+
+    let mut parts = Vec::new();
+    for (room_id, keys) in groups {
+        let prefix = match &room_id {
+            Some(room_id) => format!("room-{}", sanitize(room_id)),
+            None => "chunk".to_string(),
+        };
+
+        match chunk_size {
+            Some(size) => {
+                for (index, chunk) in keys.chunks(size.max(1)).enumerate() {
+                    parts.push(OutputPart {
+                        stem: format!("{}-{:04}", prefix, index + 1),
+                        room_id: room_id.clone(),
+                        output: part_output(chunk.to_vec()),
+                    });
+                }
+            }
+            None => parts.push(OutputPart {
+                stem: prefix,
+                room_id,
+                output: part_output(keys),
+            }),
+        }
+    }
+
+    if !output.extra_trees.is_empty() {
+        let mut extra = part_output(Vec::new());
+        extra.extra_trees = output.extra_trees.clone();
+        parts.push(OutputPart {
+            stem: EXTRA_TREES_STEM.to_string(),
+            room_id: None,
+            output: extra,
+        });
+    }
+
+    parts
+}
+
+/// Read a split export directory back into a single export
+///
+/// `read_part` decodes a single part file, so decryption and format detection
+/// stay with the caller.
+pub fn read_split_output(
+    dir: &Path,
+    mut read_part: impl FnMut(&Path) -> Result<ExtractionOutput>,
+) -> Result<ExtractionOutput> {
+    let manifest_json = std::fs::read(dir.join(MANIFEST_FILE))
+        .with_context(|| format!("Failed to read {} in {:?}", MANIFEST_FILE, dir))?;
+    let manifest: SplitManifest =
+        serde_json::from_slice(&manifest_json).context("Failed to parse split manifest")?;
+
+    let mut all_keys = Vec::with_capacity(manifest.total_keys);
+    let mut extra_trees = ExtraTreeExport::default();
+    let mut version = 1;
+    let mut metadata = None;
+
+    for part in &manifest.parts {
+        let output = read_part(&dir.join(&part.file))
+            .with_context(|| format!("Failed to read part {}", part.file))?;
+
+        version = output.version;
+        metadata = output.metadata.or(metadata);
+        all_keys.extend(output.all_keys);
+        if !output.extra_trees.is_empty() {
+            extra_trees = output.extra_trees;
+        }
+    }
+
+    let mut output = build_output(all_keys, manifest.failed_keys, true);
+    output.extra_trees = extra_trees;
+    output.version = version;
+    output.metadata = metadata;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::key;
+
+    #[test]
+    fn test_split_by_room_and_chunk() {
+        let output = build_output(
+            vec![key("!a:x.org", "1"), key("!a:x.org", "2"), key("!b:x.org", "3")],
+            0,
+            true,
+        );
+
+        let parts = split_output(&output, true, Some(1), true);
+        let stems: Vec<_> = parts.iter().map(|part| part.stem.as_str()).collect();
+
+        assert_eq!(stems, vec!["room-_a_x.org-0001", "room-_a_x.org-0002", "room-_b_x.org-0001"]);
+        assert!(parts.iter().all(|part| part.output.total_keys == 1));
+    }
+}
@@ -0,0 +1,494 @@
+//! State store migration
+//!
+//! Besides the crypto store, matrix-sdk-sled keeps a state store holding the
+//! sync token, filter IDs and cached room state. The sync token and filters
+//! are read straight from sled; rooms, their state events, member profiles,
+//! account data and read receipts go through the SDK's own sled state store. Everything is written into a
+//! matrix-sdk-sqlite state store, so the bot resumes syncing where it left off
+//! and knows its rooms and power levels right after the switch.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use matrix_sdk_base::store::{StateChanges, StateStore};
+use matrix_sdk_sled::SledStateStore;
+use matrix_sdk_sqlite::SqliteStateStore;
+use matrix_sdk_store_encryption::StoreCipher;
+use ruma::events::receipt::{ReceiptThread, ReceiptType};
+use ruma::events::{GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType};
+use serde::de::DeserializeOwned;
+use tracing::{debug, info, warn};
+
+use crate::settings::Settings;
+use crate::{deserialize_value, load_store_cipher, redact, ENCODE_SEPARATOR};
+
+/// Tree name for the sync token and filters in the sled state store
+pub const SESSION_TREE: &str = "session";
+
+/// Key of the sync token inside the session tree
+const SYNC_TOKEN_KEY: &str = "sync_token";
+
+/// Key prefix of saved filters inside the session tree
+const FILTER_KEY: &str = "filter";
+
+/// State event types copied for every room
+///
+/// The sled state store can only be queried per event type, so this covers
+/// everything the SDK needs to compute room names, membership, encryption
+/// settings and power levels.
+const STATE_EVENT_TYPES: &[&str] = &[
+    "m.room.create",
+    "m.room.member",
+    "m.room.power_levels",
+    "m.room.join_rules",
+    "m.room.history_visibility",
+    "m.room.guest_access",
+    "m.room.encryption",
+    "m.room.name",
+    "m.room.topic",
+    "m.room.avatar",
+    "m.room.canonical_alias",
+    "m.room.server_acl",
+    "m.room.tombstone",
+    "m.room.pinned_events",
+    "m.space.child",
+    "m.space.parent",
+];
+
+/// Global account data types copied by default
+const GLOBAL_ACCOUNT_DATA_TYPES: &[&str] = &[
+    "m.push_rules",
+    "m.direct",
+    "m.ignored_user_list",
+    "m.identity_server",
+    "m.secret_storage.default_key",
+];
+
+/// Room account data types copied by default
+const ROOM_ACCOUNT_DATA_TYPES: &[&str] = &["m.fully_read", "m.tag", "m.marked_unread"];
+
+/// Receipt types copied for every member of a room
+const RECEIPT_TYPES: &[ReceiptType] = &[ReceiptType::Read, ReceiptType::ReadPrivate];
+
+/// Read-only view on a sled state store
+pub struct SledStateReader {
+    db: sled::Db,
+    store_cipher: Option<StoreCipher>,
+}
+
+impl SledStateReader {
+    /// Open the sled state store at `path`, importing its store cipher if there is one
+    pub fn open(settings: &Settings, path: &Path, passphrase: &str) -> Result<Self> {
+        let db = settings
+            .sled_config()
+            .path(path)
+            .open()
+            .context("Failed to open sled state store")?;
+
+        let store_cipher = load_store_cipher(settings, &db, passphrase)?;
+
+        Ok(Self { db, store_cipher })
+    }
+
+    /// Encode a key the same way matrix-sdk-sled's state store does
+    ///
+    /// Every part is followed by the separator byte; with a store cipher the
+    /// result is additionally hashed with the table name.
+    fn encode_key(&self, table_name: &str, parts: &[&str]) -> Vec<u8> {
+        let mut key = Vec::new();
+        for part in parts {
+            key.extend_from_slice(part.as_bytes());
+            key.push(ENCODE_SEPARATOR);
+        }
+
+        match &self.store_cipher {
+            Some(cipher) => cipher.hash_key(table_name, &key).to_vec(),
+            None => key,
+        }
+    }
+
+    /// Look up and decode a single value
+    fn get<T: DeserializeOwned>(&self, tree_name: &str, parts: &[&str]) -> Result<Option<T>> {
+        let tree = self
+            .db
+            .open_tree(tree_name)
+            .with_context(|| format!("Failed to open tree '{}'", tree_name))?;
+
+        match tree.get(self.encode_key(tree_name, parts))? {
+            Some(value) => Ok(Some(deserialize_value(&value, self.store_cipher.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The sync token of the last successful sync
+    pub fn sync_token(&self) -> Result<Option<String>> {
+        self.get(SESSION_TREE, &[SYNC_TOKEN_KEY])
+    }
+
+    /// Names of all saved filters
+    ///
+    /// Only possible for unencrypted stores; with a store cipher the keys are
+    /// hashed and the names have to be supplied by the caller.
+    pub fn filter_names(&self) -> Result<Vec<String>> {
+        if self.store_cipher.is_some() {
+            return Ok(Vec::new());
+        }
+
+        let tree = self.db.open_tree(SESSION_TREE)?;
+        let mut prefix = FILTER_KEY.as_bytes().to_vec();
+        prefix.push(ENCODE_SEPARATOR);
+
+        let mut names = Vec::new();
+        for item in tree.scan_prefix(&prefix) {
+            let (key, _) = item?;
+            let name = key[prefix.len()..]
+                .strip_suffix(&[ENCODE_SEPARATOR])
+                .unwrap_or(&key[prefix.len()..]);
+            names.push(String::from_utf8_lossy(name).into_owned());
+        }
+
+        Ok(names)
+    }
+
+    /// The filter ID saved under the given name
+    pub fn filter(&self, name: &str) -> Result<Option<String>> {
+        self.get(SESSION_TREE, &[FILTER_KEY, name])
+    }
+}
+
+/// Summary of what was migrated
+#[derive(Debug, Default)]
+pub struct StateMigrationSummary {
+    /// Whether a sync token was found and written
+    pub sync_token: bool,
+    /// Number of filters written
+    pub filters: usize,
+    /// Number of rooms written
+    pub rooms: usize,
+    /// Number of state events written (including membership events)
+    pub state_events: usize,
+    /// Number of member profiles written
+    pub profiles: usize,
+    /// Number of global and room account data events written
+    pub account_data: usize,
+    /// Number of read receipts written
+    pub receipts: usize,
+}
+
+/// Migrate a sled state store into a SQLite state store
+///
+/// The sled store is opened twice: first raw for the sync token and filters,
+/// then through matrix-sdk-sled for rooms and members. sled holds an exclusive
+/// lock, so each handle is closed before the next one is opened. With
+/// `dry_run`, everything is read but the SQLite store is not even created; the
+/// summary counts what would have been written.
+#[allow(clippy::too_many_arguments)]
+pub async fn migrate_state(
+    settings: &Settings,
+    source_path: &Path,
+    source_passphrase: Option<&str>,
+    target_path: &Path,
+    target_passphrase: Option<&str>,
+    extra_filter_names: &[String],
+    extra_account_data_types: &[String],
+    dry_run: bool,
+) -> Result<StateMigrationSummary> {
+    let mut summary = StateMigrationSummary::default();
+
+    let source_passphrase = source_passphrase.unwrap_or("");
+    let (encrypted, (sync_token, filters)) = {
+        let source = SledStateReader::open(settings, source_path, source_passphrase)?;
+        (source.store_cipher.is_some(), read_session(&source, extra_filter_names)?)
+    };
+
+    let mut changes = match sync_token {
+        Some(token) => {
+            summary.sync_token = true;
+            StateChanges::new(token)
+        }
+        None => {
+            warn!("No sync token found - the bot will perform an initial sync");
+            StateChanges::default()
+        }
+    };
+
+    {
+        let mut builder = SledStateStore::builder();
+        builder.path(source_path.to_path_buf());
+        // Passing a passphrase to an unencrypted store would make the SDK create a cipher
+        if encrypted {
+            builder.passphrase(source_passphrase);
+        }
+        let source = builder.build().context("Failed to open sled state store")?;
+
+        let global_types = event_types(GLOBAL_ACCOUNT_DATA_TYPES, extra_account_data_types);
+        let room_types = event_types(ROOM_ACCOUNT_DATA_TYPES, extra_account_data_types);
+
+        read_account_data(&source, &global_types, &mut changes, &mut summary).await?;
+        read_rooms(settings, &source, &room_types, &mut changes, &mut summary).await?;
+    }
+
+    if dry_run {
+        info!(
+            "Would write the sync token, {} room(s) and {} state event(s) to {:?}",
+            summary.rooms, summary.state_events, target_path
+        );
+        for (name, _) in &filters {
+            info!("Would migrate filter '{}'", name);
+        }
+        summary.filters = filters.len();
+        return Ok(summary);
+    }
+
+    info!("Opening SQLite state store at: {:?}", target_path);
+    let target = SqliteStateStore::open(target_path, target_passphrase)
+        .await
+        .context("Failed to open SQLite state store")?;
+
+    info!(
+        "Writing sync token, {} room(s) and {} state event(s)",
+        summary.rooms, summary.state_events
+    );
+    target
+        .save_changes(&changes)
+        .await
+        .context("Failed to save state changes")?;
+
+    for (name, id) in &filters {
+        info!("Migrating filter '{}'", name);
+        target
+            .save_filter(name, id)
+            .await
+            .with_context(|| format!("Failed to save filter '{}'", name))?;
+        summary.filters += 1;
+    }
+
+    Ok(summary)
+}
+
+/// Sync token and `(name, filter ID)` pairs from the session tree
+type SessionData = (Option<String>, Vec<(String, String)>);
+
+/// Read the sync token and all known filters from the session tree
+fn read_session(source: &SledStateReader, extra_filter_names: &[String]) -> Result<SessionData> {
+    let sync_token = source.sync_token().context("Failed to read sync token")?;
+
+    let mut filter_names = source.filter_names()?;
+    for name in extra_filter_names {
+        if !filter_names.contains(name) {
+            filter_names.push(name.clone());
+        }
+    }
+    if filter_names.is_empty() && source.store_cipher.is_some() {
+        warn!("Store is encrypted - pass --filter-name to migrate filters");
+    }
+
+    let mut filters = Vec::new();
+    for name in &filter_names {
+        match source.filter(name)? {
+            Some(id) => filters.push((name.clone(), id)),
+            None => warn!("Filter '{}' not found in sled state store", name),
+        }
+    }
+
+    Ok((sync_token, filters))
+}
+
+/// The default event types followed by any extra ones given on the command line
+fn event_types<T: for<'a> From<&'a str>>(defaults: &[&str], extra: &[String]) -> Vec<T> {
+    let mut names: Vec<&str> = defaults.to_vec();
+    for name in extra {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    names.into_iter().map(T::from).collect()
+}
+
+/// Collect global account data events into `changes`
+///
+/// Account data can only be looked up by type, so only the given types are copied.
+async fn read_account_data(
+    source: &SledStateStore,
+    event_types: &[GlobalAccountDataEventType],
+    changes: &mut StateChanges,
+    summary: &mut StateMigrationSummary,
+) -> Result<()> {
+    for event_type in event_types {
+        let event = source
+            .get_account_data_event(event_type.clone())
+            .await
+            .with_context(|| format!("Failed to read {} account data", event_type))?;
+
+        if let Some(event) = event {
+            debug!("Found {} account data", event_type);
+            changes.account_data.insert(event_type.clone(), event);
+            summary.account_data += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect room infos, state events, member profiles, room account data and
+/// read receipts into `changes`
+async fn read_rooms(
+    settings: &Settings,
+    source: &SledStateStore,
+    account_data_types: &[RoomAccountDataEventType],
+    changes: &mut StateChanges,
+    summary: &mut StateMigrationSummary,
+) -> Result<()> {
+    let room_infos = source
+        .get_room_infos()
+        .await
+        .context("Failed to read room infos")?;
+    info!("Found {} room(s) in sled state store", room_infos.len());
+
+    for room_info in room_infos {
+        let room_id = room_info.room_id().to_owned();
+        debug!("Reading state of {}", redact::id(settings, &room_id));
+
+        for event_type in STATE_EVENT_TYPES {
+            let event_type = StateEventType::from(*event_type);
+            let events = source
+                .get_state_events(&room_id, event_type.clone())
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to read {} events of {}",
+                        event_type,
+                        redact::id(settings, &room_id)
+                    )
+                })?;
+
+            for event in events {
+                let state_key = match event.get_field::<String>("state_key") {
+                    Ok(Some(state_key)) => state_key,
+                    _ => {
+                        warn!(
+                            "Skipping {} event without state key in {}",
+                            event_type,
+                            redact::id(settings, &room_id)
+                        );
+                        continue;
+                    }
+                };
+
+                changes
+                    .state
+                    .entry(room_id.clone())
+                    .or_default()
+                    .entry(event_type.clone())
+                    .or_default()
+                    .insert(state_key, event);
+                summary.state_events += 1;
+            }
+        }
+
+        let mut user_ids = BTreeSet::new();
+        user_ids.extend(source.get_joined_user_ids(&room_id).await?);
+        user_ids.extend(source.get_invited_user_ids(&room_id).await?);
+
+        for user_id in user_ids {
+            if let Some(profile) = source.get_profile(&room_id, &user_id).await? {
+                changes
+                    .profiles
+                    .entry(room_id.clone())
+                    .or_default()
+                    .insert(user_id.clone(), profile);
+                summary.profiles += 1;
+            }
+
+            for receipt_type in RECEIPT_TYPES {
+                let receipt = source
+                    .get_user_room_receipt_event(
+                        &room_id,
+                        receipt_type.clone(),
+                        ReceiptThread::Unthreaded,
+                        &user_id,
+                    )
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to read receipts of {} in {}",
+                            redact::id(settings, &user_id),
+                            redact::id(settings, &room_id)
+                        )
+                    })?;
+
+                if let Some((event_id, receipt)) = receipt {
+                    changes
+                        .receipts
+                        .entry(room_id.clone())
+                        .or_default()
+                        .0
+                        .entry(event_id)
+                        .or_default()
+                        .entry(receipt_type.clone())
+                        .or_default()
+                        .insert(user_id.clone(), receipt);
+                    summary.receipts += 1;
+                }
+            }
+        }
+
+        for event_type in account_data_types {
+            let event = source
+                .get_room_account_data_event(&room_id, event_type.clone())
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to read {} account data of {}",
+                        event_type,
+                        redact::id(settings, &room_id)
+                    )
+                })?;
+
+            if let Some(event) = event {
+                changes
+                    .room_account_data
+                    .entry(room_id.clone())
+                    .or_default()
+                    .insert(event_type.clone(), event);
+                summary.account_data += 1;
+            }
+        }
+
+        changes.add_room(room_info);
+        summary.rooms += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_names_from_unencrypted_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(SESSION_TREE).unwrap();
+        let reader = SledStateReader { db, store_cipher: None };
+
+        tree.insert(reader.encode_key(SESSION_TREE, &[SYNC_TOKEN_KEY]), br#""s123""#.to_vec())
+            .unwrap();
+        tree.insert(reader.encode_key(SESSION_TREE, &[FILTER_KEY, "sync"]), br#""f1""#.to_vec())
+            .unwrap();
+
+        assert_eq!(reader.sync_token().unwrap().as_deref(), Some("s123"));
+        assert_eq!(reader.filter_names().unwrap(), vec!["sync".to_string()]);
+        assert_eq!(reader.filter("sync").unwrap().as_deref(), Some("f1"));
+    }
+
+    #[test]
+    fn test_event_types_appends_extra_once() {
+        let extra = vec!["m.tag".to_string(), "org.example.settings".to_string()];
+        let types: Vec<String> = event_types(ROOM_ACCOUNT_DATA_TYPES, &extra);
+
+        assert_eq!(types.len(), ROOM_ACCOUNT_DATA_TYPES.len() + 1);
+        assert_eq!(types.last().map(String::as_str), Some("org.example.settings"));
+    }
+}
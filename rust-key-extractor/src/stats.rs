@@ -0,0 +1,174 @@
+//! Key statistics for a sled crypto store
+//!
+//! `stats` decodes every inbound group session without writing anything and
+//! summarizes them per room and per sender, so operators can check that a store
+//! holds what they expect before migrating it. Entries that can't be decoded
+//! are counted, not fatal.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::error::ExtractorError;
+use crate::progress::Progress;
+use crate::settings::Settings;
+use crate::{
+    convert_exported_key, decode_session, load_store_cipher, schema, throttle, FailureCategory,
+};
+
+/// Upper bounds (exclusive) of the first known index buckets
+const INDEX_BUCKETS: &[(u32, &str)] = &[
+    (1, "0"),
+    (10, "1-9"),
+    (100, "10-99"),
+    (1000, "100-999"),
+    (u32::MAX, "1000+"),
+];
+
+/// Statistics over all inbound group sessions of a store
+#[derive(Debug, Default, Serialize)]
+pub struct KeyStats {
+    /// Sessions decoded successfully
+    pub sessions: usize,
+    /// Entries that could not be decoded
+    pub failed: usize,
+    /// Sessions per room
+    pub keys_per_room: BTreeMap<String, usize>,
+    /// Sessions per sender (Curve25519) key
+    pub keys_per_sender: BTreeMap<String, usize>,
+    /// Sessions received by forwarding rather than directly from the sender
+    pub forwarded: usize,
+    /// Number of sessions per first known message index range
+    ///
+    /// Sessions not starting at index 0 can't decrypt the first messages of
+    /// their session, typically because they were forwarded or shared late.
+    pub first_known_index: BTreeMap<&'static str, usize>,
+}
+
+impl KeyStats {
+    /// Count a decoded session
+    pub fn add(&mut self, room_id: &str, sender_key: &str, forwarded: bool, first_known_index: u32) {
+        self.sessions += 1;
+        *self.keys_per_room.entry(room_id.to_string()).or_default() += 1;
+        *self.keys_per_sender.entry(sender_key.to_string()).or_default() += 1;
+        if forwarded {
+            self.forwarded += 1;
+        }
+
+        let bucket = INDEX_BUCKETS
+            .iter()
+            .find(|(bound, _)| first_known_index < *bound)
+            .map_or("1000+", |(_, label)| label);
+        *self.first_known_index.entry(bucket).or_default() += 1;
+    }
+}
+
+/// Decode all inbound group sessions of the store at `path` and collect statistics
+pub async fn collect_stats(
+    settings: &Settings,
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<KeyStats> {
+    let db = settings
+        .sled_config()
+        .path(path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+    let store_cipher = load_store_cipher(settings, &db, passphrase.unwrap_or(""))?;
+    let schema = schema::detect(settings, &db)?;
+    let tree = db
+        .open_tree(&schema.inbound_group_sessions)
+        .context("Failed to open inbound group sessions tree")?;
+
+    let mut stats = KeyStats::default();
+    let mut progress = Progress::new("Reading sessions", 0, tree.len() as u64);
+
+    for (index, item) in throttle::iter(tree.iter()).enumerate() {
+        progress.inc(1);
+
+        let session = item
+            .map_err(|e| (FailureCategory::SledRead, format!("Sled read error: {}", e)))
+            .and_then(|(_, value)| decode_session(&value, store_cipher.as_ref()));
+        match session {
+            Ok(session) => {
+                let exported = convert_exported_key(&session.export().await);
+                stats.add(
+                    &exported.room_id,
+                    &exported.sender_key,
+                    !exported.forwarding_curve25519_key_chain.is_empty(),
+                    session.first_known_index(),
+                );
+            }
+            Err((_, error)) => {
+                warn!("Session {}: {}", index, error);
+                stats.failed += 1;
+            }
+        }
+    }
+    progress.finish();
+
+    info!("Read {} sessions, {} failed", stats.sessions, stats.failed);
+    Ok(stats)
+}
+
+/// The `limit` largest entries of a count map, largest first
+fn top(counts: &BTreeMap<String, usize>, limit: usize) -> Vec<(&String, &usize)> {
+    let mut entries: Vec<_> = counts.iter().collect();
+    entries.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    entries.truncate(limit);
+    entries
+}
+
+/// Print the statistics, listing the `limit` largest rooms and senders
+pub fn print_stats(stats: &KeyStats, limit: usize) {
+    println!("Sessions:           {}", stats.sessions);
+    println!("Undecodable:        {}", stats.failed);
+    println!("Rooms:              {}", stats.keys_per_room.len());
+    println!("Unique sender keys: {}", stats.keys_per_sender.len());
+    println!("Forwarded:          {}", stats.forwarded);
+
+    println!();
+    println!("First known index:");
+    for (_, label) in INDEX_BUCKETS {
+        let count = stats.first_known_index.get(label).copied().unwrap_or(0);
+        println!("  {:<10} {}", label, count);
+    }
+
+    println!();
+    println!("Top rooms:");
+    for (room_id, count) in top(&stats.keys_per_room, limit) {
+        println!("  {:>8}  {}", count, room_id);
+    }
+
+    println!();
+    println!("Top senders:");
+    for (sender_key, count) in top(&stats.keys_per_sender, limit) {
+        println!("  {:>8}  {}", count, sender_key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_buckets_and_counts() {
+        let mut stats = KeyStats::default();
+        stats.add("!a:x.org", "sender1", false, 0);
+        stats.add("!a:x.org", "sender2", true, 5);
+        stats.add("!b:x.org", "sender1", false, 1500);
+
+        assert_eq!(stats.sessions, 3);
+        assert_eq!(stats.keys_per_room["!a:x.org"], 2);
+        assert_eq!(stats.keys_per_sender.len(), 2);
+        assert_eq!(stats.forwarded, 1);
+        assert_eq!(stats.first_known_index["0"], 1);
+        assert_eq!(stats.first_known_index["1-9"], 1);
+        assert_eq!(stats.first_known_index["1000+"], 1);
+        assert_eq!(top(&stats.keys_per_room, 1)[0].0, "!a:x.org");
+    }
+}
@@ -0,0 +1,260 @@
+//! Access to the source sled store
+//!
+//! Opening a sled database is not read-only: sled may recover and rewrite its
+//! log, flush on drop and take a file lock. With `--copy-first` the extractor
+//! works on a private copy of the store directory instead, so the bot's data is
+//! never modified, even if the extraction crashes half-way.
+//!
+//! sled holds an exclusive lock on its `db` file while open. Before touching a
+//! store the tool checks that lock, so a bot that is still running is reported
+//! clearly instead of surfacing as an opaque sled I/O error.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use crate::error::ExtractorError;
+
+/// File inside a sled directory that sled locks while the database is open
+const LOCK_FILE: &str = "db";
+
+/// A process holding a sled store open
+#[derive(Debug)]
+pub struct LockHolder {
+    /// Process ID
+    pub pid: u32,
+    /// Command name, if readable
+    pub name: Option<String>,
+}
+
+/// Whether the sled store at `path` is locked by another process
+pub fn is_locked(path: &Path) -> Result<bool> {
+    let file = match std::fs::File::open(path.join(LOCK_FILE)) {
+        Ok(file) => file,
+        // Not a sled directory (yet) - sled itself will complain if needed
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {:?}", path)),
+    };
+
+    match file.try_lock() {
+        Ok(()) => Ok(false),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(true),
+        Err(std::fs::TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("Failed to check lock on {:?}", path))
+        }
+    }
+}
+
+/// Find the processes that have the store's lock file open
+///
+/// Only supported on Linux, where `/proc` lists open files; elsewhere this
+/// returns an empty list. Processes of other users are not visible.
+pub fn lock_holders(path: &Path) -> Vec<LockHolder> {
+    #[cfg(target_os = "linux")]
+    {
+        let Ok(lock_file) = path.join(LOCK_FILE).canonicalize() else {
+            return Vec::new();
+        };
+        let Ok(processes) = std::fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+
+        let mut holders = Vec::new();
+        for process in processes.flatten() {
+            let Some(pid) = process.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+                continue;
+            };
+            if pid == std::process::id() {
+                continue;
+            }
+            let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+                continue;
+            };
+            let has_open = fds
+                .flatten()
+                .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|target| target == lock_file));
+            if has_open {
+                let name = std::fs::read_to_string(process.path().join("comm"))
+                    .ok()
+                    .map(|name| name.trim().to_string());
+                holders.push(LockHolder { pid, name });
+            }
+        }
+        holders
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = path;
+        Vec::new()
+    }
+}
+
+/// Refuse to continue if the store at `path` is locked, unless `force` is set
+///
+/// Returns whether the store is locked, in which case the caller has to work
+/// on a copy: sled can't open a store that another process holds.
+pub fn check_unlocked(path: &Path, force: bool) -> Result<bool> {
+    if !is_locked(path)? {
+        return Ok(false);
+    }
+
+    let holders = lock_holders(path);
+    let holders = if holders.is_empty() {
+        "an unknown process".to_string()
+    } else {
+        holders
+            .iter()
+            .map(|holder| match &holder.name {
+                Some(name) => format!("{} (pid {})", name, holder.pid),
+                None => format!("pid {}", holder.pid),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    if !force {
+        return Err(ExtractorError::StoreLocked {
+            path: path.to_path_buf(),
+            holders,
+        }
+        .into());
+    }
+
+    warn!(
+        "Sled store {:?} is locked by {}; continuing on a copy because of --force. \
+         Data the bot has not flushed yet will be missing.",
+        path, holders
+    );
+    Ok(true)
+}
+
+/// Check the lock of the store at `path` and copy it if needed
+///
+/// With `copy_first`, or when the store is locked and `force` is set, `path` is
+/// pointed at a fresh copy. The copy lives as long as the returned guard.
+pub fn prepare_source(path: &mut PathBuf, copy_first: bool, force: bool) -> Result<Option<StoreCopy>> {
+    let locked = check_unlocked(path, force)?;
+    if !copy_first && !locked {
+        return Ok(None);
+    }
+
+    let copy = StoreCopy::create(path)?;
+    *path = copy.path().to_path_buf();
+    Ok(Some(copy))
+}
+
+/// A temporary copy of a sled store, removed again when dropped
+pub struct StoreCopy {
+    path: PathBuf,
+}
+
+impl StoreCopy {
+    /// Copy the store directory at `source` into a fresh private temp directory
+    pub fn create(source: &Path) -> Result<Self> {
+        // From here on the guard cleans up, also when copying fails
+        let copy = Self::empty()?;
+        info!("Copying sled store {:?} to {:?}", source, copy.path);
+        let bytes = copy_dir(source, &copy.path)
+            .with_context(|| format!("Failed to copy sled store {:?}", source))?;
+        info!("Copied {} bytes; the original store will not be touched", bytes);
+
+        Ok(copy)
+    }
+
+    /// Create a fresh private temp directory to fill with a store
+    pub fn empty() -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or_default();
+        let path = std::env::temp_dir().join(format!(
+            "sled-key-extractor-{}-{}",
+            std::process::id(),
+            nanos
+        ));
+
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder
+            .create(&path)
+            .with_context(|| format!("Failed to create temp directory {:?}", path))?;
+
+        Ok(Self { path })
+    }
+
+    /// Path of the copy
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StoreCopy {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove store copy {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Recursively copy the contents of `source` into the existing directory `target`
+///
+/// Returns the number of bytes copied.
+fn copy_dir(source: &Path, target: &Path) -> std::io::Result<u64> {
+    let mut bytes = 0;
+
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let target_path = target.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir(&target_path)?;
+            bytes += copy_dir(&entry.path(), &target_path)?;
+        } else {
+            bytes += std::fs::copy(entry.path(), &target_path)?;
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_store_copy_is_removed_on_drop() {
+        let dir = TempDir::new("store-test");
+        let source = dir.join("store");
+        std::fs::create_dir_all(source.join("blobs")).unwrap();
+        std::fs::write(source.join("db"), b"data").unwrap();
+        std::fs::write(source.join("blobs").join("1"), b"blob").unwrap();
+
+        let copy = StoreCopy::create(&source).unwrap();
+        let copy_path = copy.path().to_path_buf();
+        assert_eq!(std::fs::read(copy_path.join("blobs").join("1")).unwrap(), b"blob");
+
+        drop(copy);
+
+        assert!(!copy_path.exists());
+    }
+
+    #[test]
+    fn test_detects_locked_store() {
+        let dir = TempDir::new("lock-test");
+        let path = dir.join("store");
+        let db = sled::open(&path).unwrap();
+        // Locks are per open file description, so our own sled handle counts as foreign
+        assert!(is_locked(&path).unwrap());
+
+        drop(db);
+        assert!(!is_locked(&path).unwrap());
+    }
+}
@@ -0,0 +1,191 @@
+//! Streaming export writer
+//!
+//! Writes a JSON export key by key while the store is being read, instead of
+//! collecting every key and serializing the whole `ExtractionOutput` at the end.
+//! Only a counter per room is kept in memory. `keys_by_room` can't be streamed -
+//! it needs all keys of a room at once - so it stays empty and `keys_per_room`
+//! is written instead.
+//!
+//! `all_keys` comes first with one key per line; the totals and any additional
+//! tree data follow it at the end of the object. The file is written to
+//! `<output>.tmp` and only renamed into place by [`StreamWriter::finish`].
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::format::{Compression, CompressWriter};
+use crate::integrity::{FileDigest, FileIntegrity};
+use crate::metadata::ExportMetadata;
+use crate::settings::Settings;
+use crate::trees::ExtraTreeExport;
+use crate::{create_private_tmp, persist_private_tmp, ExportedKeyData};
+
+/// Fields written after `all_keys`
+#[derive(Serialize)]
+struct Trailer<'a> {
+    total_keys: usize,
+    failed_keys: usize,
+    keys_by_room: BTreeMap<String, ()>,
+    keys_per_room: &'a BTreeMap<String, usize>,
+    #[serde(flatten)]
+    extra_trees: &'a ExtraTreeExport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a ExportMetadata>,
+    integrity: FileIntegrity,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+}
+
+/// Totals of a streamed export
+#[derive(Debug)]
+pub struct StreamedExport {
+    /// Number of keys written
+    pub total_keys: usize,
+    /// Number of keys per room
+    pub keys_per_room: BTreeMap<String, usize>,
+}
+
+/// Writes keys to a JSON export as they are extracted
+pub struct StreamWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    out: CompressWriter<BufWriter<File>>,
+    total_keys: usize,
+    keys_per_room: BTreeMap<String, usize>,
+    digest: FileDigest,
+    partial: bool,
+}
+
+impl StreamWriter {
+    /// Start a new export in format `version` at `path`
+    pub fn create(
+        settings: &Settings,
+        path: &Path,
+        compression: Option<Compression>,
+        version: u32,
+    ) -> Result<Self> {
+        let (file, tmp_path) = create_private_tmp(path)
+            .with_context(|| format!("Failed to create output file {:?}", path))?;
+        let mut out = CompressWriter::new(BufWriter::new(file), compression)?;
+        write!(out, "{{\"version\":{},\"all_keys\":[", version)
+            .context("Failed to write output file")?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            tmp_path,
+            out,
+            total_keys: 0,
+            keys_per_room: BTreeMap::new(),
+            digest: FileDigest::new(version, settings.integrity_passphrase()),
+            partial: false,
+        })
+    }
+
+    /// Mark the export as partial and move it to `path` instead when finished
+    pub fn mark_partial(&mut self, path: &Path) {
+        self.path = path.to_path_buf();
+        self.partial = true;
+    }
+
+    /// Append a key to `all_keys`
+    pub fn write_key(&mut self, key: &ExportedKeyData) -> Result<()> {
+        let separator: &[u8] = if self.total_keys == 0 { b"\n" } else { b",\n" };
+        self.out
+            .write_all(separator)
+            .context("Failed to write output file")?;
+        serde_json::to_writer(&mut self.out, key).context("Failed to serialize key")?;
+
+        self.digest.add_key(key);
+        self.total_keys += 1;
+        *self.keys_per_room.entry(key.room_id.clone()).or_default() += 1;
+        Ok(())
+    }
+
+    /// Write the totals, additional tree data and metadata, then move the file into place
+    pub fn finish(
+        mut self,
+        failed_keys: usize,
+        extra_trees: &ExtraTreeExport,
+        metadata: Option<&ExportMetadata>,
+    ) -> Result<StreamedExport> {
+        let integrity =
+            self.digest.finish(self.total_keys, failed_keys, extra_trees, metadata)?;
+        let trailer = serde_json::to_vec(&Trailer {
+            total_keys: self.total_keys,
+            failed_keys,
+            keys_by_room: BTreeMap::new(),
+            keys_per_room: &self.keys_per_room,
+            extra_trees,
+            metadata,
+            integrity,
+            partial: self.partial,
+        })
+        .context("Failed to serialize output")?;
+
+        // Continue the open object with the trailer's fields
+        self.out
+            .write_all(b"\n],")
+            .and_then(|()| self.out.write_all(&trailer[1..]))
+            .context("Failed to write output file")?;
+
+        let file = self
+            .out
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .context("Failed to write output file")?;
+        file.sync_all().context("Failed to sync output file")?;
+        drop(file);
+        persist_private_tmp(&self.tmp_path, &self.path).context("Failed to write output file")?;
+
+        Ok(StreamedExport {
+            total_keys: self.total_keys,
+            keys_per_room: self.keys_per_room,
+        })
+    }
+
+    /// Give up on the export and remove the partially written file
+    pub fn abort(self) {
+        drop(self.out);
+        if let Err(e) = std::fs::remove_file(&self.tmp_path) {
+            tracing::warn!("Failed to remove {:?}: {}", self.tmp_path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{key, TempDir};
+    use crate::ExtractionOutput;
+
+    #[test]
+    fn test_streamed_export_reads_back() {
+        let dir = TempDir::new("stream-test");
+        let path = dir.join("keys.json.zst");
+
+        let settings = Settings::default();
+        let mut writer =
+            StreamWriter::create(&settings, &path, Some(Compression::Zstd), 2).unwrap();
+        for (room_id, session_id) in [("!a:x.org", "1"), ("!a:x.org", "2"), ("!b:x.org", "3")] {
+            writer.write_key(&key(room_id, session_id)).unwrap();
+        }
+        writer.finish(1, &ExtraTreeExport::default(), None).unwrap();
+
+        let output: ExtractionOutput =
+            crate::format::decode(&std::fs::read(&path).unwrap()).unwrap();
+
+        assert_eq!(output.total_keys, 3);
+        assert_eq!(output.failed_keys, 1);
+        assert_eq!(output.all_keys.len(), 3);
+        assert!(output.keys_by_room.is_empty());
+        assert_eq!(output.keys_per_room.get("!a:x.org"), Some(&2));
+        assert_eq!(output.version, 2);
+        crate::integrity::verify(&output, settings.integrity_passphrase()).unwrap();
+    }
+}
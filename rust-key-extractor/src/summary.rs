@@ -0,0 +1,301 @@
+//! Human-readable migration reports
+//!
+//! `--report` renders the same facts as the JSON run report (see
+//! [`crate::report`]) for people rather than tools: rooms and their key
+//! counts, failures grouped by reason and what to do next. The format follows
+//! the file extension - `.html`/`.htm` gives a standalone HTML page, anything
+//! else Markdown - so the result can be mailed or pasted into a ticket after a
+//! migration window.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::report::RunReport;
+use crate::FailedSession;
+
+/// Number of rooms listed before the rest are summarized in one line
+const MAX_ROOMS: usize = 200;
+
+/// Number of individual failures listed
+const MAX_FAILURES: usize = 50;
+
+/// Output format of a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// GitHub-flavored Markdown
+    Markdown,
+    /// Standalone HTML page
+    Html,
+}
+
+impl ReportFormat {
+    /// Pick the format from the extension of `path`
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm") => {
+                Self::Html
+            }
+            _ => Self::Markdown,
+        }
+    }
+}
+
+/// Collects the report in either format
+struct Writer {
+    format: ReportFormat,
+    out: String,
+}
+
+impl Writer {
+    fn heading(&mut self, level: usize, text: &str) {
+        match self.format {
+            ReportFormat::Markdown => {
+                self.out.push_str(&format!("{} {}\n\n", "#".repeat(level), text))
+            }
+            ReportFormat::Html => {
+                self.out.push_str(&format!("<h{0}>{1}</h{0}>\n", level, escape(text)))
+            }
+        }
+    }
+
+    fn paragraph(&mut self, text: &str) {
+        match self.format {
+            ReportFormat::Markdown => self.out.push_str(&format!("{}\n\n", text)),
+            ReportFormat::Html => self.out.push_str(&format!("<p>{}</p>\n", escape(text))),
+        }
+    }
+
+    fn list(&mut self, items: &[String]) {
+        match self.format {
+            ReportFormat::Markdown => {
+                for item in items {
+                    self.out.push_str(&format!("- {}\n", item));
+                }
+                self.out.push('\n');
+            }
+            ReportFormat::Html => {
+                self.out.push_str("<ul>\n");
+                for item in items {
+                    self.out.push_str(&format!("<li>{}</li>\n", escape(item)));
+                }
+                self.out.push_str("</ul>\n");
+            }
+        }
+    }
+
+    fn table(&mut self, header: &[&str], rows: &[Vec<String>]) {
+        match self.format {
+            ReportFormat::Markdown => {
+                let cell = |text: &str| text.replace('|', "\\|");
+                self.out.push_str(&format!("| {} |\n", header.join(" | ")));
+                self.out.push_str(&format!("|{}\n", "---|".repeat(header.len())));
+                for row in rows {
+                    let cells: Vec<String> = row.iter().map(|text| cell(text)).collect();
+                    self.out.push_str(&format!("| {} |\n", cells.join(" | ")));
+                }
+                self.out.push('\n');
+            }
+            ReportFormat::Html => {
+                self.out.push_str("<table>\n<tr>");
+                for title in header {
+                    self.out.push_str(&format!("<th>{}</th>", escape(title)));
+                }
+                self.out.push_str("</tr>\n");
+                for row in rows {
+                    self.out.push_str("<tr>");
+                    for text in row {
+                        self.out.push_str(&format!("<td>{}</td>", escape(text)));
+                    }
+                    self.out.push_str("</tr>\n");
+                }
+                self.out.push_str("</table>\n");
+            }
+        }
+    }
+}
+
+/// Escape text for HTML element content
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Advice for the operator, based on how the run went
+fn next_steps(report: &RunReport) -> Vec<String> {
+    let mut steps = Vec::new();
+
+    if report.options.no_secrets {
+        steps.push(
+            "This export was written with --no-secrets and holds no session keys; \
+             run extract again without it to produce an export that can be uploaded."
+                .to_string(),
+        );
+    } else if report.total_keys == 0 {
+        steps.push(
+            "No keys were extracted. Run `sled-key-extractor doctor` against the store \
+             to check the path and passphrase."
+                .to_string(),
+        );
+    } else {
+        steps.push("Upload the keys to the server-side backup with `upload-keys`.".to_string());
+        steps.push(
+            "Confirm the backup with `verify-backup` before switching the bot to the new store."
+                .to_string(),
+        );
+    }
+
+    if report.failed_keys > 0 {
+        steps.push(format!(
+            "{} entries could not be extracted. Review the failed-sessions file and the \
+             reasons below; keys for affected rooms may have to be re-shared by other devices.",
+            report.failed_keys
+        ));
+    }
+
+    if !report.options.encrypted && !report.options.no_secrets && report.total_keys > 0 {
+        steps.push(
+            "The export holds plaintext keys. Delete it once the upload has been verified."
+                .to_string(),
+        );
+    }
+
+    steps
+}
+
+/// Render a report of an `extract` run
+pub fn render(
+    format: ReportFormat,
+    report: &RunReport,
+    room_counts: &BTreeMap<String, usize>,
+    failures: &[FailedSession],
+) -> String {
+    let mut w = Writer {
+        format,
+        out: String::new(),
+    };
+
+    if format == ReportFormat::Html {
+        w.out.push_str(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Key migration report</title>\n\
+             <style>body{font-family:sans-serif;max-width:60em;margin:2em auto}\
+             table{border-collapse:collapse}th,td{border:1px solid #ccc;padding:.2em .6em;text-align:left}</style>\n\
+             </head>\n<body>\n",
+        );
+    }
+
+    w.heading(1, "Key migration report");
+    w.table(
+        &["", ""],
+        &[
+            vec!["Store".to_string(), report.sled_path.display().to_string()],
+            vec!["Export".to_string(), report.output.display().to_string()],
+            vec!["Keys exported".to_string(), report.total_keys.to_string()],
+            vec!["Rooms".to_string(), report.rooms.to_string()],
+            vec!["Failed entries".to_string(), report.failed_keys.to_string()],
+            vec!["Duration".to_string(), format!("{:.1} s", report.timings.total)],
+            vec!["Tool version".to_string(), report.tool_version.clone()],
+        ],
+    );
+
+    w.heading(2, "Next steps");
+    w.list(&next_steps(report));
+
+    if report.trees.len() > 1 {
+        w.heading(2, "Trees");
+        let rows: Vec<Vec<String>> = report
+            .trees
+            .iter()
+            .map(|(tree, count)| {
+                vec![tree.clone(), count.extracted.to_string(), count.failed.to_string()]
+            })
+            .collect();
+        w.table(&["Tree", "Extracted", "Failed"], &rows);
+    }
+
+    w.heading(2, "Rooms");
+    if room_counts.is_empty() {
+        w.paragraph("No rooms.");
+    } else {
+        let mut rooms: Vec<_> = room_counts.iter().collect();
+        rooms.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let rows: Vec<Vec<String>> = rooms
+            .iter()
+            .take(MAX_ROOMS)
+            .map(|(room_id, count)| vec![room_id.to_string(), count.to_string()])
+            .collect();
+        w.table(&["Room", "Keys"], &rows);
+        if rooms.len() > MAX_ROOMS {
+            w.paragraph(&format!("… and {} more rooms.", rooms.len() - MAX_ROOMS));
+        }
+    }
+
+    if !failures.is_empty() {
+        w.heading(2, "Failures");
+
+        let rows: Vec<Vec<String>> = report
+            .failures_by_category
+            .iter()
+            .map(|(category, count)| vec![category.clone(), count.to_string()])
+            .collect();
+        w.table(&["Category", "Entries"], &rows);
+
+        let mut reasons: BTreeMap<&str, usize> = BTreeMap::new();
+        for failure in failures {
+            *reasons.entry(failure.error.as_str()).or_default() += 1;
+        }
+        let mut reasons: Vec<_> = reasons.into_iter().collect();
+        reasons.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let rows: Vec<Vec<String>> = reasons
+            .iter()
+            .map(|(reason, count)| vec![reason.to_string(), count.to_string()])
+            .collect();
+        w.table(&["Reason", "Entries"], &rows);
+
+        let rows: Vec<Vec<String>> = failures
+            .iter()
+            .take(MAX_FAILURES)
+            .map(|failure| {
+                vec![
+                    failure.tree.clone(),
+                    failure.index.to_string(),
+                    failure.category.label().to_string(),
+                    failure.error.clone(),
+                ]
+            })
+            .collect();
+        w.table(&["Tree", "Entry", "Category", "Error"], &rows);
+        if failures.len() > MAX_FAILURES {
+            w.paragraph(&format!(
+                "… and {} more; see the failed-sessions file for all of them.",
+                failures.len() - MAX_FAILURES
+            ));
+        }
+    }
+
+    if format == ReportFormat::Html {
+        w.out.push_str("</body>\n</html>\n");
+    }
+
+    w.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_path_and_html_escaping() {
+        assert_eq!(ReportFormat::from_path(Path::new("report.md")), ReportFormat::Markdown);
+        assert_eq!(ReportFormat::from_path(Path::new("report.HTML")), ReportFormat::Html);
+
+        let mut w = Writer {
+            format: ReportFormat::Html,
+            out: String::new(),
+        };
+        w.table(&["Room"], &[vec!["<script>".to_string()]]);
+        assert!(w.out.contains("&lt;script&gt;"));
+    }
+}
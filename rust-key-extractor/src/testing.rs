@@ -0,0 +1,75 @@
+//! Helpers shared by the unit tests
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ExportedKeyData;
+
+/// A Megolm key of `session_id` in `room_id` without a secret
+///
+/// The sender key is made up, every other field is empty; tests set the
+/// fields they look at.
+pub fn key(room_id: &str, session_id: &str) -> ExportedKeyData {
+    ExportedKeyData {
+        room_id: room_id.to_string(),
+        session_id: session_id.to_string(),
+        algorithm: "m.megolm.v1.aes-sha2".to_string(),
+        session_key: String::new(),
+        sender_key: "c2VuZGVy".to_string(),
+        sender_claimed_keys: Default::default(),
+        forwarding_curve25519_key_chain: Vec::new(),
+        first_known_index: None,
+        backed_up: None,
+        imported: None,
+        extra: Default::default(),
+        sha256: String::new(),
+    }
+}
+
+/// [`key`] with `session_key` as its secret
+pub fn key_with_secret(room_id: &str, session_id: &str, session_key: &str) -> ExportedKeyData {
+    let mut key = key(room_id, session_id);
+    key.session_key = session_key.to_string();
+    key
+}
+
+/// A fresh directory in the temp directory
+///
+/// Removed with everything in it when dropped, so a failing test doesn't
+/// leave it behind.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create a directory whose name starts with `name`
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+
+    /// Path of the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of `name` in the directory
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,140 @@
+//! Limiting the disk load of an extraction
+//!
+//! Reading a 30 GB store as fast as the disk allows starves everything else
+//! on a shared host. With the global `--max-read-mbps` every tree iteration is
+//! wrapped in [`Throttled`], which counts the bytes of the entries it yields and
+//! sleeps whenever the run gets ahead of the allowed rate. The count covers
+//! keys and values as sled returns them, so pages sled reads but doesn't yield
+//! (and its cache hits) make the actual disk rate differ somewhat.
+//!
+//! `--io-nice` additionally puts the process into the idle I/O scheduling
+//! class on Linux, so it only gets the disk when no one else needs it.
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::info;
+
+/// Allowed read rate in bytes per second
+static MAX_BYTES_PER_SECOND: OnceLock<u64> = OnceLock::new();
+
+/// Limit every tree iteration started from now on to `max_read_mbps` MB/s
+pub fn set_max_read_mbps(max_read_mbps: Option<f64>) {
+    if let Some(mbps) = max_read_mbps {
+        info!("Limiting reads to {} MB/s", mbps);
+        let _ = MAX_BYTES_PER_SECOND.set((mbps * 1_000_000.0) as u64);
+    }
+}
+
+/// Parse a positive rate such as `50` or `0.5`
+pub fn parse_mbps(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(mbps) if mbps > 0.0 && mbps.is_finite() => Ok(mbps),
+        _ => Err(format!("expected a positive number of MB/s, got {:?}", value)),
+    }
+}
+
+/// Sleeps callers down to a fixed byte rate
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    started: Instant,
+    consumed: u64,
+}
+
+impl RateLimiter {
+    /// Start limiting to `bytes_per_second` from now on
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            started: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    /// Account for `bytes` read, sleeping until they fit into the rate
+    pub fn consume(&mut self, bytes: u64) {
+        self.consumed += bytes;
+        let due = Duration::from_secs_f64(self.consumed as f64 / self.bytes_per_second as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+    }
+}
+
+/// A sled iterator read at no more than the rate of `--max-read-mbps`
+pub struct Throttled<I> {
+    inner: I,
+    limiter: Option<RateLimiter>,
+}
+
+/// Wrap a sled iterator with the configured rate limit, if any
+pub fn iter<I>(inner: I) -> Throttled<I> {
+    Throttled {
+        inner,
+        limiter: MAX_BYTES_PER_SECOND.get().map(|rate| RateLimiter::new(*rate)),
+    }
+}
+
+impl<I> Iterator for Throttled<I>
+where
+    I: Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.inner.next()?;
+        if let (Some(limiter), Ok((key, value))) = (self.limiter.as_mut(), &item) {
+            limiter.consume((key.len() + value.len()) as u64);
+        }
+        Some(item)
+    }
+}
+
+/// Move this process to the idle I/O scheduling class
+///
+/// Applies to the calling thread and every thread it starts afterwards.
+#[cfg(target_os = "linux")]
+pub fn set_idle_io_priority() -> Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: ioprio_set only reads its integer arguments
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    info!("Running in the idle I/O scheduling class");
+    Ok(())
+}
+
+/// Move this process to the idle I/O scheduling class
+#[cfg(not(target_os = "linux"))]
+pub fn set_idle_io_priority() -> Result<()> {
+    tracing::warn!("--io-nice is only supported on Linux - reading at normal I/O priority");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_sleeps_down_to_the_rate() {
+        let started = Instant::now();
+        let mut limiter = RateLimiter::new(1_000_000);
+        for _ in 0..10 {
+            limiter.consume(10_000);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(95));
+    }
+}
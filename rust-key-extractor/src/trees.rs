@@ -0,0 +1,829 @@
+//! Readers for crypto-store trees beyond the inbound group sessions
+//!
+//! matrix-sdk-sled keeps every crypto object as a (possibly encrypted) JSON
+//! value in its own sled tree. These readers decode the raw values directly,
+//! without going through `SledCryptoStore`, so that a single corrupted entry
+//! doesn't take the rest of the tree down with it.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use matrix_sdk_crypto::olm::{
+    PickledAccount, PickledCrossSigningIdentity, PickledOutboundGroupSession, PickledSession,
+};
+use matrix_sdk_crypto::{GossipRequest, LocalTrust, ReadOnlyDevice, ReadOnlyUserIdentities};
+use matrix_sdk_store_encryption::StoreCipher;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::ExtractorError;
+use crate::settings::Settings;
+use crate::{
+    deserialize_value, redact, schema, throttle, FailedSession, FailureCategory,
+    ENCODE_SEPARATOR,
+};
+
+/// Tree name for our own account pickle and the backup secrets stored next to it
+pub const ACCOUNT_TREE: &str = "account";
+
+/// Key of the account pickle inside the account tree
+const ACCOUNT_KEY: &str = "account";
+
+/// Tree name for outbound group sessions in matrix-sdk-sled
+pub const OUTBOUND_GROUP_SESSIONS_TREE: &str = "outbound_group_sessions";
+
+/// Tree name for Olm (device-to-device) sessions in matrix-sdk-sled
+pub const OLM_SESSIONS_TREE: &str = "session";
+
+/// Tree name for the devices we know about in matrix-sdk-sled
+pub const DEVICES_TREE: &str = "devices";
+
+/// Tree name for user (cross-signing) identities in matrix-sdk-sled
+pub const IDENTITIES_TREE: &str = "identities";
+
+/// Tree name for our own private cross-signing identity in matrix-sdk-sled
+pub const PRIVATE_IDENTITY_TREE: &str = "private_identity";
+
+/// Tree name for the users whose device lists we keep up to date
+pub const TRACKED_USERS_TREE: &str = "tracked_users";
+
+/// Tree name for key/secret requests that were already sent out
+pub const OUTGOING_SECRET_REQUESTS_TREE: &str = "outgoing_secret_requests";
+
+/// Tree name for key/secret requests that are queued but not yet sent
+pub const UNSENT_SECRET_REQUESTS_TREE: &str = "unsent_secret_requests";
+
+/// Tree name for `m.room_key.withheld` codes received for sessions
+pub const WITHHELD_INFO_TREE: &str = "direct_withheld_info";
+
+/// Crypto-store trees that can be extracted in addition to the inbound group sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExtraTree {
+    /// Our own outbound Megolm sessions, so they don't need to be rotated after migration
+    OutboundGroupSessions,
+    /// Olm 1:1 sessions with other devices, so peers don't need to establish new ones
+    OlmSessions,
+    /// Known devices, including their local verified/blacklisted state
+    Devices,
+    /// User identities, including whether our own identity was verified
+    Identities,
+    /// Our private cross-signing keys (master, self-signing, user-signing)
+    CrossSigningKeys,
+    /// Users whose device lists are being tracked
+    TrackedUsers,
+    /// Pending outgoing room key and secret requests (incoming ones are never stored)
+    KeyRequests,
+    /// Withheld codes telling "key intentionally withheld" apart from "key missing"
+    Withheld,
+    /// Our Olm account plus the backup version and recovery key stored alongside it
+    Account,
+}
+
+impl ExtraTree {
+    /// Names of the sled trees backing this kind of data
+    pub fn tree_names(self) -> &'static [&'static str] {
+        match self {
+            ExtraTree::OutboundGroupSessions => &[OUTBOUND_GROUP_SESSIONS_TREE],
+            ExtraTree::OlmSessions => &[OLM_SESSIONS_TREE],
+            ExtraTree::Devices => &[DEVICES_TREE],
+            ExtraTree::Identities => &[IDENTITIES_TREE],
+            ExtraTree::CrossSigningKeys => &[PRIVATE_IDENTITY_TREE],
+            ExtraTree::TrackedUsers => &[TRACKED_USERS_TREE],
+            ExtraTree::KeyRequests => &[OUTGOING_SECRET_REQUESTS_TREE, UNSENT_SECRET_REQUESTS_TREE],
+            ExtraTree::Withheld => &[WITHHELD_INFO_TREE],
+            ExtraTree::Account => &[ACCOUNT_TREE],
+        }
+    }
+
+    /// The name used for this tree on the command line
+    pub fn label(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// An outbound group session, kept in its pickled form
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedOutboundSession {
+    /// Room ID the session is used in
+    pub room_id: String,
+    /// The pickle as produced by matrix-sdk-crypto (contains the session key)
+    pub pickle: serde_json::Value,
+}
+
+/// An Olm session, kept in its pickled form
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedOlmSession {
+    /// Curve25519 key of the device on the other end of the session
+    pub sender_key: String,
+    /// The pickle as produced by matrix-sdk-crypto (contains the ratchet state)
+    pub pickle: serde_json::Value,
+}
+
+/// A device of some user, with its local trust state pulled out for readability
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedDevice {
+    /// Owner of the device
+    pub user_id: String,
+    /// Device ID
+    pub device_id: String,
+    /// Local trust state (verified, blacklisted, ignored or unset)
+    pub local_trust: String,
+    /// The device as serialized by matrix-sdk-crypto
+    pub data: serde_json::Value,
+}
+
+/// A user identity (cross-signing public keys) with its verification state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedIdentity {
+    /// User the identity belongs to
+    pub user_id: String,
+    /// Whether this is our own identity
+    pub own: bool,
+    /// Whether our own identity was marked as verified (always false for other users)
+    pub verified: bool,
+    /// The identity as serialized by matrix-sdk-crypto
+    pub data: serde_json::Value,
+}
+
+/// Our private cross-signing identity, kept in its pickled form
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedCrossSigningIdentity {
+    /// User the identity belongs to
+    pub user_id: String,
+    /// Whether the public part was already uploaded to the server
+    pub shared: bool,
+    /// The pickle as produced by matrix-sdk-crypto (contains the PRIVATE cross-signing keys)
+    pub pickle: serde_json::Value,
+}
+
+/// A user whose device list is tracked, as stored by matrix-sdk-sled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackedUserEntry {
+    /// The tracked user
+    pub user_id: String,
+    /// Whether the device list is outdated and needs to be re-queried
+    pub dirty: bool,
+}
+
+/// An outgoing `m.room_key_request` or `m.secret.request`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedKeyRequest {
+    /// Transaction ID of the request
+    pub request_id: String,
+    /// User the request is addressed to
+    pub recipient: String,
+    /// Whether the request was already sent to the server
+    pub sent_out: bool,
+    /// The request as serialized by matrix-sdk-crypto
+    pub data: serde_json::Value,
+}
+
+/// A `m.room_key.withheld` entry for a session we never received the key for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedWithheldInfo {
+    /// Room the withheld session belongs to
+    pub room_id: Option<String>,
+    /// The withheld session
+    pub session_id: Option<String>,
+    /// Withheld code (e.g. `m.unverified`, `m.blacklisted`, `m.no_olm`)
+    pub code: Option<String>,
+    /// The withheld event as stored by matrix-sdk-crypto
+    pub data: serde_json::Value,
+}
+
+/// Our own Olm account, kept in its pickled form
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedAccount {
+    /// User ID of the account
+    pub user_id: String,
+    /// Device ID of the account
+    pub device_id: String,
+    /// Whether the device keys were already uploaded to the server
+    pub shared: bool,
+    /// The pickle as produced by matrix-sdk-crypto (contains the PRIVATE identity keys)
+    pub pickle: serde_json::Value,
+}
+
+/// A secret stored in the account tree next to the account pickle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedSecret {
+    /// Key the secret was stored under (e.g. `backup_version_v1`, `recovery_key_v1`)
+    pub name: String,
+    /// The decoded value
+    pub value: serde_json::Value,
+}
+
+/// Data extracted from the trees requested with `--include`
+///
+/// Every field is omitted from the JSON output when empty, so exports that
+/// don't use `--include` look exactly like before.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtraTreeExport {
+    /// Pickled outbound group sessions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outbound_group_sessions: Vec<ExportedOutboundSession>,
+    /// Pickled Olm sessions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub olm_sessions: Vec<ExportedOlmSession>,
+    /// Devices and their local trust state
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<ExportedDevice>,
+    /// User identities and their verification state
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identities: Vec<ExportedIdentity>,
+    /// Our private cross-signing identity, if the store has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cross_signing_identity: Option<ExportedCrossSigningIdentity>,
+    /// Users whose device lists are tracked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracked_users: Vec<TrackedUserEntry>,
+    /// Outgoing key and secret requests, sent or not
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_requests: Vec<ExportedKeyRequest>,
+    /// Withheld codes per session
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub withheld: Vec<ExportedWithheldInfo>,
+    /// Our own Olm account, if the store has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<ExportedAccount>,
+    /// Backup secrets stored in the account tree
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secrets: Vec<ExportedSecret>,
+}
+
+impl ExtraTreeExport {
+    /// Number of entries extracted for the given tree
+    pub fn count(&self, tree: ExtraTree) -> usize {
+        match tree {
+            ExtraTree::OutboundGroupSessions => self.outbound_group_sessions.len(),
+            ExtraTree::OlmSessions => self.olm_sessions.len(),
+            ExtraTree::Devices => self.devices.len(),
+            ExtraTree::Identities => self.identities.len(),
+            ExtraTree::CrossSigningKeys => usize::from(self.cross_signing_identity.is_some()),
+            ExtraTree::TrackedUsers => self.tracked_users.len(),
+            ExtraTree::KeyRequests => self.key_requests.len(),
+            ExtraTree::Withheld => self.withheld.len(),
+            ExtraTree::Account => usize::from(self.account.is_some()) + self.secrets.len(),
+        }
+    }
+
+    /// Whether no additional tree data was extracted at all
+    pub fn is_empty(&self) -> bool {
+        ExtraTree::value_variants()
+            .iter()
+            .all(|tree| self.count(*tree) == 0)
+    }
+}
+
+/// Read and decode every value of a sled tree
+///
+/// In fault-tolerant mode entries that can't be read or decoded are collected
+/// as failures; otherwise the first bad entry aborts the read.
+pub fn read_tree<T: DeserializeOwned>(
+    settings: &Settings,
+    db: &sled::Db,
+    tree_name: &str,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<T>, Vec<FailedSession>)> {
+    let (entries, failed) =
+        read_tree_entries(settings, db, tree_name, store_cipher, skip_errors)?;
+    Ok((entries.into_iter().map(|(_, value)| value).collect(), failed))
+}
+
+/// Decoded values together with their raw sled keys, plus the entries that failed
+type KeyedEntries<T> = (Vec<(sled::IVec, T)>, Vec<FailedSession>);
+
+/// Like [`read_tree`], but keeps the raw sled key next to each decoded value
+fn read_tree_entries<T: DeserializeOwned>(
+    settings: &Settings,
+    db: &sled::Db,
+    tree_name: &str,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<KeyedEntries<T>> {
+    let tree_name = schema::tree_name(settings, tree_name);
+
+    // sled creates trees on open, so check first to avoid adding empty trees
+    // to the store and to cope with older stores that lack newer trees
+    if !db.tree_names().iter().any(|name| name.as_ref() == tree_name.as_bytes()) {
+        info!("Tree '{}' not present in store", tree_name);
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    let tree = db
+        .open_tree(tree_name)
+        .with_context(|| format!("Failed to open tree '{}'", tree_name))?;
+
+    info!("Found {} entries in tree '{}'", tree.len(), tree_name);
+
+    let mut values = Vec::new();
+    let mut failed = Vec::new();
+
+    for (index, item) in throttle::iter(tree.iter()).enumerate() {
+        let (key_hex, category, error) = match item {
+            Ok((key, value)) => match deserialize_value::<T>(&value, store_cipher) {
+                Ok(decoded) => {
+                    values.push((key, decoded));
+                    continue;
+                }
+                Err(e) => (
+                    hex::encode(&key),
+                    FailureCategory::of_deserialize_error(&e),
+                    format!("Deserialization failed: {}", e),
+                ),
+            },
+            Err(e) => (
+                String::from("<read error>"),
+                FailureCategory::SledRead,
+                format!("Sled read error: {}", e),
+            ),
+        };
+
+        failed.push(failed_entry(
+            tree_name,
+            index,
+            key_hex,
+            category,
+            error,
+            skip_errors,
+        )?);
+    }
+
+    Ok((values, failed))
+}
+
+/// Record an entry that couldn't be decoded, or fail on it in strict mode
+fn failed_entry(
+    tree_name: &str,
+    index: usize,
+    key_hex: String,
+    category: FailureCategory,
+    error: String,
+    skip_errors: bool,
+) -> Result<FailedSession> {
+    if !skip_errors {
+        return Err(ExtractorError::CorruptPickle(format!(
+            "entry {} in tree '{}': {}",
+            index, tree_name, error
+        ))
+        .into());
+    }
+
+    warn!("Tree '{}' entry {}: {}", tree_name, index, error);
+    Ok(FailedSession {
+        index,
+        tree: tree_name.to_string(),
+        key_hex,
+        category,
+        error,
+        raw_value: None,
+    })
+}
+
+/// Extract the outbound group sessions tree
+pub fn extract_outbound_group_sessions(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<ExportedOutboundSession>, Vec<FailedSession>)> {
+    let (pickles, failed) = read_tree::<PickledOutboundGroupSession>(
+        settings,
+        db,
+        OUTBOUND_GROUP_SESSIONS_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+
+    let sessions = pickles
+        .into_iter()
+        .map(|pickle| {
+            Ok(ExportedOutboundSession {
+                room_id: pickle.room_id.to_string(),
+                pickle: serde_json::to_value(&pickle)
+                    .context("Failed to re-serialize outbound group session")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    info!("Extracted {} outbound group sessions", sessions.len());
+
+    Ok((sessions, failed))
+}
+
+/// Extract the Olm sessions tree
+pub fn extract_olm_sessions(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<ExportedOlmSession>, Vec<FailedSession>)> {
+    let (pickles, failed) =
+        read_tree::<PickledSession>(settings, db, OLM_SESSIONS_TREE, store_cipher, skip_errors)?;
+
+    let sessions = pickles
+        .into_iter()
+        .map(|pickle| {
+            Ok(ExportedOlmSession {
+                sender_key: pickle.sender_key.to_base64(),
+                pickle: serde_json::to_value(&pickle)
+                    .context("Failed to re-serialize Olm session")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    info!("Extracted {} Olm sessions", sessions.len());
+
+    Ok((sessions, failed))
+}
+
+/// Stable name for a local trust state
+fn local_trust_name(trust: LocalTrust) -> &'static str {
+    match trust {
+        LocalTrust::Verified => "verified",
+        LocalTrust::BlackListed => "blacklisted",
+        LocalTrust::Ignored => "ignored",
+        LocalTrust::Unset => "unset",
+    }
+}
+
+/// Extract the devices tree
+pub fn extract_devices(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<ExportedDevice>, Vec<FailedSession>)> {
+    let (devices, failed) =
+        read_tree::<ReadOnlyDevice>(settings, db, DEVICES_TREE, store_cipher, skip_errors)?;
+
+    let devices = devices
+        .into_iter()
+        .map(|device| {
+            Ok(ExportedDevice {
+                user_id: device.user_id().to_string(),
+                device_id: device.device_id().to_string(),
+                local_trust: local_trust_name(device.local_trust_state()).to_string(),
+                data: serde_json::to_value(&device).context("Failed to re-serialize device")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let verified = devices.iter().filter(|d| d.local_trust == "verified").count();
+    let blacklisted = devices.iter().filter(|d| d.local_trust == "blacklisted").count();
+    info!(
+        "Extracted {} devices ({} verified, {} blacklisted)",
+        devices.len(),
+        verified,
+        blacklisted
+    );
+
+    Ok((devices, failed))
+}
+
+/// Extract the user identities tree
+pub fn extract_identities(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<ExportedIdentity>, Vec<FailedSession>)> {
+    let (identities, failed) = read_tree::<ReadOnlyUserIdentities>(
+        settings,
+        db,
+        IDENTITIES_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+
+    let identities = identities
+        .into_iter()
+        .map(|identity| {
+            let own = identity.own();
+            Ok(ExportedIdentity {
+                user_id: identity.user_id().to_string(),
+                own: own.is_some(),
+                verified: own.map(|o| o.is_verified()).unwrap_or(false),
+                data: serde_json::to_value(&identity)
+                    .context("Failed to re-serialize user identity")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    info!("Extracted {} user identities", identities.len());
+
+    Ok((identities, failed))
+}
+
+/// Extract the private cross-signing identity
+///
+/// The tree holds at most one entry; a store that never bootstrapped
+/// cross-signing simply has an empty tree.
+pub fn extract_cross_signing_identity(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Option<ExportedCrossSigningIdentity>, Vec<FailedSession>)> {
+    let (pickles, failed) = read_tree::<PickledCrossSigningIdentity>(
+        settings,
+        db,
+        PRIVATE_IDENTITY_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+
+    if pickles.len() > 1 {
+        warn!(
+            "Found {} private identities, only the first one will be exported",
+            pickles.len()
+        );
+    }
+
+    let identity = match pickles.into_iter().next() {
+        Some(pickle) => {
+            info!(
+                "Extracted private cross-signing identity for {} (shared: {})",
+                redact::id(settings, &pickle.user_id),
+                pickle.shared
+            );
+            Some(ExportedCrossSigningIdentity {
+                user_id: pickle.user_id.to_string(),
+                shared: pickle.shared,
+                pickle: serde_json::to_value(&pickle)
+                    .context("Failed to re-serialize private cross-signing identity")?,
+            })
+        }
+        None => {
+            info!("No private cross-signing identity found");
+            None
+        }
+    };
+
+    Ok((identity, failed))
+}
+
+/// Extract the tracked users tree
+pub fn extract_tracked_users(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<TrackedUserEntry>, Vec<FailedSession>)> {
+    let (users, failed) = read_tree::<TrackedUserEntry>(
+        settings,
+        db,
+        TRACKED_USERS_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+
+    let dirty = users.iter().filter(|u| u.dirty).count();
+    info!("Extracted {} tracked users ({} marked dirty)", users.len(), dirty);
+
+    Ok((users, failed))
+}
+
+/// Extract the outgoing key request trees
+///
+/// matrix-sdk-sled only persists requests *we* made; incoming requests are
+/// handled in memory and never reach the store. Requests waiting to be sent
+/// live in a separate tree from the ones already sent out, so both are read
+/// and merged by request ID.
+pub fn extract_key_requests(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<ExportedKeyRequest>, Vec<FailedSession>)> {
+    let (mut requests, mut failed) = read_tree::<GossipRequest>(
+        settings,
+        db,
+        OUTGOING_SECRET_REQUESTS_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+    let (unsent, unsent_failed) = read_tree::<GossipRequest>(
+        settings,
+        db,
+        UNSENT_SECRET_REQUESTS_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+    failed.extend(unsent_failed);
+
+    for request in unsent {
+        if !requests.iter().any(|r| r.request_id == request.request_id) {
+            requests.push(request);
+        }
+    }
+
+    let requests = requests
+        .into_iter()
+        .map(|request| {
+            Ok(ExportedKeyRequest {
+                request_id: request.request_id.to_string(),
+                recipient: request.request_recipient.to_string(),
+                sent_out: request.sent_out,
+                data: serde_json::to_value(&request)
+                    .context("Failed to re-serialize key request")?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let unsent_count = requests.iter().filter(|r| !r.sent_out).count();
+    info!(
+        "Extracted {} key requests ({} not yet sent)",
+        requests.len(),
+        unsent_count
+    );
+
+    Ok((requests, failed))
+}
+
+/// Extract the withheld info tree
+///
+/// The withheld event layout changed between matrix-sdk-crypto releases, so
+/// entries are kept as generic JSON and only the identifying fields are
+/// pulled out.
+pub fn extract_withheld_info(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Vec<ExportedWithheldInfo>, Vec<FailedSession>)> {
+    let (events, failed) = read_tree::<serde_json::Value>(
+        settings,
+        db,
+        WITHHELD_INFO_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+
+    let withheld: Vec<ExportedWithheldInfo> = events
+        .into_iter()
+        .map(|event| {
+            let content = event.get("content").unwrap_or(&event);
+            let field = |name: &str| content.get(name).and_then(|v| v.as_str()).map(String::from);
+            ExportedWithheldInfo {
+                room_id: field("room_id"),
+                session_id: field("session_id"),
+                code: field("code"),
+                data: event.clone(),
+            }
+        })
+        .collect();
+
+    info!("Extracted {} withheld entries", withheld.len());
+
+    Ok((withheld, failed))
+}
+
+/// Extract our own account and the backup secrets stored next to it
+///
+/// Unlike the other trees, the account tree holds differently typed values
+/// under fixed, unhashed keys, so entries are decoded by name.
+pub fn extract_account(
+    settings: &Settings,
+    db: &sled::Db,
+    store_cipher: Option<&StoreCipher>,
+    skip_errors: bool,
+) -> Result<(Option<ExportedAccount>, Vec<ExportedSecret>, Vec<FailedSession>)> {
+    let (entries, mut failed) = read_tree_entries::<serde_json::Value>(
+        settings,
+        db,
+        ACCOUNT_TREE,
+        store_cipher,
+        skip_errors,
+    )?;
+
+    let mut account = None;
+    let mut secrets = Vec::new();
+
+    for (index, (key, value)) in entries.into_iter().enumerate() {
+        let name_bytes = key.strip_suffix(&[ENCODE_SEPARATOR]).unwrap_or(&key);
+        let name = String::from_utf8_lossy(name_bytes).into_owned();
+
+        if name == ACCOUNT_KEY {
+            let pickle = match serde_json::from_value::<PickledAccount>(value.clone()) {
+                Ok(pickle) => pickle,
+                Err(e) => {
+                    failed.push(failed_entry(
+                        schema::tree_name(settings, ACCOUNT_TREE),
+                        index,
+                        hex::encode(&key),
+                        FailureCategory::Json,
+                        format!("Failed to parse account pickle: {}", e),
+                        skip_errors,
+                    )?);
+                    continue;
+                }
+            };
+            info!(
+                "Extracted account for {} (device {})",
+                redact::id(settings, &pickle.user_id),
+                redact::id(settings, &pickle.device_id)
+            );
+            account = Some(ExportedAccount {
+                user_id: pickle.user_id.to_string(),
+                device_id: pickle.device_id.to_string(),
+                shared: pickle.shared,
+                pickle: value,
+            });
+        } else {
+            info!("Extracted account tree secret '{}'", name);
+            secrets.push(ExportedSecret { name, value });
+        }
+    }
+
+    if account.is_none() {
+        warn!("No account found in the account tree");
+    }
+
+    Ok((account, secrets, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_read_tree_missing_tree_is_empty() {
+        let settings = Settings::default();
+        let db = temp_db();
+        let (values, failed) =
+            read_tree::<serde_json::Value>(&settings, &db, WITHHELD_INFO_TREE, None, false)
+                .unwrap();
+        assert!(values.is_empty());
+        assert!(failed.is_empty());
+        assert!(!db
+            .tree_names()
+            .iter()
+            .any(|name| name.as_ref() == WITHHELD_INFO_TREE.as_bytes()));
+    }
+
+    #[test]
+    fn test_read_tree_skip_errors() {
+        let settings = Settings::default();
+        let db = temp_db();
+        let tree = db.open_tree(TRACKED_USERS_TREE).unwrap();
+        tree.insert("a", br#"{"user_id":"@a:example.org","dirty":true}"#.to_vec())
+            .unwrap();
+        tree.insert("b", b"not json".to_vec()).unwrap();
+
+        let tracked_users =
+            read_tree::<TrackedUserEntry>(&settings, &db, TRACKED_USERS_TREE, None, false);
+        assert!(tracked_users.is_err());
+
+        let (users, failed) = extract_tracked_users(&settings, &db, None, true).unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].user_id, "@a:example.org");
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].tree, TRACKED_USERS_TREE);
+        assert_eq!(failed[0].key_hex, hex::encode("b"));
+    }
+
+    #[test]
+    fn test_unreadable_account_is_skipped() {
+        let db = temp_db();
+        let tree = db.open_tree(ACCOUNT_TREE).unwrap();
+        tree.insert(ACCOUNT_KEY, br#"{"user_id":5}"#.to_vec()).unwrap();
+        tree.insert("backup_version_v1", br#""3""#.to_vec()).unwrap();
+
+        let settings = Settings::default();
+        assert!(extract_account(&settings, &db, None, false).is_err());
+
+        let (account, secrets, failed) = extract_account(&settings, &db, None, true).unwrap();
+        assert!(account.is_none());
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].category, FailureCategory::Json);
+        assert_eq!(failed[0].key_hex, hex::encode(ACCOUNT_KEY));
+    }
+
+    #[test]
+    fn test_extract_withheld_info_fields() {
+        let db = temp_db();
+        let tree = db.open_tree(WITHHELD_INFO_TREE).unwrap();
+        tree.insert(
+            "x",
+            br#"{"content":{"room_id":"!r:example.org","session_id":"s","code":"m.unverified"}}"#
+                .to_vec(),
+        )
+        .unwrap();
+
+        let (withheld, failed) =
+            extract_withheld_info(&Settings::default(), &db, None, false).unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(withheld[0].room_id.as_deref(), Some("!r:example.org"));
+        assert_eq!(withheld[0].session_id.as_deref(), Some("s"));
+        assert_eq!(withheld[0].code.as_deref(), Some("m.unverified"));
+    }
+}
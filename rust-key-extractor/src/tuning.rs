@@ -0,0 +1,50 @@
+//! sled open options
+//!
+//! sled keeps up to 1 GiB of pages in its cache by default. On small hosts
+//! iterating a large store fills it and gets the process OOM-killed, while on
+//! big machines a larger cache makes iteration considerably faster. The
+//! global `--sled-cache-mb`, `--sled-mode` and `--sled-flush-ms` options apply
+//! to every sled database a run opens, through [`Tuning::sled_config`].
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// sled's storage mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SledMode {
+    /// Compact segments eagerly, keeping the store small on disk (sled's default)
+    LowSpace,
+    /// Defer compaction in favor of throughput
+    HighThroughput,
+}
+
+/// Options given on the command line; sled's defaults where unset
+///
+/// A `flush_ms` of 0 turns sled's background flushing off.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tuning {
+    pub cache_mb: Option<u64>,
+    pub mode: Option<SledMode>,
+    pub flush_ms: Option<u64>,
+}
+
+impl Tuning {
+    /// A `sled::Config` with these options
+    pub fn sled_config(&self) -> sled::Config {
+        let mut config = sled::Config::new();
+        if let Some(cache_mb) = self.cache_mb {
+            config = config.cache_capacity(cache_mb * 1024 * 1024);
+        }
+        if let Some(mode) = self.mode {
+            config = config.mode(match mode {
+                SledMode::LowSpace => sled::Mode::LowSpace,
+                SledMode::HighThroughput => sled::Mode::HighThroughput,
+            });
+        }
+        if let Some(flush_ms) = self.flush_ms {
+            config = config.flush_every_ms((flush_ms > 0).then_some(flush_ms));
+        }
+        config
+    }
+}
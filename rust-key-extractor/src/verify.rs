@@ -0,0 +1,287 @@
+//! Comparison of a sled crypto store with the SQLite store it was migrated to
+//!
+//! `verify-migration` reads the inbound group sessions of both stores and
+//! checks them session by session: every session must exist on both sides with
+//! the same room, sender key and first known index. Anything else is listed, so
+//! operators can tell whether the SQLite store is complete before the sled
+//! store is decommissioned.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use matrix_sdk_crypto::olm::InboundGroupSession;
+use matrix_sdk_crypto::store::CryptoStore;
+use matrix_sdk_sqlite::SqliteCryptoStore;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::error::ExtractorError;
+use crate::progress::Progress;
+use crate::settings::Settings;
+use crate::{decode_session, load_store_cipher, schema, throttle};
+
+/// The fields of a session that must match between the stores
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionFingerprint {
+    pub room_id: String,
+    pub sender_key: String,
+    pub first_known_index: u32,
+}
+
+/// Sessions of one store by session ID
+pub type SessionMap = BTreeMap<String, SessionFingerprint>;
+
+/// A session present in only one of the stores
+#[derive(Debug, Serialize)]
+pub struct MissingSession {
+    pub session_id: String,
+    pub room_id: String,
+}
+
+/// A session present in both stores with differing fields
+#[derive(Debug, Serialize)]
+pub struct MismatchedSession {
+    pub session_id: String,
+    pub source: SessionFingerprint,
+    pub target: SessionFingerprint,
+}
+
+/// Result of comparing the two stores
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Sessions decoded from the sled store
+    pub source_sessions: usize,
+    /// Sled entries that could not be decoded, and so weren't compared
+    pub source_failed: usize,
+    /// Sessions in the SQLite store
+    pub target_sessions: usize,
+    /// Sessions identical in both stores
+    pub matching: usize,
+    pub only_in_source: Vec<MissingSession>,
+    pub only_in_target: Vec<MissingSession>,
+    pub mismatched: Vec<MismatchedSession>,
+}
+
+impl VerifyReport {
+    /// Number of sessions that differ between the stores
+    pub fn differences(&self) -> usize {
+        self.only_in_source.len() + self.only_in_target.len() + self.mismatched.len()
+    }
+}
+
+/// Session ID and fingerprint of a session
+fn fingerprint(session: &InboundGroupSession) -> (String, SessionFingerprint) {
+    (
+        session.session_id().to_string(),
+        SessionFingerprint {
+            room_id: session.room_id().to_string(),
+            sender_key: session.sender_key().to_base64(),
+            first_known_index: session.first_known_index(),
+        },
+    )
+}
+
+/// Read the sessions of a sled crypto store, returning them and the number of
+/// entries that could not be decoded
+pub async fn read_source(
+    settings: &Settings,
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<(SessionMap, usize)> {
+    let db = settings
+        .sled_config()
+        .path(path)
+        .open()
+        .map_err(ExtractorError::SledIo)
+        .context("Failed to open sled database")?;
+    let store_cipher = load_store_cipher(settings, &db, passphrase.unwrap_or(""))?;
+    let schema = schema::detect(settings, &db)?;
+    let tree = db
+        .open_tree(&schema.inbound_group_sessions)
+        .context("Failed to open inbound group sessions tree")?;
+
+    let mut sessions = SessionMap::new();
+    let mut failed = 0;
+    let mut progress = Progress::new("Reading sled sessions", 0, tree.len() as u64);
+
+    for (index, item) in throttle::iter(tree.iter()).enumerate() {
+        progress.inc(1);
+
+        let session = item
+            .map_err(|e| format!("Sled read error: {}", e))
+            .and_then(|(_, value)| {
+                decode_session(&value, store_cipher.as_ref()).map_err(|(_, error)| error)
+            });
+        match session {
+            Ok(session) => {
+                let (session_id, fingerprint) = fingerprint(&session);
+                sessions.insert(session_id, fingerprint);
+            }
+            Err(error) => {
+                warn!("Session {}: {}", index, error);
+                failed += 1;
+            }
+        }
+    }
+    progress.finish();
+
+    Ok((sessions, failed))
+}
+
+/// Read the sessions of a matrix-sdk-sqlite crypto store
+pub async fn read_target(path: &Path, passphrase: Option<&str>) -> Result<SessionMap> {
+    let store = SqliteCryptoStore::open(path, passphrase)
+        .await
+        .context("Failed to open SQLite crypto store")?;
+    let stored = store
+        .get_inbound_group_sessions()
+        .await
+        .context("Failed to read inbound group sessions from the SQLite store")?;
+
+    Ok(stored.iter().map(fingerprint).collect())
+}
+
+/// Compare the sessions of the two stores
+pub fn compare(source: &SessionMap, target: &SessionMap) -> VerifyReport {
+    let mut report = VerifyReport {
+        source_sessions: source.len(),
+        target_sessions: target.len(),
+        ..Default::default()
+    };
+
+    for (session_id, source_session) in source {
+        match target.get(session_id) {
+            Some(target_session) if target_session == source_session => report.matching += 1,
+            Some(target_session) => report.mismatched.push(MismatchedSession {
+                session_id: session_id.clone(),
+                source: source_session.clone(),
+                target: target_session.clone(),
+            }),
+            None => report.only_in_source.push(MissingSession {
+                session_id: session_id.clone(),
+                room_id: source_session.room_id.clone(),
+            }),
+        }
+    }
+    for (session_id, target_session) in target {
+        if !source.contains_key(session_id) {
+            report.only_in_target.push(MissingSession {
+                session_id: session_id.clone(),
+                room_id: target_session.room_id.clone(),
+            });
+        }
+    }
+
+    report
+}
+
+/// Read both stores and compare them
+pub async fn verify_migration(
+    settings: &Settings,
+    sled_path: &Path,
+    sled_passphrase: Option<&str>,
+    target_path: &Path,
+    target_passphrase: Option<&str>,
+) -> Result<VerifyReport> {
+    let (source, failed) = read_source(settings, sled_path, sled_passphrase).await?;
+    info!(
+        "Read {} sessions from the sled store, {} undecodable",
+        source.len(),
+        failed
+    );
+
+    info!("Opening SQLite crypto store at: {:?}", target_path);
+    let target = read_target(target_path, target_passphrase).await?;
+    info!("Read {} sessions from the SQLite store", target.len());
+
+    let mut report = compare(&source, &target);
+    report.source_failed = failed;
+    Ok(report)
+}
+
+/// Print the report, listing every differing session
+pub fn print_report(report: &VerifyReport) {
+    println!("Sled sessions:      {}", report.source_sessions);
+    println!("Undecodable (sled): {}", report.source_failed);
+    println!("SQLite sessions:    {}", report.target_sessions);
+    println!("Matching:           {}", report.matching);
+    println!("Only in sled:       {}", report.only_in_source.len());
+    println!("Only in SQLite:     {}", report.only_in_target.len());
+    println!("Mismatched:         {}", report.mismatched.len());
+
+    if !report.only_in_source.is_empty() {
+        println!();
+        println!("Only in sled:");
+        for session in &report.only_in_source {
+            println!("  {}  {}", session.session_id, session.room_id);
+        }
+    }
+
+    if !report.only_in_target.is_empty() {
+        println!();
+        println!("Only in SQLite:");
+        for session in &report.only_in_target {
+            println!("  {}  {}", session.session_id, session.room_id);
+        }
+    }
+
+    for session in &report.mismatched {
+        println!();
+        println!("Mismatched {}:", session.session_id);
+        let (source, target) = (&session.source, &session.target);
+        if source.room_id != target.room_id {
+            println!(
+                "  room:              {} (sled) vs {} (SQLite)",
+                source.room_id, target.room_id
+            );
+        }
+        if source.sender_key != target.sender_key {
+            println!(
+                "  sender key:        {} (sled) vs {} (SQLite)",
+                source.sender_key, target.sender_key
+            );
+        }
+        if source.first_known_index != target.first_known_index {
+            println!(
+                "  first known index: {} (sled) vs {} (SQLite)",
+                source.first_known_index, target.first_known_index
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(room_id: &str, first_known_index: u32) -> SessionFingerprint {
+        SessionFingerprint {
+            room_id: room_id.to_string(),
+            sender_key: "sender".to_string(),
+            first_known_index,
+        }
+    }
+
+    #[test]
+    fn test_compare_reports_every_kind_of_difference() {
+        let source = SessionMap::from([
+            ("same".to_string(), session("!a:x.org", 0)),
+            ("later".to_string(), session("!a:x.org", 0)),
+            ("lost".to_string(), session("!b:x.org", 0)),
+        ]);
+        let target = SessionMap::from([
+            ("same".to_string(), session("!a:x.org", 0)),
+            ("later".to_string(), session("!a:x.org", 12)),
+            ("extra".to_string(), session("!c:x.org", 0)),
+        ]);
+
+        let report = compare(&source, &target);
+
+        assert_eq!(report.matching, 1);
+        assert_eq!(report.only_in_source[0].session_id, "lost");
+        assert_eq!(report.only_in_target[0].room_id, "!c:x.org");
+        assert_eq!(report.mismatched[0].target.first_known_index, 12);
+        assert_eq!(report.differences(), 3);
+    }
+}
@@ -0,0 +1,184 @@
+//! Fault-tolerant extraction against generated stores with damaged entries
+//!
+//! Each test creates a store with `gen-fixture`, extracts it with
+//! `--skip-errors` and checks the export and the failed-sessions file against
+//! the fixture manifest: every intact session must be exported, and every
+//! damaged one reported with the failure category its corruption causes.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::Value;
+
+const BINARY: &str = env!("CARGO_BIN_EXE_sled-key-extractor");
+
+/// A temp directory removed when the test ends
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "sled-key-extractor-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run(args: &[&str]) -> std::process::Output {
+    Command::new(BINARY).args(args).output().unwrap()
+}
+
+fn read_json(path: &Path) -> Value {
+    serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+}
+
+/// Generate a fixture with `extra` options and extract it with `--skip-errors`
+///
+/// Returns the manifest, the export and the failed-sessions file.
+fn generate_and_extract(dir: &TempDir, extra: &[&str]) -> (Value, Value, Value) {
+    let store = dir.join("store");
+    let manifest = dir.join("manifest.json");
+    let output = dir.join("keys.json");
+    let failed = dir.join("failed.json");
+
+    let mut args = vec![
+        "gen-fixture",
+        "--sled-path",
+        store.to_str().unwrap(),
+        "--sessions",
+        "200",
+        "--rooms",
+        "4",
+        "--seed",
+        "42",
+        "--manifest",
+        manifest.to_str().unwrap(),
+    ];
+    args.extend_from_slice(extra);
+    let generated = run(&args);
+    assert!(generated.status.success(), "{}", String::from_utf8_lossy(&generated.stderr));
+
+    let mut args = vec![
+        "extract",
+        "--sled-path",
+        store.to_str().unwrap(),
+        "--output",
+        output.to_str().unwrap(),
+        "--failed-output",
+        failed.to_str().unwrap(),
+        "--skip-errors",
+    ];
+    if let Some(index) = extra.iter().position(|arg| *arg == "--passphrase") {
+        args.extend_from_slice(&extra[index..index + 2]);
+    }
+    let extracted = run(&args);
+    assert!(extracted.status.success(), "{}", String::from_utf8_lossy(&extracted.stderr));
+
+    (read_json(&manifest), read_json(&output), read_json(&failed))
+}
+
+/// Check the export and failures against the manifest
+fn assert_matches_manifest(manifest: &Value, output: &Value, failed: &Value) {
+    let sessions = manifest["sessions"].as_array().unwrap();
+
+    let intact: BTreeSet<(&str, &str)> = sessions
+        .iter()
+        .filter(|session| session.get("corruption").is_none())
+        .map(|session| {
+            (session["room_id"].as_str().unwrap(), session["session_id"].as_str().unwrap())
+        })
+        .collect();
+    let exported: BTreeSet<(&str, &str)> = output["all_keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| (key["room_id"].as_str().unwrap(), key["session_id"].as_str().unwrap()))
+        .collect();
+    assert_eq!(exported, intact, "the intact sessions must be exported, and only those");
+
+    let expected: BTreeMap<&str, &str> = sessions
+        .iter()
+        .filter_map(|session| {
+            let category = session.get("expected_failure")?.as_str().unwrap();
+            Some((session["key_hex"].as_str().unwrap(), category))
+        })
+        .collect();
+    let reported: BTreeMap<&str, &str> = failed["sessions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|session| {
+            (session["key_hex"].as_str().unwrap(), session["category"].as_str().unwrap())
+        })
+        .collect();
+    assert_eq!(reported, expected, "every damaged entry must be reported with its category");
+    assert_eq!(failed["total_failed"], expected.len());
+}
+
+#[test]
+fn test_encrypted_store_recovers_intact_sessions() {
+    let dir = TempDir::new("encrypted");
+    let (manifest, output, failed) = generate_and_extract(
+        &dir,
+        &[
+            "--corrupt",
+            "truncate=5%",
+            "--corrupt",
+            "bit-flip=5%",
+            "--corrupt",
+            "wrong-cipher=5%",
+            "--corrupt",
+            "garbage=3",
+            "--corrupt",
+            "bad-json=3",
+            "--passphrase",
+            "fixture",
+        ],
+    );
+
+    assert_matches_manifest(&manifest, &output, &failed);
+    assert_eq!(output["all_keys"].as_array().unwrap().len(), 200 - 36);
+    assert_eq!(failed["by_category"]["decryption"], 20);
+    assert_eq!(failed["by_category"]["json"], 16);
+}
+
+#[test]
+fn test_unencrypted_store_recovers_intact_sessions() {
+    let dir = TempDir::new("unencrypted");
+    let (manifest, output, failed) = generate_and_extract(
+        &dir,
+        &["--corrupt", "truncate=10%", "--corrupt", "garbage=2", "--corrupt", "bad-json=2"],
+    );
+
+    assert_matches_manifest(&manifest, &output, &failed);
+    assert_eq!(failed["by_category"]["json"], 24);
+}
+
+#[test]
+fn test_cipher_corruptions_need_an_encrypted_store() {
+    let dir = TempDir::new("no-cipher");
+    let store = dir.join("store");
+    let generated = run(&[
+        "gen-fixture",
+        "--sled-path",
+        store.to_str().unwrap(),
+        "--corrupt",
+        "bit-flip=1",
+    ]);
+    assert!(!generated.status.success());
+}
@@ -0,0 +1,206 @@
+//! Element's key export format
+//!
+//! Element (and every other client based on the Matrix JS or Rust SDK) imports
+//! room keys from the "megolm session data" file described in the Matrix
+//! client-server specification, section "Key exports": a JSON array of
+//! sessions, encrypted with AES-256-CTR and authenticated with HMAC-SHA256
+//! under keys derived from a passphrase with PBKDF2-HMAC-SHA512, then armored
+//! as base64 between `-----BEGIN/END MEGOLM SESSION DATA-----` lines.
+//!
+//! Binary layout before armoring:
+//!
+//! | Bytes | Content                               |
+//! |-------|---------------------------------------|
+//! | 1     | format version `0x01`                 |
+//! | 16    | salt                                  |
+//! | 16    | IV, bit 63 cleared                    |
+//! | 4     | PBKDF2 rounds, big endian             |
+//! | rest  | ciphertext                            |
+//! | 32    | HMAC-SHA256 over all preceding bytes  |
+
+use std::collections::HashMap;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Sha256, Sha512};
+use zeroize::Zeroizing;
+
+use crate::model::ExportedKeyData;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// PBKDF2 rounds Element itself uses for exports
+pub const DEFAULT_ROUNDS: u32 = 500_000;
+
+const VERSION: u8 = 0x01;
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
+const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
+
+/// Characters per line of the base64 armor
+const LINE_LEN: usize = 96;
+
+/// One session as Element exports it
+#[derive(Serialize)]
+struct ElementSession<'a> {
+    algorithm: &'a str,
+    room_id: &'a str,
+    sender_key: &'a str,
+    session_id: &'a str,
+    session_key: &'a str,
+    sender_claimed_keys: &'a HashMap<String, String>,
+    forwarding_curve25519_key_chain: &'a [String],
+}
+
+impl<'a> From<&'a ExportedKeyData> for ElementSession<'a> {
+    fn from(key: &'a ExportedKeyData) -> Self {
+        Self {
+            algorithm: &key.algorithm,
+            room_id: &key.room_id,
+            sender_key: &key.sender_key,
+            session_id: &key.session_id,
+            session_key: &key.session_key,
+            sender_claimed_keys: &key.sender_claimed_keys,
+            forwarding_curve25519_key_chain: &key.forwarding_curve25519_key_chain,
+        }
+    }
+}
+
+/// AES and HMAC keys for `passphrase` and `salt`
+fn derive_keys(passphrase: &str, salt: &[u8], rounds: u32) -> Zeroizing<[u8; 64]> {
+    let mut keys = Zeroizing::new([0u8; 64]);
+    pbkdf2::pbkdf2_hmac::<Sha512>(passphrase.as_bytes(), salt, rounds, keys.as_mut());
+    keys
+}
+
+/// HMAC-SHA256 of `data` under `key`
+fn mac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac
+}
+
+/// Encrypt `keys` for import into Element with `passphrase`
+///
+/// Fails for keys without a session key, e.g. from exports made with
+/// `--no-secrets`, since Element can't do anything with them.
+pub fn export(keys: &[ExportedKeyData], passphrase: &str, rounds: u32) -> Result<String> {
+    if passphrase.is_empty() {
+        anyhow::bail!("The Element export needs a passphrase");
+    }
+    if rounds == 0 {
+        anyhow::bail!("PBKDF2 rounds must be at least 1");
+    }
+    if let Some(key) = keys.iter().find(|key| key.session_key.is_empty()) {
+        anyhow::bail!(
+            "Session {} in {} has no session key - was the export made with --no-secrets?",
+            key.session_id,
+            key.room_id
+        );
+    }
+
+    let sessions: Vec<ElementSession> = keys.iter().map(ElementSession::from).collect();
+    let mut data = Zeroizing::new(
+        serde_json::to_vec(&sessions).context("Failed to serialize sessions")?,
+    );
+
+    let mut salt = [0u8; SALT_LEN];
+    let mut iv = [0u8; IV_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut iv);
+    // Clearing bit 63 keeps the counter from wrapping, as other clients expect
+    iv[8] &= 0x7f;
+
+    let keys = derive_keys(passphrase, &salt, rounds);
+    let (aes_key, hmac_key) = keys.split_at(32);
+    Aes256Ctr::new(aes_key.into(), &iv.into()).apply_keystream(&mut data);
+
+    let mut file = Vec::with_capacity(1 + SALT_LEN + IV_LEN + 4 + data.len() + MAC_LEN);
+    file.push(VERSION);
+    file.extend_from_slice(&salt);
+    file.extend_from_slice(&iv);
+    file.extend_from_slice(&rounds.to_be_bytes());
+    file.extend_from_slice(&data);
+    let tag = mac(hmac_key, &file).finalize().into_bytes();
+    file.extend_from_slice(&tag);
+
+    let encoded = STANDARD.encode(&file);
+    let mut armored = String::with_capacity(encoded.len() + encoded.len() / LINE_LEN + 80);
+    armored.push_str(HEADER);
+    armored.push('\n');
+    for line in encoded.as_bytes().chunks(LINE_LEN) {
+        armored.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        armored.push('\n');
+    }
+    armored.push_str(FOOTER);
+    armored.push('\n');
+    Ok(armored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decrypt an Element export, the way Element does on import
+    fn decrypt(armored: &str, passphrase: &str) -> serde_json::Value {
+        let body: String = armored
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let file = STANDARD.decode(body).unwrap();
+        assert_eq!(file[0], VERSION);
+
+        let (content, tag) = file.split_at(file.len() - MAC_LEN);
+        let salt = &content[1..1 + SALT_LEN];
+        let iv: [u8; IV_LEN] = content[1 + SALT_LEN..1 + SALT_LEN + IV_LEN].try_into().unwrap();
+        let rounds_at = 1 + SALT_LEN + IV_LEN;
+        let rounds = u32::from_be_bytes(content[rounds_at..rounds_at + 4].try_into().unwrap());
+        let keys = derive_keys(passphrase, salt, rounds);
+        mac(&keys[32..], content).verify_slice(tag).unwrap();
+
+        let mut data = content[rounds_at + 4..].to_vec();
+        Aes256Ctr::new(keys[..32].into(), &iv.into()).apply_keystream(&mut data);
+        serde_json::from_slice(&data).unwrap()
+    }
+
+    fn key(session_id: &str, session_key: &str) -> ExportedKeyData {
+        ExportedKeyData {
+            room_id: "!a:x.org".to_string(),
+            session_id: session_id.to_string(),
+            algorithm: "m.megolm.v1.aes-sha2".to_string(),
+            session_key: session_key.to_string(),
+            sender_key: "c2VuZGVy".to_string(),
+            sender_claimed_keys: HashMap::from([("ed25519".to_string(), "ZWQ".to_string())]),
+            forwarding_curve25519_key_chain: Vec::new(),
+            first_known_index: Some(3),
+            backed_up: None,
+            imported: None,
+            extra: Default::default(),
+            sha256: "ignored".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let armored = export(&[key("a", "AQID"), key("b", "BAUG")], "secret", 10).unwrap();
+
+        assert!(armored.starts_with("-----BEGIN MEGOLM SESSION DATA-----\n"));
+        assert!(armored.ends_with("\n-----END MEGOLM SESSION DATA-----\n"));
+        let sessions = decrypt(&armored, "secret");
+        assert_eq!(sessions[1]["session_id"], "b");
+        assert_eq!(sessions[1]["session_key"], "BAUG");
+        assert_eq!(sessions[0]["sender_claimed_keys"]["ed25519"], "ZWQ");
+        assert!(sessions[0].get("sha256").is_none());
+
+        assert!(export(&[key("c", "")], "secret", 10).is_err());
+    }
+}
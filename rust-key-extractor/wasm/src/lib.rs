@@ -0,0 +1,181 @@
+//! Export validation and Element conversion in the browser
+//!
+//! Export files hold every room key of an account, so users shouldn't have to
+//! upload one anywhere to find out whether it is intact or to get it into
+//! Element. This crate compiles the export reader, `check-export` and a
+//! converter to Element's key export format to WebAssembly; `index.html` is a
+//! page that runs them on a file picked by the user, without any network
+//! access.
+//!
+//! The checks and the export decryption are the modules of the main crate,
+//! included by path. Build with `wasm-pack build --target web` in this
+//! directory and serve it together with `index.html`.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use wasm_bindgen::prelude::*;
+use zeroize::Zeroizing;
+
+#[allow(dead_code)]
+#[path = "../../src/check.rs"]
+mod check;
+mod element;
+#[allow(dead_code)]
+#[path = "../../src/encryption.rs"]
+mod encryption;
+mod model;
+
+use model::{ExportedKeyData, ExtractionOutput};
+
+/// The parts of the main crate's `metadata` module the shared checks need
+mod metadata {
+    /// Export format versions this build reads
+    pub const SUPPORTED_VERSIONS: &[u32] = &[1, 2];
+}
+
+/// The parts of the main crate's `error` module the shared decryption needs
+mod error {
+    #[derive(Debug, thiserror::Error)]
+    pub enum ExtractorError {
+        /// An encrypted export could not be decrypted
+        #[error("Failed to decrypt export - wrong passphrase or corrupted file")]
+        ExportDecryption,
+    }
+}
+
+/// Magic bytes at the start of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Magic bytes at the start of a gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Decrypt, decompress and decode an export file
+///
+/// Accepts everything `import` does except zstd, whose decoder doesn't build
+/// for the browser, and split export directories.
+fn read_export(data: &[u8], passphrase: Option<&str>) -> Result<ExtractionOutput> {
+    let mut data = Zeroizing::new(data.to_vec());
+
+    if encryption::is_encrypted(&data) {
+        let passphrase = passphrase.context("Export is encrypted - enter its passphrase")?;
+        data = encryption::decrypt(&data, passphrase)?;
+    }
+    if data.starts_with(&ZSTD_MAGIC) {
+        anyhow::bail!("zstd-compressed exports can't be read here - decompress it with `zstd -d`");
+    }
+    if data.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Zeroizing::new(Vec::new());
+        flate2::read::GzDecoder::new(&data[..])
+            .read_to_end(&mut decompressed)
+            .context("Failed to decompress gzip")?;
+        data = decompressed;
+    }
+
+    // Same detection as format::OutputFormat::detect
+    match data.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => serde_json::from_slice(&data).context("Failed to parse JSON"),
+        Some(0xa0..=0xbf) => ciborium::de::from_reader(&data[..]).context("Failed to parse CBOR"),
+        Some(0x80..=0x8f | 0xde | 0xdf) => {
+            rmp_serde::from_slice(&data).context("Failed to parse MessagePack")
+        }
+        _ => anyhow::bail!("Unknown file format - expected JSON, CBOR or MessagePack"),
+    }
+}
+
+/// Empty passphrases from form fields mean none
+fn passphrase(value: &str) -> Option<&str> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+fn check(data: &[u8], input_passphrase: &str) -> Result<String> {
+    let output = read_export(data, passphrase(input_passphrase))?;
+    let report = check::check_export(&output);
+    serde_json::to_string(&report).context("Failed to serialize report")
+}
+
+fn convert(
+    data: &[u8],
+    input_passphrase: &str,
+    export_passphrase: &str,
+    rounds: Option<u32>,
+) -> Result<String> {
+    let output = read_export(data, passphrase(input_passphrase))?;
+    element::export(
+        &output.all_keys,
+        export_passphrase,
+        rounds.unwrap_or(element::DEFAULT_ROUNDS),
+    )
+}
+
+/// Check an export file and return the report as JSON
+///
+/// The report is the one `check-export --json` prints. `input_passphrase` is
+/// only needed for encrypted exports and may be empty otherwise.
+#[wasm_bindgen(js_name = checkExport)]
+pub fn check_export(data: &[u8], input_passphrase: &str) -> Result<String, JsError> {
+    check(data, input_passphrase).map_err(|e| JsError::new(&format!("{:#}", e)))
+}
+
+/// Convert an export file to an Element key export
+///
+/// Returns the armored file, encrypted with `export_passphrase`, which is
+/// what Element asks for on import. `rounds` defaults to the 500 000 PBKDF2
+/// rounds Element uses itself.
+#[wasm_bindgen(js_name = toElementExport)]
+pub fn to_element_export(
+    data: &[u8],
+    input_passphrase: &str,
+    export_passphrase: &str,
+    rounds: Option<u32>,
+) -> Result<String, JsError> {
+    convert(data, input_passphrase, export_passphrase, rounds)
+        .map_err(|e| JsError::new(&format!("{:#}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"{
+        "version": 1,
+        "total_keys": 1,
+        "failed_keys": 0,
+        "all_keys": [{
+            "room_id": "!a:x.org",
+            "session_id": "s",
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "session_key": "AQID",
+            "sender_key": "c2VuZGVy",
+            "sender_claimed_keys": {},
+            "forwarding_curve25519_key_chain": []
+        }]
+    }"#;
+
+    #[test]
+    fn test_read_encrypted_and_compressed_exports() {
+        let value: serde_json::Value = serde_json::from_str(EXPORT).unwrap();
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&value, &mut cbor).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gzip, &cbor).unwrap();
+        let params = encryption::KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let encrypted =
+            encryption::encrypt_with_params(&gzip.finish().unwrap(), "secret", params).unwrap();
+
+        let report: serde_json::Value =
+            serde_json::from_str(&check(&encrypted, "secret").unwrap()).unwrap();
+        assert_eq!(report["keys"], 1);
+        assert_eq!(report["problems"].as_array().unwrap().len(), 0);
+
+        assert!(check(&encrypted, "").is_err());
+        assert!(check(&encrypted, "wrong").is_err());
+        assert!(convert(EXPORT.as_bytes(), "", "export", Some(10))
+            .unwrap()
+            .starts_with("-----BEGIN MEGOLM SESSION DATA-----"));
+    }
+}
@@ -0,0 +1,67 @@
+//! Export structures, as far as the browser needs them
+//!
+//! Mirrors `ExportedKeyData` and `ExtractionOutput` of the main crate with the
+//! same field names and serde attributes, so every export the tool writes
+//! deserializes here. Metadata, integrity and additional trees are kept as
+//! plain values: the page only checks that they are present.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use serde_json::Value;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// One exported key
+///
+/// Wiped from memory when dropped, since `session_key` is secret.
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct ExportedKeyData {
+    pub room_id: String,
+    pub session_id: String,
+    pub algorithm: String,
+    #[serde(default)]
+    pub session_key: String,
+    pub sender_key: String,
+    #[zeroize(skip)]
+    pub sender_claimed_keys: HashMap<String, String>,
+    pub forwarding_curve25519_key_chain: Vec<String>,
+    #[zeroize(skip)]
+    #[serde(default)]
+    pub first_known_index: Option<u32>,
+    #[zeroize(skip)]
+    #[serde(default)]
+    pub backed_up: Option<bool>,
+    #[zeroize(skip)]
+    #[serde(default)]
+    pub imported: Option<bool>,
+    #[zeroize(skip)]
+    #[serde(default)]
+    pub extra: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub sha256: String,
+}
+
+/// A whole export file
+///
+/// Some fields are only here to match the main crate's struct, which the shared
+/// check tests build.
+#[allow(dead_code)]
+#[derive(Deserialize)]
+pub struct ExtractionOutput {
+    pub version: u32,
+    pub total_keys: usize,
+    pub failed_keys: usize,
+    #[serde(default)]
+    pub keys_by_room: HashMap<String, Vec<ExportedKeyData>>,
+    #[serde(default)]
+    pub keys_per_room: BTreeMap<String, usize>,
+    pub all_keys: Vec<ExportedKeyData>,
+    #[serde(flatten)]
+    pub extra_trees: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub metadata: Option<Value>,
+    #[serde(default)]
+    pub integrity: Option<Value>,
+    #[serde(default)]
+    pub partial: bool,
+}
@@ -11,6 +11,7 @@
 # Optional environment variables:
 #   CRYPTO_STORE_PATH - Path to the crypto store (default: STORAGE_PATH/encrypted)
 #   MIGRATION_DIR - Working directory for migration files (default: current directory)
+#   BACKUP_SESSIONS_FILE - Session list of the server backup; listed sessions are left out
 
 set -euo pipefail
 
@@ -124,10 +125,13 @@ fi
 echo "Extracting keys from Sled store..."
 echo ""
 
-"${EXTRACTOR_BIN}" \
-    --sled-path "${SLED_PATH}" \
-    --output "${OUTPUT_FILE}" \
-    --verbose
+EXTRACTOR_ARGS=(--sled-path "${SLED_PATH}" --output "${OUTPUT_FILE}" --verbose)
+if [ -n "${BACKUP_SESSIONS_FILE:-}" ]; then
+    echo "Leaving out sessions listed in: ${BACKUP_SESSIONS_FILE}"
+    EXTRACTOR_ARGS+=(--only-missing-from-backup "${BACKUP_SESSIONS_FILE}")
+fi
+
+"${EXTRACTOR_BIN}" "${EXTRACTOR_ARGS[@]}"
 
 if [ $? -ne 0 ]; then
     echo ""
@@ -9,14 +9,13 @@
 
 import * as fs from 'fs';
 import * as path from 'path';
-import * as crypto from 'crypto';
-import { BackupDecryptionKey } from '@ixo/matrix-sdk-crypto-nodejs';
 import { config, saveMigrationState, validateConfig } from '../config';
 import {
     whoami,
     getBackupVersion,
     createBackupVersion,
 } from '../utils/matrix-api';
+import { generateKeyPair } from '../utils/recovery-key';
 
 // ANSI color codes for terminal output
 const colors = {
@@ -59,65 +58,15 @@ async function generateBackupKey(): Promise<{
     publicKey: string;
     recoveryKey: string;
 }> {
-    // Generate random 32 bytes for the private key seed
-    const seed = crypto.randomBytes(32);
-    const seedBase64 = seed.toString('base64');
-
-    // Use the Rust SDK to create a proper BackupDecryptionKey
-    // This derives the correct Curve25519 public key from the seed
-    const decryptionKey = BackupDecryptionKey.fromBase64(seedBase64);
-    const publicKey = decryptionKey.megolmV1PublicKey.publicKeyBase64;
-
-    // Generate the recovery key in the standard Matrix format
-    // Recovery keys are base58-encoded with prefix and parity byte
-    const withoutParity = Buffer.concat([
-        Buffer.from([0x8b, 0x01]), // Prefix for recovery key v1
-        seed,
-    ]);
-    // Calculate parity byte (XOR of all bytes)
-    let parity = 0;
-    for (const byte of withoutParity) {
-        parity ^= byte;
-    }
-    const recoveryKeyBytes = Buffer.concat([withoutParity, Buffer.from([parity])]);
-    const recoveryKey = encodeBase58(recoveryKeyBytes);
-
-    // Format the recovery key with spaces for readability
-    const formattedRecoveryKey = recoveryKey.match(/.{1,4}/g)?.join(' ') || recoveryKey;
+    const { recoveryKey, recoveryKeyBase64, publicKey } = generateKeyPair();
 
     return {
-        privateKey: seed,
-        publicKey: publicKey,
-        recoveryKey: formattedRecoveryKey,
+        privateKey: Buffer.from(recoveryKeyBase64, 'base64'),
+        publicKey,
+        recoveryKey,
     };
 }
 
-/**
- * Base58 encoding (used for recovery keys)
- */
-function encodeBase58(buffer: Buffer): string {
-    const alphabet = '123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz';
-    let num = BigInt('0x' + buffer.toString('hex'));
-    let result = '';
-
-    while (num > 0) {
-        const remainder = Number(num % BigInt(58));
-        result = alphabet[remainder] + result;
-        num = num / BigInt(58);
-    }
-
-    // Add leading zeros
-    for (const byte of buffer) {
-        if (byte === 0) {
-            result = '1' + result;
-        } else {
-            break;
-        }
-    }
-
-    return result;
-}
-
 export async function runEnableBackup(): Promise<void> {
     log('==============================================');
     log('Matrix Bot Server Backup Setup');
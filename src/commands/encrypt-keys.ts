@@ -0,0 +1,176 @@
+#!/usr/bin/env npx ts-node
+/**
+ * encrypt-keys.ts
+ *
+ * Encrypts the extracted keys for the server backup locally and writes the
+ * request bodies for PUT /_matrix/client/v3/room_keys/keys to disk, without
+ * contacting the server. Any HTTP client can then upload them (curl, another
+ * language), and only ever handles encrypted keys.
+ *
+ * Requires: BACKUP_PUBLIC_KEY (the backup's Curve25519 public key) or
+ * RECOVERY_KEY (the public key is derived from it)
+ *
+ * Output: PAYLOAD_DIR (default: MIGRATION_DIR/backup-payloads) with one
+ * batch-NNNNN.json per request and a manifest.json
+ */
+
+import * as fs from 'fs';
+import * as path from 'path';
+import { OlmMachine } from '@ixo/matrix-sdk-crypto-nodejs';
+import {
+    ExtractionOutput,
+    createTempMachine,
+    loadExtractedKeys,
+    markBatchSent,
+    nextBackupBatch,
+    toImportFormat,
+} from '../utils/backup-machine';
+import { keyPairFromPrivateKey, parseBackupKey } from '../utils/recovery-key';
+
+// ANSI color codes
+const colors = {
+    reset: '\x1b[0m',
+    red: '\x1b[31m',
+    green: '\x1b[32m',
+    yellow: '\x1b[33m',
+};
+
+function log(message: string): void {
+    console.log(message);
+}
+
+function logError(message: string): void {
+    console.error(`${colors.red}ERROR: ${message}${colors.reset}`);
+}
+
+function logSuccess(message: string): void {
+    console.log(`${colors.green}${message}${colors.reset}`);
+}
+
+function logWarning(message: string): void {
+    console.log(`${colors.yellow}WARNING: ${message}${colors.reset}`);
+}
+
+/**
+ * The backup public key, given directly or derived from the recovery key
+ */
+function backupPublicKey(): string {
+    if (process.env.BACKUP_PUBLIC_KEY) {
+        return process.env.BACKUP_PUBLIC_KEY;
+    }
+    if (process.env.RECOVERY_KEY) {
+        return keyPairFromPrivateKey(parseBackupKey(process.env.RECOVERY_KEY)).publicKey;
+    }
+    throw new Error('Set BACKUP_PUBLIC_KEY or RECOVERY_KEY to encrypt keys for the backup');
+}
+
+export async function runEncryptKeys(): Promise<void> {
+    log('==============================================');
+    log('Encrypt Keys into Backup Payloads');
+    log('==============================================');
+    log('');
+
+    // No server access needed, so the server configuration isn't required
+    const migrationDir = process.env.MIGRATION_DIR || process.cwd();
+    const extractedKeysPath = path.join(migrationDir, 'extracted-keys.json');
+    const payloadDir = process.env.PAYLOAD_DIR || path.join(migrationDir, 'backup-payloads');
+    // The version only goes into the upload URL, not into the payloads
+    const backupVersion = process.env.BACKUP_VERSION || 'local';
+
+    let publicKey: string;
+    try {
+        publicKey = backupPublicKey();
+        log(`  Public key: ${publicKey.substring(0, 20)}...`);
+    } catch (e) {
+        logError((e as Error).message);
+        process.exit(1);
+    }
+
+    log('');
+    log('Loading extracted keys...');
+
+    let extractedData: ExtractionOutput;
+    try {
+        extractedData = loadExtractedKeys(extractedKeysPath);
+    } catch (e) {
+        logError(`Failed to read extracted keys: ${(e as Error).message}`);
+        process.exit(1);
+    }
+    log(`  Total keys: ${extractedData.total_keys}`);
+
+    if (extractedData.total_keys === 0) {
+        logWarning('No keys to encrypt!');
+        process.exit(0);
+    }
+
+    if (fs.existsSync(payloadDir) && fs.readdirSync(payloadDir).length > 0) {
+        logError(`Payload directory is not empty: ${payloadDir}`);
+        process.exit(1);
+    }
+    fs.mkdirSync(payloadDir, { recursive: true, mode: 0o700 });
+
+    log('');
+    log('Initializing crypto engine...');
+
+    let machine: OlmMachine;
+    try {
+        // Backup payloads aren't bound to the account, so any user ID works
+        machine = await createTempMachine(migrationDir, process.env.USER_ID || '@migration:localhost', log);
+
+        const keysForImport = toImportFormat(extractedData.all_keys);
+        const importResult = await machine.importRoomKeys(JSON.stringify(keysForImport), null);
+        log(`  Imported: ${importResult.importedCount} / ${importResult.totalCount} keys`);
+
+        await machine.enableBackupV1(publicKey, backupVersion);
+    } catch (e) {
+        logError(`Failed to prepare crypto engine: ${(e as Error).message}`);
+        process.exit(1);
+    }
+
+    log('');
+    log('Writing encrypted batches...');
+
+    const batchFiles: Array<{ file: string; keys: number }> = [];
+    let totalKeys = 0;
+    while (true) {
+        const batch = await nextBackupBatch(machine);
+        if (!batch) {
+            break;
+        }
+
+        const file = `batch-${String(batchFiles.length + 1).padStart(5, '0')}.json`;
+        fs.writeFileSync(path.join(payloadDir, file), JSON.stringify(batch.body), { mode: 0o600 });
+        batchFiles.push({ file, keys: batch.keyCount });
+        totalKeys += batch.keyCount;
+
+        // Nothing is uploaded; a fake response lets the machine move on
+        await markBatchSent(machine, batch, { count: totalKeys, etag: String(batchFiles.length) });
+    }
+
+    fs.writeFileSync(
+        path.join(payloadDir, 'manifest.json'),
+        JSON.stringify({
+            algorithm: 'm.megolm_backup.v1.curve25519-aes-sha2',
+            public_key: publicKey,
+            total_keys: totalKeys,
+            batches: batchFiles,
+        }, null, 2),
+        { mode: 0o600 },
+    );
+
+    log('');
+    logSuccess(`Wrote ${totalKeys} encrypted keys in ${batchFiles.length} batches to ${payloadDir}`);
+    log('');
+    log('Upload each batch with:');
+    log('  curl -X PUT -H "Authorization: Bearer $ACCESS_TOKEN" -H "Content-Type: application/json" \\');
+    log('    --data @batch-00001.json "$HOMESERVER_URL/_matrix/client/v3/room_keys/keys?version=$VERSION"');
+}
+
+// Allow running directly
+if (require.main === module) {
+    runEncryptKeys().catch((e) => {
+        logError(`Unexpected error: ${e.message}`);
+        console.error(e);
+        process.exit(1);
+    });
+}
@@ -3,13 +3,14 @@
  * extract-backup-key.ts
  *
  * Extracts the key backup decryption key from Matrix SSSS (Secret Storage)
- * using the oracle's recovery phrase (MATRIX_RECOVERY_PHRASE).
+ * using the oracle's recovery phrase (MATRIX_RECOVERY_PHRASE), or the secret
+ * storage recovery key.
  *
  * This is used for oracle migrations where the backup already exists
  * (created during initial cross-signing setup) and we need the backup
  * key to upload extracted sled keys and for the bot-sdk to restore them.
  *
- * Requires: RECOVERY_PHRASE environment variable
+ * Requires: RECOVERY_PHRASE or SSSS_RECOVERY_KEY environment variable
  */
 
 import * as fs from 'fs';
@@ -21,7 +22,8 @@ import {
     getBackupVersion,
     MatrixApiConfig,
 } from '../utils/matrix-api';
-import { extractBackupKeyFromSSS } from '../utils/ssss';
+import { extractBackupKeyFromSSS, ssssCredentialsFromEnv, SSSSCredentials } from '../utils/ssss';
+import { encodeRecoveryKey } from '../utils/recovery-key';
 
 // ANSI color codes
 const colors = {
@@ -49,53 +51,11 @@ function logImportant(message: string): void {
     console.log(`${colors.bold}${colors.cyan}${message}${colors.reset}`);
 }
 
-/**
- * Base58 encoding (same as enable-backup.ts)
- */
-function encodeBase58(buffer: Buffer): string {
-    const alphabet = '123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz';
-    let num = BigInt('0x' + buffer.toString('hex'));
-    let result = '';
-
-    while (num > 0) {
-        const remainder = Number(num % BigInt(58));
-        result = alphabet[remainder] + result;
-        num = num / BigInt(58);
-    }
-
-    // Add leading zeros
-    for (const byte of buffer) {
-        if (byte === 0) {
-            result = '1' + result;
-        } else {
-            break;
-        }
-    }
-
-    return result;
-}
-
 /**
  * Convert a Base64 backup key to the Matrix Base58 recovery key format.
- * Format: [0x8b, 0x01] + 32-byte key + parity byte, Base58 encoded
  */
 function backupKeyToRecoveryKey(backupKeyBase64: string): string {
-    const seed = Buffer.from(backupKeyBase64, 'base64');
-
-    const withoutParity = Buffer.concat([
-        Buffer.from([0x8b, 0x01]), // Recovery key v1 prefix
-        seed,
-    ]);
-
-    let parity = 0;
-    for (const byte of withoutParity) {
-        parity ^= byte;
-    }
-
-    const recoveryKeyBytes = Buffer.concat([withoutParity, Buffer.from([parity])]);
-    const recoveryKey = encodeBase58(recoveryKeyBytes);
-
-    return recoveryKey.match(/.{1,4}/g)?.join(' ') || recoveryKey;
+    return encodeRecoveryKey(Buffer.from(backupKeyBase64, 'base64'));
 }
 
 export async function runExtractBackupKey(): Promise<void> {
@@ -113,11 +73,18 @@ export async function runExtractBackupKey(): Promise<void> {
     }
 
     // Check for recovery phrase
-    const recoveryPhrase = process.env.RECOVERY_PHRASE;
-    if (!recoveryPhrase) {
-        logError('RECOVERY_PHRASE environment variable is required for this command.');
+    let credentials: SSSSCredentials | null;
+    try {
+        credentials = ssssCredentialsFromEnv();
+    } catch (e) {
+        logError(`Invalid SSSS_RECOVERY_KEY: ${(e as Error).message}`);
+        process.exit(1);
+    }
+    if (!credentials) {
+        logError('RECOVERY_PHRASE or SSSS_RECOVERY_KEY environment variable is required for this command.');
         log('');
-        log('This is the MATRIX_RECOVERY_PHRASE used by the oracle to set up SSSS.');
+        log('RECOVERY_PHRASE is the MATRIX_RECOVERY_PHRASE used by the oracle to set up SSSS.');
+        log('SSSS_RECOVERY_KEY is the secret storage recovery key (EsT... format).');
         log('Set it as: RECOVERY_PHRASE="your_recovery_phrase" npx @ixo/matrix-sled-migration extract-backup-key');
         process.exit(1);
     }
@@ -158,11 +125,13 @@ export async function runExtractBackupKey(): Promise<void> {
     // Extract backup key from SSSS
     log('');
     log('Extracting backup key from SSSS...');
-    log('  Deriving SSSS key from recovery phrase (PBKDF2, this may take a moment)...');
+    if ('passphrase' in credentials) {
+        log('  Deriving SSSS key from recovery phrase (PBKDF2, this may take a moment)...');
+    }
 
     let backupKeyBase64: string | null;
     try {
-        backupKeyBase64 = await extractBackupKeyFromSSS(apiConfig, userId, recoveryPhrase);
+        backupKeyBase64 = await extractBackupKeyFromSSS(apiConfig, userId, credentials);
     } catch (e) {
         logError(`Failed to extract backup key: ${(e as Error).message}`);
         process.exit(1);
@@ -238,7 +207,8 @@ ${backupKeyBase64}
 IMPORTANT: Store this key securely!
 
 This is the key backup decryption key extracted from the oracle's
-Secret Storage (SSSS). It was derived from the MATRIX_RECOVERY_PHRASE.
+Secret Storage (SSSS), unlocked with the MATRIX_RECOVERY_PHRASE or the
+secret storage recovery key.
 
 Use this key in the oracle's configuration to enable automatic
 key backup and recovery with the matrix-bot-sdk.
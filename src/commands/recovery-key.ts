@@ -0,0 +1,125 @@
+#!/usr/bin/env npx ts-node
+/**
+ * recovery-key.ts
+ *
+ * Recovery key utilities:
+ *   generate         - Generate a new backup recovery key
+ *   derive <KEY>     - Derive the backup key pair from an existing recovery key
+ *   validate <KEY>   - Check a recovery key and, if the server is configured,
+ *                      that it belongs to the account's current backup
+ *
+ * The key can also be passed in the RECOVERY_KEY environment variable, which
+ * keeps it out of shell history. Output is JSON on stdout.
+ */
+
+import {
+    BackupKeyPair,
+    generateKeyPair,
+    keyPairFromPrivateKey,
+    parseBackupKey,
+} from '../utils/recovery-key';
+import { getBackupVersion } from '../utils/matrix-api';
+
+// ANSI color codes
+const colors = {
+    reset: '\x1b[0m',
+    red: '\x1b[31m',
+    green: '\x1b[32m',
+    yellow: '\x1b[33m',
+};
+
+function logError(message: string): void {
+    console.error(`${colors.red}ERROR: ${message}${colors.reset}`);
+}
+
+function logSuccess(message: string): void {
+    console.error(`${colors.green}✓ ${message}${colors.reset}`);
+}
+
+function logWarning(message: string): void {
+    console.error(`${colors.yellow}⚠ ${message}${colors.reset}`);
+}
+
+/**
+ * The recovery key from the command line or RECOVERY_KEY
+ */
+function readKey(argument: string | undefined): string {
+    const key = argument || process.env.RECOVERY_KEY;
+    if (!key) {
+        throw new Error('No recovery key given - pass it as an argument or in RECOVERY_KEY');
+    }
+    return key;
+}
+
+/**
+ * Compare the key against the public key of the server's current backup
+ *
+ * Skipped when the server isn't configured.
+ */
+async function checkAgainstServer(publicKey: string): Promise<boolean> {
+    const homeserverUrl = process.env.HOMESERVER_URL;
+    const accessToken = process.env.ACCESS_TOKEN;
+    if (!homeserverUrl || !accessToken) {
+        logWarning('HOMESERVER_URL / ACCESS_TOKEN not set - not checking against the server backup');
+        return true;
+    }
+
+    const backupInfo = await getBackupVersion({ homeserverUrl, accessToken });
+    if (!backupInfo) {
+        logWarning('The account has no server backup to check the key against');
+        return true;
+    }
+
+    if (backupInfo.auth_data.public_key !== publicKey) {
+        logError(`The key does not belong to backup version ${backupInfo.version}`);
+        return false;
+    }
+    logSuccess(`The key matches backup version ${backupInfo.version}`);
+    return true;
+}
+
+export async function runRecoveryKey(action: string | undefined, argument?: string): Promise<void> {
+    switch (action) {
+        case 'generate': {
+            console.log(JSON.stringify(generateKeyPair(), null, 2));
+            break;
+        }
+
+        case 'derive': {
+            const keyPair = keyPairFromPrivateKey(parseBackupKey(readKey(argument)));
+            console.log(JSON.stringify(keyPair, null, 2));
+            break;
+        }
+
+        case 'validate': {
+            let keyPair: BackupKeyPair;
+            try {
+                keyPair = keyPairFromPrivateKey(parseBackupKey(readKey(argument)));
+            } catch (e) {
+                logError(`Invalid recovery key: ${(e as Error).message}`);
+                process.exit(1);
+            }
+            logSuccess('Recovery key is well-formed');
+
+            const matches = await checkAgainstServer(keyPair.publicKey);
+            console.log(JSON.stringify({ valid: matches, publicKey: keyPair.publicKey }, null, 2));
+            if (!matches) {
+                process.exit(1);
+            }
+            break;
+        }
+
+        default:
+            throw new Error(
+                `Unknown recovery-key action: ${action ?? '(none)'} - use generate, derive or validate`,
+            );
+    }
+}
+
+// Allow running directly
+if (require.main === module) {
+    runRecoveryKey(process.argv[2], process.argv[3]).catch((e) => {
+        console.error(JSON.stringify({ error: (e as Error).message }));
+        process.exit(1);
+    });
+}
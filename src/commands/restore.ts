@@ -0,0 +1,225 @@
+#!/usr/bin/env npx ts-node
+/**
+ * restore.ts
+ *
+ * Downloads all keys from the account's server-side backup, decrypts them
+ * with the recovery key and imports them into a SQLite crypto store. This is
+ * the way back when the sled store is unrecoverable but a backup exists.
+ *
+ * Requires: TARGET_STORE_PATH (the SQLite crypto store directory), and the
+ * recovery key in RECOVERY_KEY, in secret storage (unlocked with
+ * SSSS_RECOVERY_KEY or RECOVERY_PHRASE), or as backup-private-key.bin
+ * (written by `enable` and `extract-backup-key`) in MIGRATION_DIR
+ */
+
+import * as fs from 'fs';
+import * as path from 'path';
+import {
+    BackupDecryptionKey,
+    DeviceId,
+    OlmMachine,
+    StoreType,
+    UserId,
+} from '@ixo/matrix-sdk-crypto-nodejs';
+import { config, validateConfig } from '../config';
+import {
+    getBackupKeys,
+    getBackupVersion,
+    whoami,
+    withRetry,
+    MatrixApiConfig,
+} from '../utils/matrix-api';
+import { ExportedRoomKey } from '../utils/backup-machine';
+import { keyPairFromPrivateKey, parseBackupKey } from '../utils/recovery-key';
+import { extractBackupKeyFromSSS, ssssCredentialsFromEnv } from '../utils/ssss';
+
+// ANSI color codes
+const colors = {
+    reset: '\x1b[0m',
+    red: '\x1b[31m',
+    green: '\x1b[32m',
+    yellow: '\x1b[33m',
+};
+
+function log(message: string): void {
+    console.log(message);
+}
+
+function logError(message: string): void {
+    console.error(`${colors.red}ERROR: ${message}${colors.reset}`);
+}
+
+function logSuccess(message: string): void {
+    console.log(`${colors.green}${message}${colors.reset}`);
+}
+
+function logWarning(message: string): void {
+    console.log(`${colors.yellow}WARNING: ${message}${colors.reset}`);
+}
+
+/**
+ * The backup private key from RECOVERY_KEY, secret storage or the saved key file
+ */
+async function loadBackupKey(apiConfig: MatrixApiConfig, userId: string): Promise<Buffer> {
+    if (process.env.RECOVERY_KEY) {
+        return parseBackupKey(process.env.RECOVERY_KEY);
+    }
+
+    const credentials = ssssCredentialsFromEnv();
+    if (credentials) {
+        log('  Fetching backup key from secret storage...');
+        const backupKeyBase64 = await extractBackupKeyFromSSS(apiConfig, userId, credentials);
+        if (!backupKeyBase64) {
+            throw new Error('The account has no backup key in secret storage');
+        }
+        return Buffer.from(backupKeyBase64, 'base64');
+    }
+
+    const privateKeyPath = path.join(path.dirname(config.recoveryKeyPath), 'backup-private-key.bin');
+    if (fs.existsSync(privateKeyPath)) {
+        log(`  Using backup key from ${privateKeyPath}`);
+        return fs.readFileSync(privateKeyPath);
+    }
+    throw new Error(`Set RECOVERY_KEY, SSSS_RECOVERY_KEY or RECOVERY_PHRASE, or put the backup key in ${privateKeyPath}`);
+}
+
+export async function runRestore(): Promise<void> {
+    log('==============================================');
+    log('Restore Server Backup into SQLite Store');
+    log('==============================================');
+    log('');
+
+    // Validate configuration
+    try {
+        validateConfig();
+    } catch (e) {
+        logError((e as Error).message);
+        process.exit(1);
+    }
+
+    const targetStorePath = process.env.TARGET_STORE_PATH;
+    if (!targetStorePath) {
+        logError('TARGET_STORE_PATH is required - the SQLite crypto store to restore into.');
+        process.exit(1);
+    }
+
+    const apiConfig: MatrixApiConfig = {
+        homeserverUrl: config.homeserverUrl,
+        accessToken: config.accessToken,
+    };
+
+    log('Getting user info...');
+    let userId: string;
+    try {
+        userId = await whoami(apiConfig);
+        log(`  User ID: ${userId}`);
+    } catch (e) {
+        logError(`Failed to get user ID: ${(e as Error).message}`);
+        process.exit(1);
+    }
+
+    log('');
+    log('Checking backup...');
+    const backupInfo = await getBackupVersion(apiConfig);
+    if (!backupInfo) {
+        logError('The account has no server backup to restore from.');
+        process.exit(1);
+    }
+    log(`  Backup version: ${backupInfo.version}`);
+    log(`  Keys in backup: ${backupInfo.count}`);
+
+    let decryptionKey: BackupDecryptionKey;
+    try {
+        const backupKey = await loadBackupKey(apiConfig, userId);
+        if (keyPairFromPrivateKey(backupKey).publicKey !== backupInfo.auth_data.public_key) {
+            throw new Error(`The recovery key does not belong to backup version ${backupInfo.version}`);
+        }
+        decryptionKey = BackupDecryptionKey.fromBase64(backupKey.toString('base64'));
+        log('  Recovery key matches the backup');
+    } catch (e) {
+        logError((e as Error).message);
+        process.exit(1);
+    }
+
+    log('');
+    log('Downloading backed up keys...');
+    const backup = await withRetry(() => getBackupKeys(apiConfig, backupInfo.version));
+
+    const keys: ExportedRoomKey[] = [];
+    let failed = 0;
+    for (const [roomId, room] of Object.entries(backup.rooms ?? {})) {
+        for (const [sessionId, backedUp] of Object.entries(room.sessions ?? {})) {
+            try {
+                const { ephemeral, mac, ciphertext } = backedUp.session_data;
+                const sessionData = JSON.parse(decryptionKey.decryptV1(ephemeral, mac, ciphertext));
+                keys.push({
+                    ...sessionData,
+                    room_id: roomId,
+                    session_id: sessionId,
+                });
+            } catch (e) {
+                failed++;
+                logWarning(`Could not decrypt session ${sessionId} in ${roomId}: ${(e as Error).message}`);
+            }
+        }
+    }
+    log(`  Decrypted: ${keys.length} keys`);
+    if (failed > 0) {
+        logWarning(`${failed} keys could not be decrypted`);
+    }
+
+    log('');
+    log(`Opening SQLite crypto store at ${targetStorePath}...`);
+    const deviceId = process.env.TARGET_DEVICE_ID || config.newDeviceId;
+    if (!deviceId) {
+        logError('TARGET_DEVICE_ID is required - the device the SQLite store belongs to.');
+        process.exit(1);
+    }
+    fs.mkdirSync(targetStorePath, { recursive: true });
+
+    let machine: OlmMachine;
+    try {
+        machine = await OlmMachine.initialize(
+            new UserId(userId),
+            new DeviceId(deviceId),
+            targetStorePath,
+            process.env.TARGET_STORE_PASSPHRASE || '',
+            StoreType.Sqlite,
+        );
+        log(`  Device ID: ${deviceId}`);
+    } catch (e) {
+        logError(`Failed to open the SQLite crypto store: ${(e as Error).message}`);
+        process.exit(1);
+    }
+
+    log('');
+    log('Importing keys...');
+    try {
+        // Importing with the backup version marks the keys as backed up, so the
+        // bot doesn't upload them again
+        const importResult = await machine.importRoomKeys(JSON.stringify(keys), backupInfo.version);
+        log(`  Imported: ${importResult.importedCount} / ${importResult.totalCount} keys`);
+    } catch (e) {
+        logError(`Failed to import keys: ${(e as Error).message}`);
+        process.exit(1);
+    }
+
+    log('');
+    log('==============================================');
+    logSuccess('Restore Complete!');
+    log('==============================================');
+    log('');
+    log(`Restored ${keys.length} keys from backup version ${backupInfo.version} into ${targetStorePath}`);
+    if (failed > 0) {
+        process.exit(1);
+    }
+}
+
+// Allow running directly
+if (require.main === module) {
+    runRestore().catch((e) => {
+        logError(`Unexpected error: ${e.message}`);
+        console.error(e);
+        process.exit(1);
+    });
+}
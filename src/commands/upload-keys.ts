@@ -12,22 +12,36 @@
  */
 
 import * as fs from 'fs';
-import * as path from 'path';
-import {
-    OlmMachine,
-    UserId,
-    DeviceId,
-    RequestType,
-    StoreType,
-} from '@ixo/matrix-sdk-crypto-nodejs';
-import { config, validateConfig, saveMigrationState } from '../config';
+import { OlmMachine } from '@ixo/matrix-sdk-crypto-nodejs';
+import { config, validateConfig, saveMigrationState, isEnabled } from '../config';
 import {
     matrixRequest,
+    withRetry,
+    DEFAULT_RETRY_OPTIONS,
+    RetryOptions,
     getBackupVersion,
     getBackupKeyCount,
+    getBackupRoomKeys,
     whoami,
     MatrixApiConfig,
 } from '../utils/matrix-api';
+import {
+    BackupRequestBody,
+    ExtractedKey,
+    ExtractionOutput,
+    compareWithBackup,
+    createTempMachine,
+    loadExtractedKeys,
+    markBatchSent,
+    nextBackupBatch,
+    rechunk,
+    sessionCount,
+    toImportFormat,
+} from '../utils/backup-machine';
+import { runExtractor } from '../utils/extractor';
+
+/** Keys per upload request, unless UPLOAD_BATCH_SIZE says otherwise */
+const DEFAULT_BATCH_SIZE = 100;
 
 // ANSI color codes
 const colors = {
@@ -61,33 +75,19 @@ function logProgress(current: number, total: number, message: string): void {
     process.stdout.write(`\r  [${progressBar}] ${percentage}% - ${message}        `);
 }
 
-// Extracted key format from the Rust tool
-interface ExtractedKey {
-    room_id: string;
-    session_id: string;
-    algorithm: string;
-    session_key: string;
-    sender_key: string;
-    sender_claimed_keys: Record<string, string>;
-    forwarding_curve25519_key_chain: string[];
-}
-
-interface ExtractionOutput {
-    version: number;
-    total_keys: number;
-    keys_by_room: Record<string, ExtractedKey[]>;
-    all_keys: ExtractedKey[];
-}
+/**
+ * Log how many keys of each room would be uploaded, for --dry-run
+ */
+function logUploadPlan(keys: ExtractedKey[], batchSize: number): void {
+    const perRoom = new Map<string, number>();
+    for (const key of keys) {
+        perRoom.set(key.room_id, (perRoom.get(key.room_id) ?? 0) + 1);
+    }
 
-// Format expected by OlmMachine.importRoomKeys
-interface ExportedRoomKey {
-    algorithm: string;
-    room_id: string;
-    sender_key: string;
-    session_id: string;
-    session_key: string;
-    sender_claimed_keys: Record<string, string>;
-    forwarding_curve25519_key_chain: string[];
+    log(`  Would upload ${keys.length} keys of ${perRoom.size} rooms in ${Math.ceil(keys.length / batchSize)} requests:`);
+    for (const [roomId, count] of [...perRoom].sort((a, b) => b[1] - a[1])) {
+        log(`    ${roomId}: ${count}`);
+    }
 }
 
 export async function runUploadKeys(): Promise<void> {
@@ -96,6 +96,13 @@ export async function runUploadKeys(): Promise<void> {
     log('==============================================');
     log('');
 
+    // Every read, comparison and encryption step runs, but nothing is uploaded
+    const dryRun = isEnabled('DRY_RUN');
+    if (dryRun) {
+        logWarning('Dry run - nothing will be uploaded or changed');
+        log('');
+    }
+
     // Validate configuration
     try {
         validateConfig();
@@ -122,7 +129,9 @@ export async function runUploadKeys(): Promise<void> {
     try {
         userId = await whoami(apiConfig);
         log(`  User ID: ${userId}`);
-        saveMigrationState({ userId });
+        if (!dryRun) {
+            saveMigrationState({ userId });
+        }
     } catch (e) {
         logError(`Failed to get user ID: ${(e as Error).message}`);
         process.exit(1);
@@ -131,11 +140,37 @@ export async function runUploadKeys(): Promise<void> {
     // Check that backup is configured
     log('');
     log('Checking backup configuration...');
-    const backupInfo = await getBackupVersion(apiConfig);
+    let backupInfo = await getBackupVersion(apiConfig);
+    const batchSize = Number(process.env.UPLOAD_BATCH_SIZE) || DEFAULT_BATCH_SIZE;
+
+    if (!backupInfo && isEnabled('CREATE_BACKUP') && dryRun) {
+        // Without a backup there is no key to encrypt with, and nothing to compare against
+        log('  No backup version found - would create one (--create-backup)');
+        log('');
+        try {
+            logUploadPlan(loadExtractedKeys(config.extractedKeysPath).all_keys, batchSize);
+        } catch (e) {
+            logError(`Failed to read extracted keys: ${(e as Error).message}`);
+            process.exit(1);
+        }
+        log('');
+        logSuccess('Dry run complete - nothing was uploaded.');
+        return;
+    }
+
+    if (!backupInfo && isEnabled('CREATE_BACKUP')) {
+        log('  No backup version found - creating one (--create-backup)');
+        log('');
+        const { runEnableBackup } = await import('./enable-backup');
+        await runEnableBackup();
+        log('');
+        backupInfo = await getBackupVersion(apiConfig);
+    }
 
     if (!backupInfo) {
         logError('No backup version found on server.');
-        log('Please run the backup setup step first (sled-migration-tool enable)');
+        log('Please run the backup setup step first (sled-migration-tool enable),');
+        log('or pass --create-backup to create one');
         process.exit(1);
     }
 
@@ -144,6 +179,7 @@ export async function runUploadKeys(): Promise<void> {
     log(`  Existing keys: ${backupInfo.count}`);
 
     const publicKey = backupInfo.auth_data.public_key;
+    const backupVersion = backupInfo.version;
     log(`  Public key: ${publicKey.substring(0, 20)}...`);
 
     // Load extracted keys
@@ -152,15 +188,27 @@ export async function runUploadKeys(): Promise<void> {
 
     let extractedData: ExtractionOutput;
     try {
-        extractedData = JSON.parse(fs.readFileSync(config.extractedKeysPath, 'utf-8'));
+        extractedData = loadExtractedKeys(config.extractedKeysPath);
     } catch (e) {
         logError(`Failed to read extracted keys: ${(e as Error).message}`);
         process.exit(1);
     }
 
+    // Keys of another account don't belong in this account's backup
+    const exportUserId = extractedData.metadata?.user_id;
+    if (exportUserId && exportUserId !== userId) {
+        if (isEnabled('FORCE')) {
+            logWarning(`The export is of ${exportUserId}, not ${userId} - uploading anyway (--force)`);
+        } else {
+            logError(`The export is of ${exportUserId}, but the access token belongs to ${userId}`);
+            log('Pass --force to upload anyway');
+            process.exit(1);
+        }
+    }
+
     log(`  Format version: ${extractedData.version}`);
     log(`  Total keys: ${extractedData.total_keys}`);
-    log(`  Rooms: ${Object.keys(extractedData.keys_by_room).length}`);
+    log(`  Rooms: ${Object.keys(extractedData.keys_per_room ?? extractedData.keys_by_room).length}`);
 
     if (extractedData.total_keys === 0) {
         logWarning('No keys to upload!');
@@ -171,55 +219,73 @@ export async function runUploadKeys(): Promise<void> {
     log('');
     log('Preparing keys for import...');
 
-    const keysForImport: ExportedRoomKey[] = extractedData.all_keys.map(key => ({
-        algorithm: key.algorithm || 'm.megolm.v1.aes-sha2',
-        room_id: key.room_id,
-        sender_key: key.sender_key,
-        session_id: key.session_id,
-        session_key: key.session_key,
-        sender_claimed_keys: key.sender_claimed_keys || {},
-        forwarding_curve25519_key_chain: key.forwarding_curve25519_key_chain || [],
-    }));
-
-    log(`  Prepared ${keysForImport.length} keys for import`);
-
-    // Create a fresh temporary directory for the OlmMachine store
-    // Use timestamp to ensure uniqueness and avoid conflicts with previous runs
-    const tempStorePath = path.join(config.migrationDir, `temp-crypto-store-${Date.now()}`);
+    // Skip keys the backup already has in an equal or better version, so an
+    // interrupted upload can simply be run again
+    let keysToUpload: ExtractedKey[] = extractedData.all_keys;
+    let newKeyCount = keysToUpload.length;
+    // Only keys the backup lacks entirely, leaving those it has in a worse version
+    const onlyMissing = isEnabled('ONLY_MISSING_FROM_BACKUP');
+    if (backupInfo.count > 0 && !isEnabled('UPLOAD_ALL')) {
+        log('  Comparing with the keys already in the backup...');
+
+        const byRoom = new Map<string, ExtractedKey[]>();
+        for (const key of extractedData.all_keys) {
+            const roomKeys = byRoom.get(key.room_id) ?? [];
+            roomKeys.push(key);
+            byRoom.set(key.room_id, roomKeys);
+        }
 
-    // Clean up any old temp stores
-    const migrationDir = config.migrationDir;
-    const oldTempStores = fs.readdirSync(migrationDir)
-        .filter(f => f.startsWith('temp-crypto-store'))
-        .map(f => path.join(migrationDir, f));
-    for (const oldStore of oldTempStores) {
+        keysToUpload = [];
+        newKeyCount = 0;
+        let covered = 0;
+        let better = 0;
         try {
-            fs.rmSync(oldStore, { recursive: true, force: true });
-            log(`  Cleaned up old temp store: ${path.basename(oldStore)}`);
+            for (const [roomId, keys] of byRoom) {
+                const backedUp = await withRetry(() => getBackupRoomKeys(apiConfig, backupVersion, roomId));
+                for (const key of keys) {
+                    const comparison = compareWithBackup(key, backedUp[key.session_id]);
+                    if (comparison === 'covered' || (onlyMissing && comparison !== 'new')) {
+                        covered++;
+                        continue;
+                    }
+                    if (comparison === 'new') {
+                        newKeyCount++;
+                    } else {
+                        better++;
+                    }
+                    keysToUpload.push(key);
+                }
+            }
         } catch (e) {
-            // Ignore cleanup errors
+            logError(`Failed to read the server backup: ${(e as Error).message}`);
+            log('Pass --upload-all to upload every key without comparing');
+            process.exit(1);
         }
+
+        log(`  Already in backup: ${covered} (skipped)`);
+        log(`  Better than the backed up copy: ${better} (replacing)`);
+        log(`  Not in backup: ${newKeyCount}`);
     }
 
-    fs.mkdirSync(tempStorePath, { recursive: true });
+    const keysForImport = toImportFormat(keysToUpload);
 
-    // Generate a temporary device ID for this migration
-    const tempDeviceId = `MIGRATION_${Date.now()}`;
+    log(`  Prepared ${keysForImport.length} keys for import`);
+
+    if (keysForImport.length === 0) {
+        logSuccess('All keys are already in the server backup - nothing to upload.');
+        return;
+    }
+    if (dryRun) {
+        logUploadPlan(keysToUpload, batchSize);
+    }
 
     log('');
     log('Initializing crypto engine...');
 
-    // Create an OlmMachine instance
+    // Create an OlmMachine instance in a fresh temporary store
     let machine: OlmMachine;
     try {
-        machine = await OlmMachine.initialize(
-            new UserId(userId),
-            new DeviceId(tempDeviceId),
-            tempStorePath,
-            '', // passphrase
-            StoreType.Sqlite,
-        );
-        log(`  Device ID: ${tempDeviceId}`);
+        machine = await createTempMachine(config.migrationDir, userId, log);
     } catch (e) {
         logError(`Failed to initialize OlmMachine: ${(e as Error).message}`);
         process.exit(1);
@@ -263,53 +329,91 @@ export async function runUploadKeys(): Promise<void> {
     let totalBatches = 0;
     let totalKeysUploaded = 0;
 
-    try {
-        while (true) {
-            // Get a batch of properly encrypted keys from the machine
-            const request = await machine.backupRoomKeys();
+    const retryOptions: RetryOptions = {
+        ...DEFAULT_RETRY_OPTIONS,
+        maxRetries: Number(process.env.UPLOAD_MAX_RETRIES ?? DEFAULT_RETRY_OPTIONS.maxRetries),
+        onRetry: (attempt, delayMs, error) => {
+            log('');
+            logWarning(`Batch ${totalBatches}: ${error.message} - retry ${attempt} in ${(delayMs / 1000).toFixed(1)}s`);
+        },
+    };
 
-            if (!request) {
-                // No more keys to backup
-                break;
-            }
+    const uploadChunk = async (chunk: BackupRequestBody): Promise<void> => {
+        totalBatches++;
+        const chunkKeyCount = sessionCount(chunk);
 
-            totalBatches++;
-            const requestBody = JSON.parse(request.body);
+        if (dryRun) {
+            // Encrypted like a real upload, just not sent
+            logProgress(totalKeysUploaded + chunkKeyCount, keysForImport.length,
+                `Batch ${totalBatches}: would upload ${chunkKeyCount} keys`);
+            totalKeysUploaded += chunkKeyCount;
+            return;
+        }
 
-            // Count keys in this batch
-            let batchKeyCount = 0;
-            if (requestBody.rooms) {
-                for (const roomId of Object.keys(requestBody.rooms)) {
-                    const sessions = requestBody.rooms[roomId]?.sessions;
-                    if (sessions) {
-                        batchKeyCount += Object.keys(sessions).length;
-                    }
-                }
-            }
+        logProgress(totalKeysUploaded + chunkKeyCount, keysForImport.length,
+            `Batch ${totalBatches}: uploading ${chunkKeyCount} keys`);
 
-            logProgress(totalKeysUploaded + batchKeyCount, keysForImport.length,
-                `Batch ${totalBatches}: uploading ${batchKeyCount} keys`);
+        // Upload the properly encrypted keys to the server
+        await withRetry(() => matrixRequest<{ count: number; etag: string }>(
+            apiConfig,
+            'PUT',
+            `/_matrix/client/v3/room_keys/keys?version=${backupVersion}`,
+            chunk,
+        ), retryOptions);
 
-            // Upload the properly encrypted keys to the server
-            const uploadResponse = await matrixRequest<{ count: number; etag: string }>(
-                apiConfig,
-                'PUT',
-                `/_matrix/client/v3/room_keys/keys?version=${backupInfo.version}`,
-                requestBody,
-            );
+        totalKeysUploaded += chunkKeyCount;
 
-            // Mark the request as sent so the machine knows not to send it again
-            // The Rust SDK expects the server's response JSON
-            await machine.markRequestAsSent(request.id, RequestType.KeysBackup, JSON.stringify(uploadResponse));
+        // Small delay to avoid rate limiting
+        await new Promise(resolve => setTimeout(resolve, 100));
+    };
 
-            totalKeysUploaded += batchKeyCount;
+    let uploadFailed = false;
+    try {
+        // Encrypted keys not yet uploaded, re-chunked to the configured batch size
+        let pending: BackupRequestBody[] = [];
+        let pendingKeys = 0;
 
-            // Small delay to avoid rate limiting
-            await new Promise(resolve => setTimeout(resolve, 100));
+        while (true) {
+            // Get a batch of properly encrypted keys from the machine
+            const batch = await nextBackupBatch(machine);
+
+            if (batch) {
+                // The machine hands out the same batch until it is marked as sent.
+                // Its store is temporary, so the batch is marked before the upload.
+                await markBatchSent(machine, batch, { count: 0, etag: '' });
+                pending.push(batch.body);
+                pendingKeys += batch.keyCount;
+                if (pendingKeys < batchSize) {
+                    continue;
+                }
+            }
+
+            const chunks = rechunk(pending, batchSize);
+            // A partial chunk waits for more keys, unless these were the last ones
+            const last = chunks[chunks.length - 1];
+            if (batch && last && sessionCount(last) < batchSize) {
+                chunks.pop();
+                pending = [last];
+                pendingKeys = sessionCount(last);
+            } else {
+                pending = [];
+                pendingKeys = 0;
+            }
+
+            for (const chunk of chunks) {
+                await uploadChunk(chunk);
+            }
+
+            if (!batch) {
+                // No more keys to backup
+                break;
+            }
         }
     } catch (e) {
+        uploadFailed = true;
         logError(`\nFailed to upload keys: ${(e as Error).message}`);
-        log('Some keys may have been uploaded. Check the server backup count.');
+        log('Some keys may have been uploaded. Run upload again to continue - keys');
+        log('already in the backup are skipped.');
     }
 
     log(''); // New line after progress bar
@@ -326,6 +430,21 @@ export async function runUploadKeys(): Promise<void> {
         logWarning(`Could not get room key counts: ${(e as Error).message}`);
     }
 
+    if (dryRun) {
+        log('');
+        log('==============================================');
+        logSuccess('Dry run complete - nothing was uploaded.');
+        log('==============================================');
+        log('');
+        log(`Backup Version: ${backupInfo.version}`);
+        log(`Requests that would be sent: ${totalBatches}`);
+        log(`Keys that would be uploaded: ${totalKeysUploaded}`);
+        if (process.env.IMPORTED_STORE_PATH) {
+            log(`Sessions would then be marked as backed up in ${process.env.IMPORTED_STORE_PATH}`);
+        }
+        return;
+    }
+
     // Verify upload on server
     log('');
     log('Verifying upload on server...');
@@ -334,7 +453,7 @@ export async function runUploadKeys(): Promise<void> {
         const finalCount = await getBackupKeyCount(apiConfig, backupInfo.version);
         log(`  Keys in server backup: ${finalCount}`);
 
-        const expectedTotal = backupInfo.count + keysForImport.length;
+        const expectedTotal = backupInfo.count + newKeyCount;
         if (finalCount >= expectedTotal) {
             logSuccess(`  All keys uploaded successfully!`);
         } else if (finalCount > backupInfo.count) {
@@ -346,6 +465,27 @@ export async function runUploadKeys(): Promise<void> {
         logWarning(`Could not verify upload: ${(e as Error).message}`);
     }
 
+    // The keys are in the backup now, so the store they were imported into
+    // shouldn't upload them again
+    const importedStorePath = process.env.IMPORTED_STORE_PATH;
+    if (importedStorePath) {
+        log('');
+        log('Marking imported sessions as backed up...');
+        if (uploadFailed) {
+            logWarning('  Skipped - not every key was uploaded. Run upload again first.');
+        } else {
+            const args = ['mark-backed-up', '--input', config.extractedKeysPath, '--target', importedStorePath];
+            if (process.env.IMPORTED_STORE_PASSPHRASE) {
+                args.push('--target-passphrase', process.env.IMPORTED_STORE_PASSPHRASE);
+            }
+            try {
+                await runExtractor(args);
+            } catch (e) {
+                logWarning(`Could not mark sessions as backed up: ${(e as Error).message}`);
+            }
+        }
+    }
+
     // Clean up temp store (optional - keeping it allows resuming)
     // fs.rmSync(tempStorePath, { recursive: true, force: true });
 
@@ -5,16 +5,24 @@
  * Verifies that the backup was created successfully and contains
  * all the expected keys. This is a safety check before proceeding
  * with the migration.
+ *
+ * Every session in the local export is looked up in the server backup, and a
+ * diff report (per-room counts, missing sessions, index mismatches) is
+ * written to VERIFY_REPORT (default: MIGRATION_DIR/verify-report.json).
  */
 
 import * as fs from 'fs';
+import * as path from 'path';
 import { config, validateConfig } from '../config';
 import {
     getBackupVersion,
     getBackupKeys,
     listDevices,
     whoami,
+    withRetry,
+    RoomKeyBackup,
 } from '../utils/matrix-api';
+import { ExtractionOutput, firstKnownIndex } from '../utils/backup-machine';
 
 // ANSI color codes
 const colors = {
@@ -46,11 +54,96 @@ function logFail(message: string): void {
     console.log(`${colors.red}✗ ${message}${colors.reset}`);
 }
 
-interface ExtractionOutput {
-    version: number;
-    total_keys: number;
-    keys_by_room: Record<string, unknown[]>;
-    all_keys: unknown[];
+/** A session whose backed up copy starts later than the local one */
+interface IndexMismatch {
+    session_id: string;
+    local_index: number;
+    backup_index: number;
+}
+
+interface RoomDiff {
+    local_sessions: number;
+    backup_sessions: number;
+    missing_session_ids: string[];
+    index_mismatches: IndexMismatch[];
+}
+
+interface DiffReport {
+    backup_version: string;
+    generated_at: string;
+    local_sessions: number;
+    backup_sessions: number;
+    missing_sessions: number;
+    index_mismatches: number;
+    /** Rooms with at least one missing session or index mismatch */
+    rooms_with_differences: number;
+    rooms: Record<string, RoomDiff>;
+}
+
+/**
+ * Compare every session of the export with the server backup
+ *
+ * Sessions the backup has but the export doesn't (uploaded by other devices)
+ * only show up in the counts. Index mismatches need the session keys, so
+ * they're not checked for exports written with --no-secrets.
+ */
+function diffWithBackup(
+    extracted: ExtractionOutput,
+    backupRooms: Record<string, { sessions: Record<string, RoomKeyBackup> }>,
+    backupVersion: string,
+): DiffReport {
+    const report: DiffReport = {
+        backup_version: backupVersion,
+        generated_at: new Date().toISOString(),
+        local_sessions: 0,
+        backup_sessions: 0,
+        missing_sessions: 0,
+        index_mismatches: 0,
+        rooms_with_differences: 0,
+        rooms: {},
+    };
+
+    const roomDiff = (roomId: string): RoomDiff => {
+        report.rooms[roomId] ??= {
+            local_sessions: 0,
+            backup_sessions: Object.keys(backupRooms[roomId]?.sessions ?? {}).length,
+            missing_session_ids: [],
+            index_mismatches: [],
+        };
+        return report.rooms[roomId];
+    };
+
+    for (const key of extracted.all_keys) {
+        const diff = roomDiff(key.room_id);
+        diff.local_sessions++;
+        report.local_sessions++;
+
+        const backedUp = backupRooms[key.room_id]?.sessions?.[key.session_id];
+        if (!backedUp) {
+            diff.missing_session_ids.push(key.session_id);
+            report.missing_sessions++;
+            continue;
+        }
+
+        const localIndex = key.session_key ? firstKnownIndex(key.session_key) : null;
+        if (localIndex !== null && localIndex < backedUp.first_message_index) {
+            diff.index_mismatches.push({
+                session_id: key.session_id,
+                local_index: localIndex,
+                backup_index: backedUp.first_message_index,
+            });
+            report.index_mismatches++;
+        }
+    }
+
+    for (const roomId of Object.keys(backupRooms)) {
+        report.backup_sessions += roomDiff(roomId).backup_sessions;
+    }
+    report.rooms_with_differences = Object.values(report.rooms)
+        .filter(diff => diff.missing_session_ids.length > 0 || diff.index_mismatches.length > 0)
+        .length;
+
+    return report;
 }
 
 export async function runVerifyBackup(): Promise<void> {
@@ -114,7 +207,7 @@ export async function runVerifyBackup(): Promise<void> {
             extractedData = parsed;
             log(`   Extracted keys file found`);
             log(`   Total extracted keys: ${parsed.total_keys}`);
-            log(`   Rooms with keys: ${Object.keys(parsed.keys_by_room).length}`);
+            log(`   Rooms with keys: ${Object.keys(parsed.keys_per_room ?? parsed.keys_by_room).length}`);
 
             if (backupInfo) {
                 if (backupInfo.count >= parsed.total_keys) {
@@ -136,7 +229,8 @@ export async function runVerifyBackup(): Promise<void> {
     log('4. Checking backup contents...');
     if (backupInfo) {
         try {
-            const backupKeys = await getBackupKeys(apiConfig, backupInfo.version);
+            const backupVersion = backupInfo.version;
+            const backupKeys = await withRetry(() => getBackupKeys(apiConfig, backupVersion));
             const roomCount = Object.keys(backupKeys.rooms || {}).length;
             let sessionCount = 0;
 
@@ -146,20 +240,36 @@ export async function runVerifyBackup(): Promise<void> {
 
             logSuccess(`Backup contains ${sessionCount} sessions across ${roomCount} rooms`);
 
-            // Compare room IDs if we have extracted data
+            // Compare session by session if we have extracted data
             if (extractedData) {
-                const extractedRoomIds = new Set(Object.keys(extractedData.keys_by_room));
-                const backupRoomIds = new Set(Object.keys(backupKeys.rooms || {}));
-
-                const missingRooms = [...extractedRoomIds].filter(r => !backupRoomIds.has(r));
-                if (missingRooms.length > 0) {
-                    logWarning(`${missingRooms.length} rooms from extraction are missing in backup`);
-                    if (missingRooms.length <= 5) {
-                        missingRooms.forEach(r => log(`   - ${r}`));
-                    }
+                const report = diffWithBackup(extractedData, backupKeys.rooms || {}, backupVersion);
+                const reportPath = process.env.VERIFY_REPORT
+                    || path.join(config.migrationDir, 'verify-report.json');
+                fs.writeFileSync(reportPath, JSON.stringify(report, null, 2));
+
+                if (report.missing_sessions > 0) {
+                    logFail(`${report.missing_sessions} of ${report.local_sessions} extracted sessions are missing in backup`);
+                    allChecksPass = false;
                 } else {
-                    logSuccess('All extracted rooms are present in backup');
+                    logSuccess(`All ${report.local_sessions} extracted sessions are present in backup`);
+                }
+
+                if (report.index_mismatches > 0) {
+                    logFail(`${report.index_mismatches} backed up sessions start at a later index than the extracted key`);
+                    log('   Re-run upload to replace them with the extracted keys');
+                    allChecksPass = false;
+                }
+
+                const differing = Object.entries(report.rooms)
+                    .filter(([, diff]) => diff.missing_session_ids.length > 0 || diff.index_mismatches.length > 0);
+                for (const [roomId, diff] of differing.slice(0, 5)) {
+                    log(`   - ${roomId}: ${diff.local_sessions} extracted, ${diff.backup_sessions} in backup, `
+                        + `${diff.missing_session_ids.length} missing, ${diff.index_mismatches.length} index mismatches`);
+                }
+                if (differing.length > 5) {
+                    log(`   ... and ${differing.length - 5} more rooms`);
                 }
+                log(`   Diff report written to: ${reportPath}`);
             }
         } catch (e) {
             logWarning(`Could not retrieve backup contents: ${(e as Error).message}`);
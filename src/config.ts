@@ -47,11 +47,60 @@ function optionalEnv(name: string, defaultValue: string = ''): string {
     return process.env[name] || defaultValue;
 }
 
+/**
+ * Options that are switches, so the argument after them is never their value
+ */
+const BOOLEAN_FLAGS = new Set(['CREATE_BACKUP', 'FORCE_NEW_BACKUP', 'UPLOAD_ALL', 'INSECURE_SKIP_TLS_VERIFY', 'DRY_RUN',
+    'ONLY_MISSING_FROM_BACKUP', 'FORCE']);
+
+/**
+ * Apply command-line flags as environment variables
+ *
+ * `--some-option value` and `--some-option=value` set SOME_OPTION, and a
+ * switch (`--create-backup`) sets it to "true", so every variable can also be
+ * given as a flag. Returns the remaining positional arguments.
+ */
+export function applyCommandLineFlags(args: string[]): string[] {
+    const positional: string[] = [];
+
+    for (let i = 0; i < args.length; i++) {
+        const arg = args[i];
+        if (!arg.startsWith('--')) {
+            positional.push(arg);
+            continue;
+        }
+
+        const [flag, inlineValue] = arg.slice(2).split(/=(.*)/s, 2);
+        const name = flag.replace(/-/g, '_').toUpperCase();
+        let value = inlineValue;
+        if (value === undefined) {
+            const next = args[i + 1];
+            if (!BOOLEAN_FLAGS.has(name) && next !== undefined && !next.startsWith('--')) {
+                value = next;
+                i++;
+            } else {
+                value = 'true';
+            }
+        }
+        process.env[name] = value;
+    }
+
+    return positional;
+}
+
+/**
+ * Whether a boolean option is set (as a flag or a variable)
+ */
+export function isEnabled(name: string): boolean {
+    const value = process.env[name];
+    return !!value && value !== 'false' && value !== '0';
+}
+
 /**
  * Load configuration from environment variables
  */
 function loadConfig(): MigrationConfig {
-    // Core required configuration
+    // Core required configuration (ACCESS_TOKEN may come from a login, see utils/auth)
     const homeserverUrl = requireEnv('HOMESERVER_URL');
     const accessToken = requireEnv('ACCESS_TOKEN');
     const storagePath = requireEnv('STORAGE_PATH');
@@ -171,10 +220,10 @@ export function validateConfig(): void {
         const cfg = getConfig();
 
         if (!cfg.homeserverUrl) {
-            errors.push('HOMESERVER_URL environment variable is not set');
+            errors.push('HOMESERVER_URL environment variable is not set (or use SERVER_NAME)');
         }
         if (!cfg.accessToken) {
-            errors.push('ACCESS_TOKEN environment variable is not set');
+            errors.push('ACCESS_TOKEN environment variable is not set (or use ACCESS_TOKEN_FILE or LOGIN_USER)');
         }
         if (!cfg.storagePath) {
             errors.push('STORAGE_PATH environment variable is not set');
@@ -16,13 +16,19 @@
  *   extract   - Extract keys from Sled (requires Rust)
  *   enable    - Enable server backup, generate recovery key
  *   upload    - Upload extracted keys to backup
- *   verify    - Verify backup completeness
+ *   encrypt-keys - Encrypt extracted keys into backup payloads (no upload)
+ *   restore   - Restore the server backup into a SQLite crypto store
+ *   verify    - Verify backup completeness and write a diff report (alias: verify-backup)
  *   delete    - Delete old device (requires password)
  *   all       - Run full migration (enable through verify)
+ *   recovery-key - Generate, derive or validate a backup recovery key
  */
 
 import { spawn } from 'child_process';
 import * as path from 'path';
+import { applyCommandLineFlags, isEnabled } from './config';
+import { authenticate } from './utils/auth';
+import { discoverHomeserver, getBackupVersion } from './utils/matrix-api';
 
 // ANSI color codes
 const colors = {
@@ -64,23 +70,50 @@ function showHelp(): void {
     log('  ACCESS_TOKEN      Bot\'s access token');
     log('  STORAGE_PATH      Path to bot\'s storage directory');
     log('');
+    log('Every variable can also be passed as a flag: --access-token syt_xxx sets ACCESS_TOKEN.');
+    log('');
+    log('Instead of HOMESERVER_URL:');
+    log('  SERVER_NAME       Server name (example.org); the URL is found via .well-known/matrix/client');
+    log('');
+    log('Instead of ACCESS_TOKEN:');
+    log('  ACCESS_TOKEN_FILE File holding the access token');
+    log('  LOGIN_USER        Log in as this user with a password (MIGRATION_PASSWORD or a prompt);');
+    log('                    the token is kept in memory only');
+    log('  DEVICE_ID         Device to log in to, so repeated logins reuse it');
+    log('');
     log('Environment Variables (optional):');
     log('  CRYPTO_STORE_PATH Path to crypto store (default: STORAGE_PATH/encrypted)');
     log('  MIGRATION_DIR     Directory for migration files (default: current directory)');
     log('  OLD_DEVICE_ID     Device ID to delete (auto-detected from bot-sdk.json)');
     log('  MIGRATION_PASSWORD Account password for device deletion');
     log('  MIGRATION_CONFIRM  Device ID to confirm deletion (for non-interactive use)');
-    log('  RECOVERY_PHRASE   Oracle recovery phrase for SSSS extraction (oracle-all, extract-backup-key)');
+    log('  RECOVERY_PHRASE   Oracle recovery phrase for SSSS extraction (oracle-all, extract-backup-key, restore)');
+    log('  SSSS_RECOVERY_KEY Secret storage recovery key, instead of RECOVERY_PHRASE');
+    log('  CREATE_BACKUP     Create a backup (and recovery key) in upload if there is none (--create-backup)');
+    log('  ONLY_MISSING_FROM_BACKUP Leave sessions the server backup has out of extract and upload');
+    log('                    (--only-missing-from-backup)');
+    log('  DRY_RUN           Run upload up to the requests it would send, without sending them (--dry-run)');
+    log('  PROXY             HTTP proxy for homeserver requests (default: HTTPS_PROXY)');
+    log('  CA_CERT           PEM file with a private CA to trust');
+    log('  INSECURE_SKIP_TLS_VERIFY Don\'t verify the homeserver certificate (unsafe, --insecure-skip-tls-verify)');
     log('');
     log('Commands:');
     log('  backup            Create backup of current crypto store');
     log('  extract           Extract keys from Sled store (requires Rust toolchain)');
     log('  enable            Enable server backup and generate recovery key');
     log('  upload            Upload extracted keys to server backup');
-    log('  verify            Verify backup completeness');
+    log('  encrypt-keys      Encrypt extracted keys into upload-ready backup payloads, offline');
+    log('                    (needs BACKUP_PUBLIC_KEY or RECOVERY_KEY)');
+    log('  verify            Verify backup completeness, session by session, and write a diff report');
+    log('                    (alias: verify-backup)');
+    log('  restore           Download and decrypt the server backup into a SQLite crypto store');
+    log('                    (needs TARGET_STORE_PATH, TARGET_DEVICE_ID and the recovery key)');
     log('  delete            Delete old device (requires password)');
     log('  all               Run full migration (enable -> upload -> verify)');
     log('  generate-key      Generate a new recovery key (for new deployments)');
+    log('  recovery-key <generate|derive|validate> [KEY]');
+    log('                    Generate a recovery key, derive its key pair, or validate one');
+    log('                    (KEY defaults to $RECOVERY_KEY)');
     log('  extract-backup-key Extract backup key from SSSS (for oracles with existing backup)');
     log('  oracle-all        Run oracle migration (extract-backup-key -> upload -> verify)');
     log('');
@@ -92,7 +125,7 @@ function showHelp(): void {
     log('');
 }
 
-async function runCommand(command: string): Promise<void> {
+async function runCommand(command: string, args: string[]): Promise<void> {
     const scriptDir = path.resolve(__dirname, '..');
     const scriptsDir = path.join(scriptDir, 'scripts');
     const commandsDir = __dirname.includes('/lib/')
@@ -117,9 +150,27 @@ async function runCommand(command: string): Promise<void> {
 
         case 'extract': {
             const script = path.join(scriptsDir, '02-extract-keys.sh');
+            const env = { ...process.env };
+
+            // Sessions the backup already has are left out of the export
+            if (isEnabled('ONLY_MISSING_FROM_BACKUP')) {
+                const { config } = await import('./config');
+                const { writeBackupSessionList } = await import('./utils/extractor');
+                const apiConfig = { homeserverUrl: config.homeserverUrl, accessToken: config.accessToken };
+                const backupInfo = await getBackupVersion(apiConfig);
+                if (backupInfo) {
+                    const listPath = path.join(config.migrationDir, 'backup-sessions.json');
+                    const count = await writeBackupSessionList(apiConfig, backupInfo.version, listPath);
+                    log(`Backup version ${backupInfo.version} holds ${count} sessions - leaving them out`);
+                    env.BACKUP_SESSIONS_FILE = listPath;
+                } else {
+                    log('No server backup yet - extracting every key');
+                }
+            }
+
             const child = spawn('bash', [script], {
                 stdio: 'inherit',
-                env: { ...process.env },
+                env,
             });
             await new Promise<void>((resolve, reject) => {
                 child.on('close', (code) => {
@@ -142,12 +193,25 @@ async function runCommand(command: string): Promise<void> {
             break;
         }
 
-        case 'verify': {
+        case 'encrypt-keys': {
+            const { runEncryptKeys } = await import('./commands/encrypt-keys');
+            await runEncryptKeys();
+            break;
+        }
+
+        case 'verify':
+        case 'verify-backup': {
             const { runVerifyBackup } = await import('./commands/verify-backup');
             await runVerifyBackup();
             break;
         }
 
+        case 'restore': {
+            const { runRestore } = await import('./commands/restore');
+            await runRestore();
+            break;
+        }
+
         case 'delete': {
             const { runDeleteDevice } = await import('./commands/delete-device');
             await runDeleteDevice();
@@ -161,6 +225,12 @@ async function runCommand(command: string): Promise<void> {
             break;
         }
 
+        case 'recovery-key': {
+            const { runRecoveryKey } = await import('./commands/recovery-key');
+            await runRecoveryKey(args[0], args[1]);
+            break;
+        }
+
         case 'extract-backup-key': {
             const { runExtractBackupKey } = await import('./commands/extract-backup-key');
             await runExtractBackupKey();
@@ -249,7 +319,17 @@ async function main(): Promise<void> {
     const command = args[0].toLowerCase();
 
     try {
-        await runCommand(command);
+        const positional = applyCommandLineFlags(args.slice(1));
+        // Other commands would ignore it and write anyway
+        if (isEnabled('DRY_RUN') && command !== 'upload') {
+            throw new Error(`--dry-run is not supported by ${command} - only by upload`);
+        }
+        if (!process.env.HOMESERVER_URL && process.env.SERVER_NAME) {
+            process.env.HOMESERVER_URL = await discoverHomeserver(process.env.SERVER_NAME);
+            log(`Homeserver for ${process.env.SERVER_NAME}: ${process.env.HOMESERVER_URL}`);
+        }
+        await authenticate(log);
+        await runCommand(command, positional);
     } catch (e) {
         logError((e as Error).message);
         process.exit(1);
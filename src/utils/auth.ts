@@ -0,0 +1,90 @@
+/**
+ * Authentication for Server Operations
+ *
+ * Server-facing commands need an access token. It is taken from, in order:
+ * 1. ACCESS_TOKEN (`--access-token`)
+ * 2. ACCESS_TOKEN_FILE (`--access-token-file`) - keeps the token out of the
+ *    environment and shell history
+ * 3. A password login as LOGIN_USER (`--login-user`), with the password from
+ *    MIGRATION_PASSWORD or an interactive prompt
+ *
+ * A token from a login is only kept in memory (in process.env for this
+ * process) and never written to disk. DEVICE_ID (`--device-id`) logs in to an
+ * existing device instead of creating a new one, so repeated runs reuse it.
+ */
+
+import * as fs from 'fs';
+import * as readline from 'readline';
+import { loginWithPassword } from './matrix-api';
+
+/**
+ * Ask for a password without echoing it
+ */
+async function promptPassword(question: string): Promise<string> {
+    if (!process.stdin.isTTY) {
+        throw new Error('No terminal to ask for the password - set MIGRATION_PASSWORD');
+    }
+
+    const rl = readline.createInterface({
+        input: process.stdin,
+        output: process.stdout,
+        terminal: true,
+    });
+    // Swallow the echo of everything typed after the question
+    const output = rl as unknown as { _writeToOutput: (text: string) => void };
+    let muted = false;
+    output._writeToOutput = (text: string) => {
+        if (!muted || text.includes('\n')) {
+            process.stdout.write(muted ? '\n' : text);
+        }
+    };
+
+    return new Promise((resolve) => {
+        rl.question(question, (answer) => {
+            rl.close();
+            resolve(answer);
+        });
+        muted = true;
+    });
+}
+
+/**
+ * Make sure ACCESS_TOKEN is set, from a file or a password login
+ *
+ * Does nothing when no token source is configured, so commands that don't
+ * talk to the server are unaffected and the others report the missing token.
+ */
+export async function authenticate(log: (message: string) => void): Promise<void> {
+    if (process.env.ACCESS_TOKEN) {
+        return;
+    }
+
+    const tokenFile = process.env.ACCESS_TOKEN_FILE;
+    if (tokenFile) {
+        const token = fs.readFileSync(tokenFile, 'utf-8').trim();
+        if (!token) {
+            throw new Error(`Access token file is empty: ${tokenFile}`);
+        }
+        process.env.ACCESS_TOKEN = token;
+        return;
+    }
+
+    const user = process.env.LOGIN_USER;
+    if (!user) {
+        return;
+    }
+    const homeserverUrl = process.env.HOMESERVER_URL;
+    if (!homeserverUrl) {
+        throw new Error('HOMESERVER_URL or SERVER_NAME is required to log in');
+    }
+
+    const password = process.env.MIGRATION_PASSWORD || await promptPassword(`Password for ${user}: `);
+    const deviceId = process.env.DEVICE_ID || undefined;
+    const session = await loginWithPassword(homeserverUrl, user, password, deviceId);
+
+    process.env.ACCESS_TOKEN = session.access_token;
+    log(`Logged in as ${session.user_id} on device ${session.device_id} (token kept in memory only)`);
+    if (!deviceId) {
+        log(`  Pass --device-id ${session.device_id} to reuse this device next time`);
+    }
+}
@@ -0,0 +1,255 @@
+/**
+ * Backup Encryption Utilities
+ *
+ * Loads the keys extracted by the Rust tool and encrypts them for the server
+ * backup with a temporary OlmMachine:
+ * 1. Imports extracted keys into the OlmMachine using importRoomKeys()
+ * 2. Enables backup with the backup's Curve25519 public key
+ * 3. backupRoomKeys() then yields request bodies for PUT /room_keys/keys
+ *
+ * Used by `upload`, which sends the bodies, and `encrypt-keys`, which writes
+ * them to disk for another uploader.
+ */
+
+import * as fs from 'fs';
+import * as path from 'path';
+import {
+    OlmMachine,
+    UserId,
+    DeviceId,
+    RequestType,
+    StoreType,
+} from '@ixo/matrix-sdk-crypto-nodejs';
+
+// Extracted key format from the Rust tool
+export interface ExtractedKey {
+    room_id: string;
+    session_id: string;
+    algorithm: string;
+    /** Missing in exports written with `extract --no-secrets` */
+    session_key?: string;
+    sender_key: string;
+    sender_claimed_keys: Record<string, string>;
+    forwarding_curve25519_key_chain: string[];
+}
+
+/** Where a version 2 export came from */
+export interface ExportMetadata {
+    source_fingerprint: string;
+    user_id?: string;
+    device_id?: string;
+    /** Public identity keys of the source account, `ed25519` and `curve25519` */
+    identity_keys?: Record<string, string>;
+    extracted_at: number;
+    tool_version: string;
+}
+
+export interface ExtractionOutput {
+    version: number;
+    total_keys: number;
+    keys_by_room: Record<string, ExtractedKey[]>;
+    /** Written instead of keys_by_room by `extract --no-keys-by-room` */
+    keys_per_room?: Record<string, number>;
+    all_keys: ExtractedKey[];
+    metadata?: ExportMetadata;
+}
+
+// Format expected by OlmMachine.importRoomKeys
+export interface ExportedRoomKey {
+    algorithm: string;
+    room_id: string;
+    sender_key: string;
+    session_id: string;
+    session_key: string;
+    sender_claimed_keys: Record<string, string>;
+    forwarding_curve25519_key_chain: string[];
+}
+
+/** Body of PUT /room_keys/keys */
+export interface BackupRequestBody {
+    rooms: Record<string, { sessions: Record<string, unknown> }>;
+}
+
+/** A batch of encrypted keys produced by the OlmMachine */
+export interface BackupBatch {
+    requestId: string;
+    body: BackupRequestBody;
+    keyCount: number;
+}
+
+/**
+ * Read an export written by the Rust tool
+ */
+export function loadExtractedKeys(filePath: string): ExtractionOutput {
+    const data: ExtractionOutput = JSON.parse(fs.readFileSync(filePath, 'utf-8'));
+
+    if (data.total_keys > 0 && data.all_keys.every(key => !key.session_key)) {
+        throw new Error('The export holds no session keys - was it written with --no-secrets?');
+    }
+    return data;
+}
+
+/**
+ * Convert extracted keys to the format expected by importRoomKeys
+ */
+export function toImportFormat(keys: ExtractedKey[]): ExportedRoomKey[] {
+    return keys.map(key => ({
+        algorithm: key.algorithm || 'm.megolm.v1.aes-sha2',
+        room_id: key.room_id,
+        sender_key: key.sender_key,
+        session_id: key.session_id,
+        session_key: key.session_key ?? '',
+        sender_claimed_keys: key.sender_claimed_keys || {},
+        forwarding_curve25519_key_chain: key.forwarding_curve25519_key_chain || [],
+    }));
+}
+
+/**
+ * Create an OlmMachine in a fresh temporary store under `parentDir`
+ *
+ * Temporary stores of earlier runs are removed first.
+ */
+export async function createTempMachine(
+    parentDir: string,
+    userId: string,
+    log: (message: string) => void,
+): Promise<OlmMachine> {
+    const oldTempStores = fs.readdirSync(parentDir)
+        .filter(f => f.startsWith('temp-crypto-store'))
+        .map(f => path.join(parentDir, f));
+    for (const oldStore of oldTempStores) {
+        try {
+            fs.rmSync(oldStore, { recursive: true, force: true });
+            log(`  Cleaned up old temp store: ${path.basename(oldStore)}`);
+        } catch (e) {
+            // Ignore cleanup errors
+        }
+    }
+
+    // Use timestamp to ensure uniqueness and avoid conflicts with previous runs
+    const tempStorePath = path.join(parentDir, `temp-crypto-store-${Date.now()}`);
+    fs.mkdirSync(tempStorePath, { recursive: true });
+
+    // Generate a temporary device ID for this migration
+    const tempDeviceId = `MIGRATION_${Date.now()}`;
+    const machine = await OlmMachine.initialize(
+        new UserId(userId),
+        new DeviceId(tempDeviceId),
+        tempStorePath,
+        '', // passphrase
+        StoreType.Sqlite,
+    );
+    log(`  Device ID: ${tempDeviceId}`);
+    return machine;
+}
+
+/**
+ * Get the next batch of encrypted keys, or null when all keys are backed up
+ */
+export async function nextBackupBatch(machine: OlmMachine): Promise<BackupBatch | null> {
+    const request = await machine.backupRoomKeys();
+    if (!request) {
+        return null;
+    }
+
+    const body: BackupRequestBody = JSON.parse(request.body);
+    return { requestId: request.id, body, keyCount: sessionCount(body) };
+}
+
+/**
+ * Tell the machine a batch was handled, so it doesn't hand it out again
+ *
+ * The Rust SDK expects the server's response JSON.
+ */
+export async function markBatchSent(
+    machine: OlmMachine,
+    batch: BackupBatch,
+    response: unknown,
+): Promise<void> {
+    await machine.markRequestAsSent(batch.requestId, RequestType.KeysBackup, JSON.stringify(response));
+}
+
+/**
+ * Split request bodies into bodies of at most `batchSize` sessions
+ */
+export function rechunk(bodies: BackupRequestBody[], batchSize: number): BackupRequestBody[] {
+    const chunks: BackupRequestBody[] = [];
+    let current: BackupRequestBody = { rooms: {} };
+    let currentCount = 0;
+
+    for (const body of bodies) {
+        for (const [roomId, room] of Object.entries(body.rooms ?? {})) {
+            for (const [sessionId, session] of Object.entries(room?.sessions ?? {})) {
+                if (currentCount === batchSize) {
+                    chunks.push(current);
+                    current = { rooms: {} };
+                    currentCount = 0;
+                }
+                current.rooms[roomId] ??= { sessions: {} };
+                current.rooms[roomId].sessions[sessionId] = session;
+                currentCount++;
+            }
+        }
+    }
+
+    if (currentCount > 0) {
+        chunks.push(current);
+    }
+    return chunks;
+}
+
+/**
+ * Number of sessions in a request body
+ */
+export function sessionCount(body: BackupRequestBody): number {
+    let count = 0;
+    for (const room of Object.values(body.rooms ?? {})) {
+        count += Object.keys(room?.sessions ?? {}).length;
+    }
+    return count;
+}
+
+/**
+ * First message index a session key was exported at
+ *
+ * Exported Megolm session keys start with a version byte (1) and the
+ * big-endian index. Returns null for keys in another format.
+ */
+export function firstKnownIndex(sessionKey: string): number | null {
+    const bytes = Buffer.from(sessionKey, 'base64');
+    if (bytes.length < 5 || bytes[0] !== 1) {
+        return null;
+    }
+    return bytes.readUInt32BE(1);
+}
+
+/** How an extracted key compares to the copy in the server backup */
+export type BackupComparison = 'new' | 'better' | 'covered';
+
+/**
+ * Compare a key with the server's copy, following the spec's rule for
+ * replacing backed up keys: a lower first_message_index wins, then a lower
+ * forwarded_count
+ *
+ * is_verified isn't compared - the server can't tell how keys imported from
+ * an export were verified, and uploaded keys are never marked verified.
+ */
+export function compareWithBackup(
+    key: ExtractedKey,
+    backedUp: { first_message_index: number; forwarded_count: number } | undefined,
+): BackupComparison {
+    if (!backedUp) {
+        return 'new';
+    }
+
+    const index = firstKnownIndex(key.session_key ?? '');
+    if (index === null) {
+        // Can't tell - let the server decide
+        return 'better';
+    }
+    if (index !== backedUp.first_message_index) {
+        return index < backedUp.first_message_index ? 'better' : 'covered';
+    }
+    const forwardedCount = (key.forwarding_curve25519_key_chain || []).length;
+    return forwardedCount < backedUp.forwarded_count ? 'better' : 'covered';
+}
@@ -0,0 +1,89 @@
+/**
+ * Running the Rust Key Extractor
+ *
+ * Some steps need the extractor binary, e.g. to update a SQLite crypto store
+ * the keys were imported into. It is taken from, in order:
+ * 1. KEY_EXTRACTOR_BIN (`--key-extractor-bin`)
+ * 2. /usr/local/bin/key-extractor (the Docker image)
+ * 3. rust-key-extractor/target/release/sled-key-extractor in this package
+ */
+
+import * as fs from 'fs';
+import * as path from 'path';
+import { spawn } from 'child_process';
+import { MatrixApiConfig, getBackupKeys } from './matrix-api';
+
+/** Where the Docker image installs the extractor */
+const DOCKER_BINARY = '/usr/local/bin/key-extractor';
+
+/**
+ * Path of the extractor binary
+ */
+export function findExtractorBinary(): string {
+    if (process.env.KEY_EXTRACTOR_BIN) {
+        return process.env.KEY_EXTRACTOR_BIN;
+    }
+    if (fs.existsSync(DOCKER_BINARY)) {
+        return DOCKER_BINARY;
+    }
+
+    // Two levels below the package root, from both src/utils and lib/utils
+    const built = path.resolve(__dirname, '..', '..', 'rust-key-extractor', 'target', 'release', 'sled-key-extractor');
+    if (fs.existsSync(built)) {
+        return built;
+    }
+    throw new Error('Key extractor not found - build it with `cargo build --release` or set KEY_EXTRACTOR_BIN');
+}
+
+/**
+ * Save the session IDs of the server-side backup for `--only-missing-from-backup`
+ *
+ * The file has the shape of the `GET /room_keys/keys` response, without the
+ * (encrypted) session data. Returns the number of sessions listed.
+ */
+export async function writeBackupSessionList(
+    apiConfig: MatrixApiConfig,
+    version: string,
+    filePath: string
+): Promise<number> {
+    const backup = await getBackupKeys(apiConfig, version);
+
+    const rooms: Record<string, { sessions: Record<string, object> }> = {};
+    let count = 0;
+    for (const [roomId, room] of Object.entries(backup.rooms ?? {})) {
+        const sessions: Record<string, object> = {};
+        for (const sessionId of Object.keys(room.sessions ?? {})) {
+            sessions[sessionId] = {};
+            count++;
+        }
+        rooms[roomId] = { sessions };
+    }
+
+    fs.writeFileSync(filePath, JSON.stringify({ rooms }));
+    return count;
+}
+
+/**
+ * Run the extractor with the given arguments, passing its output through
+ */
+export async function runExtractor(args: string[]): Promise<void> {
+    const child = spawn(findExtractorBinary(), args, {
+        stdio: 'inherit',
+        env: { ...process.env },
+    });
+    // In a container only PID 1 gets SIGTERM; pass it on so the extractor can
+    // write its checkpoint before the grace period runs out
+    const forwardTerm = () => child.kill('SIGTERM');
+    process.on('SIGTERM', forwardTerm);
+    try {
+        await new Promise<void>((resolve, reject) => {
+            child.on('error', reject);
+            child.on('close', (code) => {
+                if (code === 0) resolve();
+                else reject(new Error(`Key extractor exited with code ${code}`));
+            });
+        });
+    } finally {
+        process.off('SIGTERM', forwardTerm);
+    }
+}
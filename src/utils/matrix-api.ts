@@ -5,8 +5,11 @@
  * for backup and device management operations.
  */
 
+import * as fs from 'fs';
 import * as https from 'https';
 import * as http from 'http';
+import * as net from 'net';
+import * as tls from 'tls';
 import { URL } from 'url';
 
 export interface MatrixApiConfig {
@@ -36,6 +39,159 @@ export interface RoomKeyBackup {
     };
 }
 
+/**
+ * Error response from the homeserver
+ *
+ * The message keeps the `Matrix API error <status>: <body>` format callers
+ * match on.
+ */
+export class MatrixApiError extends Error {
+    constructor(
+        public readonly statusCode: number,
+        public readonly body: unknown,
+        public readonly retryAfterHeaderMs?: number,
+    ) {
+        super(`Matrix API error ${statusCode}: ${JSON.stringify(body)}`);
+    }
+
+    /** The Matrix error code, e.g. M_LIMIT_EXCEEDED */
+    get errcode(): string | undefined {
+        return (this.body as { errcode?: string } | null)?.errcode;
+    }
+
+    /** How long the server asked us to wait before retrying, if it did */
+    get retryAfterMs(): number | undefined {
+        const fromBody = (this.body as { retry_after_ms?: number } | null)?.retry_after_ms;
+        return typeof fromBody === 'number' ? fromBody : this.retryAfterHeaderMs;
+    }
+}
+
+export interface RetryOptions {
+    /** Retries after the first attempt */
+    maxRetries: number;
+    /** Delay before the first retry of a transient error, doubled for each further one */
+    baseDelayMs: number;
+    /** Upper bound for the backoff delay */
+    maxDelayMs: number;
+    /** Called before waiting for a retry */
+    onRetry?: (attempt: number, delayMs: number, error: Error) => void;
+}
+
+export const DEFAULT_RETRY_OPTIONS: RetryOptions = {
+    maxRetries: 5,
+    baseDelayMs: 1000,
+    maxDelayMs: 60000,
+};
+
+/**
+ * How long to wait before retrying `error`, or null if it isn't worth retrying
+ *
+ * Rate limits (429 / M_LIMIT_EXCEEDED) wait as long as the server asks;
+ * 5xx responses and network errors back off exponentially.
+ */
+function retryDelay(error: Error, attempt: number, options: RetryOptions): number | null {
+    const backoff = Math.min(options.baseDelayMs * 2 ** (attempt - 1), options.maxDelayMs);
+
+    if (error instanceof MatrixApiError) {
+        if (error.statusCode === 429 || error.errcode === 'M_LIMIT_EXCEEDED') {
+            return error.retryAfterMs ?? backoff;
+        }
+        return error.statusCode >= 500 ? backoff : null;
+    }
+    // Connection resets, timeouts and DNS hiccups
+    return backoff;
+}
+
+/**
+ * Run `request`, retrying rate-limited and transient failures
+ */
+export async function withRetry<T>(
+    request: () => Promise<T>,
+    options: RetryOptions = DEFAULT_RETRY_OPTIONS,
+): Promise<T> {
+    for (let attempt = 1; ; attempt++) {
+        try {
+            return await request();
+        } catch (e) {
+            const error = e as Error;
+            const delay = retryDelay(error, attempt, options);
+            if (delay === null || attempt > options.maxRetries) {
+                throw error;
+            }
+            options.onRetry?.(attempt, delay, error);
+            await new Promise(resolve => setTimeout(resolve, delay));
+        }
+    }
+}
+
+/** Proxy and TLS settings shared by all requests */
+interface Transport {
+    proxy?: URL;
+    tlsOptions: Pick<tls.ConnectionOptions, 'ca' | 'rejectUnauthorized'>;
+}
+
+let _transport: Transport | null = null;
+
+/**
+ * Build the proxy and TLS settings from PROXY (or HTTPS_PROXY), CA_CERT and
+ * INSECURE_SKIP_TLS_VERIFY, once per process
+ */
+function transport(): Transport {
+    if (_transport) {
+        return _transport;
+    }
+
+    const tlsOptions: Transport['tlsOptions'] = {};
+    if (process.env.CA_CERT) {
+        // In addition to the system CAs, so public servers still work
+        tlsOptions.ca = [...tls.rootCertificates, fs.readFileSync(process.env.CA_CERT, 'utf-8')];
+    }
+    const insecure = process.env.INSECURE_SKIP_TLS_VERIFY;
+    if (insecure && insecure !== 'false' && insecure !== '0') {
+        tlsOptions.rejectUnauthorized = false;
+        console.error('\x1b[1m\x1b[31m' + [
+            '!!! WARNING: TLS certificate verification is DISABLED (--insecure-skip-tls-verify) !!!',
+            '!!! Anyone between this machine and the homeserver can read the access token    !!!',
+            '!!! and the uploaded keys. Use --ca-cert for a private CA instead.               !!!',
+        ].join('\n') + '\x1b[0m');
+    }
+
+    const proxy = process.env.PROXY || process.env.HTTPS_PROXY || process.env.https_proxy;
+    _transport = { proxy: proxy ? new URL(proxy) : undefined, tlsOptions };
+    return _transport;
+}
+
+/**
+ * Open a CONNECT tunnel through an HTTP proxy to host:port
+ */
+function proxyTunnel(proxy: URL, host: string, port: string | number): Promise<net.Socket> {
+    const headers: http.OutgoingHttpHeaders = { Host: `${host}:${port}` };
+    if (proxy.username) {
+        const credentials = `${decodeURIComponent(proxy.username)}:${decodeURIComponent(proxy.password)}`;
+        headers['Proxy-Authorization'] = `Basic ${Buffer.from(credentials).toString('base64')}`;
+    }
+
+    return new Promise((resolve, reject) => {
+        const req = http.request({
+            method: 'CONNECT',
+            hostname: proxy.hostname,
+            port: proxy.port || 80,
+            path: `${host}:${port}`,
+            headers,
+        });
+        req.on('connect', (res, socket) => {
+            if (res.statusCode === 200) {
+                resolve(socket);
+            } else {
+                socket.destroy();
+                reject(new Error(`Proxy ${proxy.host} refused the tunnel to ${host}: HTTP ${res.statusCode}`));
+            }
+        });
+        req.on('error', (e) => reject(new Error(`Proxy ${proxy.host} unreachable: ${e.message}`)));
+        req.end();
+    });
+}
+
 /**
  * Make an authenticated HTTP request to the Matrix homeserver
  */
@@ -52,13 +208,25 @@ export async function matrixRequest<T>(
     // Pre-stringify body to calculate Content-Length
     const bodyStr = body ? JSON.stringify(body) : undefined;
 
+    const { proxy, tlsOptions } = transport();
+    const port = url.port || (isHttps ? 443 : 80);
+    const tunnel = proxy ? await proxyTunnel(proxy, url.hostname, port) : undefined;
+
     const options: https.RequestOptions = {
         method,
+        ...(isHttps && tlsOptions),
+        ...(tunnel && {
+            agent: false,
+            createConnection: () => isHttps
+                ? tls.connect({ socket: tunnel, servername: url.hostname, ...tlsOptions })
+                : tunnel,
+        }),
         hostname: url.hostname,
-        port: url.port || (isHttps ? 443 : 80),
+        port,
         path: url.pathname + url.search,
         headers: {
-            'Authorization': `Bearer ${config.accessToken}`,
+            // Empty for unauthenticated endpoints (login)
+            ...(config.accessToken && { 'Authorization': `Bearer ${config.accessToken}` }),
             'Content-Type': 'application/json',
             ...(bodyStr && { 'Content-Length': Buffer.byteLength(bodyStr) }),
         },
@@ -82,7 +250,12 @@ export async function matrixRequest<T>(
                     } catch (e) {
                         errorBody = data;
                     }
-                    reject(new Error(`Matrix API error ${res.statusCode}: ${JSON.stringify(errorBody)}`));
+                    const retryAfter = Number(res.headers['retry-after']);
+                    reject(new MatrixApiError(
+                        res.statusCode ?? 0,
+                        errorBody,
+                        Number.isFinite(retryAfter) ? retryAfter * 1000 : undefined,
+                    ));
                 }
             });
         });
@@ -96,6 +269,66 @@ export async function matrixRequest<T>(
     });
 }
 
+/**
+ * Find the client API base URL of a server name via .well-known/matrix/client
+ *
+ * Falls back to https://<server name> when the server publishes no
+ * .well-known, and checks the result answers /_matrix/client/versions.
+ */
+export async function discoverHomeserver(serverName: string): Promise<string> {
+    const serverUrl = /^https?:\/\//.test(serverName) ? serverName : `https://${serverName}`;
+
+    let baseUrl = serverUrl;
+    try {
+        const wellKnown = await matrixRequest<{ 'm.homeserver'?: { base_url?: string } }>(
+            { homeserverUrl: serverUrl, accessToken: '' },
+            'GET',
+            '/.well-known/matrix/client'
+        );
+        const discovered = wellKnown?.['m.homeserver']?.base_url;
+        if (typeof discovered !== 'string' || !discovered) {
+            throw new Error(`.well-known/matrix/client of ${serverName} has no m.homeserver.base_url`);
+        }
+        baseUrl = discovered.replace(/\/+$/, '');
+    } catch (e) {
+        if (!(e instanceof MatrixApiError && e.statusCode === 404)) {
+            throw e;
+        }
+    }
+
+    try {
+        await matrixRequest({ homeserverUrl: baseUrl, accessToken: '' }, 'GET', '/_matrix/client/versions');
+    } catch (e) {
+        throw new Error(`${baseUrl} (discovered for ${serverName}) is not a Matrix homeserver: ${(e as Error).message}`);
+    }
+    return baseUrl;
+}
+
+/**
+ * Log in with a password, returning the new access token and device
+ *
+ * With `deviceId`, the server reuses that device (and its keys) instead of
+ * creating a new one.
+ */
+export async function loginWithPassword(
+    homeserverUrl: string,
+    user: string,
+    password: string,
+    deviceId?: string
+): Promise<{ user_id: string; access_token: string; device_id: string }> {
+    return matrixRequest(
+        { homeserverUrl, accessToken: '' },
+        'POST',
+        '/_matrix/client/v3/login',
+        {
+            type: 'm.login.password',
+            identifier: { type: 'm.id.user', user },
+            password,
+            ...(deviceId ? { device_id: deviceId } : { initial_device_display_name: 'sled-migration-tool' }),
+        }
+    );
+}
+
 /**
  * Get the current user's ID
  */
@@ -185,6 +418,30 @@ export async function uploadRoomKeysBatch(
     );
 }
 
+/**
+ * Get the backed up keys of one room
+ */
+export async function getBackupRoomKeys(
+    config: MatrixApiConfig,
+    version: string,
+    roomId: string
+): Promise<Record<string, RoomKeyBackup>> {
+    try {
+        const response = await matrixRequest<{ sessions: Record<string, RoomKeyBackup> }>(
+            config,
+            'GET',
+            `/_matrix/client/v3/room_keys/keys/${encodeURIComponent(roomId)}?version=${version}`
+        );
+        return response.sessions ?? {};
+    } catch (e) {
+        const error = e as Error;
+        if (error.message.includes('404')) {
+            return {};
+        }
+        throw e;
+    }
+}
+
 /**
  * Get the count of keys in the backup
  */
@@ -0,0 +1,122 @@
+/**
+ * Recovery Key Utilities
+ *
+ * Encoding, decoding and key derivation for Matrix key backup recovery keys.
+ *
+ * A recovery key is the 32-byte backup private key (the Curve25519 seed of
+ * m.megolm_backup.v1.curve25519-aes-sha2) with a two-byte prefix and a parity
+ * byte, Base58 encoded and split into groups of four characters:
+ *
+ *   [0x8b, 0x01] + 32-byte key + XOR of all previous bytes
+ *
+ * Reference: Matrix spec, "Recovery key" under Secret Storage
+ */
+
+import * as crypto from 'crypto';
+import bs58 from 'bs58';
+import { BackupDecryptionKey } from '@ixo/matrix-sdk-crypto-nodejs';
+
+/** Prefix of version 1 recovery keys */
+const RECOVERY_KEY_PREFIX = Buffer.from([0x8b, 0x01]);
+
+/** Length of the backup private key */
+const KEY_LENGTH = 32;
+
+export interface BackupKeyPair {
+    /** Base58 recovery key with spaces (human-readable) */
+    recoveryKey: string;
+    /** The backup private key as Base64 (for config) */
+    recoveryKeyBase64: string;
+    /** The Curve25519 public key the server backup is created with */
+    publicKey: string;
+}
+
+/**
+ * XOR of all bytes, used as the recovery key parity byte
+ */
+function parity(bytes: Buffer): number {
+    let result = 0;
+    for (const byte of bytes) {
+        result ^= byte;
+    }
+    return result;
+}
+
+/**
+ * Encode a backup private key as a recovery key
+ */
+export function encodeRecoveryKey(privateKey: Buffer): string {
+    if (privateKey.length !== KEY_LENGTH) {
+        throw new Error(`Backup key must be ${KEY_LENGTH} bytes, got ${privateKey.length}`);
+    }
+
+    const withoutParity = Buffer.concat([RECOVERY_KEY_PREFIX, privateKey]);
+    const bytes = Buffer.concat([withoutParity, Buffer.from([parity(withoutParity)])]);
+    const encoded = bs58.encode(bytes);
+
+    return encoded.match(/.{1,4}/g)?.join(' ') || encoded;
+}
+
+/**
+ * Decode a recovery key into the backup private key
+ *
+ * Whitespace is ignored. Throws with the reason if the key is malformed.
+ */
+export function decodeRecoveryKey(recoveryKey: string): Buffer {
+    const compact = recoveryKey.replace(/\s+/g, '');
+
+    let bytes: Buffer;
+    try {
+        bytes = Buffer.from(bs58.decode(compact));
+    } catch (e) {
+        throw new Error('Recovery key contains characters that are not Base58');
+    }
+
+    const expectedLength = RECOVERY_KEY_PREFIX.length + KEY_LENGTH + 1;
+    if (bytes.length !== expectedLength) {
+        throw new Error(`Recovery key decodes to ${bytes.length} bytes, expected ${expectedLength}`);
+    }
+    if (!bytes.subarray(0, RECOVERY_KEY_PREFIX.length).equals(RECOVERY_KEY_PREFIX)) {
+        throw new Error('Recovery key has the wrong prefix - is this a recovery phrase?');
+    }
+    if (parity(bytes.subarray(0, bytes.length - 1)) !== bytes[bytes.length - 1]) {
+        throw new Error('Recovery key parity check failed - check for typos');
+    }
+
+    return bytes.subarray(RECOVERY_KEY_PREFIX.length, RECOVERY_KEY_PREFIX.length + KEY_LENGTH);
+}
+
+/**
+ * Read a backup private key given as a recovery key or as Base64
+ */
+export function parseBackupKey(input: string): Buffer {
+    const trimmed = input.trim();
+    const base64 = Buffer.from(trimmed, 'base64');
+    if (/^[A-Za-z0-9+/]{43}=?$/.test(trimmed) && base64.length === KEY_LENGTH) {
+        return base64;
+    }
+    return decodeRecoveryKey(trimmed);
+}
+
+/**
+ * Derive the recovery key and public key of a backup private key
+ */
+export function keyPairFromPrivateKey(privateKey: Buffer): BackupKeyPair {
+    const recoveryKeyBase64 = privateKey.toString('base64');
+
+    // The Rust SDK derives the Curve25519 public key from the seed
+    const decryptionKey = BackupDecryptionKey.fromBase64(recoveryKeyBase64);
+
+    return {
+        recoveryKey: encodeRecoveryKey(privateKey),
+        recoveryKeyBase64,
+        publicKey: decryptionKey.megolmV1PublicKey.publicKeyBase64,
+    };
+}
+
+/**
+ * Generate a new backup key pair
+ */
+export function generateKeyPair(): BackupKeyPair {
+    return keyPairFromPrivateKey(crypto.randomBytes(KEY_LENGTH));
+}
@@ -9,6 +9,9 @@
  * - HKDF-SHA256 for SSSS key → AES + HMAC key derivation
  * - AES-CTR for decryption, HMAC-SHA256 for verification
  *
+ * The secret storage key can also be given directly, as a recovery key in the
+ * same Base58 format as the backup recovery key.
+ *
  * Reference: matrix-js-sdk implementations:
  * - src/crypto-api/key-passphrase.ts (PBKDF2)
  * - src/utils/internal/deriveKeys.ts (HKDF)
@@ -17,6 +20,7 @@
 
 import * as crypto from 'crypto';
 import { getAccountData, MatrixApiConfig } from './matrix-api';
+import { decodeRecoveryKey } from './recovery-key';
 
 // Interfaces matching Matrix spec account_data event formats
 
@@ -239,13 +243,33 @@ export async function verifySSSSKey(
     return expected === actual;
 }
 
+/**
+ * What unlocks secret storage: the passphrase it was set up with, or the
+ * secret storage key itself (decoded from its recovery key)
+ */
+export type SSSSCredentials = { passphrase: string } | { key: Uint8Array };
+
+/**
+ * Secret storage credentials from SSSS_RECOVERY_KEY or RECOVERY_PHRASE, if set
+ */
+export function ssssCredentialsFromEnv(): SSSSCredentials | null {
+    if (process.env.SSSS_RECOVERY_KEY) {
+        return { key: Uint8Array.from(decodeRecoveryKey(process.env.SSSS_RECOVERY_KEY)) };
+    }
+    if (process.env.RECOVERY_PHRASE) {
+        return { passphrase: process.env.RECOVERY_PHRASE };
+    }
+    return null;
+}
+
 /**
  * Extract the key backup decryption key from SSSS.
  *
  * This performs the full chain:
  * 1. Fetch SSSS default key ID from account_data
  * 2. Fetch SSSS key metadata (salt, iterations)
- * 3. Derive SSSS master key from passphrase (PBKDF2-SHA512)
+ * 3. Derive SSSS master key from passphrase (PBKDF2-SHA512), unless the key
+ *    itself was given
  * 4. Verify key against stored MAC
  * 5. Fetch encrypted backup key from account_data
  * 6. Decrypt using SSSS
@@ -255,7 +279,7 @@ export async function verifySSSSKey(
 export async function extractBackupKeyFromSSS(
     apiConfig: MatrixApiConfig,
     userId: string,
-    recoveryPhrase: string,
+    credentials: SSSSCredentials,
 ): Promise<string | null> {
     // 1. Get default SSSS key ID
     const defaultKeyData = await getAccountData(apiConfig, userId, 'm.secret_storage.default_key');
@@ -278,24 +302,29 @@ export async function extractBackupKeyFromSSS(
         throw new Error(`Unsupported SSSS algorithm: ${keyInfo.algorithm}`);
     }
 
-    if (!keyInfo.passphrase || keyInfo.passphrase.algorithm !== 'm.pbkdf2') {
-        throw new Error('SSSS key is not passphrase-based (no PBKDF2 info). Cannot derive from recovery phrase.');
-    }
-
     // 3. Derive SSSS master key from passphrase
-    const masterKey = await deriveSSSSKeyFromPassphrase(
-        recoveryPhrase,
-        keyInfo.passphrase.salt,
-        keyInfo.passphrase.iterations,
-        keyInfo.passphrase.bits || 256,
-    );
+    let masterKey: Uint8Array;
+    if ('key' in credentials) {
+        masterKey = credentials.key;
+    } else {
+        if (!keyInfo.passphrase || keyInfo.passphrase.algorithm !== 'm.pbkdf2') {
+            throw new Error(
+                'SSSS key is not passphrase-based (no PBKDF2 info). Use the secret storage recovery key instead.',
+            );
+        }
+        masterKey = await deriveSSSSKeyFromPassphrase(
+            credentials.passphrase,
+            keyInfo.passphrase.salt,
+            keyInfo.passphrase.iterations,
+            keyInfo.passphrase.bits || 256,
+        );
+    }
 
     // 4. Verify key
     const keyValid = await verifySSSSKey(masterKey, keyInfo);
     if (!keyValid) {
-        throw new Error('SSSS key verification failed: recovery phrase does not match');
+        throw new Error('SSSS key verification failed: recovery phrase or key does not match');
     }
-
     // 5. Fetch encrypted backup key
     const backupSecret: SSSSSecretData | null = await getAccountData(
         apiConfig,